@@ -1,83 +1,118 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
-use kvsserver::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{BatchSize, Bencher, Criterion, ParameterizedBenchmark};
+use kvsserver::{open_engine, EngineOptions, KvsEngine};
 use rand::prelude::*;
-use sled::Db;
 use std::iter;
+use std::ops::Bound::{Excluded, Included};
+use std::path::Path;
 use tempfile::TempDir;
 
+/// the benched engines: a label, the registered engine and its options
+fn engines() -> Vec<(&'static str, &'static str, EngineOptions)> {
+    let mut mmap = EngineOptions::new();
+    mmap.set("mmap", "true");
+    vec![
+        ("kvs", "kvs", EngineOptions::new()),
+        ("kvs_mmap", "kvs", mmap),
+        ("sled", "sled", EngineOptions::new()),
+        ("lsm", "lsm", EngineOptions::new()),
+        ("btree", "btree", EngineOptions::new()),
+    ]
+}
+
+fn open(name: &str, path: &Path, options: &EngineOptions) -> Box<dyn KvsEngine + Send> {
+    open_engine(name, path, options).unwrap()
+}
+
+/// set `keys` keys and compact, so lookups hit the immutable files of the engine
+fn filled(name: &str, path: &Path, options: &EngineOptions, keys: u32) -> Box<dyn KvsEngine + Send> {
+    let mut store = open(name, path, options);
+    for key_i in 0..keys {
+        store
+            .set(format!("key{:08}", key_i), "value".to_string())
+            .unwrap();
+    }
+    store.compact().unwrap();
+    store
+}
+
 fn set_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, _| {
+    let mut engines = engines().into_iter().filter(|(label, _, _)| *label != "kvs_mmap");
+    let bench_fn = |name: &'static str, options: EngineOptions| {
+        move |b: &mut Bencher, _: &()| {
             b.iter_batched(
                 || {
                     let temp_dir = TempDir::new().unwrap();
-                    KvStore::open(temp_dir.path()).unwrap()
+                    (open(name, temp_dir.path(), &options), temp_dir)
                 },
-                |mut store| {
+                |(mut store, _temp_dir)| {
                     for i in 1..(1 << 12) {
                         store.set(format!("key{}", i), "value".to_string()).unwrap();
                     }
                 },
                 BatchSize::SmallInput,
             )
-        },
-        iter::once(()),
-    )
-    .with_function("sled", |b, _| {
-        b.iter_batched(
-            || {
-                let temp_dir = TempDir::new().unwrap();
-                SledKvsEngine::new(Db::start_default(&temp_dir).unwrap())
-            },
-            |mut db| {
-                for i in 1..(1 << 12) {
-                    db.set(format!("key{}", i), "value".to_string()).unwrap();
-                }
-            },
-            BatchSize::SmallInput,
-        )
-    });
+        }
+    };
+    let (label, name, options) = engines.next().unwrap();
+    let mut bench = ParameterizedBenchmark::new(label, bench_fn(name, options), iter::once(()));
+    for (label, name, options) in engines {
+        bench = bench.with_function(label, bench_fn(name, options));
+    }
     c.bench("set_bench", bench);
 }
 
 fn get_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, i| {
+    let mut engines = engines().into_iter();
+    let bench_fn = |name: &'static str, options: EngineOptions| {
+        move |b: &mut Bencher, i: &u32| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
-            for key_i in 1..(1 << i) {
-                store
-                    .set(format!("key{}", key_i), "value".to_string())
-                    .unwrap();
-            }
+            let mut store = filled(name, temp_dir.path(), &options, 1 << i);
             let mut rng = SmallRng::from_seed([0; 16]);
             b.iter(|| {
                 store
-                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .get(format!("key{:08}", rng.gen_range(0, 1 << i)))
                     .unwrap();
             })
-        },
-        vec![8, 12, 16, 20],
-    )
-    .with_function("sled", |b, i| {
-        let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::new(Db::start_default(&temp_dir).unwrap());
-        for key_i in 1..(1 << i) {
-            db.set(format!("key{}", key_i), "value".to_string())
-                .unwrap();
         }
-        let mut rng = SmallRng::from_seed([0; 16]);
-        b.iter(|| {
-            db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
-        })
-    });
+    };
+    let (label, name, options) = engines.next().unwrap();
+    let mut bench = ParameterizedBenchmark::new(label, bench_fn(name, options), vec![8, 12, 16, 20]);
+    for (label, name, options) in engines {
+        bench = bench.with_function(label, bench_fn(name, options));
+    }
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
+/// scan 100 consecutive keys out of 2^16, engines keeping their keys sorted only read the range
+fn scan_bench(c: &mut Criterion) {
+    let mut engines = engines().into_iter().filter(|(label, _, _)| *label != "kvs_mmap");
+    let bench_fn = |name: &'static str, options: EngineOptions| {
+        move |b: &mut Bencher, _: &()| {
+            let temp_dir = TempDir::new().unwrap();
+            let mut store = filled(name, temp_dir.path(), &options, 1 << 16);
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                let start = rng.gen_range(0, (1 << 16) - 100);
+                let pairs = store
+                    .scan(
+                        Included(format!("key{:08}", start)),
+                        Excluded(format!("key{:08}", start + 100)),
+                    )
+                    .unwrap();
+                assert_eq!(pairs.len(), 100);
+            })
+        }
+    };
+    let (label, name, options) = engines.next().unwrap();
+    let mut bench = ParameterizedBenchmark::new(label, bench_fn(name, options), iter::once(()));
+    for (label, name, options) in engines {
+        bench = bench.with_function(label, bench_fn(name, options));
+    }
+    c.bench("scan_bench", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench, scan_bench);
 criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Heap bytes per key of the KvStore index in each mode, measured with a
+//! counting allocator: `cargo bench --bench index_memory`
+
+use kvsserver::{IndexMode, KvStore, KvStoreConfig, KvsEngine};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+const KEYS : usize = 200_000;
+
+struct Counting;
+
+static ALLOCATED : AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL : Counting = Counting;
+
+fn measure(name : &str, index_mode : IndexMode, sorted_index : bool) {
+    let temp_dir = TempDir::new().unwrap();
+    let config = KvStoreConfig {
+        index_mode,
+        sorted_index,
+        // a single compaction at the end
+        compaction_threshold : u64::max_value(),
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone()).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("user:{:012}", i * 7919), "value".to_owned()).unwrap();
+    }
+    store.compact().unwrap();
+    drop(store);
+
+    // the index is rebuilt when the store is reopened
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let store = KvStore::with_config(temp_dir.path(), config).unwrap();
+    let after = ALLOCATED.load(Ordering::SeqCst);
+    println!("{:<16} {:>8.1} bytes per key", name, (after - before) as f64 / KEYS as f64);
+    drop(store);
+}
+
+fn main() {
+    measure("tree", IndexMode::Tree, false);
+    measure("prefix", IndexMode::Prefix, false);
+    measure("hashed", IndexMode::Hashed, false);
+    measure("hashed+sorted", IndexMode::Hashed, true);
+}
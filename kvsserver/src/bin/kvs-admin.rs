@@ -0,0 +1,301 @@
+#[macro_use]
+extern crate log;
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use log::LevelFilter;
+
+use kvsserver::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+const STAGING_DIR : &str = "migrate.tmp";
+const DUMP_FILE : &str = "migrate.dump";
+const NEW_KEY_ENV : &str = "KVS_NEW_ENCRYPTION_KEY";
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let names = engine_names();
+    let names : Vec<&str> = names.iter().map(String::as_str).collect();
+    let engine_arg = |name : &'static str, long : &'static str| {
+        Arg::with_name(name)
+            .long(long)
+            .takes_value(true)
+            .required(true)
+            .possible_values(&names)
+            .help("engine of the data directory")
+    };
+    let dir_arg = || Arg::with_name("DIR").help("data directory").required(true);
+
+    let matches = App::new("kvs-admin")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("dump every pair of the data directory")
+                .arg(engine_arg("ENGINE", "engine"))
+                .arg(Arg::with_name("FORMAT")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "binary"])
+                        .help("dump format, json lines by default")
+                )
+                .arg(dir_arg())
+                .arg(Arg::with_name("FILE").help("the dump file").required(true))
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("set every pair of a dump in the data directory")
+                .arg(engine_arg("ENGINE", "engine"))
+                .arg(dir_arg())
+                .arg(Arg::with_name("FILE").help("the dump file").required(true))
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("copy the data directory to another engine and switch the engine marker")
+                .arg(engine_arg("FROM", "from"))
+                .arg(engine_arg("TO", "to"))
+                .arg(dir_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about("rewrite the kvs data directory under a new encryption key")
+                .arg(Arg::with_name("KEY_FILE")
+                        .long("key-file")
+                        .takes_value(true)
+                        .help("file of the current key, KVS_ENCRYPTION_KEY otherwise")
+                )
+                .arg(Arg::with_name("NEW_KEY_FILE")
+                        .long("new-key-file")
+                        .takes_value(true)
+                        .conflicts_with("DECRYPT")
+                        .help("file of the new key, KVS_NEW_ENCRYPTION_KEY otherwise")
+                )
+                .arg(Arg::with_name("DECRYPT")
+                        .long("decrypt")
+                        .help("rewrite the data directory in plaintext")
+                )
+                .arg(Arg::with_name("ALLOW_PLAINTEXT")
+                        .long("allow-plaintext")
+                        .help("also read the records and blobs written before encryption was enabled")
+                )
+                .arg(dir_arg())
+        )
+        .get_matches();
+
+    if let Err(err) = run(matches) {
+        eprintln!("error occured : {}", err);
+        exit(1);
+    }
+}
+
+fn run(matches : ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("export", Some(matches)) => {
+            let mut engine = open_dir_engine(matches.value_of("ENGINE").unwrap(), matches.value_of("DIR").unwrap())?;
+            let format = matches.value_of("FORMAT").unwrap_or("json").parse()?;
+            let mut writer = BufWriter::new(File::create(matches.value_of("FILE").unwrap())?);
+            let count = engine.export(&mut writer, format)?;
+            writer.get_ref().sync_all()?;
+            info!("exported {} pairs", count);
+        },
+        ("import", Some(matches)) => {
+            let mut engine = open_dir_engine(matches.value_of("ENGINE").unwrap(), matches.value_of("DIR").unwrap())?;
+            let mut reader = BufReader::new(File::open(matches.value_of("FILE").unwrap())?);
+            let count = engine.import(&mut reader)?;
+            info!("imported {} pairs", count);
+        },
+        ("migrate", Some(matches)) => {
+            migrate(
+                matches.value_of("FROM").unwrap(),
+                matches.value_of("TO").unwrap(),
+                Path::new(matches.value_of("DIR").unwrap()),
+            )?;
+        },
+        ("rekey", Some(matches)) => {
+            let key = match matches.value_of("KEY_FILE") {
+                Some(path) => Some(EncryptionKey::from_file(path.as_ref())?),
+                None => EncryptionKey::from_env(EncryptionKey::ENV)?,
+            };
+            let new_key = if matches.is_present("DECRYPT") {
+                None
+            } else {
+                let new_key = match matches.value_of("NEW_KEY_FILE") {
+                    Some(path) => Some(EncryptionKey::from_file(path.as_ref())?),
+                    None => EncryptionKey::from_env(NEW_KEY_ENV)?,
+                };
+                Some(new_key.ok_or_else(|| KvsError::StringError("no new key, use --new-key-file or --decrypt".to_owned()))?)
+            };
+            let config = KvStoreConfig {
+                encryption_key : key,
+                allow_plaintext : matches.is_present("ALLOW_PLAINTEXT"),
+                ..KvStoreConfig::default()
+            };
+            let mut store = KvStore::with_config(matches.value_of("DIR").unwrap(), config)?;
+            store.rekey(new_key)?;
+            info!("rekeyed {}", matches.value_of("DIR").unwrap());
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// open the engine in the data directory, a memory engine works on its snapshot
+fn open_dir_engine<P : AsRef<Path>>(engine : &str, path : P) -> Result<Box<dyn KvsEngine + Send>> {
+    let mut options = EngineOptions::new();
+    options.set("snapshot", "true");
+    open_engine(engine, path.as_ref(), &options)
+}
+
+/// files of the engine in the data directory
+fn engine_files(engine : &str, dir : &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let owned = match engine {
+            "kvs" => name == "sorted.index" || path.extension().map_or(false, |ext| ext == "log" || ext == "blob"),
+            "sled" => name == "conf" || name == "db" || name == "blobs" || name.starts_with("snap."),
+            "lsm" => name == "MANIFEST" || name == "MANIFEST.tmp" || path.extension().map_or(false, |ext| ext == "sst" || ext == "wal"),
+            "btree" => name == "btree.db",
+            "memory" => name == "mem.snapshot",
+            _ => false,
+        };
+        if owned {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn remove_path(path : &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// fsync the directory so that the renames and removals in it are durable
+fn sync_dir(dir : &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// fsync the file, or the directory with everything in it
+fn sync_tree(path : &Path) -> Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
+        }
+    }
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Copy every pair and secondary index into a staging directory through a binary dump,
+/// verify the copy, move the new files in and rewrite the engine marker. The marker
+/// is the commit point, the files of the old engine are only removed once it names
+/// the new one. An interrupted migration is finished if the marker was rewritten,
+/// otherwise the old engine is still complete and the migration starts over.
+fn migrate(from : &str, to : &str, dir : &Path) -> Result<()> {
+    if from == to {
+        return Err(KvsError::StringError("source and target engine are the same".to_owned()));
+    }
+    let staging = dir.join(STAGING_DIR);
+    let dump_path = dir.join(DUMP_FILE);
+    let interrupted = staging.exists() || dump_path.exists();
+    match EngineDescriptor::read(dir)? {
+        Some(ref descriptor) if descriptor.engine == to && interrupted => {
+            info!("finishing the interrupted migration of {} to {}", dir.display(), to);
+            return finish_migration(from, dir);
+        },
+        Some(ref descriptor) if descriptor.engine != from => {
+            return Err(KvsError::StringError(format!("the data directory isn't a {} directory", from)));
+        },
+        _ => {},
+    }
+
+    if interrupted {
+        // the marker wasn't rewritten, the files of the new engine are discarded
+        info!("restarting the interrupted migration of {} to {}", dir.display(), to);
+        for path in engine_files(to, dir)? {
+            remove_path(&path)?;
+        }
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+    }
+    fs::create_dir(&staging)?;
+
+    {
+        let mut source = open_dir_engine(from, dir)?;
+        let mut writer = BufWriter::new(File::create(&dump_path)?);
+        let exported = source.export(&mut writer, DumpFormat::Binary)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        let mut target = open_dir_engine(to, &staging)?;
+        let imported = target.import(&mut BufReader::new(File::open(&dump_path)?))?;
+        if imported != exported {
+            return Err(KvsError::StringError(format!("exported {} pairs but imported {}", exported, imported)));
+        }
+        let indexes = source.indexes()?;
+        for (name, keyspace, pointer) in indexes.iter().cloned() {
+            target.create_index(name, keyspace, pointer)?;
+        }
+
+        // verify the copy against the source
+        let keys = source.keys()?;
+        if keys.len() as u64 != exported || target.keys()?.len() != keys.len() {
+            return Err(KvsError::StringError("the number of keys differs after the copy".to_owned()));
+        }
+        for key in keys {
+            if source.get(key.clone())? != target.get(key.clone())? {
+                return Err(KvsError::StringError(format!("value of {:?} differs after the copy", key)));
+            }
+        }
+        if target.indexes()? != indexes {
+            return Err(KvsError::StringError("the secondary indexes differ after the copy".to_owned()));
+        }
+        info!("copied and verified {} pairs and {} secondary indexes", exported, indexes.len());
+    }
+
+    // the files of the two engines never have the same names
+    sync_tree(&staging)?;
+    for entry in fs::read_dir(&staging)? {
+        let path = entry?.path();
+        fs::rename(&path, dir.join(path.file_name().expect("entry without name")))?;
+    }
+    sync_dir(dir)?;
+    EngineDescriptor::new(to).write(dir)?;
+    sync_dir(dir)?;
+    finish_migration(from, dir)?;
+
+    info!("migrated {} from {} to {}", dir.display(), from, to);
+    Ok(())
+}
+
+/// remove the files of the old engine, the staging directory and the dump
+/// once the engine marker names the new engine
+fn finish_migration(from : &str, dir : &Path) -> Result<()> {
+    for path in engine_files(from, dir)? {
+        remove_path(&path)?;
+    }
+    let staging = dir.join(STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let dump_path = dir.join(DUMP_FILE);
+    if dump_path.exists() {
+        fs::remove_file(&dump_path)?;
+    }
+    sync_dir(dir)?;
+    Ok(())
+}
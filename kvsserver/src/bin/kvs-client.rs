@@ -22,6 +22,12 @@ fn main() {
                         .value_name("SET_TIMES")
                         .help("set the set times to test the performance")
                 )
+                .arg(Arg::with_name("TTL")
+                        .long("ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .help("expire the pair after the seconds, needs a server in cache mode")
+                )
                 .arg(Arg::with_name("ADDR")
                         .long("addr")
                         .takes_value(true)
@@ -33,6 +39,12 @@ fn main() {
             SubCommand::with_name("get")
                 .about("get the value from key")
                 .arg(Arg::with_name("KEY").help("a string key").required(true))
+                .arg(Arg::with_name("VERSION")
+                        .long("version")
+                        .takes_value(true)
+                        .value_name("VERSION")
+                        .help("get the value at a previous version")
+                )
                 .arg(Arg::with_name("ADDR")
                         .long("addr")
                         .takes_value(true)
@@ -51,6 +63,94 @@ fn main() {
                         .help("server address like (HOST|IP):ADDR")
                 )
         )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("list the kept versions of the key")
+                .arg(Arg::with_name("KEY").help("a string key").required(true))
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .about("add a delta to the integer value of the key")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("KEY").help("a string key").required(true))
+                .arg(Arg::with_name("DELTA").help("the integer to add, 1 by default"))
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .about("append a string to the value of the key")
+                .arg(Arg::with_name("KEY").help("a string key").required(true))
+                .arg(Arg::with_name("VALUE").help("the string to append").required(true))
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("create-index")
+                .about("index the JSON values of a keyspace by a field")
+                .arg(Arg::with_name("NAME").help("name of the index").required(true))
+                .arg(Arg::with_name("KEYSPACE").help("prefix of the indexed keys").required(true))
+                .arg(Arg::with_name("POINTER").help("JSON pointer of the field, like /address/city").required(true))
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("drop-index")
+                .about("remove an index")
+                .arg(Arg::with_name("NAME").help("name of the index").required(true))
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("list the keys whose indexed field matches, values are JSON or plain strings")
+                .arg(Arg::with_name("INDEX").help("name of the index").required(true))
+                .arg(Arg::with_name("VALUE")
+                        .help("the value of the field")
+                        .required_unless_one(&["FROM", "TO"])
+                        .conflicts_with_all(&["FROM", "TO"])
+                )
+                .arg(Arg::with_name("FROM")
+                        .long("from")
+                        .takes_value(true)
+                        .value_name("VALUE")
+                        .help("least value of the field")
+                )
+                .arg(Arg::with_name("TO")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("VALUE")
+                        .help("greatest value of the field")
+                )
+                .arg(Arg::with_name("ADDR")
+                        .long("addr")
+                        .takes_value(true)
+                        .value_name("IPADDR")
+                        .help("server address like (HOST|IP):ADDR")
+                )
+        )
         .get_matches();
 
 
@@ -77,15 +177,33 @@ fn run(matches : ArgMatches) -> Result<()> {
             let mut kvs_client = KvsClient::new(addr)?;
             let key = matches.value_of("KEY").expect("Key is not setted");
             let value = matches.value_of("VALUE").expect("Value is not setted");
+            let ttl = match matches.value_of("TTL") {
+                Some(ttl) => Some(ttl
+                    .parse::<u64>()
+                    .map_err(|_| KvsError::StringError("TTL should be an integer".to_owned()))?),
+                None => None,
+            };
             for i in 0..times {
-                kvs_client.set(key.to_string(), value.to_string())?;
+                match ttl {
+                    Some(ttl) => kvs_client.set_with_ttl(key.to_string(), value.to_string(), ttl)?,
+                    None => kvs_client.set(key.to_string(), value.to_string())?,
+                }
             }
         },
         ("get", Some(matches)) => {
             let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
             let mut kvs_client = KvsClient::new(addr)?;
             let key = matches.value_of("KEY").expect("Value is empty");
-            match kvs_client.get(key.to_string())? {
+            let value = match matches.value_of("VERSION") {
+                Some(version) => {
+                    let version = version
+                        .parse::<u64>()
+                        .map_err(|_| KvsError::StringError("VERSION should be an integer".to_owned()))?;
+                    kvs_client.get_version(key.to_string(), version)?
+                },
+                None => kvs_client.get(key.to_string())?,
+            };
+            match value {
                 Some(value) => println!("{}", value),
                 None => println!("Key not found"),
             }
@@ -96,8 +214,68 @@ fn run(matches : ArgMatches) -> Result<()> {
             let key = matches.value_of("KEY").expect("Value is empty");
             kvs_client.remove(key.to_string())?;           
         },
+        ("history", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let key = matches.value_of("KEY").expect("Key is not setted");
+            let mut kvs_client = KvsClient::new(addr)?;
+            for (version, value) in kvs_client.history(key.to_string())? {
+                match value {
+                    Some(value) => println!("{}\t{}", version, value),
+                    None => println!("{}\t(removed)", version),
+                }
+            }
+        },
+        ("incr", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let key = matches.value_of("KEY").expect("Key is not setted");
+            let delta = matches
+                .value_of("DELTA")
+                .unwrap_or("1")
+                .parse::<i64>()
+                .map_err(|_| KvsError::StringError("DELTA should be an integer".to_owned()))?;
+            let mut kvs_client = KvsClient::new(addr)?;
+            println!("{}", kvs_client.incr(key.to_string(), delta)?);
+        },
+        ("append", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let key = matches.value_of("KEY").expect("Key is not setted");
+            let value = matches.value_of("VALUE").expect("Value is not setted");
+            let mut kvs_client = KvsClient::new(addr)?;
+            println!("{}", kvs_client.append(key.to_string(), value.to_string())?);
+        },
+        ("create-index", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let name = matches.value_of("NAME").expect("Name is not setted");
+            let keyspace = matches.value_of("KEYSPACE").expect("Keyspace is not setted");
+            let pointer = matches.value_of("POINTER").expect("Pointer is not setted");
+            let mut kvs_client = KvsClient::new(addr)?;
+            kvs_client.create_index(name.to_string(), keyspace.to_string(), pointer.to_string())?;
+        },
+        ("drop-index", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let name = matches.value_of("NAME").expect("Name is not setted");
+            let mut kvs_client = KvsClient::new(addr)?;
+            kvs_client.drop_index(name.to_string())?;
+        },
+        ("query", Some(matches)) => {
+            let addr = matches.value_of("ADDR").unwrap_or("localhost:8900");
+            let index = matches.value_of("INDEX").expect("Index is not setted");
+            let query = match matches.value_of("VALUE") {
+                Some(value) => IndexQuery::Value(json_value(value)),
+                None => IndexQuery::Range(matches.value_of("FROM").map(json_value), matches.value_of("TO").map(json_value)),
+            };
+            let mut kvs_client = KvsClient::new(addr)?;
+            for key in kvs_client.query(index.to_string(), query)? {
+                println!("{}", key);
+            }
+        },
         _ => unreachable!(),
     };
 
     Ok(())
 }
+
+/// parse the argument as JSON, or take it as a string
+fn json_value(arg : &str) -> serde_json::Value {
+    serde_json::from_str(arg).unwrap_or_else(|_| serde_json::Value::String(arg.to_owned()))
+}
@@ -4,22 +4,29 @@ extern crate log;
 use std::env;
 use std::process::exit;
 use std::net::SocketAddr;
-use std::path::PathBuf;
 
 use log::LevelFilter;
 
 use kvsserver::*;
 use clap::{App, AppSettings, Arg};
 
-#[derive(PartialEq, Eq, Debug, Copy, Clone)]
-enum KvsEngineType {
-    kvs,
-    sled,
-}
-
+// kvs-server flags passed on to the engine as options of the same name
+const ENGINE_FLAGS : &[(&str, &str)] = &[
+    ("MAX_VERSIONS", "max-versions"),
+    ("MAX_BYTES", "max-bytes"),
+    ("MAX_KEYS", "max-keys"),
+    ("EVICTION", "eviction"),
+    ("VALUE_CACHE", "value-cache"),
+    ("CACHE_ADMISSION", "cache-admission"),
+    ("BLOB_THRESHOLD", "blob-threshold"),
+    ("COMPRESSION", "compression"),
+    ("INDEX", "index"),
+    ("KEY_FILE", "key-file"),
+];
 
 fn main() -> Result<()> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
+    let engine_help = format!("select engine in ({}), kvs by default", engine_names().join(", "));
     let matches = App::new("kvs-server")
         .version("CARGO_PKG_VERSION")
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -29,10 +36,77 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .help("server address like (HOST|IP):ADDR")
         )
+        .arg(Arg::with_name("PROTOCOL")
+                .long("--protocol")
+                .takes_value(true)
+                .possible_values(&["kvs", "resp", "http"])
+                .help("protocol of the listener, resp for Redis clients, http for curl and browsers, kvs by default")
+        )
         .arg(Arg::with_name("ENGINE")
                 .long("--engine")
                 .takes_value(true)
-                .help("select engine in (KvStore, SledKvStore)")
+                .help(&engine_help)
+        )
+        .arg(Arg::with_name("MAX_VERSIONS")
+                .long("--max-versions")
+                .takes_value(true)
+                .help("previous versions kept for each key by the kvs and memory engines, 0 by default")
+        )
+        .arg(Arg::with_name("MAX_BYTES")
+                .long("--max-bytes")
+                .takes_value(true)
+                .help("run the kvs engine as a cache evicting keys beyond this many live bytes")
+        )
+        .arg(Arg::with_name("MAX_KEYS")
+                .long("--max-keys")
+                .takes_value(true)
+                .help("run the kvs engine as a cache evicting keys beyond this many keys")
+        )
+        .arg(Arg::with_name("EVICTION")
+                .long("--eviction")
+                .takes_value(true)
+                .help("eviction policy of the cache in (lru, lfu, ttl), lru by default")
+        )
+        .arg(Arg::with_name("VALUE_CACHE")
+                .long("--value-cache")
+                .takes_value(true)
+                .help("keep the values of this many hot keys of the kvs engine in memory")
+        )
+        .arg(Arg::with_name("CACHE_ADMISSION")
+                .long("--cache-admission")
+                .takes_value(true)
+                .help("values admitted into the value cache in (all, second-hit), all by default")
+        )
+        .arg(Arg::with_name("BLOB_THRESHOLD")
+                .long("--blob-threshold")
+                .takes_value(true)
+                .help("store values of at least this many bytes in blob files with the kvs engine")
+        )
+        .arg(Arg::with_name("COMPRESSION")
+                .long("--compression")
+                .takes_value(true)
+                .help("compress the records of the kvs engine with (none, deflate, snappy), none by default")
+        )
+        .arg(Arg::with_name("INDEX")
+                .long("--index")
+                .takes_value(true)
+                .help("keep the keys of the kvs engine index as (tree, hashed, prefix), tree by default")
+        )
+        .arg(Arg::with_name("SORTED_INDEX")
+                .long("--sorted-index")
+                .help("move the kvs engine index to a sorted file on compaction")
+        )
+        .arg(Arg::with_name("KEY_FILE")
+                .long("--key-file")
+                .takes_value(true)
+                .help("encrypt the kvs engine with the hex key of the file, KVS_ENCRYPTION_KEY otherwise")
+        )
+        .arg(Arg::with_name("OPTION")
+                .long("--option")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("engine option as NAME=VALUE, like page-size=8192 for the btree engine")
         )
         .arg(Arg::with_name("VERSION")
                 .short("-V")
@@ -51,64 +125,35 @@ fn main() -> Result<()> {
     let bindaddr = matches.value_of("ADDR").unwrap_or("localhost:8900");
     info!("bind address at {}", bindaddr.parse::<SocketAddr>().expect("Error format of ipaddress"));
     
-    let specified_engine = match matches.value_of("ENGINE") {
-        Some("kvs") | None => KvsEngineType::kvs,
-        Some("sled") => KvsEngineType::sled,
-        _ =>  {
-            eprintln!("unknown engine!");
-            exit(1);
+    let engine_name = matches.value_of("ENGINE").unwrap_or("kvs");
+    let mut options = EngineOptions::new();
+    for &(flag, name) in ENGINE_FLAGS {
+        if let Some(value) = matches.value_of(flag) {
+            options.set(name, value);
         }
-    };
-
-    let current_engine = match get_current_engine(env::current_dir()?)? {
-        Some(engine) => {
-            if (engine != specified_engine)
-            {
-                eprintln!("current_engine is different from the specified_engine");
+    }
+    if matches.is_present("SORTED_INDEX") {
+        options.set("sorted-index", "true");
+    }
+    for option in matches.values_of("OPTION").into_iter().flatten() {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => options.set(name, value),
+            _ => {
+                eprintln!("engine option {:?} is not NAME=VALUE", option);
                 exit(1);
-            }
-            engine
-        }
-        None => {
-            std::fs::write(env::current_dir()?.join("engine"), format!("{:?}", specified_engine));
-            specified_engine
-        }
-    };
-
-
-    match current_engine {
-        KvsEngineType::kvs => {
-            // start engine
-            let engine = KvStore::open(env::current_dir()?)?;
-
-            // Start server and listen
-            KvsServer::new(engine).run(bindaddr)?;
-            info!("start engine kvs successsful!");
-        },
-        KvsEngineType::sled => {
-            let engine = SledKvStore::new(env::current_dir()?)?;
-            KvsServer::new(engine).run(bindaddr)?;
-            info!("start engine sled successsful!");
-        },
+            },
+        };
     }
-    
-
-    Ok(())
-}
 
-
-fn get_current_engine<P : Into<PathBuf>> (path : P) -> Result<Option<KvsEngineType>> {
-    let path = path.into();
-    let engine = path.join("engine");
-
-    if !engine.exists() {
-        return Ok(None);
+    let engine = open_data_dir(engine_name, &env::current_dir()?, &options)?;
+    info!("start engine {} successsful!", engine_name);
+    let mut server = KvsServer::new(engine);
+    match matches.value_of("PROTOCOL") {
+        Some("resp") => server.run_resp(bindaddr)?,
+        Some("http") => server.run_http(bindaddr)?,
+        _ => server.run(bindaddr)?,
     }
 
-    match std::fs::read_to_string(engine)?.as_ref() {
-        "kvs" => Ok(Some(KvsEngineType::kvs)),
-        "sled" => Ok(Some(KvsEngineType::sled)),
-        _ => Ok(None)
-    }
+    Ok(())
 }
-
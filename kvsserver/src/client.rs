@@ -1,79 +1,265 @@
-use std::net::{TcpStream, TcpListener, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use serde::Deserialize;
-use serde_json::{self, Serializer};
-use serde_json::de::{Deserializer, IoRead};
+use serde_json::Deserializer;
 use crate::errors::{Result, KvsError};
-use crate::common::{Request, GetResponse, SetResponse, RemoveResponse};
+use crate::common::{LegacyResponse, Request, Response};
+use crate::engine::IndexQuery;
+use crate::protocol::{self, Protocol, MAX_FRAME_SIZE};
+use std::collections::{HashMap, HashSet};
 use std::io::{BufReader, BufWriter, Write};
 
 const RETRY_TIMES : u64 = 100;
 
-/// Use buffered TcpStream with a Deserializer to get the response
-/// from remote server, and Buffered Writer of TcpStreamto send request
+/// Use buffered TcpStream to get the response from remote server,
+/// and Buffered Writer of TcpStream to send request
 pub struct KvsClient {
-    reader : Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader : BufReader<TcpStream>,
     writer : BufWriter<TcpStream>,
+    protocol : Protocol,
     connect_times : u64,
+    next_id : u64,
+    /// ids of the requests sent whose response wasn't waited for
+    pending : HashSet<u64>,
+    /// responses read while waiting for another one
+    received : HashMap<u64, Response>,
+}
+
+/// Handle of a request sent with `KvsClient::send_many`
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestHandle {
+    id : u64,
+}
+
+impl RequestHandle {
+    /// the id of the request, the same in its response
+    pub fn id(&self) -> u64 {
+        self.id
+    }
 }
 
 
 impl KvsClient {
-   /// create new KvsClient and connect to the remote addresss
+   /// create new KvsClient and connect to the remote addresss,
+   /// using the best protocol the server supports
    pub fn new<A : ToSocketAddrs>(addr : A) -> Result<KvsClient> {
-        let mut stream = TcpStream::connect(addr)?;
+        KvsClient::with_protocol(addr, Protocol::Binary)
+    }
+
+    /// create new KvsClient using at best `protocol`, a server which
+    /// predates the handshake is spoken to in the legacy protocol
+    pub fn with_protocol<A : ToSocketAddrs>(addr : A, protocol : Protocol) -> Result<KvsClient> {
+        let addrs : Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let mut client = KvsClient::connect(&addrs[..], Protocol::Legacy)?;
+        if protocol == Protocol::Legacy {
+            return Ok(client);
+        }
+        match protocol::offer(&mut client.reader, &mut client.writer, protocol) {
+            Ok(protocol) => {
+                client.protocol = protocol;
+                Ok(client)
+            },
+            // an older server closes the connection on the handshake
+            Err(KvsError::Io(err)) => {
+                debug!("handshake failed with {}, falling back to the legacy protocol", err);
+                KvsClient::connect(&addrs[..], Protocol::Legacy)
+            },
+            Err(err) => Err(err),
+        }
+    }
 
+    fn connect(addrs : &[SocketAddr], protocol : Protocol) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addrs)?;
         let writer = BufWriter::new(stream.try_clone()?);
-        let reader = Deserializer::new(IoRead::new(BufReader::new(stream)));
+        let reader = BufReader::new(stream);
         Ok(KvsClient {
            reader,
            writer,
+           protocol,
            connect_times : 10,
+           next_id : 1,
+           pending : HashSet::new(),
+           received : HashMap::new(),
         })
     }
-    /// set the key-value pair to the KvStore Engine
-    /// Ok(()) => set the value and receive response successful
-    /// Err(err) => Some error occured
-    pub fn set(&mut self, key : String, value : String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set(key, value))?;
+
+    /// the protocol spoken with the server
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// send the request and read back its result, an error sent by the server
+    /// comes back as the `KvsError` it was
+    fn call(&mut self, request : Request) -> Result<Response> {
+        let handle = self.send(request)?;
         // ensure the request send successful
         self.writer.flush()?;
+        self.wait(handle)
+    }
+
+    /// Send the requests without waiting for their responses, each one is
+    /// read back with `wait` and its handle, in any order. The server may run
+    /// them in any order as well, a request depending on the result of another
+    /// one should be sent once that one is done.
+    /// A server without the handshake gets them one at a time.
+    pub fn send_many<I : IntoIterator<Item = Request>>(&mut self, requests : I) -> Result<Vec<RequestHandle>> {
+        let mut handles = Vec::new();
+        for request in requests {
+            handles.push(self.send(request)?);
+        }
+        self.writer.flush()?;
+        Ok(handles)
+    }
 
-        let response = SetResponse::deserialize(&mut self.reader)?;
+    /// wait for the response of the request sent with `send_many`,
+    /// the responses which come first are kept for their own handles
+    pub fn wait(&mut self, handle : RequestHandle) -> Result<Response> {
+        if !self.pending.remove(&handle.id) {
+            return Err(KvsError::Protocol(format!("request {} wasn't sent by this client", handle.id)));
+        }
+        let response = loop {
+            if let Some(response) = self.received.remove(&handle.id) {
+                break response;
+            }
+            let (id, response) = self.protocol.read(&mut self.reader, MAX_FRAME_SIZE)?;
+            if id == handle.id {
+                break response;
+            }
+            // the server reports a message it couldn't read with id 0
+            if id == 0 {
+                if let Response::Err(code, message) = response {
+                    return Err(KvsError::from_code(code, message));
+                }
+            }
+            if !self.pending.contains(&id) {
+                return Err(KvsError::Protocol(format!("response to unknown request {}", id)));
+            }
+            self.received.insert(id, response);
+        };
         match response {
-            SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(err) => Err(KvsError::StringError(err)),
+            Response::Err(code, message) => Err(KvsError::from_code(code, message)),
+            response => Ok(response),
+        }
+    }
+
+    /// send the request with the next id, the writer isn't flushed
+    fn send(&mut self, request : Request) -> Result<RequestHandle> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.protocol.write(&mut self.writer, id, &request)?;
+        self.pending.insert(id);
+        // the legacy protocol has no ids, its responses are read right away
+        if self.protocol == Protocol::Legacy {
+            self.writer.flush()?;
+            let legacy = LegacyResponse::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
+            self.received.insert(id, Response::from_legacy(&request, legacy)?);
+        }
+        Ok(RequestHandle { id })
+    }
+
+    /// set the key-value pair to the KvStore Engine
+    /// Ok(()) => set the value and receive response successful
+    /// Err(err) => Some error occured
+    pub fn set(&mut self, key : String, value : String) -> Result<()> {
+        match self.call(Request::Set(key, value))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
         }
     }
 
 
+    /// set the key-value pair which expires after `ttl` seconds,
+    /// only supported by engines which can expire keys
+    pub fn set_with_ttl(&mut self, key : String, value : String, ttl : u64) -> Result<()> {
+        match self.call(Request::SetEx(key, value, ttl))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
     /// get the value use key from KvStore Engine
     /// Ok(Some(value)) => get value successful
     /// Ok(None)  => Key not found  
     pub fn get(&mut self, key : String)  -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get(key))?;
-        self.writer.flush()?;
+        match self.call(Request::Get(key))? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// remove the key from the KvStore Engine
+    /// Ok(()) => remove item correct and receive the response
+    /// Err(KvsError::KeyNotFound) => the key doesn't exist
+    pub fn remove(&mut self, key : String) -> Result<()> {
+        match self.call(Request::Remove(key))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// get the value of the key at `version`
+    /// Ok(None) => the key was removed by that version
+    pub fn get_version(&mut self, key : String, version : u64) -> Result<Option<String>> {
+        match self.call(Request::GetVersion(key, version))? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
 
+    /// the kept versions of the key, oldest first
+    /// a `None` value is a removal
+    pub fn history(&mut self, key : String) -> Result<Vec<(u64, Option<String>)>> {
+        match self.call(Request::History(key))? {
+            Response::History(versions) => Ok(versions),
+            response => Err(unexpected(response)),
+        }
+    }
 
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+    /// index the JSON values of the keys starting with `keyspace` by the field at `pointer`
+    pub fn create_index(&mut self, name : String, keyspace : String, pointer : String) -> Result<()> {
+        match self.call(Request::CreateIndex(name, keyspace, pointer))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
 
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(err) => Err(KvsError::StringError(err)),
+    pub fn drop_index(&mut self, name : String) -> Result<()> {
+        match self.call(Request::DropIndex(name))? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
         }
+    }
 
+    /// the keys whose indexed field matches the query
+    pub fn query(&mut self, index : String, query : IndexQuery) -> Result<Vec<String>> {
+        match self.call(Request::Query(index, query))? {
+            Response::Keys(keys) => Ok(keys),
+            response => Err(unexpected(response)),
+        }
     }
 
-    /// remove the key from the KvStore Engine
-    /// Ok(()) => remove item correct and receive the response
-    /// Err(err) => Err occured
-    pub fn remove(&mut self, key : String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove(key))?;
-        self.writer.flush()?;
+    /// add `delta` to the integer value of the key, a missing key counts as 0
+    /// Ok(value) => the value after the increment
+    pub fn incr(&mut self, key : String, delta : i64) -> Result<i64> {
+        let value = self.merge(Request::Incr(key, delta))?;
+        value
+            .parse()
+            .map_err(|_| KvsError::StringError(format!("{:?} is not an integer", value)))
+    }
 
-        let response = RemoveResponse::deserialize(&mut self.reader)?;
-        match response {
-            RemoveResponse::Ok(()) => Ok(()),
-            RemoveResponse::Err(err) => Err(KvsError::StringError(err)),
+    /// append `suffix` to the value of the key
+    /// Ok(value) => the value after the append
+    pub fn append(&mut self, key : String, suffix : String) -> Result<String> {
+        self.merge(Request::Append(key, suffix))
+    }
+
+    fn merge(&mut self, request : Request) -> Result<String> {
+        match self.call(request)? {
+            Response::Merged(value) => Ok(value),
+            response => Err(unexpected(response)),
         }
     }
 }
+
+fn unexpected(response : Response) -> KvsError {
+    KvsError::Protocol(format!("unexpected response {:?}", response))
+}
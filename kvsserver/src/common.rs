@@ -1,33 +1,88 @@
 use serde::{Serialize, Deserialize};
+use crate::engine::IndexQuery;
+use crate::errors::{ErrorCode, KvsError, Result};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Request to the server, each one is answered by a `Response`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Request {
     Get(String),
     Set(String, String),
     Remove(String),
+    /// set a pair which expires after the given seconds
+    SetEx(String, String, u64),
+    Incr(String, i64),
+    Append(String, String),
+    GetVersion(String, u64),
+    History(String),
+    /// define the index named first over the keyspace, by the field at the JSON pointer
+    CreateIndex(String, String, String),
+    DropIndex(String),
+    Query(String, IndexQuery),
 }
 
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+/// Response to every request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    /// the value of `Get` and `GetVersion`
+    Value(Option<String>),
+    /// a request without result succeeded
+    Done,
+    /// the kept versions of the key for `History`, oldest first
+    History(Vec<(u64, Option<String>)>),
+    /// the matching keys of `Query`, ordered by the indexed field
+    Keys(Vec<String>),
+    /// the merged value of `Incr` and `Append`
+    Merged(String),
+    /// the code and message of the error, see `KvsError::from_code`
+    Err(ErrorCode, String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+impl From<KvsError> for Response {
+    fn from(err : KvsError) -> Response {
+        Response::Err(err.code(), err.message())
+    }
+}
+
+impl Response {
+    /// the response in the shape known by clients without the handshake
+    pub(crate) fn into_legacy(self) -> Result<LegacyResponse> {
+        let value = match self {
+            Response::Value(value) => serde_json::to_value(value)?,
+            Response::Done => serde_json::Value::Null,
+            Response::History(versions) => serde_json::to_value(versions)?,
+            Response::Keys(keys) => serde_json::to_value(keys)?,
+            Response::Merged(value) => serde_json::Value::String(value),
+            Response::Err(code, message) => return Ok(LegacyResponse::Err(KvsError::from_code(code, message).to_string())),
+        };
+        Ok(LegacyResponse::Ok(value))
+    }
+
+    /// the response to `request` of a server without the handshake
+    pub(crate) fn from_legacy(request : &Request, legacy : LegacyResponse) -> Result<Response> {
+        let value = match legacy {
+            LegacyResponse::Ok(value) => value,
+            LegacyResponse::Err(message) => return Ok(Response::Err(ErrorCode::Internal, message)),
+        };
+        let response = match request {
+            Request::Get(_) | Request::GetVersion(..) => Response::Value(serde_json::from_value(value)?),
+            Request::Set(..)
+            | Request::SetEx(..)
+            | Request::Remove(_)
+            | Request::CreateIndex(..)
+            | Request::DropIndex(_) => Response::Done,
+            Request::History(_) => Response::History(serde_json::from_value(value)?),
+            Request::Query(..) => Response::Keys(serde_json::from_value(value)?),
+            Request::Incr(..) | Request::Append(..) => Response::Merged(serde_json::from_value(value)?),
+        };
+        Ok(response)
+    }
 }
 
+/// Responses sent before the handshake existed, each request had its own
+/// response type, all of them an `Ok` with the result or an `Err` with the message
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
-    Ok(()),
+pub enum LegacyResponse {
+    Ok(serde_json::Value),
     Err(String),
 }
-
-
-
-
-
-
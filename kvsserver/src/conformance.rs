@@ -0,0 +1,501 @@
+//! A test suite every `KvsEngine` is expected to pass.
+//!
+//! Engines outside of this crate can run it from their own tests:
+//!
+//! ```no_run
+//! use kvsserver::{conformance, KvStore};
+//!
+//! conformance::run(|path| KvStore::open(path)).unwrap();
+//! ```
+//!
+//! The checks panic on a semantic mismatch and return the engine error
+//! when an operation fails unexpectedly.
+
+use crate::{register_merge_operator, IndexQuery, KvsEngine, KvsError, MergeOperand, Result};
+use serde_json::Value;
+use std::fs;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static DIR_COUNTER : AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system temp dir, removed on drop
+struct TestDir {
+    path : PathBuf,
+}
+
+impl TestDir {
+    fn new() -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+        let path = std::env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}-{}",
+            std::process::id(),
+            DIR_COUNTER.fetch_add(1, Ordering::SeqCst),
+            nanos,
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(TestDir { path })
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Run every check against the engine opened by `open`.
+/// `open` is called with an empty directory, and again with the same
+/// directory to check persistence.
+/// Optional features are checked apart, like `secondary_indexes`, `versions` and `expiry`.
+pub fn run<E, F>(open : F) -> Result<()>
+    where E : KvsEngine + Send + 'static,
+          F : Fn(&Path) -> Result<E>,
+{
+    basic_crud(&open)?;
+    overwrite(&open)?;
+    reopen_persistence(&open)?;
+    large_values(&open)?;
+    empty_keys(&open)?;
+    concurrency(&open)?;
+    compaction(&open)?;
+    merge_operators(&open)?;
+    range_scan(&open)?;
+    Ok(())
+}
+
+/// set, get and remove, removing a missing key fails with `KeyNotFound`
+pub fn basic_crud<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None, "get on an empty engine");
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None, "get after remove");
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    for key in &["key1", "key3"] {
+        match engine.remove(key.to_string()) {
+            Err(KvsError::KeyNotFound) => {},
+            other => panic!("remove of missing key {:?} returned {:?}", key, other),
+        }
+    }
+    Ok(())
+}
+
+/// the latest set wins, also after a remove
+pub fn overwrite<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+/// sets and removes survive dropping and reopening the engine
+pub fn reopen_persistence<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set("key0".to_owned(), "overwritten".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("overwritten".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, None, "removed key after reopen");
+    for i in 2..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+/// values of a few MB, with non-ASCII and JSON-like content
+pub fn large_values<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    let large : String = "{\"kvs\":\"数据\\n\"}".repeat(200_000);
+    engine.set("large".to_owned(), large.clone())?;
+    engine.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("large".to_owned())?, Some(large.clone()));
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert_eq!(engine.get("large".to_owned())?, Some(large));
+    assert_eq!(engine.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+/// the empty string is a valid key and a valid value
+pub fn empty_keys<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    engine.set(String::new(), "empty key".to_owned())?;
+    engine.set("empty value".to_owned(), String::new())?;
+    assert_eq!(engine.get(String::new())?, Some("empty key".to_owned()));
+    assert_eq!(engine.get("empty value".to_owned())?, Some(String::new()));
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert_eq!(engine.get(String::new())?, Some("empty key".to_owned()));
+    engine.remove(String::new())?;
+    assert_eq!(engine.get(String::new())?, None);
+    Ok(())
+}
+
+/// writers on several threads sharing the engine behind a mutex
+pub fn concurrency<E, F>(open : F) -> Result<()>
+    where E : KvsEngine + Send + 'static,
+          F : Fn(&Path) -> Result<E>,
+{
+    const THREADS : usize = 8;
+    const KEYS : usize = 100;
+
+    let dir = TestDir::new()?;
+    let engine = Arc::new(Mutex::new(open(&dir.path)?));
+
+    let handles : Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS {
+                    let key = format!("thread{}-key{}", thread_id, i);
+                    let mut engine = engine.lock().unwrap();
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(engine.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("writer thread panicked")?;
+    }
+
+    let mut engine = engine.lock().unwrap();
+    for thread_id in 0..THREADS {
+        for i in 0..KEYS {
+            let key = format!("thread{}-key{}", thread_id, i);
+            assert_eq!(engine.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+/// overwritten and removed keys stay correct across `compact` and reopen
+pub fn compaction<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    for round in 0..20 {
+        for i in 0..100 {
+            engine.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in 0..10 {
+        engine.remove(format!("key{}", i))?;
+    }
+    engine.compact()?;
+
+    let check = |engine : &mut E| -> Result<()> {
+        for i in 0..10 {
+            assert_eq!(engine.get(format!("key{}", i))?, None, "removed key after compaction");
+        }
+        for i in 10..100 {
+            assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}-19", i)));
+        }
+        Ok(())
+    };
+    check(&mut engine)?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    check(&mut engine)?;
+    Ok(())
+}
+
+/// built-in and custom merge operators, folded values survive compaction and reopen
+pub fn merge_operators<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    register_merge_operator("conformance-max", |existing, operand| {
+        let existing = existing.unwrap_or("").to_owned();
+        Ok(existing.max(operand.to_owned()))
+    });
+
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    engine.merge("counter".to_owned(), MergeOperand::add(1))?;
+    engine.merge("counter".to_owned(), MergeOperand::add(41))?;
+    assert_eq!(engine.get("counter".to_owned())?, Some("42".to_owned()));
+
+    engine.set("text".to_owned(), "foo".to_owned())?;
+    engine.merge("text".to_owned(), MergeOperand::append("bar"))?;
+    assert_eq!(engine.get("text".to_owned())?, Some("foobar".to_owned()));
+
+    engine.merge("set".to_owned(), MergeOperand::new("union", "[1,2]"))?;
+    engine.merge("set".to_owned(), MergeOperand::new("union", "[2,3]"))?;
+    assert_eq!(engine.get("set".to_owned())?, Some("[1,2,3]".to_owned()));
+
+    engine.merge("max".to_owned(), MergeOperand::new("conformance-max", "b"))?;
+    engine.merge("max".to_owned(), MergeOperand::new("conformance-max", "a"))?;
+    assert_eq!(engine.get("max".to_owned())?, Some("b".to_owned()));
+
+    match engine.merge("counter".to_owned(), MergeOperand::new("no-such-operator", "")) {
+        Err(KvsError::UnknownMergeOperator(_)) => {},
+        other => panic!("merge with an unknown operator returned {:?}", other),
+    }
+
+    engine.remove("counter".to_owned())?;
+    engine.merge("counter".to_owned(), MergeOperand::add(-5))?;
+    engine.compact()?;
+    engine.merge("counter".to_owned(), MergeOperand::add(1))?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert_eq!(engine.get("counter".to_owned())?, Some("-4".to_owned()));
+    assert_eq!(engine.get("text".to_owned())?, Some("foobar".to_owned()));
+    assert_eq!(engine.get("set".to_owned())?, Some("[1,2,3]".to_owned()));
+    Ok(())
+}
+
+/// scans return the live pairs, or their keys, within the bounds in key order, also after compaction and reopen
+pub fn range_scan<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    for i in (0..50).rev() {
+        engine.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    engine.set("other".to_owned(), "value".to_owned())?;
+    engine.remove("key12".to_owned())?;
+    engine.set("key13".to_owned(), "new".to_owned())?;
+
+    let check = |engine : &mut E| -> Result<()> {
+        let pairs = engine.scan(Included("key10".to_owned()), Excluded("key15".to_owned()))?;
+        let expected : Vec<(String, String)> = vec![
+            ("key10".to_owned(), "value10".to_owned()),
+            ("key11".to_owned(), "value11".to_owned()),
+            ("key13".to_owned(), "new".to_owned()),
+            ("key14".to_owned(), "value14".to_owned()),
+        ];
+        assert_eq!(pairs, expected, "scan of key10..key15");
+
+        let keys : Vec<String> = engine.scan(Excluded("key47".to_owned()), Unbounded)?.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["key48", "key49", "other"], "scan from an excluded key");
+        assert_eq!(engine.scan(Unbounded, Unbounded)?.len(), 50, "scan of every pair");
+        assert_eq!(engine.scan(Included("key12".to_owned()), Included("key12".to_owned()))?, Vec::<(String, String)>::new(), "scan of a removed key");
+        assert_eq!(engine.scan_keys(Included("key10".to_owned()), Unbounded, 3)?, vec!["key10", "key11", "key13"], "keys of key10.. up to 3");
+        assert_eq!(engine.scan_keys(Excluded("key48".to_owned()), Excluded("other".to_owned()), 10)?, vec!["key49"], "keys of key48..other");
+        assert!(engine.scan(Included("key20".to_owned()), Excluded("key10".to_owned()))?.is_empty(), "scan of inverted bounds");
+        assert!(engine.scan_keys(Excluded("key20".to_owned()), Excluded("key20".to_owned()), 10)?.is_empty(), "keys of empty bounds");
+        Ok(())
+    };
+    check(&mut engine)?;
+    engine.compact()?;
+    check(&mut engine)?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    check(&mut engine)?;
+    Ok(())
+}
+
+/// indexes follow sets, removes and merges of the covered keys, and survive compaction and reopen.
+/// Only for engines supporting secondary indexes
+pub fn secondary_indexes<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    let user = |city : &str, age : u32| format!(r#"{{"city":"{}","age":{}}}"#, city, age);
+    engine.set("user:1".to_owned(), user("paris", 30))?;
+    engine.set("user:2".to_owned(), user("berlin", 25))?;
+    engine.create_index("by_city".to_owned(), "user:".to_owned(), "/city".to_owned())?;
+    engine.create_index("by_age".to_owned(), "user:".to_owned(), "/age".to_owned())?;
+    engine.set("user:3".to_owned(), user("paris", 41))?;
+    engine.set("user:4".to_owned(), r#"{"city":"rome","age":60"#.to_owned())?;
+    engine.set("admin:1".to_owned(), user("paris", 50))?;
+
+    let by_city = |engine : &mut E, city : &str| engine.query("by_city".to_owned(), IndexQuery::Value(city.into()));
+    assert_eq!(by_city(&mut engine, "paris")?, vec!["user:1", "user:3"]);
+    assert_eq!(
+        engine.query("by_age".to_owned(), IndexQuery::Range(Some(25.into()), Some(30.into())))?,
+        vec!["user:2", "user:1"],
+    );
+    assert_eq!(engine.query("by_age".to_owned(), IndexQuery::Range(Some(30.5.into()), None))?, vec!["user:3"]);
+    assert!(engine.query("by_age".to_owned(), IndexQuery::Range(Some(30.into()), Some(25.into())))?.is_empty());
+    // an open range stays within the type of its bound
+    engine.set("user:6".to_owned(), r#"{"city":"oslo","age":"old"}"#.to_owned())?;
+    engine.set("user:7".to_owned(), r#"{"city":"oslo","age":null}"#.to_owned())?;
+    engine.set("user:8".to_owned(), r#"{"city":"oslo","age":true}"#.to_owned())?;
+    let by_age = |engine : &mut E, start : Option<Value>, end : Option<Value>| {
+        engine.query("by_age".to_owned(), IndexQuery::Range(start, end))
+    };
+    assert_eq!(by_age(&mut engine, Some(30.into()), None)?, vec!["user:1", "user:3"]);
+    assert_eq!(by_age(&mut engine, None, Some(30.into()))?, vec!["user:2", "user:1"]);
+    assert_eq!(by_age(&mut engine, None, Some("z".into()))?, vec!["user:6"]);
+    assert_eq!(by_age(&mut engine, Some(false.into()), None)?, vec!["user:8"]);
+    assert_eq!(by_age(&mut engine, None, Some(Value::Null))?, vec!["user:7"]);
+    for key in &["user:6", "user:7", "user:8"] {
+        engine.remove(key.to_string())?;
+    }
+
+    engine.set("user:1".to_owned(), user("berlin", 30))?;
+    engine.remove("user:3".to_owned())?;
+    assert_eq!(by_city(&mut engine, "rome")?, Vec::<String>::new());
+    // the merged value becomes JSON
+    engine.merge("user:4".to_owned(), MergeOperand::append("}"))?;
+    assert_eq!(by_city(&mut engine, "rome")?, vec!["user:4"]);
+    assert_eq!(by_city(&mut engine, "paris")?, Vec::<String>::new());
+    assert_eq!(by_city(&mut engine, "berlin")?, vec!["user:1", "user:2"]);
+    engine.compact()?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert_eq!(by_city(&mut engine, "berlin")?, vec!["user:1", "user:2"]);
+    assert_eq!(engine.query("by_age".to_owned(), IndexQuery::Range(None, None))?, vec!["user:2", "user:1", "user:4"]);
+
+    engine.drop_index("by_city".to_owned())?;
+    match by_city(&mut engine, "berlin") {
+        Err(KvsError::IndexNotFound(_)) => {},
+        other => panic!("query of a dropped index returned {:?}", other),
+    }
+    // redefined by another field
+    engine.create_index("by_age".to_owned(), "user:".to_owned(), "".to_owned())?;
+    engine.set("user:5".to_owned(), "\"plain\"".to_owned())?;
+    engine.compact()?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    assert!(by_city(&mut engine, "berlin").is_err());
+    assert_eq!(engine.indexes()?, vec![("by_age".to_owned(), "user:".to_owned(), "".to_owned())]);
+    assert_eq!(engine.query("by_age".to_owned(), IndexQuery::Value("plain".into()))?, vec!["user:5"]);
+    assert_eq!(engine.query("by_age".to_owned(), IndexQuery::Value(30.into()))?, Vec::<String>::new());
+    Ok(())
+}
+
+/// the current version and the kept previous ones, removals included, also after compaction
+/// and reopen. Only for engines keeping two previous versions of each key
+pub fn versions<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    assert_eq!(engine.version("key1".to_owned())?, None, "version of a missing key");
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let first = engine.version("key1".to_owned())?.expect("version of a set key");
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.version("key1".to_owned())?, None, "version of a removed key");
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+
+    let check = |engine : &mut E| -> Result<()> {
+        let history = engine.history("key1".to_owned())?;
+        let values : Vec<Option<&str>> = history.iter().map(|(_, value)| value.as_ref().map(String::as_str)).collect();
+        // value1 is beyond the two previous versions
+        assert_eq!(values, vec![Some("value2"), None, Some("value3")], "history of key1");
+        assert!(history.windows(2).all(|pair| pair[0].0 < pair[1].0), "versions in order: {:?}", history);
+        assert!(history[0].0 > first, "versions grow: {:?}", history);
+        assert_eq!(engine.version("key1".to_owned())?, Some(history[2].0));
+        assert_eq!(engine.get_version("key1".to_owned(), history[0].0)?, Some("value2".to_owned()));
+        assert_eq!(engine.get_version("key1".to_owned(), history[1].0)?, None, "value at the removal");
+        assert_eq!(engine.get_version("key1".to_owned(), history[2].0)?, Some("value3".to_owned()));
+        match engine.get_version("key1".to_owned(), first) {
+            Err(KvsError::VersionNotFound(_)) => {},
+            other => panic!("get of a dropped version returned {:?}", other),
+        }
+        Ok(())
+    };
+    check(&mut engine)?;
+    engine.compact()?;
+    check(&mut engine)?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    check(&mut engine)?;
+    Ok(())
+}
+
+/// keys set with a ttl are gone once it passed, a plain set of the key drops its ttl.
+/// Only for engines supporting ttls
+pub fn expiry<E, F>(open : F) -> Result<()>
+    where E : KvsEngine,
+          F : Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.path)?;
+
+    let short = Duration::from_millis(200);
+    engine.set_with_ttl("short".to_owned(), "value".to_owned(), short)?;
+    engine.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+    engine.set_with_ttl("cleared".to_owned(), "value".to_owned(), short)?;
+    engine.set("cleared".to_owned(), "kept".to_owned())?;
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()), "get before the ttl passed");
+    thread::sleep(short * 2);
+
+    let check = |engine : &mut E| -> Result<()> {
+        assert_eq!(engine.get("short".to_owned())?, None, "get after the ttl passed");
+        assert_eq!(engine.get("long".to_owned())?, Some("value".to_owned()));
+        assert_eq!(engine.get("cleared".to_owned())?, Some("kept".to_owned()));
+        let mut keys = engine.keys()?;
+        keys.sort();
+        assert_eq!(keys, vec!["cleared", "long"], "keys after the ttl passed");
+        Ok(())
+    };
+    check(&mut engine)?;
+    drop(engine);
+
+    let mut engine = open(&dir.path)?;
+    check(&mut engine)?;
+    Ok(())
+}
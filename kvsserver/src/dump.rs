@@ -0,0 +1,235 @@
+//! A portable dump of key-value pairs, used to move data between engines.
+//!
+//! Two formats are supported, both ending with the number of pairs so a
+//! truncated dump is detected:
+//!
+//! * JSON lines, one `DumpLine` per line, starting with a `Header`
+//! * binary, `KVSDUMP\0` and a little-endian u32 version, then records
+//!   tagged by one byte: `1` followed by the length-prefixed key and value,
+//!   or `0` followed by the u64 count.
+
+use crate::{KvsError, Result};
+use serde::{Serialize, Deserialize};
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+const VERSION : u32 = 1;
+const MAGIC : &[u8; 8] = b"KVSDUMP\0";
+const PAIR_TAG : u8 = 1;
+const END_TAG : u8 = 0;
+
+/// Format of a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    JsonLines,
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "json" | "jsonl" => Ok(DumpFormat::JsonLines),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(KvsError::Dump(format!("unknown dump format {:?}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DumpLine {
+    Header { version : u32 },
+    Pair { key : String, value : String },
+    End { count : u64 },
+}
+
+/// Writes pairs in the given format, `finish` must be called to complete the dump
+pub struct DumpWriter<W : Write> {
+    writer : W,
+    format : DumpFormat,
+    count : u64,
+}
+
+impl<W : Write> DumpWriter<W> {
+    /// write the header of the dump
+    pub fn new(mut writer : W, format : DumpFormat) -> Result<Self> {
+        match format {
+            DumpFormat::JsonLines => write_line(&mut writer, &DumpLine::Header { version : VERSION })?,
+            DumpFormat::Binary => {
+                writer.write_all(MAGIC)?;
+                writer.write_all(&VERSION.to_le_bytes())?;
+            },
+        }
+        Ok(DumpWriter {
+            writer,
+            format,
+            count : 0,
+        })
+    }
+
+    pub fn write(&mut self, key : &str, value : &str) -> Result<()> {
+        match self.format {
+            DumpFormat::JsonLines => write_line(&mut self.writer, &DumpLine::Pair {
+                key : key.to_owned(),
+                value : value.to_owned(),
+            })?,
+            DumpFormat::Binary => {
+                self.writer.write_all(&[PAIR_TAG])?;
+                write_bytes(&mut self.writer, key.as_bytes())?;
+                write_bytes(&mut self.writer, value.as_bytes())?;
+            },
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// write the trailer and return the number of pairs
+    pub fn finish(mut self) -> Result<u64> {
+        match self.format {
+            DumpFormat::JsonLines => write_line(&mut self.writer, &DumpLine::End { count : self.count })?,
+            DumpFormat::Binary => {
+                self.writer.write_all(&[END_TAG])?;
+                self.writer.write_all(&self.count.to_le_bytes())?;
+            },
+        }
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn write_line<W : Write>(writer : &mut W, line : &DumpLine) -> Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_bytes<W : Write>(writer : &mut W, bytes : &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Reads the pairs of a dump in either format.
+/// The iterator yields an error if the dump is truncated or malformed.
+pub struct DumpReader<R : BufRead> {
+    reader : R,
+    format : DumpFormat,
+    count : u64,
+    finished : bool,
+}
+
+impl<R : BufRead> DumpReader<R> {
+    /// detect the format from the header
+    pub fn new(mut reader : R) -> Result<Self> {
+        let format = match reader.fill_buf()?.first() {
+            Some(b'{') => {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                match serde_json::from_str(&line)? {
+                    DumpLine::Header { version : VERSION } => DumpFormat::JsonLines,
+                    header => return Err(KvsError::Dump(format!("unsupported header {:?}", header))),
+                }
+            },
+            _ => {
+                let mut magic = [0; 8];
+                reader.read_exact(&mut magic)?;
+                if &magic != MAGIC {
+                    return Err(KvsError::Dump("not a kvs dump".to_owned()));
+                }
+                let version = read_u32(&mut reader)?;
+                if version != VERSION {
+                    return Err(KvsError::Dump(format!("unsupported version {}", version)));
+                }
+                DumpFormat::Binary
+            },
+        };
+
+        Ok(DumpReader {
+            reader,
+            format,
+            count : 0,
+            finished : false,
+        })
+    }
+
+    pub fn format(&self) -> DumpFormat {
+        self.format
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(String, String)>> {
+        let (pair, count) = match self.format {
+            DumpFormat::JsonLines => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Err(KvsError::Dump("truncated dump".to_owned()));
+                }
+                match serde_json::from_str(&line)? {
+                    DumpLine::Pair { key, value } => (Some((key, value)), None),
+                    DumpLine::End { count } => (None, Some(count)),
+                    DumpLine::Header { .. } => return Err(KvsError::Dump("unexpected header".to_owned())),
+                }
+            },
+            DumpFormat::Binary => {
+                let mut tag = [0; 1];
+                self.reader.read_exact(&mut tag)?;
+                match tag[0] {
+                    PAIR_TAG => {
+                        let key = read_string(&mut self.reader)?;
+                        let value = read_string(&mut self.reader)?;
+                        (Some((key, value)), None)
+                    },
+                    END_TAG => {
+                        let mut count = [0; 8];
+                        self.reader.read_exact(&mut count)?;
+                        (None, Some(u64::from_le_bytes(count)))
+                    },
+                    tag => return Err(KvsError::Dump(format!("unknown record tag {}", tag))),
+                }
+            },
+        };
+
+        if let Some(count) = count {
+            if count != self.count {
+                return Err(KvsError::Dump(format!("expected {} pairs, read {}", count, self.count)));
+            }
+            return Ok(None);
+        }
+        self.count += 1;
+        Ok(pair)
+    }
+}
+
+impl<R : BufRead> Iterator for DumpReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.next_pair() {
+            Ok(Some(pair)) => Some(Ok(pair)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            },
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+fn read_u32<R : Read>(reader : &mut R) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string<R : Read>(reader : &mut R) -> Result<String> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| KvsError::Utf8Error(err.utf8_error()))
+}
@@ -0,0 +1,72 @@
+use super::Result;
+use super::keydir::corrupted;
+
+const FNV_OFFSET : u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME : u64 = 0x0000_0100_0000_01b3;
+
+/// A bloom filter over the keys of a table. The hash is written out here,
+/// so that a filter stays valid across builds of the crate.
+pub struct BloomFilter {
+    bits : Vec<u8>,
+    hashes : u32,
+}
+
+impl BloomFilter {
+    /// an empty filter sized for `keys` keys with `bits_per_key` bits each
+    pub fn new(keys : usize, bits_per_key : usize) -> Self {
+        let bits = (keys * bits_per_key).max(64);
+        // k = ln 2 * m / n minimizes the false positive rate
+        let hashes = ((bits_per_key as f64 * 0.69) as u32).max(1).min(30);
+        BloomFilter {
+            bits : vec![0; (bits + 7) / 8],
+            hashes,
+        }
+    }
+
+    /// insert the key of the hash, hashes are collected first to size the filter
+    pub fn insert(&mut self, hash : u64) {
+        let len = self.bits.len() as u64 * 8;
+        for bit in probes(hash, self.hashes, len) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// false if the key is certainly missing
+    pub fn may_contain(&self, key : &str) -> bool {
+        let len = self.bits.len() as u64 * 8;
+        probes(key_hash(key), self.hashes, len).all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// the number of hashes followed by the bits
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.push(self.hashes as u8);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn decode(buf : &[u8]) -> Result<Self> {
+        match buf.split_first() {
+            Some((&hashes, bits)) if hashes > 0 && !bits.is_empty() => Ok(BloomFilter {
+                bits : bits.to_vec(),
+                hashes : u32::from(hashes),
+            }),
+            _ => Err(corrupted()),
+        }
+    }
+}
+
+/// the bits probed for the key of the hash, derived from it by double hashing
+fn probes(hash : u64, hashes : u32, len : u64) -> impl Iterator<Item = u64> {
+    let delta = hash.rotate_left(31) | 1;
+    (0..u64::from(hashes)).map(move |i| hash.wrapping_add(i.wrapping_mul(delta)) % len)
+}
+
+/// FNV-1a hash of the key
+pub fn key_hash(key : &str) -> u64 {
+    fnv1a(key.as_bytes())
+}
+
+pub fn fnv1a(bytes : &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
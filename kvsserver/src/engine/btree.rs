@@ -0,0 +1,635 @@
+use super::{KvsEngine, EngineStats, Result, KvsError, LruCache};
+use super::keydir::{corrupted, get_varint, put_varint};
+use super::pager::{u64_at, Pager, MAX_PAGE_SIZE, MIN_PAGE_SIZE};
+use std::fs;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const FILE : &str = "btree.db";
+const PAGE_SIZE : usize = 4096;
+const BUFFER_POOL_PAGES : usize = 1024;
+
+const PAGE_LEAF : u8 = 1;
+const PAGE_BRANCH : u8 = 2;
+// kind and the number of entries or keys
+const NODE_HEADER : usize = 1 + 4;
+const SLOT_INLINE : u8 = 0;
+const SLOT_OVERFLOW : u8 = 1;
+
+/// Tunables of the BTreeKvStore
+#[derive(Debug, Clone)]
+pub struct BTreeConfig {
+    /// size of the pages of a new file, an existing file keeps its own
+    pub page_size : usize,
+    /// keep up to this many decoded pages in memory
+    pub buffer_pool_pages : usize,
+    /// fsync the pages before switching to the new root and the meta after it,
+    /// so that a commit also survives a power loss and not only a crash of the process
+    pub sync : bool,
+}
+
+impl Default for BTreeConfig {
+    fn default() -> Self {
+        BTreeConfig {
+            page_size : PAGE_SIZE,
+            buffer_pool_pages : BUFFER_POOL_PAGES,
+            sync : false,
+        }
+    }
+}
+
+/// The value of an entry, kept in the leaf or in a chain of overflow pages
+#[derive(Debug, Clone)]
+enum Slot {
+    Inline(String),
+    Overflow {
+        len : u64,
+        page : u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf(Vec<(String, Slot)>),
+    /// `children[i]` holds the keys from `keys[i - 1]` up to `keys[i]`, excluded
+    Branch {
+        keys : Vec<String>,
+        children : Vec<u64>,
+    },
+}
+
+/// A node replacing the updated one, with the separator before it except for the first node
+type Piece = (Option<String>, Node);
+
+/// Copy-on-write B+tree engine in a single file of fixed-size pages. A write copies
+/// the path from the leaf to the root into free pages, then commits by writing the
+/// new root to the meta slot, so readers of the file never see a half applied write
+/// and nothing needs compacting: the pages of the old path are reused by later writes.
+pub struct BTreeKvStore {
+    pager : Pager,
+    // decoded nodes by page. A page is only read while it is reachable, and it is
+    // written through the pool again before it becomes reachable after being freed
+    pool : LruCache<u64, Arc<Node>>,
+    pool_hits : u64,
+    pool_misses : u64,
+}
+
+impl BTreeKvStore {
+    pub fn open<P : Into<PathBuf>>(path : P) -> Result<Self> {
+        BTreeKvStore::with_config(path, BTreeConfig::default())
+    }
+
+    pub fn with_config<P : Into<PathBuf>>(path : P, config : BTreeConfig) -> Result<Self> {
+        if config.page_size < MIN_PAGE_SIZE || config.page_size > MAX_PAGE_SIZE {
+            return Err(KvsError::StringError(format!(
+                "page size {} is not within {}..={}", config.page_size, MIN_PAGE_SIZE, MAX_PAGE_SIZE
+            )));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(BTreeKvStore {
+            pager : Pager::open(&path.join(FILE), config.page_size, config.sync)?,
+            pool : LruCache::new(config.buffer_pool_pages),
+            pool_hits : 0,
+            pool_misses : 0,
+        })
+    }
+
+    /// an entry takes at most a quarter of a page, so that a node split in two halves fits
+    fn max_entry(&self) -> usize {
+        self.pager.page_size() / 4
+    }
+
+    fn check_key(&self, key : &str) -> Result<()> {
+        // the key with a value in overflow pages, or with a child page in a branch
+        if key.len() + 32 > self.max_entry() {
+            return Err(KvsError::StringError(format!(
+                "the key takes {} bytes, at most {} fit in a page", key.len(), self.max_entry() - 32
+            )));
+        }
+        Ok(())
+    }
+
+    fn node(&mut self, page : u64) -> Result<Arc<Node>> {
+        if let Some(node) = self.pool.get_mut(&page) {
+            self.pool_hits += 1;
+            return Ok(node.clone());
+        }
+        self.pool_misses += 1;
+        let node = Arc::new(Node::decode(&self.pager.read_page(page)?)?);
+        self.pool.insert(page, node.clone());
+        Ok(node)
+    }
+
+    fn write_node(&mut self, node : Node) -> Result<u64> {
+        let page = self.pager.allocate();
+        self.pager.write_page(page, node.encode())?;
+        self.pool.insert(page, Arc::new(node));
+        Ok(page)
+    }
+
+    fn lookup(&mut self, key : &str) -> Result<Option<Slot>> {
+        let mut page = self.pager.meta.root;
+        if page == 0 {
+            return Ok(None);
+        }
+        loop {
+            let node = self.node(page)?;
+            match &*node {
+                Node::Leaf(entries) => {
+                    return Ok(entries
+                        .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+                        .ok()
+                        .map(|i| entries[i].1.clone()));
+                },
+                Node::Branch { keys, children } => page = children[child_index(keys, key)],
+            }
+        }
+    }
+
+    fn value(&mut self, slot : Slot) -> Result<String> {
+        match slot {
+            Slot::Inline(value) => Ok(value),
+            Slot::Overflow { len, page } => {
+                let value = self.pager.read_overflow(page, len)?;
+                String::from_utf8(value).map_err(|err| KvsError::Utf8Error(err.utf8_error()))
+            },
+        }
+    }
+
+    /// visit the entries within the bounds in key order, walking the subtrees overlapping them only.
+    /// Returns false once the end bound is passed or `visit` returned false.
+    fn walk(&mut self, page : u64, start : &Bound<String>, end : &Bound<String>, visit : &mut dyn FnMut(&String, &Slot) -> bool) -> Result<bool> {
+        let node = self.node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                for (key, slot) in entries {
+                    if past_end(key, end) {
+                        return Ok(false);
+                    }
+                    let after_start = match start {
+                        Included(start) => key >= start,
+                        Excluded(start) => key > start,
+                        Unbounded => true,
+                    };
+                    if after_start && !visit(key, slot) {
+                        return Ok(false);
+                    }
+                }
+            },
+            Node::Branch { keys, children } => {
+                let first = match start {
+                    Included(start) | Excluded(start) => child_index(keys, start),
+                    Unbounded => 0,
+                };
+                for i in first..children.len() {
+                    if i > 0 && past_end(&keys[i - 1], end) {
+                        return Ok(false);
+                    }
+                    if !self.walk(children[i], start, end, visit)? {
+                        return Ok(false);
+                    }
+                }
+            },
+        }
+        Ok(true)
+    }
+
+    /// keep the value in the leaf if the entry is small enough, or in overflow pages
+    fn slot(&mut self, key : &str, value : String) -> Result<Slot> {
+        let inline = varint_len(key.len()) + key.len() + 1 + varint_len(value.len()) + value.len();
+        if inline <= self.max_entry() {
+            return Ok(Slot::Inline(value));
+        }
+        Ok(Slot::Overflow {
+            len : value.len() as u64,
+            page : self.pager.write_overflow(value.as_bytes())?,
+        })
+    }
+
+    /// apply the write as a transaction, rolled back if any step fails.
+    /// Returns false if there was no key to remove.
+    fn write(&mut self, key : &str, value : Option<String>) -> Result<bool> {
+        let result = self.try_write(key, value);
+        if result.is_err() {
+            if let Err(err) = self.pager.rollback() {
+                error!("unable to roll back the failed write: {}", err);
+            }
+        }
+        result
+    }
+
+    fn try_write(&mut self, key : &str, value : Option<String>) -> Result<bool> {
+        let slot = match value {
+            Some(value) => Some(self.slot(key, value)?),
+            None => None,
+        };
+        let removal = slot.is_none();
+        let root = self.pager.meta.root;
+        let updated = if root == 0 {
+            slot.map(|slot| (vec![(None, Node::Leaf(vec![(key.to_owned(), slot)]))], None))
+        } else {
+            self.update(root, key, slot)?
+        };
+        let (pieces, old) = match updated {
+            Some(updated) => updated,
+            None => return Ok(false),
+        };
+        self.pager.meta.root = self.new_root(pieces)?;
+        if removal {
+            self.pager.meta.keys -= 1;
+        } else if old.is_none() {
+            self.pager.meta.keys += 1;
+        }
+        if let Some(Slot::Overflow { page, .. }) = old {
+            self.pager.free_overflow(page)?;
+        }
+        self.pager.commit()?;
+        Ok(true)
+    }
+
+    /// copy the node of `page` with the slot of the key set, or removed if `slot` is `None`.
+    /// Returns the nodes replacing it, none if it is left empty, with the previous slot
+    /// of the key, or `None` if there was nothing to remove.
+    fn update(&mut self, page : u64, key : &str, slot : Option<Slot>) -> Result<Option<(Vec<Piece>, Option<Slot>)>> {
+        let page_size = self.pager.page_size();
+        let node = self.node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                let mut entries = entries.clone();
+                let old = match (entries.binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key)), slot) {
+                    (Ok(i), Some(slot)) => Some(std::mem::replace(&mut entries[i].1, slot)),
+                    (Ok(i), None) => Some(entries.remove(i).1),
+                    (Err(i), Some(slot)) => {
+                        entries.insert(i, (key.to_owned(), slot));
+                        None
+                    },
+                    (Err(_), None) => return Ok(None),
+                };
+                self.pager.free(page);
+                Ok(Some((Node::Leaf(entries).split(page_size), old)))
+            },
+            Node::Branch { keys, children } => {
+                let (mut keys, mut children) = (keys.clone(), children.clone());
+                let i = child_index(&keys, key);
+                let (pieces, old) = match self.update(children[i], key, slot)? {
+                    Some(updated) => updated,
+                    None => return Ok(None),
+                };
+                self.pager.free(page);
+                self.replace_child(&mut keys, &mut children, i, pieces)?;
+                Ok(Some((Node::Branch { keys, children }.split(page_size), old)))
+            },
+        }
+    }
+
+    /// replace child `i` by the nodes of its update. A child left with less than a
+    /// quarter of a page is merged with a sibling, the merged node is split again if needed.
+    fn replace_child(&mut self, keys : &mut Vec<String>, children : &mut Vec<u64>, i : usize, mut pieces : Vec<Piece>) -> Result<()> {
+        let page_size = self.pager.page_size();
+        if pieces.is_empty() {
+            children.remove(i);
+            if !keys.is_empty() {
+                keys.remove(if i == 0 { 0 } else { i - 1 });
+            }
+            return Ok(());
+        }
+        if pieces.len() == 1 && pieces[0].1.size() < page_size / 4 && children.len() > 1 {
+            let (_, node) = pieces.pop().expect("a piece");
+            let (left, right) = if i + 1 < children.len() { (i, i + 1) } else { (i - 1, i) };
+            let sibling_page = if left == i { children[right] } else { children[left] };
+            let sibling = (*self.node(sibling_page)?).clone();
+            self.pager.free(sibling_page);
+            let separator = keys.remove(left);
+            children.remove(right);
+            let merged = if left == i {
+                node.merge(separator, sibling)
+            } else {
+                sibling.merge(separator, node)
+            };
+            return self.insert_pieces(keys, children, left, merged.split(page_size));
+        }
+        self.insert_pieces(keys, children, i, pieces)
+    }
+
+    /// write the nodes, the first one in place of child `at`
+    fn insert_pieces(&mut self, keys : &mut Vec<String>, children : &mut Vec<u64>, at : usize, pieces : Vec<Piece>) -> Result<()> {
+        for (j, (separator, node)) in pieces.into_iter().enumerate() {
+            let page = self.write_node(node)?;
+            if j == 0 {
+                children[at] = page;
+            } else {
+                children.insert(at + j, page);
+                keys.insert(at + j - 1, separator.expect("separator of a split node"));
+            }
+        }
+        Ok(())
+    }
+
+    /// write the nodes replacing the root, under new branches while there are several
+    fn new_root(&mut self, mut pieces : Vec<Piece>) -> Result<u64> {
+        while pieces.len() > 1 {
+            let mut keys = Vec::with_capacity(pieces.len() - 1);
+            let mut children = Vec::with_capacity(pieces.len());
+            for (separator, node) in pieces {
+                keys.extend(separator);
+                children.push(self.write_node(node)?);
+            }
+            pieces = Node::Branch { keys, children }.split(self.pager.page_size());
+        }
+        match pieces.pop() {
+            None => Ok(0),
+            // a root left with a single child gives way to it
+            Some((_, Node::Branch { ref children, .. })) if children.len() == 1 => Ok(children[0]),
+            Some((_, node)) => self.write_node(node),
+        }
+    }
+
+    fn depth(&mut self) -> Result<u64> {
+        let mut page = self.pager.meta.root;
+        let mut depth = 0;
+        while page != 0 {
+            depth += 1;
+            page = match &*self.node(page)? {
+                Node::Leaf(_) => 0,
+                Node::Branch { children, .. } => children[0],
+            };
+        }
+        Ok(depth)
+    }
+}
+
+impl KvsEngine for BTreeKvStore {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.check_key(&key)?;
+        self.write(&key, Some(value))?;
+        Ok(())
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        match self.lookup(&key)? {
+            Some(slot) => Ok(Some(self.value(slot)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        if !self.write(&key, None)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    /// every key in key order, walking the leaves without reading the values
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.scan_keys(Unbounded, Unbounded, usize::MAX)
+    }
+
+    /// descend to the first key of the range and walk the leaves from there
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        let root = self.pager.meta.root;
+        if root != 0 {
+            self.walk(root, &start, &end, &mut |key, slot| {
+                entries.push((key.clone(), slot.clone()));
+                true
+            })?;
+        }
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, slot) in entries {
+            pairs.push((key, self.value(slot)?));
+        }
+        Ok(pairs)
+    }
+
+    /// walk the leaves of the range up to `limit` keys, without reading the values
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let root = self.pager.meta.root;
+        if root != 0 && limit > 0 {
+            self.walk(root, &start, &end, &mut |key, _| {
+                keys.push(key.clone());
+                keys.len() < limit
+            })?;
+        }
+        Ok(keys)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut stats = EngineStats::default();
+        stats.set("keys", self.pager.meta.keys);
+        stats.set("depth", self.depth()?);
+        stats.set("page_size", self.pager.page_size() as u64);
+        stats.set("pages", self.pager.meta.pages);
+        stats.set("free_pages", self.pager.free_pages() as u64);
+        stats.set("commits", self.pager.meta.txid);
+        stats.set("buffer_pool_pages", self.pool.len() as u64);
+        stats.set("buffer_pool_hits", self.pool_hits);
+        stats.set("buffer_pool_misses", self.pool_misses);
+        Ok(stats)
+    }
+}
+
+impl Node {
+    /// bytes of the encoded node
+    fn size(&self) -> usize {
+        match self {
+            Node::Leaf(entries) => NODE_HEADER + entries.iter().map(|(key, slot)| leaf_entry_len(key, slot)).sum::<usize>(),
+            Node::Branch { keys, .. } => NODE_HEADER + 8 + keys.iter().map(|key| branch_entry_len(key)).sum::<usize>(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.size());
+        match self {
+            Node::Leaf(entries) => {
+                buf.push(PAGE_LEAF);
+                buf.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (key, slot) in entries {
+                    put_varint(&mut buf, key.len() as u64);
+                    buf.extend_from_slice(key.as_bytes());
+                    match slot {
+                        Slot::Inline(value) => {
+                            buf.push(SLOT_INLINE);
+                            put_varint(&mut buf, value.len() as u64);
+                            buf.extend_from_slice(value.as_bytes());
+                        },
+                        Slot::Overflow { len, page } => {
+                            buf.push(SLOT_OVERFLOW);
+                            put_varint(&mut buf, *len);
+                            buf.extend_from_slice(&page.to_be_bytes());
+                        },
+                    }
+                }
+            },
+            Node::Branch { keys, children } => {
+                buf.push(PAGE_BRANCH);
+                buf.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&children[0].to_be_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    put_varint(&mut buf, key.len() as u64);
+                    buf.extend_from_slice(key.as_bytes());
+                    buf.extend_from_slice(&child.to_be_bytes());
+                }
+            },
+        }
+        buf
+    }
+
+    fn decode(page : &[u8]) -> Result<Node> {
+        if page.len() < NODE_HEADER {
+            return Err(corrupted());
+        }
+        let mut count = [0; 4];
+        count.copy_from_slice(&page[1..NODE_HEADER]);
+        let count = u32::from_be_bytes(count) as usize;
+        let mut buf = &page[NODE_HEADER..];
+        match page[0] {
+            PAGE_LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = take_string(&mut buf)?;
+                    let slot = match take(&mut buf, 1)?[0] {
+                        SLOT_INLINE => Slot::Inline(take_string(&mut buf)?),
+                        SLOT_OVERFLOW => Slot::Overflow {
+                            len : get_varint(&mut buf)?,
+                            page : u64_at(take(&mut buf, 8)?),
+                        },
+                        _ => return Err(corrupted()),
+                    };
+                    entries.push((key, slot));
+                }
+                Ok(Node::Leaf(entries))
+            },
+            PAGE_BRANCH => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(u64_at(take(&mut buf, 8)?));
+                for _ in 0..count {
+                    keys.push(take_string(&mut buf)?);
+                    children.push(u64_at(take(&mut buf, 8)?));
+                }
+                Ok(Node::Branch { keys, children })
+            },
+            _ => Err(corrupted()),
+        }
+    }
+
+    /// the node as nodes fitting in a page, none if it is empty.
+    /// Halves are split again until they fit.
+    fn split(self, page_size : usize) -> Vec<Piece> {
+        let size = self.size();
+        match self {
+            Node::Leaf(ref entries) if entries.is_empty() => Vec::new(),
+            Node::Branch { ref children, .. } if children.is_empty() => Vec::new(),
+            node if size <= page_size => vec![(None, node)],
+            Node::Leaf(mut entries) => {
+                let sizes : Vec<usize> = entries.iter().map(|(key, slot)| leaf_entry_len(key, slot)).collect();
+                let mid = midpoint(&sizes, 1, entries.len() - 1);
+                let right = entries.split_off(mid);
+                let separator = right[0].0.clone();
+                join(Node::Leaf(entries).split(page_size), separator, Node::Leaf(right).split(page_size))
+            },
+            Node::Branch { mut keys, mut children } => {
+                let sizes : Vec<usize> = keys.iter().map(|key| branch_entry_len(key)).collect();
+                // the key at the middle moves up, both halves keep a key
+                let mid = midpoint(&sizes, 1, keys.len() - 2);
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().expect("the middle key");
+                let right_children = children.split_off(mid + 1);
+                join(
+                    Node::Branch { keys, children }.split(page_size),
+                    separator,
+                    Node::Branch { keys : right_keys, children : right_children }.split(page_size),
+                )
+            },
+        }
+    }
+
+    /// concatenate the node with its right sibling, `separator` sits between them in the parent
+    fn merge(self, separator : String, right : Node) -> Node {
+        match (self, right) {
+            (Node::Leaf(mut entries), Node::Leaf(right)) => {
+                entries.extend(right);
+                Node::Leaf(entries)
+            },
+            (Node::Branch { mut keys, mut children }, Node::Branch { keys : right_keys, children : right_children }) => {
+                keys.push(separator);
+                keys.extend(right_keys);
+                children.extend(right_children);
+                Node::Branch { keys, children }
+            },
+            _ => unreachable!("siblings are at the same depth"),
+        }
+    }
+}
+
+/// the index of the child holding the key
+fn child_index(keys : &[String], key : &str) -> usize {
+    match keys.binary_search_by(|separator| separator.as_str().cmp(key)) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+fn past_end(key : &str, end : &Bound<String>) -> bool {
+    match end {
+        Included(end) => key > end.as_str(),
+        Excluded(end) => key >= end.as_str(),
+        Unbounded => false,
+    }
+}
+
+/// the index where the sizes before it first reach half of the total, within `min..=max`
+fn midpoint(sizes : &[usize], min : usize, max : usize) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut total = 0;
+    let mut mid = 0;
+    while mid < sizes.len() && total + sizes[mid] <= half {
+        total += sizes[mid];
+        mid += 1;
+    }
+    mid.max(min).min(max)
+}
+
+fn join(mut left : Vec<Piece>, separator : String, mut right : Vec<Piece>) -> Vec<Piece> {
+    right[0].0 = Some(separator);
+    left.append(&mut right);
+    left
+}
+
+fn varint_len(mut value : usize) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn leaf_entry_len(key : &str, slot : &Slot) -> usize {
+    varint_len(key.len()) + key.len() + 1 + match slot {
+        Slot::Inline(value) => varint_len(value.len()) + value.len(),
+        Slot::Overflow { len, .. } => varint_len(*len as usize) + 8,
+    }
+}
+
+fn branch_entry_len(key : &str) -> usize {
+    varint_len(key.len()) + key.len() + 8
+}
+
+fn take<'a>(buf : &mut &'a [u8], len : usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(corrupted());
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+fn take_string(buf : &mut &[u8]) -> Result<String> {
+    let len = get_varint(buf)? as usize;
+    let bytes = take(buf, len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|err| KvsError::Utf8Error(err.utf8_error()))
+}
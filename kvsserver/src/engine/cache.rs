@@ -0,0 +1,94 @@
+use super::{Result, KvsError, LruCache};
+use std::str::FromStr;
+
+/// Which values read from the log are kept in the value cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheAdmission {
+    /// every value read
+    All,
+    /// values read a second time while the key is remembered, so that
+    /// a scan of cold keys doesn't flush the hot ones
+    SecondHit,
+}
+
+impl Default for CacheAdmission {
+    fn default() -> Self {
+        CacheAdmission::All
+    }
+}
+
+impl FromStr for CacheAdmission {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "all" => Ok(CacheAdmission::All),
+            "second-hit" => Ok(CacheAdmission::SecondHit),
+            _ => Err(KvsError::StringError(format!("unknown cache admission {}", s))),
+        }
+    }
+}
+
+/// Bounded cache of the current values of the hottest keys. It holds values
+/// rather than positions in the log, so compaction doesn't affect it.
+pub struct ValueCache {
+    values : LruCache<String, String>,
+    admission : CacheAdmission,
+    // keys missed once, only used by `SecondHit`
+    candidates : LruCache<String, ()>,
+    pub hits : u64,
+    pub misses : u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity : usize, admission : CacheAdmission) -> Self {
+        ValueCache {
+            values : LruCache::new(capacity),
+            admission,
+            candidates : LruCache::new(capacity),
+            hits : 0,
+            misses : 0,
+        }
+    }
+
+    pub fn get(&mut self, key : &str) -> Option<String> {
+        match self.values.get_mut(key) {
+            Some(value) => {
+                self.hits += 1;
+                Some(value.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// offer a value read from the log after a miss
+    pub fn admit(&mut self, key : &str, value : &str) {
+        if self.admission == CacheAdmission::SecondHit
+            && self.candidates.remove(key).is_none()
+        {
+            self.candidates.insert(key.to_owned(), ());
+            return;
+        }
+        self.values.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// drop the value of a written key
+    pub fn invalidate(&mut self, key : &str) {
+        self.values.remove(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// hits per hundred reads
+    pub fn hit_rate(&self) -> u64 {
+        match self.hits + self.misses {
+            0 => 0,
+            reads => self.hits * 100 / reads,
+        }
+    }
+}
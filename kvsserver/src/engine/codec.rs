@@ -0,0 +1,192 @@
+use super::{Result, KvsError};
+use super::crypto::LogCipher;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+// the first byte of a record is its flag, a plain json record starts with `{`
+const FLAG_PLAIN : u8 = b'{';
+const FLAG_DEFLATE : u8 = 1;
+const FLAG_SNAPPY : u8 = 2;
+// the payload is a plain or compressed record sealed by the cipher
+const FLAG_SEALED : u8 = 3;
+// flag byte and big-endian u32 length of the payload
+const HEADER_LEN : usize = 5;
+
+/// How the records of the log are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Deflate,
+    Snappy,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::None
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Codec::None),
+            "deflate" => Ok(Codec::Deflate),
+            "snappy" => Ok(Codec::Snappy),
+            _ => Err(KvsError::StringError(format!("unknown codec {}", s))),
+        }
+    }
+}
+
+/// Encodes the records of the log, records smaller than `threshold`
+/// or which don't shrink are kept as plain json. Every record is sealed
+/// when a cipher is given, and records which aren't are refused then.
+#[derive(Clone)]
+pub struct RecordEncoder {
+    pub codec : Codec,
+    pub threshold : usize,
+    pub cipher : Option<Arc<LogCipher>>,
+    // read the records which aren't sealed although a cipher is given, only while they are rewritten
+    pub allow_plaintext : bool,
+}
+
+impl RecordEncoder {
+    /// encode the record written at `pos` of generation `gen`
+    pub fn encode<T : Serialize>(&self, record : &T, gen : u64, pos : u64) -> Result<Vec<u8>> {
+        let inner = self.compress(record)?;
+        self.seal(inner, gen, pos)
+    }
+
+    fn compress<T : Serialize>(&self, record : &T) -> Result<Vec<u8>> {
+        let plain = serde_json::to_vec(record)?;
+        if plain.len() < self.threshold {
+            return Ok(plain);
+        }
+        let (flag, payload) = match self.codec {
+            Codec::None => return Ok(plain),
+            Codec::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&plain)?;
+                (FLAG_DEFLATE, encoder.finish()?)
+            },
+            Codec::Snappy => {
+                let payload = snap::raw::Encoder::new()
+                    .compress_vec(&plain)
+                    .map_err(|err| KvsError::Compression(err.to_string()))?;
+                (FLAG_SNAPPY, payload)
+            },
+        };
+        if payload.len() + HEADER_LEN >= plain.len() {
+            return Ok(plain);
+        }
+        Ok(frame(flag, &payload))
+    }
+
+    /// seal a plain or compressed record for its position, as it is without a cipher
+    pub fn seal(&self, inner : Vec<u8>, gen : u64, pos : u64) -> Result<Vec<u8>> {
+        match self.cipher.as_ref() {
+            Some(cipher) => Ok(frame(FLAG_SEALED, &cipher.seal_record(gen, pos, &inner)?)),
+            None => Ok(inner),
+        }
+    }
+
+    /// the plain or compressed record inside a sealed one. Records which
+    /// aren't sealed are only read as they are without a cipher, or with `allow_plaintext`
+    pub fn unseal(&self, record : &[u8], gen : u64, pos : u64) -> Result<Vec<u8>> {
+        match record.first() {
+            Some(&FLAG_SEALED) if record.len() >= HEADER_LEN => match self.cipher.as_ref() {
+                Some(cipher) => cipher.open_record(gen, pos, &record[HEADER_LEN..]),
+                None => Err(KvsError::Encryption("the log is encrypted, but no key is given".to_owned())),
+            },
+            _ => {
+                self.check_plaintext(gen, pos)?;
+                Ok(record.to_vec())
+            },
+        }
+    }
+
+    /// a record which isn't sealed can't be authenticated, it is refused once a cipher is given
+    fn check_plaintext(&self, gen : u64, pos : u64) -> Result<()> {
+        if self.cipher.is_some() && !self.allow_plaintext {
+            return Err(KvsError::Encryption(format!("the record at {} of generation {} isn't sealed", pos, gen)));
+        }
+        Ok(())
+    }
+
+    /// decode the whole record read from `pos` of generation `gen`
+    pub fn decode<T : DeserializeOwned>(&self, record : &[u8], gen : u64, pos : u64) -> Result<T> {
+        if record.first() == Some(&FLAG_SEALED) {
+            return decode_inner(&self.unseal(record, gen, pos)?);
+        }
+        self.check_plaintext(gen, pos)?;
+        decode_inner(record)
+    }
+
+    /// read the next record, which starts at `pos` of generation `gen`, `None` at the end of the log
+    pub fn read<T : DeserializeOwned, R : BufRead>(&self, reader : &mut R, gen : u64, pos : u64) -> Result<Option<T>> {
+        let flag = match reader.fill_buf()?.first() {
+            Some(&flag) => flag,
+            None => return Ok(None),
+        };
+        if flag != FLAG_SEALED {
+            self.check_plaintext(gen, pos)?;
+        }
+        if flag == FLAG_PLAIN {
+            // a json object ends with its closing brace, nothing after it is read
+            let mut de = serde_json::Deserializer::from_reader(reader);
+            return Ok(Some(T::deserialize(&mut de)?));
+        }
+
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut payload)?;
+        if flag == FLAG_SEALED {
+            let mut record = header.to_vec();
+            record.extend_from_slice(&payload);
+            return self.decode(&record, gen, pos).map(Some);
+        }
+        decompress(flag, &payload).map(Some)
+    }
+}
+
+fn frame(flag : u8, payload : &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(payload.len() + HEADER_LEN);
+    record.push(flag);
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// decode a plain or compressed record
+fn decode_inner<T : DeserializeOwned>(record : &[u8]) -> Result<T> {
+    match record.first() {
+        Some(&FLAG_PLAIN) => Ok(serde_json::from_slice(record)?),
+        Some(&flag) if record.len() >= HEADER_LEN => decompress(flag, &record[HEADER_LEN..]),
+        _ => Err(KvsError::Compression("truncated record".to_owned())),
+    }
+}
+
+fn decompress<T : DeserializeOwned>(flag : u8, payload : &[u8]) -> Result<T> {
+    let plain = match flag {
+        FLAG_DEFLATE => {
+            let mut plain = Vec::new();
+            DeflateDecoder::new(payload).read_to_end(&mut plain)?;
+            plain
+        },
+        FLAG_SNAPPY => snap::raw::Decoder::new()
+            .decompress_vec(payload)
+            .map_err(|err| KvsError::Compression(err.to_string()))?,
+        _ => return Err(KvsError::Compression(format!("unknown record flag {}", flag))),
+    };
+    Ok(serde_json::from_slice(&plain)?)
+}
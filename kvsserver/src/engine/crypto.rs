@@ -0,0 +1,130 @@
+use super::{Result, KvsError};
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const KEY_LEN : usize = 32;
+const NONCE_LEN : usize = 24;
+const DOMAIN_LOG : u8 = b'l';
+// tells a sealed blob from one written before encryption was enabled
+const BLOB_MAGIC : &[u8] = b"\0kvsseal";
+
+/// A 256-bit key of the records at rest
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// environment variable holding the hex encoded key when no key file is given
+    pub const ENV : &'static str = "KVS_ENCRYPTION_KEY";
+
+    /// parse 64 hex digits, surrounding whitespace is ignored
+    pub fn from_hex(hex : &str) -> Result<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(KvsError::Encryption(format!("a key is {} hex digits", KEY_LEN * 2)));
+        }
+        let mut key = [0; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| KvsError::Encryption("the key is not hex".to_owned()))?;
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    /// read the hex encoded key from a file
+    pub fn from_file(path : &Path) -> Result<Self> {
+        EncryptionKey::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// read the hex encoded key from the environment variable, `None` if it isn't set
+    pub fn from_env(var : &str) -> Result<Option<Self>> {
+        match env::var(var) {
+            Ok(hex) => EncryptionKey::from_hex(&hex).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+// keep the key out of logs and panics
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Seals the records with XChaCha20-Poly1305. The nonce of a log record is derived
+/// from its generation and offset, which are never written twice. A blob may be
+/// written again for the same version after a crash loses its record, so its nonce
+/// is random and stored in front of it, and the version is authenticated with it.
+pub struct LogCipher {
+    cipher : XChaCha20Poly1305,
+}
+
+impl LogCipher {
+    pub fn new(key : &EncryptionKey) -> Self {
+        LogCipher {
+            cipher : XChaCha20Poly1305::new(&key.0.into()),
+        }
+    }
+
+    fn nonce(domain : u8, gen : u64, pos : u64) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = domain;
+        nonce[8..16].copy_from_slice(&gen.to_be_bytes());
+        nonce[16..].copy_from_slice(&pos.to_be_bytes());
+        nonce
+    }
+
+    fn seal(&self, nonce : [u8; NONCE_LEN], plain : &[u8], aad : &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .encrypt(&nonce.into(), Payload { msg : plain, aad })
+            .map_err(|_| KvsError::Encryption("cannot encrypt".to_owned()))
+    }
+
+    fn open(&self, nonce : [u8; NONCE_LEN], sealed : &[u8], aad : &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .decrypt(&nonce.into(), Payload { msg : sealed, aad })
+            .map_err(|_| KvsError::Encryption("the record is corrupted or sealed with another key".to_owned()))
+    }
+
+    /// seal the record written at `pos` of generation `gen`
+    pub fn seal_record(&self, gen : u64, pos : u64, plain : &[u8]) -> Result<Vec<u8>> {
+        self.seal(LogCipher::nonce(DOMAIN_LOG, gen, pos), plain, &[])
+    }
+
+    pub fn open_record(&self, gen : u64, pos : u64, sealed : &[u8]) -> Result<Vec<u8>> {
+        self.open(LogCipher::nonce(DOMAIN_LOG, gen, pos), sealed, &[])
+    }
+
+    /// seal the blob of `version` under a random nonce
+    pub fn seal_blob(&self, version : u64, plain : &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng::new()
+            .and_then(|mut rng| rng.try_fill_bytes(&mut nonce))
+            .map_err(|err| KvsError::Encryption(format!("cannot draw a nonce: {}", err)))?;
+        let mut blob = BLOB_MAGIC.to_vec();
+        blob.extend_from_slice(&nonce);
+        blob.extend(self.seal(nonce, plain, &version.to_be_bytes())?);
+        Ok(blob)
+    }
+
+    /// the value of the blob of `version`, a blob which isn't sealed is refused
+    pub fn open_blob(&self, version : u64, blob : &[u8]) -> Result<Vec<u8>> {
+        if !is_sealed_blob(blob) || blob.len() < BLOB_MAGIC.len() + NONCE_LEN {
+            return Err(KvsError::Encryption(format!("the blob of version {} isn't sealed", version)));
+        }
+        let (stored, sealed) = blob[BLOB_MAGIC.len()..].split_at(NONCE_LEN);
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(stored);
+        self.open(nonce, sealed, &version.to_be_bytes())
+    }
+}
+
+pub fn is_sealed_blob(blob : &[u8]) -> bool {
+    blob.starts_with(BLOB_MAGIC)
+}
@@ -0,0 +1,174 @@
+use super::{Result, KvsError};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Which key is evicted first once the budget of a cache is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// the least recently used key
+    Lru,
+    /// the least frequently used key, the least recently used one among equals
+    Lfu,
+    /// the key which expires first, keys without a ttl go last in lru order
+    TtlNearest,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Lru
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            "ttl" => Ok(EvictionPolicy::TtlNearest),
+            _ => Err(KvsError::StringError(format!("unknown eviction policy {}", s))),
+        }
+    }
+}
+
+/// milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+struct Usage {
+    bytes : u64,
+    tick : u64,
+    hits : u64,
+    expires_at : Option<u64>,
+}
+
+/// Tracks the live keys of a store with a budget, and orders them by
+/// the eviction policy
+pub struct Evictor {
+    policy : EvictionPolicy,
+    max_bytes : u64,
+    max_keys : usize,
+    // incremented on every access, smaller means older
+    tick : u64,
+    usage : HashMap<String, Usage>,
+    // (rank, tick, key), the first one is evicted first
+    ranks : BTreeSet<(u64, u64, String)>,
+    live_bytes : u64,
+    pub evictions : u64,
+    pub expirations : u64,
+}
+
+impl Evictor {
+    /// a budget of 0 is unlimited
+    pub fn new(policy : EvictionPolicy, max_bytes : u64, max_keys : usize) -> Self {
+        Evictor {
+            policy,
+            max_bytes,
+            max_keys,
+            tick : 0,
+            usage : HashMap::new(),
+            ranks : BTreeSet::new(),
+            live_bytes : 0,
+            evictions : 0,
+            expirations : 0,
+        }
+    }
+
+    fn rank(&self, usage : &Usage) -> u64 {
+        match self.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => usage.hits,
+            EvictionPolicy::TtlNearest => usage.expires_at.unwrap_or(u64::max_value()),
+        }
+    }
+
+    /// track a written key, it replaces the previous usage of the key
+    pub fn insert(&mut self, key : &str, bytes : u64, expires_at : Option<u64>) {
+        self.remove(key);
+        self.tick += 1;
+        let usage = Usage { bytes, tick : self.tick, hits : 1, expires_at };
+        self.ranks.insert((self.rank(&usage), usage.tick, key.to_owned()));
+        self.live_bytes += bytes;
+        self.usage.insert(key.to_owned(), usage);
+    }
+
+    /// mark the key as used, `bytes` is its new size if it changed
+    pub fn touch(&mut self, key : &str, bytes : Option<u64>) {
+        let mut usage = match self.usage.remove(key) {
+            Some(usage) => usage,
+            None => return,
+        };
+        self.ranks.remove(&(self.rank(&usage), usage.tick, key.to_owned()));
+        self.tick += 1;
+        usage.tick = self.tick;
+        usage.hits += 1;
+        if let Some(bytes) = bytes {
+            self.live_bytes = self.live_bytes - usage.bytes + bytes;
+            usage.bytes = bytes;
+        }
+        self.ranks.insert((self.rank(&usage), usage.tick, key.to_owned()));
+        self.usage.insert(key.to_owned(), usage);
+    }
+
+    /// change the size of the key without using it, as compaction does
+    pub fn resize(&mut self, key : &str, bytes : u64) {
+        if let Some(usage) = self.usage.get_mut(key) {
+            self.live_bytes = self.live_bytes - usage.bytes + bytes;
+            usage.bytes = bytes;
+        }
+    }
+
+    pub fn remove(&mut self, key : &str) {
+        if let Some(usage) = self.usage.remove(key) {
+            self.ranks.remove(&(self.rank(&usage), usage.tick, key.to_owned()));
+            self.live_bytes -= usage.bytes;
+        }
+    }
+
+    /// track the written key, keeping its ttl if it is tracked already
+    pub fn write(&mut self, key : &str, bytes : u64) {
+        if self.usage.contains_key(key) {
+            self.touch(key, Some(bytes));
+        } else {
+            self.insert(key, bytes, None);
+        }
+    }
+
+    pub fn expires_at(&self, key : &str) -> Option<u64> {
+        self.usage.get(key).and_then(|usage| usage.expires_at)
+    }
+
+    /// whether the ttl of the key has passed at `now`
+    pub fn expired(&self, key : &str, now : u64) -> bool {
+        self.expires_at(key).map_or(false, |expires_at| expires_at <= now)
+    }
+
+    /// the keys with a ttl which hasn't passed at `now`
+    pub fn expiring(&self, now : u64) -> usize {
+        self.usage.values().filter(|usage| usage.expires_at.map_or(false, |expires_at| expires_at > now)).count()
+    }
+
+    pub fn over_budget(&self) -> bool {
+        (self.max_bytes > 0 && self.live_bytes > self.max_bytes)
+            || (self.max_keys > 0 && self.usage.len() > self.max_keys)
+    }
+
+    /// the key to evict next, `spare` is only evicted if it is the last one
+    pub fn victim(&self, spare : Option<&str>) -> Option<&str> {
+        self.ranks
+            .iter()
+            .map(|(_, _, key)| key.as_str())
+            .find(|key| Some(*key) != spare)
+            .or_else(|| self.ranks.iter().next().map(|(_, _, key)| key.as_str()))
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+}
@@ -0,0 +1,799 @@
+use super::{Result, KvsError};
+use super::kv::CommandPos;
+use super::vfs::{Vfs, VfsFile};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Bound::{Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+const SORTED_INDEX : &str = "sorted.index";
+const SORTED_MAGIC : &[u8; 8] = b"kvsindex";
+// keys of a prefix-compressed block, in memory and on disk
+const BLOCK_KEYS : usize = 64;
+
+/// How the KvStore keeps the keys of its index in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMode {
+    /// every key in a sorted tree
+    Tree,
+    /// 64-bit fingerprints of the keys only, a match is verified against the log
+    Hashed,
+    /// sorted blocks of prefix-compressed keys
+    Prefix,
+}
+
+impl Default for IndexMode {
+    fn default() -> Self {
+        IndexMode::Tree
+    }
+}
+
+impl FromStr for IndexMode {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "tree" => Ok(IndexMode::Tree),
+            "hashed" => Ok(IndexMode::Hashed),
+            "prefix" => Ok(IndexMode::Prefix),
+            _ => Err(KvsError::StringError(format!("unknown index mode {}", s))),
+        }
+    }
+}
+
+/// Reads the key of the record at a position of the log
+pub trait KeySource {
+    fn key_at(&mut self, cmd_pos : CommandPos) -> Result<String>;
+}
+
+/// Position of the current set of each live key. The recent entries are kept
+/// in memory as configured by the `IndexMode`. With a sorted index, compaction
+/// moves every entry to a prefix-compressed file of which only the first key
+/// of each block stays in memory.
+pub struct KeyDir {
+    vfs : Arc<dyn Vfs>,
+    dir : PathBuf,
+    entries : Entries,
+    use_sorted : bool,
+    sorted : Option<SortedIndex>,
+    // sorted entries overwritten or removed since the index was written
+    superseded : Bits,
+    // while loading, a sorted entry is only visible once its record is replayed
+    replayed : Option<Bits>,
+}
+
+impl KeyDir {
+    /// open the sorted index of `dir` if `use_sorted`, otherwise remove it
+    pub fn open(vfs : &Arc<dyn Vfs>, dir : &Path, mode : IndexMode, use_sorted : bool) -> Result<Self> {
+        let path = dir.join(SORTED_INDEX);
+        let sorted = match (use_sorted, vfs.exists(&path)) {
+            (true, true) => Some(SortedIndex::open(vfs.as_ref(), &path)?),
+            (false, true) => {
+                vfs.remove_file(&path)?;
+                None
+            },
+            _ => None,
+        };
+        let count = sorted.as_ref().map_or(0, |sorted| sorted.count);
+        Ok(KeyDir {
+            vfs : Arc::clone(vfs),
+            dir : dir.to_owned(),
+            entries : Entries::new(mode),
+            use_sorted,
+            sorted,
+            superseded : Bits::new(count),
+            replayed : Some(Bits::new(count)),
+        })
+    }
+
+    /// remove the sorted index of `dir`, the index is rebuilt from the log
+    pub fn remove_sorted(vfs : &dyn Vfs, dir : &Path) -> Result<()> {
+        vfs.remove_file(&dir.join(SORTED_INDEX))?;
+        Ok(())
+    }
+
+    /// end of the replay of the log, false if an entry of the sorted index wasn't replayed
+    pub fn finish_load(&mut self) -> bool {
+        let replayed = self.replayed.take().map_or(0, |replayed| replayed.ones);
+        replayed == self.sorted.as_ref().map_or(0, |sorted| sorted.count)
+    }
+
+    pub fn get(&mut self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        if let Some(cmd_pos) = self.entries.get(key, src)? {
+            return Ok(Some(cmd_pos));
+        }
+        Ok(self.visible_sorted(key)?.map(|(_, cmd_pos)| cmd_pos))
+    }
+
+    /// point the key at `cmd_pos`, returns the position it replaces
+    pub fn insert(&mut self, key : String, cmd_pos : CommandPos, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        if self.replayed.is_some() {
+            if let Some((ordinal, sorted_pos)) = self.sorted_get(&key)? {
+                if sorted_pos == cmd_pos {
+                    self.replayed.as_mut().expect("loading").set(ordinal);
+                    return self.entries.remove(&key, src);
+                }
+            }
+        }
+        let sorted = self.visible_sorted(&key)?;
+        match self.entries.insert(key, cmd_pos, src)? {
+            Some(old) => Ok(Some(old)),
+            None => Ok(sorted.map(|(ordinal, old)| {
+                self.superseded.set(ordinal);
+                old
+            })),
+        }
+    }
+
+    pub fn remove(&mut self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        if let Some(old) = self.entries.remove(key, src)? {
+            return Ok(Some(old));
+        }
+        Ok(self.visible_sorted(key)?.map(|(ordinal, old)| {
+            self.superseded.set(ordinal);
+            old
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        let sorted = match self.replayed.as_ref() {
+            Some(replayed) => replayed.ones,
+            None => self.sorted.as_ref().map_or(0, |sorted| sorted.count),
+        };
+        self.entries.len() + sorted - self.superseded.ones
+    }
+
+    /// entries of the sorted index, 0 without one
+    pub fn sorted_len(&self) -> usize {
+        self.sorted.as_ref().map_or(0, |sorted| sorted.count)
+    }
+
+    /// every live position
+    pub fn positions(&mut self) -> Result<Vec<CommandPos>> {
+        let mut positions = self.entries.positions();
+        self.for_each_sorted(|_, cmd_pos| positions.push(cmd_pos))?;
+        Ok(positions)
+    }
+
+    /// every live key in order
+    pub fn keys(&mut self, src : &mut dyn KeySource) -> Result<Vec<String>> {
+        let mut keys = self.entries.keys(src)?;
+        self.for_each_sorted(|key, _| keys.push(key))?;
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// take the entries kept in memory when they move to the sorted index on compaction,
+    /// the keys of a hashed index are read before their records move
+    pub fn drain_recent(&mut self, src : &mut dyn KeySource) -> Result<Vec<(String, CommandPos)>> {
+        if !self.use_sorted {
+            return Ok(Vec::new());
+        }
+        self.entries.drain_sorted(src)
+    }
+
+    /// map every live position through `relocate`. With a sorted index it is
+    /// rewritten with the `recent` entries merged in.
+    pub fn relocate<F>(&mut self, recent : Vec<(String, CommandPos)>, mut relocate : F) -> Result<()>
+        where F : FnMut(CommandPos) -> Result<CommandPos>
+    {
+        if !self.use_sorted {
+            return self.entries.relocate(&mut relocate);
+        }
+
+        let mut writer = SortedWriter::create(Arc::clone(&self.vfs), &self.dir.join(SORTED_INDEX))?;
+        let mut recent = recent.into_iter().peekable();
+        if let Some(sorted) = self.sorted.as_mut() {
+            for block in 0..sorted.blocks.len() {
+                for (i, (key, cmd_pos)) in sorted.read_block(block)?.into_iter().enumerate() {
+                    if self.superseded.get(block * BLOCK_KEYS + i) {
+                        continue;
+                    }
+                    while recent.peek().map_or(false, |(recent_key, _)| *recent_key < key) {
+                        let (recent_key, recent_pos) = recent.next().expect("peeked");
+                        writer.push(&recent_key, relocate(recent_pos)?)?;
+                    }
+                    writer.push(&key, relocate(cmd_pos)?)?;
+                }
+            }
+        }
+        for (key, cmd_pos) in recent {
+            writer.push(&key, relocate(cmd_pos)?)?;
+        }
+        let sorted = writer.finish()?;
+        self.superseded = Bits::new(sorted.count);
+        self.sorted = Some(sorted);
+        Ok(())
+    }
+
+    /// the sorted entry of the key, even if it isn't visible
+    fn sorted_get(&mut self, key : &str) -> Result<Option<(usize, CommandPos)>> {
+        match self.sorted.as_mut() {
+            Some(sorted) => sorted.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn visible(&self, ordinal : usize) -> bool {
+        !self.superseded.get(ordinal) && self.replayed.as_ref().map_or(true, |replayed| replayed.get(ordinal))
+    }
+
+    fn visible_sorted(&mut self, key : &str) -> Result<Option<(usize, CommandPos)>> {
+        Ok(self.sorted_get(key)?.filter(|&(ordinal, _)| self.visible(ordinal)))
+    }
+
+    fn for_each_sorted<F : FnMut(String, CommandPos)>(&mut self, mut f : F) -> Result<()> {
+        let blocks = self.sorted.as_ref().map_or(0, |sorted| sorted.blocks.len());
+        for block in 0..blocks {
+            let entries = self.sorted.as_mut().expect("sorted index").read_block(block)?;
+            for (i, (key, cmd_pos)) in entries.into_iter().enumerate() {
+                if self.visible(block * BLOCK_KEYS + i) {
+                    f(key, cmd_pos);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The entries kept in memory
+enum Entries {
+    Tree(BTreeMap<String, CommandPos>),
+    Hashed(Fingerprints),
+    Prefix(PrefixBlocks),
+}
+
+impl Entries {
+    fn new(mode : IndexMode) -> Self {
+        match mode {
+            IndexMode::Tree => Entries::Tree(BTreeMap::new()),
+            IndexMode::Hashed => Entries::Hashed(Fingerprints::default()),
+            IndexMode::Prefix => Entries::Prefix(PrefixBlocks::default()),
+        }
+    }
+
+    fn get(&self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        match self {
+            Entries::Tree(tree) => Ok(tree.get(key).cloned()),
+            Entries::Hashed(hashed) => hashed.get(key, src),
+            Entries::Prefix(prefix) => Ok(prefix.get(key)),
+        }
+    }
+
+    fn insert(&mut self, key : String, cmd_pos : CommandPos, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        match self {
+            Entries::Tree(tree) => Ok(tree.insert(key, cmd_pos)),
+            Entries::Hashed(hashed) => hashed.insert(&key, cmd_pos, src),
+            Entries::Prefix(prefix) => Ok(prefix.insert(key, cmd_pos)),
+        }
+    }
+
+    fn remove(&mut self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        match self {
+            Entries::Tree(tree) => Ok(tree.remove(key)),
+            Entries::Hashed(hashed) => hashed.remove(key, src),
+            Entries::Prefix(prefix) => Ok(prefix.remove(key)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Entries::Tree(tree) => tree.len(),
+            Entries::Hashed(hashed) => hashed.slots.len() + hashed.collisions.values().map(Vec::len).sum::<usize>(),
+            Entries::Prefix(prefix) => prefix.len,
+        }
+    }
+
+    fn positions(&self) -> Vec<CommandPos> {
+        match self {
+            Entries::Tree(tree) => tree.values().cloned().collect(),
+            Entries::Hashed(hashed) => hashed.positions().collect(),
+            Entries::Prefix(prefix) => prefix.blocks.values().flat_map(|block| block.positions.iter().cloned()).collect(),
+        }
+    }
+
+    fn keys(&self, src : &mut dyn KeySource) -> Result<Vec<String>> {
+        match self {
+            Entries::Tree(tree) => Ok(tree.keys().cloned().collect()),
+            Entries::Hashed(hashed) => hashed.positions().map(|cmd_pos| src.key_at(cmd_pos)).collect(),
+            Entries::Prefix(prefix) => Ok(prefix.blocks.iter().flat_map(|(first, block)| block.keys(first)).collect()),
+        }
+    }
+
+    /// take every entry in key order
+    fn drain_sorted(&mut self, src : &mut dyn KeySource) -> Result<Vec<(String, CommandPos)>> {
+        match self {
+            Entries::Tree(tree) => Ok(mem::replace(tree, BTreeMap::new()).into_iter().collect()),
+            Entries::Hashed(hashed) => {
+                let mut entries = hashed
+                    .positions()
+                    .map(|cmd_pos| Ok((src.key_at(cmd_pos)?, cmd_pos)))
+                    .collect::<Result<Vec<_>>>()?;
+                entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                *hashed = Fingerprints::default();
+                Ok(entries)
+            },
+            Entries::Prefix(prefix) => {
+                let prefix = mem::replace(prefix, PrefixBlocks::default());
+                Ok(prefix.blocks
+                    .into_iter()
+                    .flat_map(|(first, block)| block.keys(&first).into_iter().zip(block.positions))
+                    .collect())
+            },
+        }
+    }
+
+    fn relocate(&mut self, relocate : &mut dyn FnMut(CommandPos) -> Result<CommandPos>) -> Result<()> {
+        let positions : Vec<&mut CommandPos> = match self {
+            Entries::Tree(tree) => tree.values_mut().collect(),
+            Entries::Hashed(hashed) => hashed.slots.values_mut().chain(hashed.collisions.values_mut().flatten()).collect(),
+            Entries::Prefix(prefix) => prefix.blocks.values_mut().flat_map(|block| block.positions.iter_mut()).collect(),
+        };
+        for cmd_pos in positions {
+            *cmd_pos = relocate(*cmd_pos)?;
+        }
+        Ok(())
+    }
+}
+
+fn fingerprint(key : &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Positions by the fingerprint of their key, the keys themselves are only in the log
+#[derive(Default)]
+struct Fingerprints {
+    slots : HashMap<u64, CommandPos>,
+    // further keys sharing the fingerprint of a slot
+    collisions : HashMap<u64, Vec<CommandPos>>,
+}
+
+impl Fingerprints {
+    fn positions<'a>(&'a self) -> impl Iterator<Item = CommandPos> + 'a {
+        self.slots.values().chain(self.collisions.values().flatten()).cloned()
+    }
+
+    fn get(&self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        let fingerprint = fingerprint(key);
+        let candidates = self.slots.get(&fingerprint).into_iter().chain(self.collisions.get(&fingerprint).into_iter().flatten());
+        for &cmd_pos in candidates {
+            if src.key_at(cmd_pos)? == key {
+                return Ok(Some(cmd_pos));
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, key : &str, cmd_pos : CommandPos, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        let fingerprint = fingerprint(key);
+        match self.slots.get(&fingerprint).cloned() {
+            None => {
+                self.slots.insert(fingerprint, cmd_pos);
+                return Ok(None);
+            },
+            Some(old) if src.key_at(old)? == key => {
+                self.slots.insert(fingerprint, cmd_pos);
+                return Ok(Some(old));
+            },
+            Some(_) => {},
+        }
+        let others = self.collisions.entry(fingerprint).or_default();
+        for other in others.iter_mut() {
+            if src.key_at(*other)? == key {
+                return Ok(Some(mem::replace(other, cmd_pos)));
+            }
+        }
+        others.push(cmd_pos);
+        Ok(None)
+    }
+
+    fn remove(&mut self, key : &str, src : &mut dyn KeySource) -> Result<Option<CommandPos>> {
+        let fingerprint = fingerprint(key);
+        let old = match self.slots.get(&fingerprint) {
+            Some(&old) => old,
+            None => return Ok(None),
+        };
+        if src.key_at(old)? == key {
+            // a colliding key takes the slot
+            match self.collisions.get_mut(&fingerprint).and_then(Vec::pop) {
+                Some(other) => self.slots.insert(fingerprint, other),
+                None => self.slots.remove(&fingerprint),
+            };
+            if self.collisions.get(&fingerprint).map_or(false, Vec::is_empty) {
+                self.collisions.remove(&fingerprint);
+            }
+            return Ok(Some(old));
+        }
+        if let Some(others) = self.collisions.get_mut(&fingerprint) {
+            for i in 0..others.len() {
+                if src.key_at(others[i])? == key {
+                    let old = others.swap_remove(i);
+                    if others.is_empty() {
+                        self.collisions.remove(&fingerprint);
+                    }
+                    return Ok(Some(old));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Sorted blocks of at most `BLOCK_KEYS` keys, found by their first key
+#[derive(Default)]
+struct PrefixBlocks {
+    blocks : BTreeMap<String, Block>,
+    len : usize,
+}
+
+/// The keys of a block after its first one, each coded against the previous key
+struct Block {
+    suffixes : Vec<u8>,
+    positions : Vec<CommandPos>,
+}
+
+impl Block {
+    fn new(keys : &[String], positions : Vec<CommandPos>) -> Self {
+        let mut suffixes = Vec::new();
+        for pair in keys.windows(2) {
+            push_key(&mut suffixes, &pair[0], &pair[1]);
+        }
+        Block { suffixes, positions }
+    }
+
+    fn keys(&self, first : &str) -> Vec<String> {
+        let mut keys = vec![first.to_owned()];
+        let mut current = first.to_owned();
+        let mut buf = &self.suffixes[..];
+        while !buf.is_empty() {
+            next_key(&mut buf, &mut current).expect("prefix block is well formed");
+            keys.push(current.clone());
+        }
+        keys
+    }
+
+    /// index of the key in the block, decoded without allocating every key
+    fn find(&self, first : &str, key : &str) -> Option<usize> {
+        if first == key {
+            return Some(0);
+        }
+        let mut current = first.to_owned();
+        let mut buf = &self.suffixes[..];
+        let mut i = 0;
+        while !buf.is_empty() {
+            next_key(&mut buf, &mut current).expect("prefix block is well formed");
+            i += 1;
+            if current == key {
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+impl PrefixBlocks {
+    /// first key of the block which holds or would hold the key
+    fn block_of(&self, key : &str) -> Option<String> {
+        self.blocks
+            .range::<str, _>((Unbounded, Included(key)))
+            .next_back()
+            .or_else(|| self.blocks.iter().next())
+            .map(|(first, _)| first.clone())
+    }
+
+    fn get(&self, key : &str) -> Option<CommandPos> {
+        let (first, block) = self.blocks.range::<str, _>((Unbounded, Included(key))).next_back()?;
+        block.find(first, key).map(|i| block.positions[i])
+    }
+
+    fn insert(&mut self, key : String, cmd_pos : CommandPos) -> Option<CommandPos> {
+        let first = match self.block_of(&key) {
+            Some(first) => first,
+            None => {
+                self.len += 1;
+                self.put(vec![key], vec![cmd_pos]);
+                return None;
+            },
+        };
+        let block = self.blocks.remove(&first).expect("block of the key");
+        let mut keys = block.keys(&first);
+        let mut positions = block.positions;
+        let old = match keys.binary_search(&key) {
+            Ok(i) => Some(mem::replace(&mut positions[i], cmd_pos)),
+            Err(i) => {
+                keys.insert(i, key);
+                positions.insert(i, cmd_pos);
+                self.len += 1;
+                None
+            },
+        };
+        self.put(keys, positions);
+        old
+    }
+
+    fn remove(&mut self, key : &str) -> Option<CommandPos> {
+        let first = self.blocks.range::<str, _>((Unbounded, Included(key))).next_back()?.0.clone();
+        let i = self.blocks[&first].find(&first, key)?;
+        let block = self.blocks.remove(&first).expect("block of the key");
+        let mut keys = block.keys(&first);
+        let mut positions = block.positions;
+        keys.remove(i);
+        let old = positions.remove(i);
+        self.len -= 1;
+        self.put(keys, positions);
+        Some(old)
+    }
+
+    /// store the sorted keys as one block, or two if it is full
+    fn put(&mut self, mut keys : Vec<String>, mut positions : Vec<CommandPos>) {
+        if keys.len() > BLOCK_KEYS {
+            let upper_keys = keys.split_off(keys.len() / 2);
+            let upper_positions = positions.split_off(positions.len() / 2);
+            self.put(upper_keys, upper_positions);
+        }
+        if !keys.is_empty() {
+            let block = Block::new(&keys, positions);
+            self.blocks.insert(keys.swap_remove(0), block);
+        }
+    }
+}
+
+/// The sorted index file: a magic and the number of entries, then blocks of
+/// `BLOCK_KEYS` entries, each a big-endian u32 length and the entries coded
+/// against the previous key of the block.
+struct SortedIndex {
+    file : Box<dyn VfsFile>,
+    blocks : Vec<BlockHandle>,
+    count : usize,
+    // the block read last, keys are mostly looked up in order while loading
+    cached : Option<(usize, Vec<(String, CommandPos)>)>,
+}
+
+struct BlockHandle {
+    first_key : String,
+    offset : u64,
+    len : u32,
+}
+
+impl SortedIndex {
+    fn open(vfs : &dyn Vfs, path : &Path) -> Result<Self> {
+        let mut file = vfs.open(path)?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header)?;
+        if &header[..8] != SORTED_MAGIC {
+            return Err(corrupted());
+        }
+        let mut count = [0; 8];
+        count.copy_from_slice(&header[8..]);
+        let count = u64::from_be_bytes(count) as usize;
+
+        let mut index = SortedIndex { file, blocks : Vec::new(), count, cached : None };
+        let mut offset = header.len() as u64;
+        let end = index.file.size()?;
+        while offset < end {
+            let mut len = [0; 4];
+            index.file.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len);
+            let mut body = vec![0; len as usize];
+            index.file.read_exact(&mut body)?;
+            let mut first_key = String::new();
+            next_key(&mut &body[..], &mut first_key)?;
+            index.blocks.push(BlockHandle { first_key, offset : offset + 4, len });
+            offset += 4 + len as u64;
+        }
+        if index.blocks.len() != (count + BLOCK_KEYS - 1) / BLOCK_KEYS {
+            return Err(corrupted());
+        }
+        Ok(index)
+    }
+
+    fn read_block(&mut self, block : usize) -> Result<Vec<(String, CommandPos)>> {
+        let handle = &self.blocks[block];
+        let mut body = vec![0; handle.len as usize];
+        self.file.seek(SeekFrom::Start(handle.offset))?;
+        self.file.read_exact(&mut body)?;
+
+        let mut entries = Vec::with_capacity(BLOCK_KEYS);
+        let mut current = String::new();
+        let mut buf = &body[..];
+        while !buf.is_empty() {
+            next_key(&mut buf, &mut current)?;
+            let gen = get_varint(&mut buf)?;
+            let pos = get_varint(&mut buf)?;
+            let len = get_varint(&mut buf)?;
+            let version = get_varint(&mut buf)?;
+            let blob = get_varint(&mut buf)? == 1;
+            entries.push((current.clone(), CommandPos { gen, pos, len, version, blob }));
+        }
+        Ok(entries)
+    }
+
+    /// the ordinal and position of the key
+    fn get(&mut self, key : &str) -> Result<Option<(usize, CommandPos)>> {
+        let block = match self.blocks.binary_search_by(|handle| handle.first_key.as_str().cmp(key)) {
+            Ok(block) => block,
+            Err(0) => return Ok(None),
+            Err(block) => block - 1,
+        };
+        if self.cached.as_ref().map_or(true, |(cached, _)| *cached != block) {
+            self.cached = Some((block, self.read_block(block)?));
+        }
+        let entries = &self.cached.as_ref().expect("cached block").1;
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+            .ok()
+            .map(|i| (block * BLOCK_KEYS + i, entries[i].1)))
+    }
+}
+
+/// Writes a sorted index next to the current one, which it replaces when finished
+struct SortedWriter {
+    vfs : Arc<dyn Vfs>,
+    path : PathBuf,
+    tmp_path : PathBuf,
+    writer : BufWriter<Box<dyn VfsFile>>,
+    blocks : Vec<BlockHandle>,
+    block : Vec<u8>,
+    block_keys : usize,
+    first_key : String,
+    prev_key : String,
+    offset : u64,
+    count : usize,
+}
+
+impl SortedWriter {
+    fn create(vfs : Arc<dyn Vfs>, path : &Path) -> Result<Self> {
+        let tmp_path = path.with_extension("index.tmp");
+        let mut writer = BufWriter::new(vfs.create(&tmp_path)?);
+        // the number of entries is written once known
+        writer.write_all(SORTED_MAGIC)?;
+        writer.write_all(&[0; 8])?;
+        Ok(SortedWriter {
+            vfs,
+            path : path.to_owned(),
+            tmp_path,
+            writer,
+            blocks : Vec::new(),
+            block : Vec::new(),
+            block_keys : 0,
+            first_key : String::new(),
+            prev_key : String::new(),
+            offset : 16,
+            count : 0,
+        })
+    }
+
+    /// append an entry, keys are pushed in order
+    fn push(&mut self, key : &str, cmd_pos : CommandPos) -> Result<()> {
+        if self.block_keys == 0 {
+            self.first_key = key.to_owned();
+            self.prev_key.clear();
+        }
+        push_key(&mut self.block, &self.prev_key, key);
+        put_varint(&mut self.block, cmd_pos.gen);
+        put_varint(&mut self.block, cmd_pos.pos);
+        put_varint(&mut self.block, cmd_pos.len);
+        put_varint(&mut self.block, cmd_pos.version);
+        put_varint(&mut self.block, cmd_pos.blob as u64);
+        self.prev_key = key.to_owned();
+        self.block_keys += 1;
+        self.count += 1;
+        if self.block_keys == BLOCK_KEYS {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        let len = self.block.len() as u32;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            first_key : mem::replace(&mut self.first_key, String::new()),
+            offset : self.offset + 4,
+            len,
+        });
+        self.offset += 4 + len as u64;
+        self.block.clear();
+        self.block_keys = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<SortedIndex> {
+        if self.block_keys > 0 {
+            self.flush_block()?;
+        }
+        let mut file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(8))?;
+        file.write_all(&(self.count as u64).to_be_bytes())?;
+        file.sync()?;
+        self.vfs.rename(&self.tmp_path, &self.path)?;
+        Ok(SortedIndex {
+            file : self.vfs.open(&self.path)?,
+            blocks : self.blocks,
+            count : self.count,
+            cached : None,
+        })
+    }
+}
+
+/// Flags of the entries of a sorted index
+struct Bits {
+    words : Vec<u64>,
+    ones : usize,
+}
+
+impl Bits {
+    fn new(len : usize) -> Self {
+        Bits { words : vec![0; (len + 63) / 64], ones : 0 }
+    }
+
+    fn get(&self, i : usize) -> bool {
+        self.words.get(i / 64).map_or(false, |word| word & (1 << (i % 64)) != 0)
+    }
+
+    fn set(&mut self, i : usize) {
+        if !self.get(i) {
+            self.words[i / 64] |= 1 << (i % 64);
+            self.ones += 1;
+        }
+    }
+}
+
+/// the error of a sorted index or table which can't be decoded
+pub(super) fn corrupted() -> KvsError {
+    KvsError::Corruption("the sorted file is corrupted".to_owned())
+}
+
+pub(crate) fn put_varint(buf : &mut Vec<u8>, mut value : u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn get_varint(buf : &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or_else(corrupted)?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(corrupted())
+}
+
+/// append `key` as the length of the prefix it shares with `prev` and the rest of it
+pub(super) fn push_key(buf : &mut Vec<u8>, prev : &str, key : &str) {
+    let mut shared = prev.bytes().zip(key.bytes()).take_while(|(a, b)| a == b).count();
+    while !key.is_char_boundary(shared) {
+        shared -= 1;
+    }
+    put_varint(buf, shared as u64);
+    put_varint(buf, (key.len() - shared) as u64);
+    buf.extend_from_slice(key[shared..].as_bytes());
+}
+
+/// replace `current` by the next key coded against it
+pub(super) fn next_key(buf : &mut &[u8], current : &mut String) -> Result<()> {
+    let shared = get_varint(buf)? as usize;
+    let len = get_varint(buf)? as usize;
+    if shared > current.len() || len > buf.len() || !current.is_char_boundary(shared) {
+        return Err(corrupted());
+    }
+    let suffix = std::str::from_utf8(&buf[..len]).map_err(|_| corrupted())?;
+    current.truncate(shared);
+    current.push_str(suffix);
+    *buf = &buf[len..];
+    Ok(())
+}
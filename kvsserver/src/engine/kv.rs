@@ -1,31 +1,183 @@
-use std::collections::{HashMap, BTreeMap};
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
 use super::{Result, KvsError};
-use std::fs::{self, File, OpenOptions};
 use std::path::{Path, PathBuf};
-use std::io::{self, Read, Write, Seek, SeekFrom, BufWriter, BufReader};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufRead, BufWriter, BufReader};
 use std::ffi::OsStr;
 use std::ops::Range;
-use super::KvsEngine;
+use std::sync::Arc;
+use std::time::Duration;
+use super::{KvsEngine, EngineStats, LruCache, MergeOperand};
+use super::cache::{CacheAdmission, ValueCache};
+use super::codec::{Codec, RecordEncoder};
+use super::crypto::{is_sealed_blob, EncryptionKey, LogCipher};
+use super::evict::{now_millis, EvictionPolicy, Evictor};
+use super::keydir::{IndexMode, KeyDir, KeySource};
+use super::secondary::{encode, IndexDef, IndexQuery, SecondaryIndexes};
+use super::vfs::{Mapping, OsVfs, Vfs, VfsFile};
 
-use serde_json::{self};
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 
 
 
 const MAX_UNCOMPACTED_SIZE : u64 = 1024 * 1024;
+const MAX_FILE_SIZE : u64 = 4 * 1024 * 1024;
+const COMPACTION_RATIO : f64 = 0.5;
+const MAX_OPEN_READERS : usize = 64;
+const COMPRESSION_THRESHOLD : usize = 256;
+// the progress of an interrupted rekey
+const REKEY_FILE : &str = "rekey";
+const REKEY_BLOB_SUFFIX : &str = ".blob.rekey";
+
+/// Tunables of the KvStore
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// roll the active generation to a new file once it grows beyond this size
+    pub max_file_size : u64,
+    /// start a compaction once this many stale bytes are in the log
+    pub compaction_threshold : u64,
+    /// a generation is selected for compaction once this ratio of it is stale
+    pub compaction_ratio : f64,
+    /// at most this many generation files are kept open for reading, or mapped with `use_mmap`
+    pub max_open_readers : usize,
+    /// read the immutable generations through memory maps instead of buffered readers
+    pub use_mmap : bool,
+    /// keep up to this many previous versions of each key, also across compaction
+    pub max_versions : usize,
+    /// evict keys once their records take more than this many bytes of the log, 0 is unlimited
+    pub max_live_bytes : u64,
+    /// evict keys once there are more than this many, 0 is unlimited
+    pub max_keys : usize,
+    /// which keys are evicted first when `max_live_bytes` or `max_keys` is reached
+    pub eviction_policy : EvictionPolicy,
+    /// keep the values of up to this many hot keys in memory, 0 disables the value cache
+    pub value_cache_entries : usize,
+    /// which values read from the log are admitted into the value cache
+    pub value_cache_admission : CacheAdmission,
+    /// values of at least this many bytes are stored in blob files next to the log, 0 disables blobs
+    pub blob_threshold : usize,
+    /// codec of the records written to the log
+    pub compression : Codec,
+    /// records smaller than this many bytes are not compressed
+    pub compression_threshold : usize,
+    /// compaction re-encodes the records it copies with `compression`, instead of copying them as they are
+    pub recompress : bool,
+    /// seal every record and blob with this key, records and blobs which aren't sealed are refused.
+    /// The log and the blobs hold all the data the store writes, there are no hint files to seal.
+    /// A `sorted_index` isn't sealed, it is refused with `KvsError::Unsupported` along with the key
+    pub encryption_key : Option<EncryptionKey>,
+    /// read the records and blobs which aren't sealed although `encryption_key` is given,
+    /// like the ones written before encryption was enabled. They aren't authenticated,
+    /// it is only meant to open the store for `rekey`, which seals them
+    pub allow_plaintext : bool,
+    /// how the keys of the index are kept in memory
+    pub index_mode : IndexMode,
+    /// move the index to a sorted file on compaction, only recent writes stay in memory
+    pub sorted_index : bool,
+    /// sync the log after every write, a write may be lost on power loss otherwise.
+    /// Blobs are synced whatever this says, before the record referring to them is written
+    pub sync : bool,
+    /// the filesystem of the log, the blobs and the sorted index
+    pub vfs : Arc<dyn Vfs>,
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            max_file_size : MAX_FILE_SIZE,
+            compaction_threshold : MAX_UNCOMPACTED_SIZE,
+            compaction_ratio : COMPACTION_RATIO,
+            max_open_readers : MAX_OPEN_READERS,
+            use_mmap : false,
+            max_versions : 0,
+            max_live_bytes : 0,
+            max_keys : 0,
+            eviction_policy : EvictionPolicy::Lru,
+            value_cache_entries : 0,
+            value_cache_admission : CacheAdmission::All,
+            blob_threshold : 0,
+            compression : Codec::None,
+            compression_threshold : COMPRESSION_THRESHOLD,
+            recompress : false,
+            encryption_key : None,
+            allow_plaintext : false,
+            index_mode : IndexMode::Tree,
+            sorted_index : false,
+            sync : false,
+            vfs : Arc::new(OsVfs),
+        }
+    }
+}
 
 /// KeyValue pairs storage engine use HashMap<String, String>
 ///
 ///
 pub struct KvStore {
     path : PathBuf,
-    writer : BufWriterWithPos<File>,
-    readers : HashMap<u64, BufReaderWithPos<File>>,
-    // index of each item
-    index : BTreeMap<String, CommandPos>,
+    config : KvStoreConfig,
+    encoder : RecordEncoder,
+    writer : BufWriterWithPos<Box<dyn VfsFile>>,
+    readers : Readers,
+    // only used when `use_mmap` is enabled
+    mmaps : Option<MmapReaders>,
+    // index of each live item, removed keys leave the index
+    index : KeyDir,
+    // merge operands written after the latest set or remove of a key, folded on get
+    merges : HashMap<String, Vec<CommandPos>>,
+    // previous versions of the keys ordered by version, at most `max_versions` of each
+    history : HashMap<String, VecDeque<VersionPos>>,
+    // secondary indexes, their entries are recorded in the log
+    secondary : SecondaryIndexes,
+    // definition record of each secondary index, its version tells the definitions of a name apart
+    secondary_defs : HashMap<String, CommandPos>,
+    // entry record of each key in each secondary index, by index name
+    secondary_entries : HashMap<String, HashMap<String, CommandPos>>,
+    // entry records read when loading, used once they match the version of their key
+    loaded_entries : HashMap<(String, String), LoadedEntry>,
+    // keys indexed from their values on open, their entries were missing or stale
+    rebuilt_keys : u64,
+    // stamped on the next write
+    next_version : u64,
+    // only used in cache mode, when a budget of live bytes or keys is configured
+    evictor : Option<Evictor>,
+    // only used when `value_cache_entries` is set
+    values : Option<ValueCache>,
+    // size and stale bytes of each generation
+    gens : BTreeMap<u64, GenInfo>,
     current_gen : u64,
+    // the generation a compaction is writing, like the active one it isn't mapped
+    compaction_gen : Option<u64>,
     // uncompacted size of the removed data
     uncompacted : u64,
+    // a write to the log failed, what reached the files is only known once reopened
+    failed : bool,
+    // versions of the blobs of dropped records, removed once the records superseding them are durable
+    dropped_blobs : Vec<u64>,
+}
+
+/// Size, stale bytes and tombstones of a generation
+#[derive(Debug, Default, Clone)]
+struct GenInfo {
+    size : u64,
+    stale : u64,
+    // the removed keys aren't kept in memory, they are read back when compacting
+    tombstones : Vec<CommandPos>,
+}
+
+/// An entry record of a secondary index read when loading
+struct LoadedEntry {
+    cmd_pos : CommandPos,
+    // version of the definition of the index
+    def : u64,
+    field : Option<Value>,
+}
+
+/// Position of a previous version of a key
+#[derive(Debug, Clone, Copy)]
+struct VersionPos {
+    cmd_pos : CommandPos,
+    // tombstones are counted as stale when written
+    stale : bool,
 }
 
 
@@ -41,148 +193,1269 @@ impl KvStore {
     /// build index and
     pub fn open<P>(path : P) -> Result<KvStore>
         where P : Into<PathBuf>
+    {
+        KvStore::with_config(path, KvStoreConfig::default())
+    }
+
+    /// open file from specified path with the given config
+    pub fn with_config<P>(path : P, config : KvStoreConfig) -> Result<KvStore>
+        where P : Into<PathBuf>
     {
         let path = path.into();
-        let mut index = BTreeMap::new();
-        let mut readers = HashMap::new();
+        // the keys in the sorted index would be readable
+        if config.sorted_index && config.encryption_key.is_some() {
+            return Err(KvsError::Unsupported("a sorted index of an encrypted store".to_owned()));
+        }
+        recover_rekey(config.vfs.as_ref(), &path)?;
+        let index = KeyDir::open(&config.vfs, &path, config.index_mode, config.sorted_index)?;
+        let gen_list = sorted_gen_list(config.vfs.as_ref(), &path)?;
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(config.vfs.as_ref(), &path, current_gen)?;
+        let readers = Readers::new(Arc::clone(&config.vfs), path.clone(), config.max_open_readers);
+        let mmaps = if config.use_mmap {
+            Some(MmapReaders::new(Arc::clone(&config.vfs), path.clone(), config.max_open_readers))
+        } else {
+            None
+        };
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0 as u64;
+        let evictor = if config.max_live_bytes > 0 || config.max_keys > 0 {
+            Some(Evictor::new(config.eviction_policy, config.max_live_bytes, config.max_keys))
+        } else {
+            None
+        };
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
-        }
+        let values = if config.value_cache_entries > 0 {
+            Some(ValueCache::new(config.value_cache_entries, config.value_cache_admission))
+        } else {
+            None
+        };
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        // add writer to readers
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let encoder = RecordEncoder {
+            codec : config.compression,
+            threshold : config.compression_threshold,
+            cipher : config.encryption_key.as_ref().map(|key| Arc::new(LogCipher::new(key))),
+            allow_plaintext : config.allow_plaintext,
+        };
 
-        Ok(KvStore {
+        let mut store = KvStore {
             path,
+            config,
+            encoder,
             writer,
             readers,
+            mmaps,
             index,
+            merges : HashMap::new(),
+            history : HashMap::new(),
+            secondary : SecondaryIndexes::default(),
+            secondary_defs : HashMap::new(),
+            secondary_entries : HashMap::new(),
+            loaded_entries : HashMap::new(),
+            rebuilt_keys : 0,
+            next_version : 1,
+            evictor,
+            values,
+            gens : BTreeMap::new(),
             current_gen,
-            uncompacted,
-        })
+            compaction_gen : None,
+            uncompacted : 0,
+            failed : false,
+            dropped_blobs : Vec::new(),
+        };
+        // replaying a record removes the blob it supersedes, which must not outlive the record
+        let sync_log = !blob_list(store.config.vfs.as_ref(), &store.path)?.is_empty();
+        for &gen in &gen_list {
+            store.load(gen, sync_log)?;
+        }
+        if !store.index.finish_load() {
+            warn!("the sorted index doesn't match the log, it is rebuilt");
+            let KvStore { path, config, .. } = store;
+            KeyDir::remove_sorted(config.vfs.as_ref(), &path)?;
+            return KvStore::with_config(path, config);
+        }
+        store.gens.insert(current_gen, GenInfo::default());
+        store.remove_dropped_blobs()?;
+        store.remove_orphan_blobs()?;
+        store.build_indexes()?;
+        // the budget may have been lowered since the last run
+        store.enforce_budget(None)?;
+        Ok(store)
+    }
+
+    /// Load Command from specified gen log file,
+    /// save the each command int the index.
+    /// A record torn by a crash ends the generation, which is never appended to again.
+    /// The generation is synced first if `sync`, its records may not be durable yet after a crash
+    fn load(&mut self, gen : u64, sync : bool) -> Result<()> {
+        let mut file = self.config.vfs.open(&log_path(&self.path, gen))?;
+        if sync {
+            file.sync()?;
+        }
+        let mut reader = BufReaderWithPos::new(file)?;
+        // start pos of file
+        let mut pos = 0 as u64;
+
+        loop {
+            let command = match self.encoder.read::<Command, _>(&mut reader, gen, pos) {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(ref err) if is_torn(err) => {
+                    warn!("generation {} is torn at {}, the rest is dropped: {}", gen, pos, err);
+                    break;
+                },
+                Err(err) => return Err(err),
+            };
+            let new_pos = reader.pos;
+            debug_assert!(pos < new_pos, "new_pos shuld be smaller than new_pos");
+
+            let mut cmd_pos = CommandPos::new(gen, pos..new_pos, command.version());
+            cmd_pos.blob = command.blob();
+            self.next_version = self.next_version.max(cmd_pos.version + 1);
+            match command {
+                Command::Set{key, expires_at, ..} => {
+                    match self.index_get(&key)? {
+                        // a compaction which crashed before removing its input leaves
+                        // both copies of a record, they share the blob of the version
+                        Some(old) if old.blob && old.version == cmd_pos.version => self.mark_stale(&old),
+                        _ => self.retire(&key, false)?,
+                    }
+                    if let Some(evictor) = self.evictor.as_mut() {
+                        evictor.insert(&key, cmd_pos.len, expires_at);
+                    }
+                    self.index_insert(key, cmd_pos)?;
+                },
+                Command::Remove{key, ..} | Command::Evict{key, ..} => {
+                    self.retire(&key, false)?;
+                    self.add_tombstone(&key, cmd_pos);
+                    if let Some(evictor) = self.evictor.as_mut() {
+                        evictor.remove(&key);
+                    }
+                },
+                Command::Merge{key, ..} => {
+                    self.merges.entry(key.clone()).or_default().push(cmd_pos);
+                    self.track_write(&key)?;
+                },
+                Command::Version{key, ..} => {
+                    self.push_history(&key, VersionPos { cmd_pos, stale : false });
+                },
+                // the indexes are built once every generation is loaded
+                Command::Index{name, def, ..} => self.apply_index_def(name, def, cmd_pos),
+                Command::Entry{index, key, def, field, ..} => self.load_entry(index, key, LoadedEntry { cmd_pos, def, field }),
+            }
+
+            pos = new_pos;
+        }
+        self.gens.entry(gen).or_default().size = pos;
+
+        Ok(())
+    }
+
+    fn new_log_file(&mut self, gen : u64) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
+        let writer = new_log_file(self.config.vfs.as_ref(), &self.path, gen)?;
+        self.gens.insert(gen, GenInfo::default());
+        Ok(writer)
+    }
+
+    /// start a new active generation, the old one becomes immutable
+    fn roll(&mut self) -> Result<()> {
+        self.current_gen += 1;
+        self.writer = self.new_log_file(self.current_gen)?;
+        Ok(())
+    }
+
+    /// bookkeeping after a command has been appended to the active generation
+    fn after_write(&mut self, len : u64) -> Result<()> {
+        self.gens.entry(self.current_gen).or_default().size += len;
+        self.remove_dropped_blobs()?;
+        if self.writer.pos >= self.config.max_file_size {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    fn mark_stale(&mut self, cmd_pos : &CommandPos) {
+        mark_stale(&mut self.gens, cmd_pos);
+        self.uncompacted += cmd_pos.len;
+    }
+
+    /// refuse to write once a write to the log failed, the store must be reopened
+    fn check_failed(&self) -> Result<()> {
+        if self.failed {
+            return Err(KvsError::StringError("a write to the log failed, the store must be reopened".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// append the command to the active generation
+    fn append_command(&mut self, cmd : &Command) -> Result<CommandPos> {
+        self.check_failed()?;
+        let pos = self.writer.pos;
+        let record = self.encoder.encode(cmd, self.current_gen, pos)?;
+        if let Err(err) = self.write_record(&record) {
+            self.failed = true;
+            return Err(err);
+        }
+        let mut cmd_pos = CommandPos::new(self.current_gen, pos..self.writer.pos, cmd.version());
+        cmd_pos.blob = cmd.blob();
+        Ok(cmd_pos)
+    }
+
+    fn write_record(&mut self, record : &[u8]) -> Result<()> {
+        self.writer.write_all(record)?;
+        // BufWriter should be flushed after serialize
+        self.writer.flush()?;
+        if self.config.sync {
+            self.writer.get_mut().sync()?;
+        }
+        Ok(())
+    }
+
+    /// the record at `cmd_pos` is superseded and no longer referenced,
+    /// the blob it owns is removed with it by `remove_dropped_blobs`
+    fn drop_record(&mut self, cmd_pos : &CommandPos) {
+        self.mark_stale(cmd_pos);
+        if cmd_pos.blob {
+            self.dropped_blobs.push(cmd_pos.version);
+        }
+    }
+
+    /// Remove the blobs of the dropped records. The records superseding them are
+    /// in the active generation, or in generations synced by loading or compaction,
+    /// the active one is synced first so that a power loss never keeps a record
+    /// whose blob is gone.
+    fn remove_dropped_blobs(&mut self) -> Result<()> {
+        if self.dropped_blobs.is_empty() {
+            return Ok(());
+        }
+        if !self.config.sync {
+            self.writer.flush()?;
+            if let Err(err) = self.writer.get_mut().sync() {
+                self.failed = true;
+                return Err(err.into());
+            }
+        }
+        for version in std::mem::replace(&mut self.dropped_blobs, Vec::new()) {
+            // the blob is gone already if a removal is replayed when loading
+            match self.config.vfs.remove_file(&blob_path(&self.path, version)) {
+                Err(ref err) if err.kind() != io::ErrorKind::NotFound => {
+                    warn!("cannot remove the blob of version {}: {}", version, err);
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// remove the blob files which no record refers to, left by a crash before their record was written
+    fn remove_orphan_blobs(&mut self) -> Result<()> {
+        let referenced : HashSet<u64> = self.index
+            .positions()?
+            .iter()
+            .chain(self.history.values().flatten().map(|entry| &entry.cmd_pos))
+            .filter(|cmd_pos| cmd_pos.blob)
+            .map(|cmd_pos| cmd_pos.version)
+            .collect();
+        for version in blob_list(self.config.vfs.as_ref(), &self.path)? {
+            if !referenced.contains(&version) {
+                self.config.vfs.remove_file(&blob_path(&self.path, version))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// read the value stored in the blob of `version`
+    fn blob_value(&self, version : u64) -> Result<String> {
+        String::from_utf8(self.blob_bytes(version)?).map_err(|err| KvsError::Utf8Error(err.utf8_error()))
+    }
+
+    fn blob_bytes(&self, version : u64) -> Result<Vec<u8>> {
+        let bytes = self.config.vfs.read(&blob_path(&self.path, version))?;
+        match self.encoder.cipher.as_ref() {
+            Some(_) if self.encoder.allow_plaintext && !is_sealed_blob(&bytes) => Ok(bytes),
+            Some(cipher) => cipher.open_blob(version, &bytes),
+            None if is_sealed_blob(&bytes) => Err(KvsError::Encryption("the blob is encrypted, but no key is given".to_owned())),
+            None => Ok(bytes),
+        }
+    }
+
+    /// write the blob of `version`, sealed if `encoder` has a cipher
+    fn write_blob(&self, path : &Path, encoder : &RecordEncoder, version : u64, value : &[u8]) -> Result<()> {
+        let mut file = self.config.vfs.create(path)?;
+        match encoder.cipher.as_ref() {
+            Some(cipher) => file.write_all(&cipher.seal_blob(version, value)?)?,
+            None => file.write_all(value)?,
+        }
+        self.sync_blob(&mut file)
+    }
+
+    /// the blob must be durable before the record referring to it is written,
+    /// a compaction may make the record durable even without `sync`
+    fn sync_blob(&self, file : &mut Box<dyn VfsFile>) -> Result<()> {
+        file.sync()?;
+        Ok(())
+    }
+
+    /// the set of the value at `version`, values beyond `blob_threshold` are written to a blob
+    fn set_command(
+        &self,
+        encoder : &RecordEncoder,
+        key : String,
+        value : String,
+        version : u64,
+        expires_at : Option<u64>,
+    ) -> Result<Command> {
+        if self.config.blob_threshold > 0 && value.len() >= self.config.blob_threshold {
+            self.write_blob(&blob_path(&self.path, version), encoder, version, value.as_bytes())?;
+            return Ok(Command::Set { key, value : String::new(), version, expires_at, blob : true });
+        }
+        Ok(Command::Set { key, value, version, expires_at, blob : false })
+    }
+
+    fn next_version(&mut self) -> u64 {
+        let version = self.next_version;
+        self.next_version += 1;
+        version
+    }
+
+    /// version of the current value, the version of the last merge operand if there are any
+    fn current_version(&mut self, key : &str) -> Result<Option<u64>> {
+        if let Some(operand) = self.merges.get(key).and_then(|operands| operands.last()) {
+            return Ok(Some(operand.version));
+        }
+        Ok(self.index_get(key)?.map(|cmd_pos| cmd_pos.version))
+    }
+
+    /// the index and a reader of the log, which a hashed index verifies its keys with
+    fn split_index(&mut self) -> (&mut KeyDir, LogReader<'_>) {
+        let log = LogReader {
+            readers : &mut self.readers,
+            mmaps : self.mmaps.as_mut(),
+            encoder : &self.encoder,
+            current_gen : self.current_gen,
+            compaction_gen : self.compaction_gen,
+        };
+        (&mut self.index, log)
+    }
+
+    fn index_get(&mut self, key : &str) -> Result<Option<CommandPos>> {
+        let (index, mut log) = self.split_index();
+        index.get(key, &mut log)
+    }
+
+    fn index_insert(&mut self, key : String, cmd_pos : CommandPos) -> Result<Option<CommandPos>> {
+        let (index, mut log) = self.split_index();
+        index.insert(key, cmd_pos, &mut log)
+    }
+
+    fn index_remove(&mut self, key : &str) -> Result<Option<CommandPos>> {
+        let (index, mut log) = self.split_index();
+        index.remove(key, &mut log)
+    }
+
+    /// Take the current version of the key out of the index and the merge operands.
+    /// It becomes stale, or moves into the history when previous versions are kept.
+    /// Pending merge operands are folded into a version record first if `fold_merges`,
+    /// when loading that record follows in the log.
+    fn retire(&mut self, key : &str, fold_merges : bool) -> Result<()> {
+        if let Some(values) = self.values.as_mut() {
+            values.invalidate(key);
+        }
+        if self.config.max_versions == 0 || !self.merges.contains_key(key) {
+            self.clear_merges(key);
+            if let Some(old_cmd) = self.index_remove(key)? {
+                if self.config.max_versions == 0 {
+                    self.drop_record(&old_cmd);
+                } else {
+                    self.push_history(key, VersionPos { cmd_pos : old_cmd, stale : false });
+                }
+            }
+            return Ok(());
+        }
+
+        if fold_merges {
+            let version = self.current_version(key)?.unwrap_or(0);
+            let value = self.folded_value(key)?;
+            let cmd_pos = self.append_command(&Command::Version { key : key.to_owned(), version, value, blob : false })?;
+            self.gens.entry(self.current_gen).or_default().size += cmd_pos.len;
+            self.push_history(key, VersionPos { cmd_pos, stale : false });
+        }
+        self.clear_merges(key);
+        if let Some(old_cmd) = self.index_remove(key)? {
+            self.drop_record(&old_cmd);
+        }
+        Ok(())
+    }
+
+    /// keep the tombstone of a removed key until it is compacted with the older generations
+    fn add_tombstone(&mut self, key : &str, cmd_pos : CommandPos) {
+        // the tombstone itself is garbage once compacted with the older generations
+        self.mark_stale(&cmd_pos);
+        self.gens.entry(cmd_pos.gen).or_default().tombstones.push(cmd_pos);
+        // tombstones written by compaction carry no version
+        if self.config.max_versions > 0 && cmd_pos.version > 0 {
+            self.push_history(key, VersionPos { cmd_pos, stale : true });
+        }
+    }
+
+    /// insert the previous version in order, the oldest versions beyond `max_versions` become stale
+    fn push_history(&mut self, key : &str, entry : VersionPos) {
+        let max_versions = self.config.max_versions;
+        let mut evicted = Vec::new();
+        let mut duplicate = None;
+        {
+            let history = self.history.entry(key.to_owned()).or_default();
+            // a tombstone kept by compaction next to the version record of the same removal,
+            // or the copy of a version record left by a crashed compaction, which shares its blob
+            if history.iter().any(|old| old.cmd_pos.version == entry.cmd_pos.version) {
+                duplicate = Some(entry);
+            } else {
+                let at = history
+                    .iter()
+                    .position(|old| old.cmd_pos.version > entry.cmd_pos.version)
+                    .unwrap_or_else(|| history.len());
+                history.insert(at, entry);
+            }
+            while history.len() > max_versions {
+                evicted.extend(history.pop_front());
+            }
+        }
+        if let Some(duplicate) = duplicate.filter(|duplicate| !duplicate.stale) {
+            self.mark_stale(&duplicate.cmd_pos);
+        }
+        for old in evicted {
+            if !old.stale {
+                self.drop_record(&old.cmd_pos);
+            }
+        }
+    }
+
+    /// bytes of the log taken by the current value of the key
+    fn live_bytes(&mut self, key : &str) -> Result<u64> {
+        let base = self.index_get(key)?.map_or(0, |cmd_pos| cmd_pos.len);
+        let operands : u64 = self.merges.get(key).map_or(0, |operands| operands.iter().map(|cmd_pos| cmd_pos.len).sum());
+        Ok(base + operands)
+    }
+
+    /// account a write of the key in cache mode
+    fn track_write(&mut self, key : &str) -> Result<()> {
+        if self.evictor.is_some() {
+            let bytes = self.live_bytes(key)?;
+            self.evictor.as_mut().expect("cache mode").write(key, bytes);
+        }
+        Ok(())
+    }
+
+    /// write the set of the key, which expires at `expires_at` in cache mode
+    fn write_set(&mut self, key : String, value : String, expires_at : Option<u64>) -> Result<()> {
+        let version = self.next_version();
+        let cmd = self.set_command(&self.encoder, key.clone(), value, version, expires_at)?;
+        self.write_set_command(key, &cmd, expires_at)
+    }
+
+    fn write_set_command(&mut self, key : String, cmd : &Command, expires_at : Option<u64>) -> Result<()> {
+        let cmd_pos = self.append_command(cmd)?;
+
+        self.retire(&key, true)?;
+        if let Some(evictor) = self.evictor.as_mut() {
+            evictor.insert(&key, cmd_pos.len, expires_at);
+        }
+        self.index_insert(key.clone(), cmd_pos)?;
+        self.reindex(&key)?;
+        self.after_write(cmd_pos.len)?;
+        self.enforce_budget(Some(&key))?;
+        self.maybe_compact()
+    }
+
+    /// remove the key with an eviction tombstone
+    fn evict(&mut self, key : String) -> Result<()> {
+        let version = self.next_version();
+        let cmd_pos = self.append_command(&Command::Evict { key : key.clone(), version })?;
+
+        self.retire(&key, true)?;
+        self.add_tombstone(&key, cmd_pos);
+        self.index_value(&key, None)?;
+        if let Some(evictor) = self.evictor.as_mut() {
+            evictor.remove(&key);
+        }
+        self.after_write(cmd_pos.len)
+    }
+
+    /// evict keys by the eviction policy until the store fits its budget again,
+    /// the key just written is spared as long as there are others
+    fn enforce_budget(&mut self, written : Option<&str>) -> Result<()> {
+        loop {
+            let victim = match self.evictor.as_ref() {
+                Some(evictor) if evictor.over_budget() => evictor.victim(written).map(str::to_owned),
+                _ => None,
+            };
+            match victim {
+                Some(key) => {
+                    self.evict(key)?;
+                    self.evictor.as_mut().expect("cache mode").evictions += 1;
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// whether the ttl of the key has passed
+    fn expired(&self, key : &str) -> bool {
+        self.evictor.as_ref().map_or(false, |evictor| evictor.expired(key, now_millis()))
+    }
+
+    /// evict the key whose ttl has passed, expired keys are only removed when accessed
+    fn expire(&mut self, key : String) -> Result<()> {
+        self.evict(key)?;
+        self.evictor.as_mut().expect("cache mode").expirations += 1;
+        Ok(())
+    }
+
+    /// the value recorded at `cmd_pos`, `None` if it is a removal
+    fn recorded_value(&mut self, cmd_pos : CommandPos) -> Result<Option<String>> {
+        match self.read_command(cmd_pos)? {
+            Command::Set{blob : true, version, ..} | Command::Version{blob : true, version, ..} => {
+                Ok(Some(self.blob_value(version)?))
+            },
+            Command::Set{value, ..} => Ok(Some(value)),
+            Command::Remove{..} | Command::Evict{..} => Ok(None),
+            Command::Version{value, ..} => Ok(value),
+            Command::Merge{..} | Command::Index{..} | Command::Entry{..} => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// drop the pending merge operands of the key, they are superseded
+    fn clear_merges(&mut self, key : &str) {
+        if let Some(operands) = self.merges.remove(key) {
+            for cmd_pos in &operands {
+                self.mark_stale(cmd_pos);
+            }
+        }
+    }
+
+    /// the value of the latest set, without the pending merge operands
+    fn base_value(&mut self, key : &str) -> Result<Option<String>> {
+        match self.index_get(key)? {
+            Some(cmd_pos) => match self.read_command(cmd_pos)? {
+                Command::Set{blob : true, version, ..} => Ok(Some(self.blob_value(version)?)),
+                Command::Set{value, ..} => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// the base value with every pending merge operand folded in
+    fn folded_value(&mut self, key : &str) -> Result<Option<String>> {
+        let mut value = self.base_value(key)?;
+        let operands = match self.merges.get(key) {
+            Some(operands) => operands.clone(),
+            None => return Ok(value),
+        };
+        for cmd_pos in operands {
+            match self.read_command(cmd_pos)? {
+                Command::Merge{operand, ..} => {
+                    value = Some(operand.apply(value.as_ref().map(String::as_str))?);
+                },
+                _ => return Err(KvsError::UnexpectedCommandType),
+            }
+        }
+        Ok(value)
+    }
+
+    /// apply the record defining the secondary index, or dropping it if `def` is `None`
+    fn apply_index_def(&mut self, name : String, def : Option<IndexDef>, cmd_pos : CommandPos) {
+        if let Some(old_def) = self.secondary_defs.remove(&name) {
+            self.mark_stale(&old_def);
+        }
+        // the entries of the previous definition
+        if let Some(entries) = self.secondary_entries.remove(&name) {
+            for cmd_pos in entries.values() {
+                self.mark_stale(cmd_pos);
+            }
+        }
+        match def {
+            Some(def) => {
+                self.secondary.define(name.clone(), def);
+                self.secondary_defs.insert(name, cmd_pos);
+            },
+            None => {
+                self.secondary.remove(&name);
+                self.add_tombstone(&name, cmd_pos);
+            },
+        }
+    }
+
+    /// keep the latest entry record of the key in the index, a compaction which
+    /// crashed before removing its input leaves both copies of a record
+    fn load_entry(&mut self, index : String, key : String, entry : LoadedEntry) {
+        let order = |entry : &LoadedEntry| (entry.cmd_pos.version, entry.def);
+        let stale = match self.loaded_entries.get(&(index.clone(), key.clone())) {
+            Some(old) if order(old) > order(&entry) => Some(entry.cmd_pos),
+            Some(old) => Some(old.cmd_pos),
+            None => None,
+        };
+        if stale != Some(entry.cmd_pos) {
+            self.loaded_entries.insert((index, key), entry);
+        }
+        if let Some(cmd_pos) = stale {
+            self.mark_stale(&cmd_pos);
+        }
+    }
+
+    /// index every key covered by the secondary index
+    fn build_index(&mut self, name : &str) -> Result<()> {
+        let def = match self.secondary.def(name) {
+            Some(def) => def.clone(),
+            None => return Ok(()),
+        };
+        for key in self.keys()? {
+            if def.covers(&key) {
+                let version = self.current_version(&key)?.unwrap_or(0);
+                let value = self.folded_value(&key)?;
+                self.write_entry(name, &key, version, value.as_ref().map(String::as_str))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index the keys covered by every secondary index on open by the entry records
+    /// matching their current versions. A key whose entries are missing, because a crash
+    /// cut them off its write or the store predates them, is indexed by its value, which
+    /// records the entries again.
+    fn build_indexes(&mut self) -> Result<()> {
+        let mut loaded = std::mem::replace(&mut self.loaded_entries, HashMap::new());
+        if !self.secondary_defs.is_empty() {
+            for key in self.keys()? {
+                let names = self.secondary.covering(&key);
+                if names.is_empty() {
+                    continue;
+                }
+                let version = self.current_version(&key)?;
+                let mut missing = false;
+                for name in names {
+                    let def = self.secondary_defs[&name].version;
+                    match loaded.remove(&(name.clone(), key.clone())) {
+                        Some(entry) if entry.def == def && Some(entry.cmd_pos.version) == version => {
+                            self.secondary.set_field(&name, &key, entry.field.as_ref().and_then(encode));
+                            self.secondary_entries.entry(name).or_default().insert(key.clone(), entry.cmd_pos);
+                        },
+                        Some(entry) => {
+                            self.mark_stale(&entry.cmd_pos);
+                            missing = true;
+                        },
+                        None => missing = true,
+                    }
+                }
+                if missing {
+                    self.reindex(&key)?;
+                    self.rebuilt_keys += 1;
+                }
+            }
+            debug!("{} secondary indexes loaded, {} keys rebuilt from their values", self.secondary.len(), self.rebuilt_keys);
+        }
+        // the entries of removed keys and dropped indexes
+        for entry in loaded.values() {
+            self.mark_stale(&entry.cmd_pos);
+        }
+        Ok(())
+    }
+
+    /// update the secondary indexes covering the key with its current value
+    fn reindex(&mut self, key : &str) -> Result<()> {
+        if self.secondary.covers(key) {
+            let value = self.folded_value(key)?;
+            self.index_value(key, value.as_ref().map(String::as_str))?;
+        }
+        Ok(())
+    }
+
+    /// index the current value of the key, `None` once it is removed. The entries
+    /// of a removed key are stale, its tombstone supersedes them when loading
+    fn index_value(&mut self, key : &str, value : Option<&str>) -> Result<()> {
+        let names = self.secondary.covering(key);
+        if names.is_empty() {
+            return Ok(());
+        }
+        let version = match value {
+            Some(_) => self.current_version(key)?.unwrap_or(0),
+            None => 0,
+        };
+        for name in names {
+            match value {
+                Some(_) => self.write_entry(&name, key, version, value)?,
+                None => {
+                    let old = self.secondary_entries.get_mut(&name).and_then(|entries| entries.remove(key));
+                    if let Some(old) = old {
+                        self.mark_stale(&old);
+                    }
+                    self.secondary.set_field(&name, key, None);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// append the entry of the key in the index for the value of `version`, superseding the previous one
+    fn write_entry(&mut self, name : &str, key : &str, version : u64, value : Option<&str>) -> Result<()> {
+        let field = match (self.secondary.def(name), value) {
+            (Some(def), Some(value)) => def.scalar(value),
+            _ => None,
+        };
+        let def = self.secondary_defs.get(name).map_or(0, |cmd_pos| cmd_pos.version);
+        let encoded = field.as_ref().and_then(encode);
+        let cmd = Command::Entry { index : name.to_owned(), key : key.to_owned(), version, def, field };
+        let cmd_pos = self.append_command(&cmd)?;
+        self.gens.entry(self.current_gen).or_default().size += cmd_pos.len;
+
+        self.secondary.set_field(name, key, encoded);
+        let old = self.secondary_entries.entry(name.to_owned()).or_default().insert(key.to_owned(), cmd_pos);
+        if let Some(old) = old {
+            self.mark_stale(&old);
+        }
+        Ok(())
+    }
+
+    /// generations whose stale ratio reached `compaction_ratio`,
+    /// or all of them when none did
+    fn select_compaction_gens(&self) -> Vec<u64> {
+        let selected : Vec<u64> = self
+            .gens
+            .iter()
+            .filter(|(_, info)| {
+                info.size > 0 && info.stale as f64 >= info.size as f64 * self.config.compaction_ratio
+            })
+            .map(|(&gen, _)| gen)
+            .collect();
+
+        if selected.is_empty() {
+            self.gens.keys().cloned().collect()
+        } else {
+            selected
+        }
+    }
+
+    /// merge the live commands of the given generations into a new generation
+    /// and remove them. The active generation is rolled first, so it may be
+    /// selected as well.
+    pub fn compact_gens(&mut self, gens : &[u64]) -> Result<()> {
+        let output = self.encoder.clone();
+        self.compact_into(gens, &output, &mut || Ok(()))
+    }
+
+    /// Rewrite every generation and blob under `key`, or in plaintext if it is `None`.
+    /// The progress is kept in the rekey file, a rekey interrupted before the
+    /// rewritten log is durable is rolled back on open, and finished after.
+    pub fn rekey(&mut self, key : Option<EncryptionKey>) -> Result<()> {
+        let output = RecordEncoder {
+            cipher : key.as_ref().map(|key| Arc::new(LogCipher::new(key))),
+            allow_plaintext : false,
+            ..self.encoder.clone()
+        };
+        // the compaction rolls the active generation and writes the one after it
+        let progress = RekeyProgress { first_gen : self.current_gen + 1, committed : false };
+        progress.write(self.config.vfs.as_ref(), &self.path)?;
+
+        // the blobs are sealed aside first, and replace the old ones once the log is rewritten
+        let blobs : Vec<u64> = self.index
+            .positions()?
+            .iter()
+            .chain(self.history.values().flatten().map(|entry| &entry.cmd_pos))
+            .filter(|cmd_pos| cmd_pos.blob)
+            .map(|cmd_pos| cmd_pos.version)
+            .collect();
+        for &version in &blobs {
+            let value = self.blob_bytes(version)?;
+            self.write_blob(&rekey_blob_path(&self.path, version), &output, version, &value)?;
+        }
+
+        let gens : Vec<u64> = self.gens.keys().cloned().collect();
+        let vfs = Arc::clone(&self.config.vfs);
+        let path = self.path.clone();
+        let committed = RekeyProgress { committed : true, ..progress };
+        self.compact_into(&gens, &output, &mut || committed.write(vfs.as_ref(), &path))?;
+        self.encoder = output;
+        self.config.encryption_key = key;
+        self.config.allow_plaintext = false;
+        for &version in &blobs {
+            // folding merge operands may have dropped the blob meanwhile
+            if self.config.vfs.exists(&blob_path(&self.path, version)) {
+                self.config.vfs.rename(&rekey_blob_path(&self.path, version), &blob_path(&self.path, version))?;
+            } else {
+                self.config.vfs.remove_file(&rekey_blob_path(&self.path, version))?;
+            }
+        }
+        self.config.vfs.remove_file(&self.path.join(REKEY_FILE))?;
+        Ok(())
     }
 
-    fn new_log_file(&mut self, gen : u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+    /// compact the generations into one written by `output`, `durable` runs once
+    /// it is synced and before the compacted generations are removed
+    fn compact_into(&mut self, gens : &[u64], output : &RecordEncoder, durable : &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        self.check_failed()?;
+        let result = self.try_compact_into(gens, output, durable);
+        if let Some(compaction_gen) = self.compaction_gen.take() {
+            // a map of the generation would be as short as it was while it was written
+            if let Some(mmaps) = self.mmaps.as_mut() {
+                mmaps.remove(compaction_gen);
+            }
+        }
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
     }
 
-    pub fn compact(&mut self) -> Result<()> {
+    fn try_compact_into(&mut self, gens : &[u64], output : &RecordEncoder, durable : &mut dyn FnMut() -> Result<()>) -> Result<()> {
+        let selected : HashSet<u64> = gens
+            .iter()
+            .filter(|gen| self.gens.contains_key(gen))
+            .cloned()
+            .collect();
+        if selected.is_empty() {
+            return Ok(());
+        }
+
+        // every command copied into the compaction generation is the latest one of its key,
+        // the new active generation must still be replayed after it
         let compaction_gen = self.current_gen + 1;
+        self.compaction_gen = Some(compaction_gen);
         self.current_gen += 2;
         self.writer = self.new_log_file(self.current_gen)?;
         let mut compaction_writer = self.new_log_file(compaction_gen)?;
+        let mut kept_tombstones = Vec::new();
+        let input = self.encoder.clone();
+        let recompress = self.config.recompress;
+
+        // collapse the pending merge operands of every key into a set
+        let merged_keys : Vec<String> = self.merges.keys().cloned().collect();
+        for key in merged_keys {
+            let operands = self.merges[&key].clone();
+            match self.folded_value(&key) {
+                Ok(Some(value)) => {
+                    let version = self.current_version(&key)?.unwrap_or(0);
+                    let expires_at = self.evictor.as_ref().and_then(|evictor| evictor.expires_at(&key));
+                    let cmd = self.set_command(output, key.clone(), value, version, expires_at)?;
+                    let pos = compaction_writer.pos;
+                    compaction_writer.write_all(&output.encode(&cmd, compaction_gen, pos)?)?;
+                    let mut cmd_pos = CommandPos::new(compaction_gen, pos..compaction_writer.pos, version);
+                    cmd_pos.blob = cmd.blob();
+                    self.clear_merges(&key);
+                    if let Some(old_cmd) = self.index_insert(key.clone(), cmd_pos)? {
+                        self.drop_record(&old_cmd);
+                    }
+                    if let Some(evictor) = self.evictor.as_mut() {
+                        evictor.resize(&key, cmd_pos.len);
+                    }
+                },
+                result => {
+                    // keep the records, but move them together so they are replayed in order
+                    if let Err(err) = result {
+                        warn!("cannot collapse the merge operands of {:?}: {}", key, err);
+                    }
+                    if let Some(base) = self.index_get(&key)? {
+                        let cmd_pos = copy_command(&mut self.readers, base, compaction_gen, &mut compaction_writer, &input, output, recompress)?;
+                        self.mark_stale(&base);
+                        self.index_insert(key.clone(), cmd_pos)?;
+                    } else {
+                        // an older set must not become the base again
+                        let pos = compaction_writer.pos;
+                        compaction_writer.write_all(&output.encode(&Command::remove(key.clone(), 0), compaction_gen, pos)?)?;
+                        kept_tombstones.push(CommandPos::new(compaction_gen, pos..compaction_writer.pos, 0));
+                    }
+                    let mut moved = Vec::with_capacity(operands.len());
+                    for &operand in &operands {
+                        moved.push(copy_command(&mut self.readers, operand, compaction_gen, &mut compaction_writer, &input, output, recompress)?);
+                        self.mark_stale(&operand);
+                    }
+                    self.merges.insert(key, moved);
+                },
+            }
+        }
 
-        let mut new_pos = 0;
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                     .readers
-                     .get_mut(&cmd_pos.gen)
-                     .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        // a hashed index reads the keys of the moved records back
+        compaction_writer.flush()?;
+        // the recent entries move to the sorted index, which is rewritten in key order
+        let recent = {
+            let (index, mut log) = self.split_index();
+            index.drain_recent(&mut log)?
+        };
+        let readers = &mut self.readers;
+        let writer = &mut compaction_writer;
+        self.index.relocate(recent, |cmd_pos| {
+            if selected.contains(&cmd_pos.gen) {
+                copy_command(readers, cmd_pos, compaction_gen, writer, &input, output, recompress)
+            } else {
+                Ok(cmd_pos)
             }
+        })?;
+        compaction_writer.flush()?;
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+        for (_, cmd_pos) in self.secondary_defs.iter_mut() {
+            if selected.contains(&cmd_pos.gen) {
+                *cmd_pos = copy_command(&mut self.readers, *cmd_pos, compaction_gen, &mut compaction_writer, &input, output, recompress)?;
+            }
+        }
+        for cmd_pos in self.secondary_entries.values_mut().flat_map(HashMap::values_mut) {
+            if selected.contains(&cmd_pos.gen) {
+                *cmd_pos = copy_command(&mut self.readers, *cmd_pos, compaction_gen, &mut compaction_writer, &input, output, recompress)?;
+            }
         }
 
-        // remove stale readers 
-        let stale_gens : Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
+        // previous versions are rewritten as version records, which never change the current value
+        let moved_versions : Vec<(String, usize, CommandPos)> = self
+            .history
+            .iter()
+            .flat_map(|(key, history)| {
+                history
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| selected.contains(&entry.cmd_pos.gen))
+                    .map(move |(i, entry)| (key.clone(), i, entry.cmd_pos))
+            })
             .collect();
+        for (key, i, cmd_pos) in moved_versions {
+            // a blob stays where it is, only the reference to it is rewritten
+            let value = if cmd_pos.blob { None } else { self.recorded_value(cmd_pos)? };
+            let version = cmd_pos.version;
+            let pos = compaction_writer.pos;
+            let cmd = Command::Version { key : key.clone(), version, value, blob : cmd_pos.blob };
+            compaction_writer.write_all(&output.encode(&cmd, compaction_gen, pos)?)?;
+            let entry = &mut self.history.get_mut(&key).expect("history of moved version")[i];
+            entry.cmd_pos = CommandPos::new(compaction_gen, pos..compaction_writer.pos, version);
+            entry.cmd_pos.blob = cmd_pos.blob;
+            entry.stale = false;
+        }
 
-        for &stale_gen in &stale_gens {
-            self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+        // a tombstone is only needed while an older generation that is kept may hold its key
+        let oldest_kept = self.gens.keys().find(|gen| !selected.contains(gen)).cloned();
+        for &gen in &selected {
+            let tombstones = match self.gens.get_mut(&gen) {
+                Some(info) => std::mem::replace(&mut info.tombstones, Vec::new()),
+                None => continue,
+            };
+            if oldest_kept.map_or(true, |oldest| oldest > gen) {
+                continue;
+            }
+            for tombstone in tombstones {
+                // the key has been set again, or is handled with its merge operands,
+                // or the index has been defined again
+                let live = match self.read_command(tombstone)? {
+                    Command::Remove{key, ..} | Command::Evict{key, ..} => {
+                        self.merges.contains_key(&key) || self.index_get(&key)?.is_some()
+                    },
+                    Command::Index{name, ..} => self.secondary.contains(&name),
+                    _ => return Err(KvsError::UnexpectedCommandType),
+                };
+                if live {
+                    continue;
+                }
+                kept_tombstones.push(copy_command(&mut self.readers, tombstone, compaction_gen, &mut compaction_writer, &input, output, recompress)?);
+            }
+        }
+        compaction_writer.flush()?;
+        // the compacted generations are removed below, their records must be durable first
+        compaction_writer.get_mut().sync()?;
+        durable()?;
+
+        let tombstone_size = kept_tombstones.iter().map(|cmd_pos : &CommandPos| cmd_pos.len).sum();
+        self.gens.insert(compaction_gen, GenInfo {
+            size : compaction_writer.pos,
+            stale : tombstone_size,
+            tombstones : kept_tombstones,
+        });
+        self.uncompacted += tombstone_size;
+
+        // remove stale readers, the oldest generation first so that
+        // a crash never leaves a generation without the older ones it overrides
+        let mut stale_gens : Vec<u64> = selected.into_iter().collect();
+        stale_gens.sort_unstable();
+        for stale_gen in stale_gens {
+            self.readers.remove(stale_gen);
+            if let Some(mmaps) = self.mmaps.as_mut() {
+                mmaps.remove(stale_gen);
+            }
+            if let Some(info) = self.gens.remove(&stale_gen) {
+                self.uncompacted -= info.stale;
+            }
+            self.config.vfs.remove_file(&log_path(&self.path, stale_gen))?;
         }
+        // the records superseding the dropped blobs are in the synced compaction generation
+        self.remove_dropped_blobs()
+    }
+
+    /// read the command at `cmd_pos`, immutable generations are sliced
+    /// from their memory map when `use_mmap` is enabled
+    fn read_command(&mut self, cmd_pos : CommandPos) -> Result<Command> {
+        self.split_index().1.read(cmd_pos)
+    }
 
-        self.uncompacted = 0;
+    /// compact the generations selected by the stale ratio
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.uncompacted >= self.config.compaction_threshold {
+            let gens = self.select_compaction_gens();
+            self.compact_gens(&gens)?;
+        }
         Ok(())
     }
 }
 
 impl KvsEngine for KvStore {
     fn set(&mut self, key : String, value : String) -> Result<()> {
-        let set_command = Command::set(key.clone(), value.clone());
-        
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &set_command)?;
-        self.writer.flush()?;
-        let now_pos = self.writer.pos;
-        
-        if let Some(old_cmd) = self.index.insert(key, (self.current_gen, pos..now_pos).into()) {
-            self.uncompacted += old_cmd.len;
+        self.write_set(key, value, None)
+    }
+
+    /// store the value read from `reader` in a blob file without holding it in memory,
+    /// it must be UTF-8 to be read back with `get`
+    fn set_from_reader(&mut self, key : String, reader : &mut dyn Read) -> Result<()> {
+        if self.config.blob_threshold == 0 {
+            let mut value = String::new();
+            reader.read_to_string(&mut value)?;
+            return self.set(key, value);
+        }
+        let version = self.next_version();
+        if self.encoder.cipher.is_some() {
+            // a blob is sealed as a whole
+            let mut value = Vec::new();
+            reader.read_to_end(&mut value)?;
+            self.write_blob(&blob_path(&self.path, version), &self.encoder, version, &value)?;
+        } else {
+            let mut file = self.config.vfs.create(&blob_path(&self.path, version))?;
+            io::copy(reader, &mut file)?;
+            self.sync_blob(&mut file)?;
         }
+        let cmd = Command::Set { key : key.clone(), value : String::new(), version, expires_at : None, blob : true };
+        self.write_set_command(key, &cmd, None)
+    }
 
-        if self.uncompacted >= MAX_UNCOMPACTED_SIZE {
-            self.compact()?;
+    /// stream the value from its blob file, other values and sealed blobs are read into memory
+    fn get_reader(&mut self, key : String) -> Result<Option<Box<dyn Read>>> {
+        let streamed = self.encoder.cipher.is_none() && !self.merges.contains_key(&key) && !self.expired(&key);
+        let blob = match self.index_get(&key)? {
+            Some(cmd_pos) if cmd_pos.blob && streamed => cmd_pos.version,
+            _ => {
+                let value = self.get(key)?;
+                return Ok(value.map(|value| Box::new(io::Cursor::new(value.into_bytes())) as Box<dyn Read>));
+            },
+        };
+        if let Some(evictor) = self.evictor.as_mut() {
+            evictor.touch(&key, None);
         }
+        Ok(Some(Box::new(BufReader::new(self.config.vfs.open(&blob_path(&self.path, blob))?))))
+    }
 
-        Ok(())
+    /// set the pair which expires after `ttl`, only in cache mode
+    fn set_with_ttl(&mut self, key : String, value : String, ttl : Duration) -> Result<()> {
+        if self.evictor.is_none() {
+            return Err(KvsError::Unsupported("ttl outside of cache mode".to_owned()));
+        }
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.write_set(key, value, Some(expires_at))
     }
 
     /// get the value from key from anything which implement the Into<String> trait
-     fn get(&mut self, key : String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("File not found");
-            
-
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            }
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        if self.expired(&key) {
+            self.expire(key)?;
+            self.maybe_compact()?;
+            return Ok(None);
+        }
+        if let Some(evictor) = self.evictor.as_mut() {
+            evictor.touch(&key, None);
+        }
+        if let Some(value) = self.values.as_mut().and_then(|values| values.get(&key)) {
+            return Ok(Some(value));
+        }
+        let value = self.folded_value(&key)?;
+        if let (Some(values), Some(value)) = (self.values.as_mut(), value.as_ref()) {
+            values.admit(&key, value);
+        }
+        Ok(value)
+    }
 
-            let entry_reader = reader.take(cmd_pos.len);
-            if let Command::Set{value, ..} = serde_json::from_reader(entry_reader)? {
-                return Ok(Some(value));
-            } else {
-                return Ok(None);
+    /// append the operand to the log, it is folded on get and collapsed on compaction
+    fn merge(&mut self, key : String, operand : MergeOperand) -> Result<()> {
+        if self.expired(&key) {
+            self.expire(key.clone())?;
+        }
+        // an operand which cannot be folded would make the key unreadable
+        let existing = self.folded_value(&key)?;
+        let value = operand.apply(existing.as_ref().map(String::as_str))?;
+        // every merged value is a version of its own when previous versions are kept
+        if self.config.max_versions > 0 {
+            let expires_at = self.evictor.as_ref().and_then(|evictor| evictor.expires_at(&key));
+            return self.write_set(key, value, expires_at);
+        }
+        let version = self.next_version();
+        let cmd_pos = self.append_command(&Command::merge(key.clone(), operand, version))?;
+
+        self.merges.entry(key.clone()).or_default().push(cmd_pos);
+        if let Some(values) = self.values.as_mut() {
+            values.invalidate(&key);
+        }
+        self.index_value(&key, Some(&value))?;
+        self.track_write(&key)?;
+        self.after_write(cmd_pos.len)?;
+        self.enforce_budget(Some(&key))?;
+        self.maybe_compact()
+    }
+
+    /// the value of the key at `version`, `None` if the key was removed by that version
+    fn get_version(&mut self, key : String, version : u64) -> Result<Option<String>> {
+        if self.current_version(&key)? == Some(version) {
+            return self.folded_value(&key);
+        }
+        let cmd_pos = self
+            .history
+            .get(&key)
+            .and_then(|history| history.iter().find(|entry| entry.cmd_pos.version == version))
+            .map(|entry| entry.cmd_pos)
+            .ok_or(KvsError::VersionNotFound(version))?;
+        self.recorded_value(cmd_pos)
+    }
+
+    /// the kept previous versions and the current one, oldest first
+    fn history(&mut self, key : String) -> Result<Vec<(u64, Option<String>)>> {
+        let entries : Vec<CommandPos> = self
+            .history
+            .get(&key)
+            .map(|history| history.iter().map(|entry| entry.cmd_pos).collect())
+            .unwrap_or_default();
+        let mut versions = Vec::with_capacity(entries.len() + 1);
+        for cmd_pos in entries {
+            versions.push((cmd_pos.version, self.recorded_value(cmd_pos)?));
+        }
+        if let Some(version) = self.current_version(&key)? {
+            versions.push((version, self.folded_value(&key)?));
+        }
+        Ok(versions)
+    }
+
+    fn version(&mut self, key : String) -> Result<Option<u64>> {
+        if self.expired(&key) {
+            return Ok(None);
+        }
+        self.current_version(&key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = {
+            let (index, mut log) = self.split_index();
+            index.keys(&mut log)?
+        };
+        let merged : Vec<String> = self.merges.keys().cloned().collect();
+        for key in merged {
+            if self.index_get(&key)?.is_none() {
+                keys.push(key);
             }
         }
-        Ok(None)
+        keys.retain(|key| !self.expired(key));
+        Ok(keys)
+    }
+
+    /// The definition is recorded in the log, followed by an entry record of every
+    /// covered key. Each later write of a covered key appends its entries after its
+    /// record, stamped with its version, so an entry cut off by a crash is noticed on open.
+    fn create_index(&mut self, name : String, keyspace : String, pointer : String) -> Result<()> {
+        let def = IndexDef::new(keyspace, pointer)?;
+        let version = self.next_version();
+        let cmd_pos = self.append_command(&Command::Index { name : name.clone(), def : Some(def.clone()), version })?;
+        self.apply_index_def(name.clone(), Some(def), cmd_pos);
+        self.build_index(&name)?;
+        self.after_write(cmd_pos.len)?;
+        self.maybe_compact()
+    }
+
+    fn drop_index(&mut self, name : String) -> Result<()> {
+        if !self.secondary.contains(&name) {
+            return Err(KvsError::IndexNotFound(name));
+        }
+        let cmd_pos = self.append_command(&Command::Index { name : name.clone(), def : None, version : 0 })?;
+        self.apply_index_def(name, None, cmd_pos);
+        self.after_write(cmd_pos.len)?;
+        self.maybe_compact()
+    }
+
+    /// the matching keys whose ttl hasn't passed
+    fn query(&mut self, index : String, query : IndexQuery) -> Result<Vec<String>> {
+        let mut keys = self.secondary.query(&index, &query)?;
+        keys.retain(|key| !self.expired(key));
+        Ok(keys)
+    }
+
+    fn indexes(&mut self) -> Result<Vec<(String, String, String)>> {
+        Ok(self.secondary.defs().map(|(name, def)| (name.clone(), def.keyspace.clone(), def.pointer.clone())).collect())
+    }
+
+    /// merge every generation into a new one
+    fn compact(&mut self) -> Result<()> {
+        let gens : Vec<u64> = self.gens.keys().cloned().collect();
+        self.compact_gens(&gens)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut stats = EngineStats::default();
+        stats.set("keys", self.index.len() as u64);
+        stats.set("tombstones", self.gens.values().map(|info| info.tombstones.len() as u64).sum());
+        stats.set("history_entries", self.history.values().map(|history| history.len() as u64).sum());
+        stats.set("generations", self.gens.len() as u64);
+        stats.set("uncompacted_bytes", self.uncompacted);
+        stats.set("open_readers", self.readers.cache.len() as u64);
+        stats.set("reader_cache_hits", self.readers.hits);
+        stats.set("reader_cache_misses", self.readers.misses);
+        if let Some(mmaps) = self.mmaps.as_ref() {
+            stats.set("mapped_generations", mmaps.maps.len() as u64);
+        }
+        stats.set("secondary_indexes", self.secondary.len() as u64);
+        stats.set("secondary_rebuilt_keys", self.rebuilt_keys);
+        if self.config.sorted_index {
+            stats.set("sorted_index_keys", self.index.sorted_len() as u64);
+        }
+        let blobs = self.index
+            .positions()?
+            .iter()
+            .chain(self.history.values().flatten().map(|entry| &entry.cmd_pos))
+            .filter(|cmd_pos| cmd_pos.blob)
+            .count();
+        stats.set("blobs", blobs as u64);
+        if let Some(values) = self.values.as_ref() {
+            stats.set("value_cache_entries", values.len() as u64);
+            stats.set("value_cache_hits", values.hits);
+            stats.set("value_cache_misses", values.misses);
+            stats.set("value_cache_hit_rate", values.hit_rate());
+        }
+        if let Some(evictor) = self.evictor.as_ref() {
+            stats.set("live_bytes", evictor.live_bytes());
+            stats.set("evictions", evictor.evictions);
+            stats.set("expirations", evictor.expirations);
+            stats.set("expiring_keys", evictor.expiring(now_millis()) as u64);
+        }
+        Ok(stats)
     }
 
 
     /// remove the key-value pair from kv-storage if it exist
     fn remove(&mut self, key : String) -> Result<()> {
-        if let None = self.index.get(&key) {
+        if !self.merges.contains_key(&key) && self.index_get(&key)?.is_none() {
             return Err(KvsError::KeyNotFound);
         }
-        let cmd = Command::remove(key.clone());
+        let version = self.next_version();
+        let cmd_pos = self.append_command(&Command::remove(key.clone(), version))?;
 
-        let prev_pos = self.writer.pos; 
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        // BufWriter should be flushed after serialize
-        self.writer.flush()?;
-        let new_pos = self.writer.pos;
-        let cmd_pos : CommandPos = (self.current_gen , prev_pos..new_pos).into();
-
-        self.uncompacted += cmd_pos.len;
-        if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
-            self.uncompacted += old_cmd.len;
+        self.retire(&key, true)?;
+        self.add_tombstone(&key, cmd_pos);
+        self.index_value(&key, None)?;
+        if let Some(evictor) = self.evictor.as_mut() {
+            evictor.remove(&key);
         }
-        Ok(())
+        self.after_write(cmd_pos.len)?;
+        self.maybe_compact()
     }
 }
 
-fn sorted_gen_list(path : &Path) -> Result<Vec<u64>>{
-    let mut gen_list : Vec<u64> = fs::read_dir(path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path())})
-        .filter(|path| path.is_file() && Some(OsStr::new("log")) == path.extension())
+fn sorted_gen_list(vfs : &dyn Vfs, path : &Path) -> Result<Vec<u64>>{
+    let mut gen_list : Vec<u64> = vfs.list(path)?
+        .into_iter()
+        .filter(|path| Some(OsStr::new("log")) == path.extension())
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -200,72 +1473,419 @@ fn log_path(dir : &Path, gen : u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-fn new_log_file(
-    path : &Path, 
-    gen : u64, 
-    readers : &mut HashMap<u64, BufReaderWithPos<File>>
-) -> Result<BufWriterWithPos<File>> {
+fn blob_path(dir : &Path, version : u64) -> PathBuf {
+    dir.join(format!("{}.blob", version))
+}
+
+/// the blob of `version` sealed under a new key, until the log is rewritten
+fn rekey_blob_path(dir : &Path, version : u64) -> PathBuf {
+    dir.join(format!("{}{}", version, REKEY_BLOB_SUFFIX))
+}
+
+/// versions of the blobs sealed under a new key in the directory
+fn rekey_blob_list(vfs : &dyn Vfs, path : &Path) -> Result<Vec<u64>> {
+    Ok(vfs.list(path)?
+        .into_iter()
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .filter(|name| name.ends_with(REKEY_BLOB_SUFFIX))
+                .map(|name| name[..name.len() - REKEY_BLOB_SUFFIX.len()].parse::<u64>())
+        })
+        .flatten()
+        .collect())
+}
+
+/// The progress of `rekey`, kept in the directory until the blobs are in place
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RekeyProgress {
+    // the generations from this one on are written under the new key
+    first_gen : u64,
+    // the rewritten log is durable, the older generations are only left over
+    committed : bool,
+}
+
+impl RekeyProgress {
+    /// replace the rekey file, the new one is synced before it replaces the old one
+    fn write(&self, vfs : &dyn Vfs, dir : &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", REKEY_FILE));
+        let mut file = vfs.create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync()?;
+        vfs.rename(&tmp, &dir.join(REKEY_FILE))?;
+        Ok(())
+    }
+}
+
+/// Finish the rekey interrupted once its log was durable, the store is then
+/// opened with the new key. Otherwise roll it back, the store is opened with the old key
+fn recover_rekey(vfs : &dyn Vfs, dir : &Path) -> Result<()> {
+    let marker = dir.join(REKEY_FILE);
+    let tmp = dir.join(format!("{}.tmp", REKEY_FILE));
+    if vfs.exists(&tmp) {
+        vfs.remove_file(&tmp)?;
+    }
+    let progress = if vfs.exists(&marker) {
+        Some(serde_json::from_slice::<RekeyProgress>(&vfs.read(&marker)?)?)
+    } else {
+        None
+    };
+
+    match progress {
+        Some(progress) if progress.committed => {
+            warn!("finishing an interrupted rekey, the store is under the new key");
+            for gen in sorted_gen_list(vfs, dir)? {
+                if gen < progress.first_gen {
+                    vfs.remove_file(&log_path(dir, gen))?;
+                }
+            }
+            for version in rekey_blob_list(vfs, dir)? {
+                if vfs.exists(&blob_path(dir, version)) {
+                    vfs.rename(&rekey_blob_path(dir, version), &blob_path(dir, version))?;
+                } else {
+                    vfs.remove_file(&rekey_blob_path(dir, version))?;
+                }
+            }
+        },
+        progress => {
+            if let Some(progress) = progress {
+                warn!("rolling back an interrupted rekey, the store stays under the old key");
+                for gen in sorted_gen_list(vfs, dir)? {
+                    if gen >= progress.first_gen {
+                        vfs.remove_file(&log_path(dir, gen))?;
+                    }
+                }
+            }
+            for version in rekey_blob_list(vfs, dir)? {
+                vfs.remove_file(&rekey_blob_path(dir, version))?;
+            }
+        },
+    }
+    if vfs.exists(&marker) {
+        vfs.remove_file(&marker)?;
+    }
+    Ok(())
+}
+
+/// versions of the blob files in the directory
+fn blob_list(vfs : &dyn Vfs, path : &Path) -> Result<Vec<u64>> {
+    Ok(vfs.list(path)?
+        .into_iter()
+        .filter(|path| Some(OsStr::new("blob")) == path.extension())
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(|s| s.parse::<u64>())
+        })
+        .flatten()
+        .collect())
+}
+
+fn is_false(value : &bool) -> bool {
+    !*value
+}
+
+fn new_log_file(vfs : &dyn Vfs, path : &Path, gen : u64) -> Result<BufWriterWithPos<Box<dyn VfsFile>>> {
     let path = log_path(path, gen);
-    let writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&path)?
-    )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(path)?)?);
+    let writer = BufWriterWithPos::new(vfs.append(&path)?)?;
     Ok(writer)
 }
 
+/// whether the record ended with the file, as it does when a crash tore its write
+fn is_torn(err : &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Serde(err) => err.is_eof(),
+        _ => false,
+    }
+}
 
+/// Reads the records of the log. It borrows the readers apart from the index,
+/// so that a hashed index can verify its keys while it is updated.
+struct LogReader<'a> {
+    readers : &'a mut Readers,
+    mmaps : Option<&'a mut MmapReaders>,
+    encoder : &'a RecordEncoder,
+    // the generations still written, the active one and the one of a running compaction
+    current_gen : u64,
+    compaction_gen : Option<u64>,
+}
+
+impl<'a> LogReader<'a> {
+    /// read the command at `cmd_pos`, immutable generations are sliced
+    /// from their memory map when `use_mmap` is enabled
+    fn read(&mut self, cmd_pos : CommandPos) -> Result<Command> {
+        let written = cmd_pos.gen == self.current_gen || Some(cmd_pos.gen) == self.compaction_gen;
+        if let Some(mmaps) = self.mmaps.as_mut().filter(|_| !written) {
+            let map = mmaps.get(cmd_pos.gen)?;
+            let start = cmd_pos.pos as usize;
+            let end = (cmd_pos.pos + cmd_pos.len) as usize;
+            let record = map.get(start..end).ok_or_else(|| KvsError::Corruption(format!(
+                "the record at {} of generation {} ends beyond its {} bytes", cmd_pos.pos, cmd_pos.gen, map.len()
+            )))?;
+            return self.encoder.decode(record, cmd_pos.gen, cmd_pos.pos);
+        }
+
+        let reader = self.readers.get(cmd_pos.gen)?;
+        if reader.pos != cmd_pos.pos {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        }
+        let mut record = vec![0; cmd_pos.len as usize];
+        reader.read_exact(&mut record)?;
+        self.encoder.decode(&record, cmd_pos.gen, cmd_pos.pos)
+    }
+}
+
+impl<'a> KeySource for LogReader<'a> {
+    fn key_at(&mut self, cmd_pos : CommandPos) -> Result<String> {
+        Ok(self.read(cmd_pos)?.into_key())
+    }
+}
+
+/// copy the raw command at `cmd_pos` to the end of `writer`, which writes generation `gen`
+/// re-encoded by `output` if `recompress` is set. Sealed records are always
+/// resealed, their nonce depends on the position.
+fn copy_command(
+    readers : &mut Readers,
+    cmd_pos : CommandPos,
+    gen : u64,
+    writer : &mut BufWriterWithPos<Box<dyn VfsFile>>,
+    input : &RecordEncoder,
+    output : &RecordEncoder,
+    recompress : bool,
+) -> Result<CommandPos> {
+    let reader = readers.get(cmd_pos.gen)?;
+    if reader.pos != cmd_pos.pos {
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    }
+
+    let pos = writer.pos;
+    let mut entry_reader = reader.take(cmd_pos.len);
+    if recompress || input.cipher.is_some() || output.cipher.is_some() {
+        let mut record = Vec::with_capacity(cmd_pos.len as usize);
+        entry_reader.read_to_end(&mut record)?;
+        let record = if recompress {
+            output.encode(&input.decode::<Command>(&record, cmd_pos.gen, cmd_pos.pos)?, gen, pos)?
+        } else {
+            output.seal(input.unseal(&record, cmd_pos.gen, cmd_pos.pos)?, gen, pos)?
+        };
+        writer.write_all(&record)?;
+    } else {
+        io::copy(&mut entry_reader, writer)?;
+    }
+    Ok(CommandPos { blob : cmd_pos.blob, ..CommandPos::new(gen, pos..writer.pos, cmd_pos.version) })
+}
+
+/// Readers of the generation files, at most `capacity` of them are
+/// kept open and the others are reopened on demand
+struct Readers {
+    vfs : Arc<dyn Vfs>,
+    path : PathBuf,
+    cache : LruCache<u64, BufReaderWithPos<Box<dyn VfsFile>>>,
+    hits : u64,
+    misses : u64,
+}
+
+impl Readers {
+    fn new(vfs : Arc<dyn Vfs>, path : PathBuf, capacity : usize) -> Self {
+        Readers {
+            vfs,
+            path,
+            cache : LruCache::new(capacity),
+            hits : 0,
+            misses : 0,
+        }
+    }
+
+    /// get the reader of the generation, open it if it isn't cached
+    fn get(&mut self, gen : u64) -> Result<&mut BufReaderWithPos<Box<dyn VfsFile>>> {
+        if self.cache.get_mut(&gen).is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let reader = BufReaderWithPos::new(self.vfs.open(&log_path(&self.path, gen))?)?;
+            self.cache.insert(gen, reader);
+        }
+        Ok(self.cache.get_mut(&gen).expect("reader was just cached"))
+    }
+
+    fn remove(&mut self, gen : u64) {
+        self.cache.remove(&gen);
+    }
+}
+
+/// Memory maps of the immutable generations, at most `capacity` of them are
+/// kept mapped like the open readers. The file descriptor is closed once mapped.
+/// Reads still go through `&mut KvStore`, a map saves the seek and the copy of
+/// the buffered reader but isn't shared by concurrent readers of the store.
+/// A filesystem which cannot map files reads them into memory instead.
+struct MmapReaders {
+    vfs : Arc<dyn Vfs>,
+    path : PathBuf,
+    maps : LruCache<u64, Mapping>,
+}
+
+impl MmapReaders {
+    fn new(vfs : Arc<dyn Vfs>, path : PathBuf, capacity : usize) -> Self {
+        MmapReaders {
+            vfs,
+            path,
+            maps : LruCache::new(capacity),
+        }
+    }
+
+    /// get the map of the generation, the generation must not be written anymore
+    fn get(&mut self, gen : u64) -> Result<Mapping> {
+        if let Some(map) = self.maps.get_mut(&gen) {
+            return Ok(Arc::clone(map));
+        }
+        // generations are append-only and never modified once rolled
+        let map = self.vfs.map(&log_path(&self.path, gen))?;
+        self.maps.insert(gen, Arc::clone(&map));
+        Ok(map)
+    }
+
+    fn remove(&mut self, gen : u64) {
+        self.maps.remove(&gen);
+    }
+}
+
+
+/// A record of the log, every write is stamped with a version.
+/// Records written before versions were introduced have version 0.
 #[derive(Serialize, Deserialize)]
 pub enum Command {
     Set{
         key : String,
         value : String,
+        #[serde(default)]
+        version : u64,
+        // milliseconds since the unix epoch, only written in cache mode
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at : Option<u64>,
+        // the value is in the blob file of the version
+        #[serde(default, skip_serializing_if = "is_false")]
+        blob : bool,
     },
     Remove {
         key : String,
-    }
+        #[serde(default)]
+        version : u64,
+    },
+    Merge {
+        key : String,
+        operand : MergeOperand,
+        #[serde(default)]
+        version : u64,
+    },
+    /// tombstone of a key evicted in cache mode
+    Evict {
+        key : String,
+        version : u64,
+    },
+    /// a previous version of a key kept by compaction, it never changes the current value
+    Version {
+        key : String,
+        version : u64,
+        value : Option<String>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        blob : bool,
+    },
+    /// definition of a secondary index, or its tombstone if `def` is `None`
+    Index {
+        name : String,
+        def : Option<IndexDef>,
+        // only definitions carry a version, the entries refer to it
+        #[serde(default)]
+        version : u64,
+    },
+    /// the field of the key at `version` in the index defined at `def`,
+    /// `None` if the value has no scalar at the pointer of the index
+    Entry {
+        index : String,
+        key : String,
+        version : u64,
+        def : u64,
+        field : Option<Value>,
+    },
 }
 
 impl Command {
-    fn set(key : String, value : String) -> Command {
-        Command::Set { key, value }
+    fn remove(key : String, version : u64) -> Command {
+        Command::Remove { key, version }
     }
 
-    fn remove(key : String) -> Command {
-        Command::Remove { key }
+    fn merge(key : String, operand : MergeOperand, version : u64) -> Command {
+        Command::Merge { key, operand, version }
+    }
+
+    fn into_key(self) -> String {
+        match self {
+            Command::Set{key, ..}
+            | Command::Remove{key, ..}
+            | Command::Merge{key, ..}
+            | Command::Evict{key, ..}
+            | Command::Version{key, ..}
+            | Command::Entry{key, ..} => key,
+            Command::Index{name, ..} => name,
+        }
+    }
+
+    /// whether the value is stored in a blob file
+    fn blob(&self) -> bool {
+        match *self {
+            Command::Set{blob, ..} | Command::Version{blob, ..} => blob,
+            _ => false,
+        }
+    }
+
+    fn version(&self) -> u64 {
+        match *self {
+            Command::Set{version, ..}
+            | Command::Remove{version, ..}
+            | Command::Merge{version, ..}
+            | Command::Evict{version, ..}
+            | Command::Version{version, ..}
+            | Command::Index{version, ..}
+            | Command::Entry{version, ..} => version,
+        }
     }
 }
 
 /// Represents of the position and length of a json-serialized command in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CommandPos {
     // serialize number of the log
-    gen : u64,
+    pub(super) gen : u64,
     // command len
-    len : u64,
+    pub(super) len : u64,
 
-    pos : u64,
+    pub(super) pos : u64,
+    // version stamped on the command
+    pub(super) version : u64,
+    // the command refers to the blob file of its version
+    pub(super) blob : bool,
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range) : (u64, Range<u64>)) -> Self {
+impl CommandPos {
+    fn new(gen : u64, range : Range<u64>, version : u64) -> Self {
         CommandPos {
             gen,
             pos : range.start,
             len : range.end - range.start,
+            version,
+            blob : false,
         }
     }
 }
 
-struct BufReaderWithPos<R : Read + Seek> {
+pub(super) struct BufReaderWithPos<R : Read + Seek> {
     reader : BufReader<R>,
-    pos    : u64,
+    pub(super) pos : u64,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner : R) -> Result<Self> {
+    pub(super) fn new(mut inner : R) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufReaderWithPos {
             reader : BufReader::new(inner),
@@ -290,19 +1910,35 @@ impl<R : Read + Seek> Read for BufReaderWithPos<R> {
     }
 }
 
-struct BufWriterWithPos<W : Write + Seek> {
+impl<R : Read + Seek> BufRead for BufReaderWithPos<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt : usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
+pub(super) struct BufWriterWithPos<W : Write + Seek> {
     writer : BufWriter<W>,
-    pos     : u64,
+    pub(super) pos : u64,
 }
 
 impl<W : Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner : W) -> Result<Self> {
+    pub(super) fn new(mut inner : W) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufWriterWithPos {
             writer : BufWriter::new(inner),
             pos,
         })
     }
+
+    /// the underlying writer, the buffer should be flushed before it is used
+    pub(super) fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
 }
 
 impl<W : Write + Seek> Seek for BufWriterWithPos<W> {
@@ -325,38 +1961,6 @@ impl<W : Write + Seek> Write for BufWriterWithPos<W> {
 }
 
 
-/// Load Command from specified gen log file,
-/// save the each command int the index
-fn load<R>(gen : u64, reader : &mut BufReaderWithPos<R>, index : &mut BTreeMap<String, CommandPos>) -> Result<u64>
-    where R : Read + Seek
-{
-    // start pos of file
-    let mut pos = 0 as u64;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0;
-
-    while let Some(command) = stream.next() {
-        let command = command.unwrap();
-        let new_pos = stream.byte_offset() as u64;
-
-        debug_assert!(pos < new_pos, "new_pos shuld be smaller than new_pos");
-        
-        match command {
-            Command::Set{key, ..} => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len; 
-                }
-            },
-            Command::Remove{key} => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
-                }
-                uncompacted += new_pos - pos;
-            }
-        }
-
-        pos = new_pos;
-    }
-
-    Ok(uncompacted)
+fn mark_stale(gens : &mut BTreeMap<u64, GenInfo>, cmd_pos : &CommandPos) {
+    gens.entry(cmd_pos.gen).or_default().stale += cmd_pos.len;
 }
@@ -0,0 +1,80 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A bounded map which evicts the least recently used entry
+/// once `capacity` is exceeded.
+pub struct LruCache<K, V> {
+    capacity : usize,
+    // incremented on every access, smaller means older
+    tick : u64,
+    entries : HashMap<K, (u64, V)>,
+    order : BTreeMap<u64, K>,
+}
+
+impl<K : Hash + Eq + Clone, V> LruCache<K, V> {
+    /// create a cache holding at most `capacity` entries, at least one
+    pub fn new(capacity : usize) -> Self {
+        LruCache {
+            capacity : capacity.max(1),
+            tick : 0,
+            entries : HashMap::new(),
+            order : BTreeMap::new(),
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// get the entry and mark it as the most recently used one
+    pub fn get_mut<Q>(&mut self, key : &Q) -> Option<&mut V>
+        where K : Borrow<Q>, Q : Hash + Eq + ?Sized
+    {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                let key = self.order.remove(&entry.0).expect("ordered entry");
+                self.order.insert(tick, key);
+                entry.0 = tick;
+                Some(&mut entry.1)
+            },
+            None => None,
+        }
+    }
+
+    /// insert the entry and return the evicted one if the cache was full
+    pub fn insert(&mut self, key : K, value : V) -> Option<(K, V)> {
+        let tick = self.next_tick();
+        if let Some((old_tick, _)) = self.entries.insert(key.clone(), (tick, value)) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, key);
+
+        if self.entries.len() > self.capacity {
+            self.pop_lru()
+        } else {
+            None
+        }
+    }
+
+    /// remove and return the least recently used entry
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let oldest = *self.order.keys().next()?;
+        let key = self.order.remove(&oldest)?;
+        self.entries.remove(&key).map(|(_, value)| (key, value))
+    }
+
+    pub fn remove<Q>(&mut self, key : &Q) -> Option<V>
+        where K : Borrow<Q>, Q : Hash + Eq + ?Sized
+    {
+        let (tick, value) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
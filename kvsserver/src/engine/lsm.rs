@@ -0,0 +1,653 @@
+use super::{inverted_bounds, KvsEngine, EngineStats, Result, KvsError};
+use super::codec::{Codec, RecordEncoder};
+use super::kv::{BufReaderWithPos, BufWriterWithPos};
+use super::sstable::{table_path, BlockCache, Entry, Table, TableWriter};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::iter::Peekable;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+const MANIFEST : &str = "MANIFEST";
+const MEMTABLE_SIZE : usize = 4 * 1024 * 1024;
+const BLOCK_SIZE : usize = 4096;
+const TABLE_SIZE : u64 = 2 * 1024 * 1024;
+const LEVEL0_TABLES : usize = 4;
+const LEVEL_BASE_BYTES : u64 = 10 * 1024 * 1024;
+const LEVEL_MULTIPLIER : u64 = 10;
+const BLOOM_BITS_PER_KEY : usize = 10;
+const BLOCK_CACHE_BLOCKS : usize = 256;
+
+/// Tunables of the LsmKvStore
+#[derive(Debug, Clone)]
+pub struct LsmConfig {
+    /// flush the memtable to a level 0 table once its keys and values take this many bytes
+    pub memtable_size : usize,
+    /// the entries of a table are read in blocks of about this size
+    pub block_size : usize,
+    /// compaction starts a new table once the one it writes reaches this size
+    pub table_size : u64,
+    /// compact level 0 into level 1 once it holds this many tables
+    pub level0_tables : usize,
+    /// level 1 may hold this many bytes of tables before it is compacted further
+    pub level_base_bytes : u64,
+    /// each level below level 1 may hold this many times more than the one above
+    pub level_multiplier : u64,
+    /// size of the bloom filter of each table, in bits per key
+    pub bloom_bits_per_key : usize,
+    /// keep up to this many decoded blocks in memory, 0 disables the block cache
+    pub block_cache_blocks : usize,
+    /// codec of the records of the write-ahead log
+    pub wal_compression : Codec,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        LsmConfig {
+            memtable_size : MEMTABLE_SIZE,
+            block_size : BLOCK_SIZE,
+            table_size : TABLE_SIZE,
+            level0_tables : LEVEL0_TABLES,
+            level_base_bytes : LEVEL_BASE_BYTES,
+            level_multiplier : LEVEL_MULTIPLIER,
+            bloom_bits_per_key : BLOOM_BITS_PER_KEY,
+            block_cache_blocks : BLOCK_CACHE_BLOCKS,
+            wal_compression : Codec::None,
+        }
+    }
+}
+
+/// The tables of each level and the write-ahead log holding the memtable,
+/// replaced as a whole so that a flush or a compaction is applied at once
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id : u64,
+    wal : u64,
+    levels : Vec<Vec<u64>>,
+}
+
+/// A write of the memtable, replayed from the write-ahead log on open
+#[derive(Serialize, Deserialize)]
+enum WalRecord {
+    Set {
+        key : String,
+        value : String,
+    },
+    Remove {
+        key : String,
+    },
+}
+
+/// Log-structured merge tree engine. Writes go to the write-ahead log and a sorted
+/// memtable, which is flushed to an immutable sorted table in level 0. Tables are
+/// merged into the deeper levels by leveled compaction, where the tables of a level
+/// don't overlap and each level is `level_multiplier` times larger than the one above.
+pub struct LsmKvStore {
+    path : PathBuf,
+    config : LsmConfig,
+    encoder : RecordEncoder,
+    // the latest write of each key since the last flush, `None` is a removal
+    memtable : BTreeMap<String, Option<String>>,
+    memtable_bytes : usize,
+    wal : BufWriterWithPos<File>,
+    wal_id : u64,
+    next_id : u64,
+    // level 0 is ordered from the oldest table and its tables may overlap,
+    // the tables of the deeper levels are ordered by key
+    levels : Vec<Vec<Table>>,
+    // the largest key of the table of each level compacted last, the next one follows it
+    compact_pointers : Vec<Option<String>>,
+    // only used when `block_cache_blocks` is set
+    cache : Option<BlockCache>,
+    bloom_skips : u64,
+    flushes : u64,
+    compactions : u64,
+}
+
+impl LsmKvStore {
+    pub fn open<P : Into<PathBuf>>(path : P) -> Result<Self> {
+        LsmKvStore::with_config(path, LsmConfig::default())
+    }
+
+    /// open the store, replaying the write-ahead log of the memtable.
+    /// Files left by an interrupted flush or compaction are removed.
+    pub fn with_config<P : Into<PathBuf>>(path : P, config : LsmConfig) -> Result<Self> {
+        // compaction needs a table in level 0, and budgets which let the data settle in a level
+        if config.level0_tables == 0 || config.level_base_bytes == 0 || config.level_multiplier == 0 {
+            return Err(KvsError::StringError(format!(
+                "level0_tables {}, level_base_bytes {} and level_multiplier {} must be at least 1",
+                config.level0_tables, config.level_base_bytes, config.level_multiplier
+            )));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let manifest_path = path.join(MANIFEST);
+        let fresh = !manifest_path.exists();
+        let mut manifest : Manifest = if fresh {
+            Manifest { next_id : 1, ..Manifest::default() }
+        } else {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        };
+        if fresh {
+            manifest.wal = manifest.next_id;
+            manifest.next_id += 1;
+        }
+
+        let mut levels = Vec::with_capacity(manifest.levels.len());
+        for ids in &manifest.levels {
+            let mut level = Vec::with_capacity(ids.len());
+            for &id in ids {
+                level.push(Table::open(&path, id)?);
+            }
+            levels.push(level);
+        }
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+
+        for entry in fs::read_dir(&path)? {
+            let file = entry?.path();
+            let id = match file.file_stem().and_then(OsStr::to_str).and_then(|stem| stem.parse::<u64>().ok()) {
+                Some(id) => id,
+                None => continue,
+            };
+            let orphan = match file.extension().and_then(OsStr::to_str) {
+                Some("sst") => !manifest.levels.iter().flatten().any(|&table| table == id),
+                Some("wal") => id != manifest.wal,
+                _ => false,
+            };
+            if orphan {
+                fs::remove_file(&file)?;
+            }
+        }
+
+        let encoder = RecordEncoder {
+            codec : config.wal_compression,
+            threshold : 0,
+            cipher : None,
+            allow_plaintext : false,
+        };
+        let cache = if config.block_cache_blocks > 0 {
+            Some(BlockCache::new(config.block_cache_blocks))
+        } else {
+            None
+        };
+        let wal = BufWriterWithPos::new(
+            OpenOptions::new().create(true).append(true).open(wal_path(&path, manifest.wal))?
+        )?;
+        let mut store = LsmKvStore {
+            path,
+            config,
+            encoder,
+            memtable : BTreeMap::new(),
+            memtable_bytes : 0,
+            wal,
+            wal_id : manifest.wal,
+            next_id : manifest.next_id,
+            compact_pointers : vec![None; levels.len()],
+            levels,
+            cache,
+            bloom_skips : 0,
+            flushes : 0,
+            compactions : 0,
+        };
+        store.replay_wal()?;
+        if fresh {
+            store.commit()?;
+        }
+        Ok(store)
+    }
+
+    /// load the memtable from the write-ahead log, a record torn by a crash ends it
+    fn replay_wal(&mut self) -> Result<()> {
+        let path = wal_path(&self.path, self.wal_id);
+        let mut reader = BufReaderWithPos::new(File::open(&path)?)?;
+        let mut pos = 0;
+        loop {
+            match self.encoder.read::<WalRecord, _>(&mut reader, self.wal_id, pos) {
+                Ok(Some(WalRecord::Set { key, value })) => self.insert(key, Some(value)),
+                Ok(Some(WalRecord::Remove { key })) => self.insert(key, None),
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("the write-ahead log is torn at {}, the rest is dropped: {}", pos, err);
+                    OpenOptions::new().write(true).open(&path)?.set_len(pos)?;
+                    break;
+                },
+            }
+            pos = reader.pos;
+        }
+        self.wal.pos = pos;
+        Ok(())
+    }
+
+    fn insert(&mut self, key : String, value : Option<String>) {
+        self.memtable_bytes += key.len() + value.as_ref().map_or(0, String::len);
+        self.memtable.insert(key, value);
+    }
+
+    /// log the write, apply it to the memtable and flush the memtable once it is full
+    fn write(&mut self, key : String, value : Option<String>) -> Result<()> {
+        let record = match value {
+            Some(ref value) => WalRecord::Set { key : key.clone(), value : value.clone() },
+            None => WalRecord::Remove { key : key.clone() },
+        };
+        let pos = self.wal.pos;
+        self.wal.write_all(&self.encoder.encode(&record, self.wal_id, pos)?)?;
+        self.wal.flush()?;
+        self.insert(key, value);
+        if self.memtable_bytes >= self.config.memtable_size {
+            self.flush_memtable()?;
+            self.maybe_compact()?;
+        }
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Replace the manifest by the current levels and write-ahead log. The directory is
+    /// synced with it, so the new tables and log it names are durable before the files
+    /// it no longer names are removed.
+    fn commit(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id : self.next_id,
+            wal : self.wal_id,
+            levels : self.levels.iter().map(|level| level.iter().map(|table| table.id).collect()).collect(),
+        };
+        let tmp = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &manifest)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp, self.path.join(MANIFEST))?;
+        File::open(&self.path)?.sync_all()?;
+        Ok(())
+    }
+
+    /// write the memtable to a level 0 table and start a new write-ahead log
+    pub fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.next_id();
+        let mut writer = TableWriter::create(&self.path, id, self.config.block_size, self.config.bloom_bits_per_key)?;
+        for (key, value) in &self.memtable {
+            writer.add(key, value.as_ref().map(String::as_str))?;
+        }
+        let table = writer.finish()?;
+
+        let old_wal = self.wal_id;
+        self.wal_id = self.next_id();
+        self.wal = BufWriterWithPos::new(
+            OpenOptions::new().create(true).append(true).open(wal_path(&self.path, self.wal_id))?
+        )?;
+        self.levels[0].push(table);
+        self.commit()?;
+        fs::remove_file(wal_path(&self.path, old_wal))?;
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        self.flushes += 1;
+        Ok(())
+    }
+
+    /// the bytes level `level` may hold, level 0 is bounded by its number of tables instead
+    fn max_level_bytes(&self, level : usize) -> u64 {
+        let mut max = self.config.level_base_bytes;
+        for _ in 1..level {
+            max = max.saturating_mul(self.config.level_multiplier);
+        }
+        max
+    }
+
+    /// compact the levels until each of them is within its budget
+    fn maybe_compact(&mut self) -> Result<()> {
+        loop {
+            if self.levels[0].len() >= self.config.level0_tables {
+                self.compact_level(0)?;
+                continue;
+            }
+            let over = (1..self.levels.len()).find(|&level| {
+                self.levels[level].iter().map(|table| table.size).sum::<u64>() > self.max_level_bytes(level)
+            });
+            match over {
+                Some(level) => self.compact_level(level)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// merge level 0, or the next table of a deeper level, with the overlapping tables of the level below
+    fn compact_level(&mut self, level : usize) -> Result<()> {
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+            self.compact_pointers.push(None);
+        }
+        let upper : Vec<usize> = if level == 0 {
+            (0..self.levels[0].len()).collect()
+        } else {
+            let tables = &self.levels[level];
+            let next = match self.compact_pointers[level] {
+                Some(ref pointer) => tables.iter().position(|table| table.smallest() > pointer.as_str()).unwrap_or(0),
+                None => 0,
+            };
+            vec![next]
+        };
+        let smallest = upper.iter().map(|&i| self.levels[level][i].smallest()).min().expect("a table to compact").to_owned();
+        let largest = upper.iter().map(|&i| self.levels[level][i].largest()).max().expect("a table to compact").to_owned();
+        if level > 0 {
+            self.compact_pointers[level] = Some(largest.clone());
+        }
+        let lower : Vec<usize> = (0..self.levels[level + 1].len())
+            .filter(|&i| self.levels[level + 1][i].overlaps(&smallest, &largest))
+            .collect();
+
+        // a table overlapping nothing below moves down as it is
+        if level > 0 && lower.is_empty() {
+            let table = self.levels[level].remove(upper[0]);
+            let at = self.levels[level + 1].iter().position(|other| other.smallest() > table.smallest()).unwrap_or_else(|| self.levels[level + 1].len());
+            self.levels[level + 1].insert(at, table);
+            return self.commit();
+        }
+
+        // newer tables come first, level 0 is ordered from the oldest one
+        let mut sources = Vec::with_capacity(upper.len() + lower.len());
+        for &i in upper.iter().rev() {
+            sources.push(self.levels[level][i].iter(None)?);
+        }
+        for &i in &lower {
+            sources.push(self.levels[level + 1][i].iter(None)?);
+        }
+        // nothing deeper may hold an older value the tombstone hides
+        let last_level = self.levels[level + 2..].iter().all(Vec::is_empty);
+        let outputs = self.write_tables(MergeIter::new(sources), last_level)?;
+
+        let mut inputs = Vec::with_capacity(upper.len() + lower.len());
+        for &i in upper.iter().rev() {
+            inputs.push(self.levels[level].remove(i).id);
+        }
+        for &i in lower.iter().rev() {
+            inputs.push(self.levels[level + 1].remove(i).id);
+        }
+        let at = lower.first().cloned().unwrap_or_else(|| {
+            self.levels[level + 1].iter().position(|other| other.smallest() > smallest.as_str()).unwrap_or_else(|| self.levels[level + 1].len())
+        });
+        let below = &mut self.levels[level + 1];
+        let tail = below.split_off(at);
+        below.extend(outputs);
+        below.extend(tail);
+        self.commit()?;
+        self.remove_tables(&inputs)?;
+        self.compactions += 1;
+        Ok(())
+    }
+
+    /// write the merged entries to new tables split at `table_size`, without tombstones if `drop_tombstones`
+    fn write_tables<I>(&mut self, entries : I, drop_tombstones : bool) -> Result<Vec<Table>>
+        where I : Iterator<Item = Result<Entry>>
+    {
+        let mut tables = Vec::new();
+        let mut writer : Option<TableWriter> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            if writer.is_none() {
+                let id = self.next_id();
+                writer = Some(TableWriter::create(&self.path, id, self.config.block_size, self.config.bloom_bits_per_key)?);
+            }
+            let full = {
+                let writer = writer.as_mut().expect("table writer");
+                writer.add(&key, value.as_ref().map(String::as_str))?;
+                writer.size() >= self.config.table_size
+            };
+            if full {
+                tables.push(writer.take().expect("table writer").finish()?);
+            }
+        }
+        if let Some(writer) = writer {
+            if !writer.is_empty() {
+                tables.push(writer.finish()?);
+            }
+        }
+        Ok(tables)
+    }
+
+    fn remove_tables(&mut self, ids : &[u64]) -> Result<()> {
+        for &id in ids {
+            fs::remove_file(table_path(&self.path, id))?;
+        }
+        Ok(())
+    }
+
+    /// the first `limit` live pairs within the bounds, stopping the merge there
+    fn live(&self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<(String, String)>> {
+        let from = match start {
+            Included(ref key) | Excluded(ref key) => Some(key.as_str()),
+            Unbounded => None,
+        };
+        let to = match end {
+            Included(ref key) => Included(key.as_str()),
+            Excluded(ref key) => Excluded(key.as_str()),
+            Unbounded => Unbounded,
+        };
+        let range = (start.clone(), end.clone());
+        let mut pairs = Vec::new();
+        for entry in self.merged(from, to)? {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, value) = entry?;
+            if !range.contains(&key) {
+                match range.end_bound() {
+                    Included(end) if key > *end => break,
+                    Excluded(end) if key >= *end => break,
+                    _ => continue,
+                }
+            }
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// merge every source from the first key not less than `start`, newer sources first
+    fn merged(&self, start : Option<&str>, end : Bound<&str>) -> Result<MergeIter> {
+        let memtable_start = match start {
+            Some(start) => Included(start),
+            None => Unbounded,
+        };
+        let memtable : Vec<Entry> = if inverted_bounds(&memtable_start, &end) {
+            Vec::new()
+        } else {
+            self.memtable
+                .range::<str, _>((memtable_start, end))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        };
+        let mut sources : Vec<Box<dyn Iterator<Item = Result<Entry>>>> = vec![Box::new(memtable.into_iter().map(Ok))];
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(table.iter(start)?));
+        }
+        for level in &self.levels[1..] {
+            for table in level {
+                if start.map_or(true, |start| table.largest() >= start) {
+                    sources.push(Box::new(table.iter(start)?));
+                }
+            }
+        }
+        Ok(MergeIter::new(sources))
+    }
+}
+
+impl KvsEngine for LsmKvStore {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.write(key, Some(value))
+    }
+
+    /// look the key up in the memtable, then in the tables from the newest one,
+    /// skipping the tables whose bloom filter rules the key out
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        let LsmKvStore { levels, cache, bloom_skips, .. } = self;
+        let (level0, deeper) = levels.split_at_mut(1);
+        let mut candidates : Vec<&mut Table> = level0[0].iter_mut().rev().collect();
+        for level in deeper.iter_mut() {
+            let at = match level.binary_search_by(|table| table.largest().cmp(key.as_str())) {
+                Ok(at) | Err(at) => at,
+            };
+            if let Some(table) = level.get_mut(at) {
+                candidates.push(table);
+            }
+        }
+        for table in candidates {
+            if !table.may_contain(&key) {
+                *bloom_skips += 1;
+                continue;
+            }
+            if let Some(value) = table.get(&key, cache.as_mut())? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write(key, None)
+    }
+
+    /// every live key, in key order
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in self.merged(None, Unbounded)? {
+            if let (key, Some(_)) = entry? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// merge the memtable and the tables overlapping the range, only reading the blocks in it
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        self.live(start, end, usize::MAX)
+    }
+
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        Ok(self.live(start, end, limit)?.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// flush the memtable and merge every table into the deepest level, without tombstones
+    fn compact(&mut self) -> Result<()> {
+        self.flush_memtable()?;
+        let target = (1..self.levels.len()).rev().find(|&level| !self.levels[level].is_empty()).unwrap_or(1);
+        let mut sources = Vec::new();
+        for table in self.levels[0].iter().rev() {
+            sources.push(table.iter(None)?);
+        }
+        for level in &self.levels[1..] {
+            for table in level {
+                sources.push(table.iter(None)?);
+            }
+        }
+        if sources.is_empty() {
+            return Ok(());
+        }
+        let outputs = self.write_tables(MergeIter::new(sources), true)?;
+
+        let inputs : Vec<u64> = self.levels.iter().flatten().map(|table| table.id).collect();
+        let levels = target + 1;
+        self.levels = (0..levels).map(|_| Vec::new()).collect();
+        self.levels[target] = outputs;
+        self.compact_pointers = vec![None; levels];
+        self.commit()?;
+        self.remove_tables(&inputs)?;
+        self.compactions += 1;
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut stats = EngineStats::default();
+        stats.set("memtable_keys", self.memtable.len() as u64);
+        stats.set("memtable_bytes", self.memtable_bytes as u64);
+        stats.set("wal_bytes", self.wal.pos);
+        stats.set("tables", self.levels.iter().map(|level| level.len() as u64).sum());
+        for (i, level) in self.levels.iter().enumerate() {
+            stats.set(format!("level{}_tables", i), level.len() as u64);
+            stats.set(format!("level{}_bytes", i), level.iter().map(|table| table.size).sum());
+        }
+        stats.set("table_entries", self.levels.iter().flatten().map(|table| table.entries).sum());
+        stats.set("bloom_skips", self.bloom_skips);
+        stats.set("flushes", self.flushes);
+        stats.set("compactions", self.compactions);
+        if let Some(cache) = self.cache.as_ref() {
+            stats.set("block_cache_blocks", cache.len() as u64);
+            stats.set("block_cache_hits", cache.hits);
+            stats.set("block_cache_misses", cache.misses);
+        }
+        Ok(stats)
+    }
+}
+
+fn wal_path(dir : &Path, id : u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// Merges sorted sources into one sorted stream. Sources are ordered from
+/// the newest one, whose entry wins when several hold the same key.
+struct MergeIter {
+    sources : Vec<Peekable<Box<dyn Iterator<Item = Result<Entry>>>>>,
+}
+
+impl MergeIter {
+    fn new<I : Iterator<Item = Result<Entry>> + 'static>(sources : Vec<I>) -> Self {
+        MergeIter {
+            sources : sources
+                .into_iter()
+                .map(|source| (Box::new(source) as Box<dyn Iterator<Item = Result<Entry>>>).peekable())
+                .collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the newest source holding the smallest key
+        let mut next : Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) => {
+                    if next.as_ref().map_or(true, |(_, smallest)| key < smallest) {
+                        next = Some((i, key.clone()));
+                    }
+                },
+                Some(Err(_)) => return source.next(),
+                None => {},
+            }
+        }
+        let (newest, key) = next?;
+        // the older entries of the key are superseded
+        for source in self.sources.iter_mut().skip(newest + 1) {
+            if let Some(Ok((other, _))) = source.peek() {
+                if *other == key {
+                    source.next();
+                }
+            }
+        }
+        self.sources[newest].next()
+    }
+}
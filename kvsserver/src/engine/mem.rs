@@ -0,0 +1,284 @@
+use super::{inverted_bounds, KvsEngine, EngineStats, Result, KvsError, MergeOperand};
+use super::evict::now_millis;
+use super::secondary::{IndexDef, IndexQuery, SecondaryIndexes};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Bound::{self, Included, Unbounded};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const SNAPSHOT_FILE : &str = "mem.snapshot";
+
+/// KvsEngine keeping every pair in memory, nothing is written to disk
+/// unless it is opened with a snapshot directory.
+#[derive(Default)]
+pub struct MemKvStore {
+    state : State,
+    // previous versions kept for each key
+    max_versions : usize,
+    // built from the definitions of the state
+    secondary : SecondaryIndexes,
+    // the snapshot file written on drop
+    snapshot : Option<PathBuf>,
+}
+
+/// Everything the snapshot keeps
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    pairs : BTreeMap<String, String>,
+    // the version of the current value of each key
+    #[serde(default)]
+    versions : BTreeMap<String, u64>,
+    // previous versions of the keys, oldest first, `None` for removals
+    #[serde(default)]
+    history : BTreeMap<String, VecDeque<(u64, Option<String>)>>,
+    #[serde(default)]
+    last_version : u64,
+    // the time in milliseconds at which the keys with a ttl expire
+    #[serde(default)]
+    expires_at : BTreeMap<String, u64>,
+    #[serde(default)]
+    index_defs : BTreeMap<String, IndexDef>,
+}
+
+impl MemKvStore {
+    /// create an empty store which is lost on drop
+    pub fn new() -> Self {
+        MemKvStore::default()
+    }
+
+    /// load the snapshot from the specified directory if it exists,
+    /// the store is snapshotted back into it on drop
+    pub fn open<P : Into<PathBuf>>(path : P) -> Result<Self> {
+        let snapshot = path.into().join(SNAPSHOT_FILE);
+        let state = if snapshot.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&snapshot)?))?
+        } else {
+            State::default()
+        };
+
+        let mut store = MemKvStore {
+            state,
+            max_versions : 0,
+            secondary : SecondaryIndexes::default(),
+            snapshot : Some(snapshot),
+        };
+        let defs : Vec<(String, IndexDef)> = store.state.index_defs.iter().map(|(name, def)| (name.clone(), def.clone())).collect();
+        for (name, def) in defs {
+            store.build_index(name, def);
+        }
+        Ok(store)
+    }
+
+    /// keep this many previous versions of each key, none by default
+    pub fn with_max_versions(mut self, max_versions : usize) -> Self {
+        self.max_versions = max_versions;
+        self
+    }
+
+    /// write every pair to the snapshot file, do nothing without a snapshot directory
+    pub fn snapshot(&self) -> Result<()> {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => return Ok(()),
+        };
+
+        // replace the old snapshot only after the new one is complete
+        let tmp = snapshot.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &self.state)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp, snapshot)?;
+        Ok(())
+    }
+
+    /// replace the value of the key with a new version, `None` removes it.
+    /// The replaced value and the removal move into the history when versions are kept
+    fn write(&mut self, key : &str, value : Option<String>) {
+        self.state.last_version += 1;
+        let version = self.state.last_version;
+        let mut retired = Vec::new();
+        if let (Some(old_version), Some(old)) = (self.state.versions.remove(key), self.state.pairs.remove(key)) {
+            retired.push((old_version, Some(old)));
+        }
+        if value.is_none() {
+            retired.push((version, None));
+        }
+        if self.max_versions > 0 && !retired.is_empty() {
+            let history = self.state.history.entry(key.to_owned()).or_default();
+            history.extend(retired);
+            while history.len() > self.max_versions {
+                history.pop_front();
+            }
+        }
+        self.secondary.update(key, value.as_ref().map(String::as_str));
+        if let Some(value) = value {
+            self.state.pairs.insert(key.to_owned(), value);
+            self.state.versions.insert(key.to_owned(), version);
+        }
+    }
+
+    /// remove the key if its ttl passed
+    fn expire(&mut self, key : &str) {
+        if self.state.expires_at.get(key).map_or(false, |&expires_at| expires_at <= now_millis()) {
+            self.state.expires_at.remove(key);
+            self.write(key, None);
+        }
+    }
+
+    /// remove every key whose ttl passed
+    fn expire_all(&mut self) {
+        let now = now_millis();
+        let expired : Vec<String> = self.state.expires_at
+            .iter()
+            .filter(|(_, &expires_at)| expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.expire(&key);
+        }
+    }
+
+    /// define the index and index every key it covers
+    fn build_index(&mut self, name : String, def : IndexDef) {
+        self.secondary.define(name.clone(), def.clone());
+        let keyspace = self.state.pairs.range::<String, _>((Included(&def.keyspace), Unbounded));
+        for (key, value) in keyspace.take_while(|(key, _)| def.covers(key)) {
+            self.secondary.update_index(&name, key, Some(value));
+        }
+    }
+}
+
+impl KvsEngine for MemKvStore {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.state.expires_at.remove(&key);
+        self.write(&key, Some(value));
+        Ok(())
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        self.expire(&key);
+        Ok(self.state.pairs.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        self.expire(&key);
+        if !self.state.pairs.contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.state.expires_at.remove(&key);
+        self.write(&key, None);
+        Ok(())
+    }
+
+    /// the merged value is a new version, which keeps the ttl of the key
+    fn merge(&mut self, key : String, operand : MergeOperand) -> Result<()> {
+        self.expire(&key);
+        let value = operand.apply(self.state.pairs.get(&key).map(String::as_str))?;
+        self.write(&key, Some(value));
+        Ok(())
+    }
+
+    fn set_with_ttl(&mut self, key : String, value : String, ttl : Duration) -> Result<()> {
+        self.write(&key, Some(value));
+        self.state.expires_at.insert(key, now_millis() + ttl.as_millis() as u64);
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.expire_all();
+        Ok(self.state.pairs.keys().cloned().collect())
+    }
+
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        if inverted_bounds(&start, &end) {
+            return Ok(Vec::new());
+        }
+        self.expire_all();
+        Ok(self.state.pairs.range((start, end)).map(|(key, value)| (key.clone(), value.clone())).collect())
+    }
+
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        if inverted_bounds(&start, &end) {
+            return Ok(Vec::new());
+        }
+        self.expire_all();
+        Ok(self.state.pairs.range((start, end)).take(limit).map(|(key, _)| key.clone()).collect())
+    }
+
+    /// the value of the key at `version`, `None` if the key was removed by that version
+    fn get_version(&mut self, key : String, version : u64) -> Result<Option<String>> {
+        self.expire(&key);
+        if self.state.versions.get(&key) == Some(&version) {
+            return Ok(self.state.pairs.get(&key).cloned());
+        }
+        self.state.history
+            .get(&key)
+            .and_then(|history| history.iter().find(|(old, _)| *old == version))
+            .map(|(_, value)| value.clone())
+            .ok_or(KvsError::VersionNotFound(version))
+    }
+
+    /// the kept previous versions and the current one, oldest first
+    fn history(&mut self, key : String) -> Result<Vec<(u64, Option<String>)>> {
+        self.expire(&key);
+        let mut versions : Vec<(u64, Option<String>)> = self.state.history
+            .get(&key)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default();
+        if let (Some(&version), Some(value)) = (self.state.versions.get(&key), self.state.pairs.get(&key)) {
+            versions.push((version, Some(value.clone())));
+        }
+        Ok(versions)
+    }
+
+    fn version(&mut self, key : String) -> Result<Option<u64>> {
+        self.expire(&key);
+        Ok(self.state.versions.get(&key).cloned())
+    }
+
+    fn create_index(&mut self, name : String, keyspace : String, pointer : String) -> Result<()> {
+        let def = IndexDef::new(keyspace, pointer)?;
+        self.state.index_defs.insert(name.clone(), def.clone());
+        self.build_index(name, def);
+        Ok(())
+    }
+
+    fn drop_index(&mut self, name : String) -> Result<()> {
+        if self.state.index_defs.remove(&name).is_none() {
+            return Err(KvsError::IndexNotFound(name));
+        }
+        self.secondary.remove(&name);
+        Ok(())
+    }
+
+    fn indexes(&mut self) -> Result<Vec<(String, String, String)>> {
+        Ok(self.state.index_defs.iter().map(|(name, def)| (name.clone(), def.keyspace.clone(), def.pointer.clone())).collect())
+    }
+
+    fn query(&mut self, index : String, query : IndexQuery) -> Result<Vec<String>> {
+        self.expire_all();
+        self.secondary.query(&index, &query)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.expire_all();
+        let mut stats = EngineStats::default();
+        stats.set("keys", self.state.pairs.len() as u64);
+        stats.set("history_entries", self.state.history.values().map(|history| history.len() as u64).sum());
+        stats.set("expiring_keys", self.state.expires_at.len() as u64);
+        stats.set("secondary_indexes", self.state.index_defs.len() as u64);
+        Ok(stats)
+    }
+}
+
+impl Drop for MemKvStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.snapshot() {
+            error!("failed to snapshot the memory store: {}", err);
+        }
+    }
+}
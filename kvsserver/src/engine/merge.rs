@@ -0,0 +1,107 @@
+use super::{Result, KvsError};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Folds an operand into the existing value of a key, `None` if the key has no value
+pub type MergeFn = dyn Fn(Option<&str>, &str) -> Result<String> + Send + Sync;
+
+lazy_static! {
+    static ref OPERATORS : RwLock<HashMap<String, Arc<MergeFn>>> = {
+        let mut operators : HashMap<String, Arc<MergeFn>> = HashMap::new();
+        operators.insert("add".to_owned(), Arc::new(add));
+        operators.insert("append".to_owned(), Arc::new(append));
+        operators.insert("union".to_owned(), Arc::new(union));
+        RwLock::new(operators)
+    };
+}
+
+/// Register a merge operator under `name` for every engine in the process,
+/// replacing the operator registered before.
+/// `add`, `append` and `union` are registered by default.
+pub fn register_merge_operator<F>(name : &str, operator : F)
+    where F : Fn(Option<&str>, &str) -> Result<String> + Send + Sync + 'static
+{
+    OPERATORS
+        .write()
+        .expect("merge operators lock poisoned")
+        .insert(name.to_owned(), Arc::new(operator));
+}
+
+fn operator(name : &str) -> Result<Arc<MergeFn>> {
+    OPERATORS
+        .read()
+        .expect("merge operators lock poisoned")
+        .get(name)
+        .cloned()
+        .ok_or_else(|| KvsError::UnknownMergeOperator(name.to_owned()))
+}
+
+/// An operand and the name of the registered operator folding it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeOperand {
+    pub operator : String,
+    pub operand : String,
+}
+
+impl MergeOperand {
+    pub fn new<O : Into<String>, V : Into<String>>(operator : O, operand : V) -> Self {
+        MergeOperand {
+            operator : operator.into(),
+            operand : operand.into(),
+        }
+    }
+
+    /// add `delta` to the integer value, a missing value counts as 0
+    pub fn add(delta : i64) -> Self {
+        MergeOperand::new("add", delta.to_string())
+    }
+
+    /// append `suffix` to the string value
+    pub fn append<V : Into<String>>(suffix : V) -> Self {
+        MergeOperand::new("append", suffix)
+    }
+
+    /// union the JSON array value with the JSON array `items`
+    pub fn union(items : &[Value]) -> Self {
+        MergeOperand::new("union", Value::Array(items.to_vec()).to_string())
+    }
+
+    /// fold the operand into `existing`
+    pub fn apply(&self, existing : Option<&str>) -> Result<String> {
+        operator(&self.operator)?(existing, &self.operand)
+    }
+}
+
+fn add(existing : Option<&str>, operand : &str) -> Result<String> {
+    let parse = |s : &str| {
+        s.parse::<i64>()
+            .map_err(|_| KvsError::Merge(format!("{:?} is not an integer", s)))
+    };
+    let existing = existing.map(parse).unwrap_or(Ok(0))?;
+    existing
+        .checked_add(parse(operand)?)
+        .map(|sum| sum.to_string())
+        .ok_or_else(|| KvsError::Merge("integer overflow".to_owned()))
+}
+
+fn append(existing : Option<&str>, operand : &str) -> Result<String> {
+    Ok(format!("{}{}", existing.unwrap_or(""), operand))
+}
+
+fn union(existing : Option<&str>, operand : &str) -> Result<String> {
+    let parse = |s : &str| -> Result<Vec<Value>> {
+        match serde_json::from_str(s)? {
+            Value::Array(items) => Ok(items),
+            _ => Err(KvsError::Merge(format!("{:?} is not a JSON array", s))),
+        }
+    };
+    let mut items = existing.map(parse).unwrap_or_else(|| Ok(Vec::new()))?;
+    for item in parse(operand)? {
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+    Ok(Value::Array(items).to_string())
+}
@@ -1,4 +1,11 @@
 use super::errors::*;
+use crate::dump::{DumpFormat, DumpReader, DumpWriter};
+use std::collections::BTreeMap;
+use std::io::{BufReader, Cursor, Read, Write};
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
 
 pub trait KvsEngine { 
     fn set(&mut self, key : String, value : String) -> Result<()>;
@@ -6,10 +13,294 @@ pub trait KvsEngine {
     fn get(&mut self, key : String)  -> Result<Option<String>>;
 
     fn remove(&mut self, key : String) -> Result<()>;
+
+    /// fold `operand` into the value of `key` with its registered operator.
+    /// The default reads and writes the value, which is atomic as long as
+    /// the engine is only reachable through `&mut self`.
+    fn merge(&mut self, key : String, operand : MergeOperand) -> Result<()> {
+        let existing = self.get(key.clone())?;
+        let value = operand.apply(existing.as_ref().map(String::as_str))?;
+        self.set(key, value)
+    }
+
+    /// set the value read from `reader`, engines storing large values
+    /// apart may do so without holding the value in memory
+    fn set_from_reader(&mut self, key : String, reader : &mut dyn Read) -> Result<()> {
+        let mut value = String::new();
+        reader.read_to_string(&mut value)?;
+        self.set(key, value)
+    }
+
+    /// a reader of the value, engines storing large values apart
+    /// may stream it instead of reading it into memory
+    fn get_reader(&mut self, key : String) -> Result<Option<Box<dyn Read>>> {
+        let value = self.get(key)?;
+        Ok(value.map(|value| Box::new(Cursor::new(value.into_bytes())) as Box<dyn Read>))
+    }
+
+    /// set the pair which expires after `ttl`.
+    /// Only engines which can expire keys support it
+    fn set_with_ttl(&mut self, _key : String, _value : String, _ttl : Duration) -> Result<()> {
+        Err(KvsError::Unsupported("ttl".to_owned()))
+    }
+
+    /// every live key, in no particular order. Every engine of this crate supports it,
+    /// the default only keeps engines written before it compiling
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Err(KvsError::Unsupported("keys".to_owned()))
+    }
+
+    /// the live pairs whose keys are within the bounds, in key order.
+    /// The default sorts every key, engines keeping their keys sorted scan the range only
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        let range = (start, end);
+        let mut keys = self.keys()?;
+        keys.retain(|key| range.contains(key));
+        keys.sort();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// the first `limit` live keys within the bounds, in key order, without their values.
+    /// The default sorts every key, engines keeping their keys sorted stop after `limit` keys
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        let range = (start, end);
+        let mut keys = self.keys()?;
+        keys.retain(|key| range.contains(key));
+        keys.sort();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    /// write every live pair to `writer` and return the number of pairs
+    fn export(&mut self, writer : &mut dyn Write, format : DumpFormat) -> Result<u64> {
+        let mut dump = DumpWriter::new(writer, format)?;
+        for key in self.keys()? {
+            // the key may be gone if the engine expires keys by itself
+            if let Some(value) = self.get(key.clone())? {
+                dump.write(&key, &value)?;
+            }
+        }
+        dump.finish()
+    }
+
+    /// set every pair of a dump in either format and return the number of pairs.
+    /// Existing keys which are not in the dump are kept.
+    fn import(&mut self, reader : &mut dyn Read) -> Result<u64> {
+        let mut count = 0;
+        for pair in DumpReader::new(BufReader::new(reader))? {
+            let (key, value) = pair?;
+            self.set(key, value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// the value of the key at `version`, `None` if the key was removed by then.
+    /// Only engines which keep previous versions support it
+    fn get_version(&mut self, _key : String, version : u64) -> Result<Option<String>> {
+        Err(KvsError::Unsupported(format!("get version {}", version)))
+    }
+
+    /// the kept versions of the key with their values, oldest first
+    fn history(&mut self, _key : String) -> Result<Vec<(u64, Option<String>)>> {
+        Err(KvsError::Unsupported("history".to_owned()))
+    }
+
+    /// the version of the current value of the key, `None` if it doesn't exist,
+    /// without reading the value. Only engines which keep versions support it
+    fn version(&mut self, _key : String) -> Result<Option<u64>> {
+        Err(KvsError::Unsupported("version".to_owned()))
+    }
+
+    /// index the JSON values of the keys starting with `keyspace` by the field at
+    /// the JSON pointer `pointer`, replacing the index of the same name.
+    /// Only engines with secondary indexes support it
+    fn create_index(&mut self, name : String, _keyspace : String, _pointer : String) -> Result<()> {
+        Err(KvsError::Unsupported(format!("create index {}", name)))
+    }
+
+    fn drop_index(&mut self, name : String) -> Result<()> {
+        Err(KvsError::Unsupported(format!("drop index {}", name)))
+    }
+
+    /// the keys whose indexed field matches the query, ordered by field then key
+    fn query(&mut self, index : String, _query : IndexQuery) -> Result<Vec<String>> {
+        Err(KvsError::Unsupported(format!("query {}", index)))
+    }
+
+    /// the name, keyspace and pointer of every secondary index, sorted by name.
+    /// Engines without secondary indexes have none
+    fn indexes(&mut self) -> Result<Vec<(String, String, String)>> {
+        Ok(Vec::new())
+    }
+
+    /// reclaim the space of stale data, a no-op for engines which do it themselves
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// counters of the engine, empty if the engine reports nothing
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats::default())
+    }
+}
+
+/// Engines picked at runtime, like the ones of the registry, are used through a box
+impl<E : KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn merge(&mut self, key : String, operand : MergeOperand) -> Result<()> {
+        (**self).merge(key, operand)
+    }
+
+    fn set_from_reader(&mut self, key : String, reader : &mut dyn Read) -> Result<()> {
+        (**self).set_from_reader(key, reader)
+    }
+
+    fn get_reader(&mut self, key : String) -> Result<Option<Box<dyn Read>>> {
+        (**self).get_reader(key)
+    }
+
+    fn set_with_ttl(&mut self, key : String, value : String, ttl : Duration) -> Result<()> {
+        (**self).set_with_ttl(key, value, ttl)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        (**self).keys()
+    }
+
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        (**self).scan(start, end)
+    }
+
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        (**self).scan_keys(start, end, limit)
+    }
+
+    fn export(&mut self, writer : &mut dyn Write, format : DumpFormat) -> Result<u64> {
+        (**self).export(writer, format)
+    }
+
+    fn import(&mut self, reader : &mut dyn Read) -> Result<u64> {
+        (**self).import(reader)
+    }
+
+    fn get_version(&mut self, key : String, version : u64) -> Result<Option<String>> {
+        (**self).get_version(key, version)
+    }
+
+    fn history(&mut self, key : String) -> Result<Vec<(u64, Option<String>)>> {
+        (**self).history(key)
+    }
+
+    fn version(&mut self, key : String) -> Result<Option<u64>> {
+        (**self).version(key)
+    }
+
+    fn create_index(&mut self, name : String, keyspace : String, pointer : String) -> Result<()> {
+        (**self).create_index(name, keyspace, pointer)
+    }
+
+    fn drop_index(&mut self, name : String) -> Result<()> {
+        (**self).drop_index(name)
+    }
+
+    fn query(&mut self, index : String, query : IndexQuery) -> Result<Vec<String>> {
+        (**self).query(index, query)
+    }
+
+    fn indexes(&mut self) -> Result<Vec<(String, String, String)>> {
+        (**self).indexes()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        (**self).stats()
+    }
+}
+
+/// Named counters reported by an engine
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats(BTreeMap<String, u64>);
+
+impl EngineStats {
+    pub fn set<S : Into<String>>(&mut self, name : S, value : u64) {
+        self.0.insert(name.into(), value);
+    }
+
+    pub fn get(&self, name : &str) -> Option<u64> {
+        self.0.get(name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &u64)> {
+        self.0.iter()
+    }
+}
+
+/// whether the bounds are inverted, they hold no key while `BTreeMap::range` panics on them
+pub(crate) fn inverted_bounds<T : Ord>(start : &Bound<T>, end : &Bound<T>) -> bool {
+    match (start, end) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (Bound::Included(start), Bound::Included(end))
+        | (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start > end,
+        _ => false,
+    }
 }
 
+mod bloom;
+mod btree;
+mod cache;
+mod codec;
+mod crypto;
+mod evict;
+mod keydir;
 mod kv;
+mod lru;
+mod lsm;
+mod mem;
+mod merge;
+mod pager;
+mod registry;
+mod secondary;
 mod sled;
+mod sstable;
+mod vfs;
 
-pub use self::kv::KvStore;
+pub(crate) use self::bloom::fnv1a;
+pub use self::btree::{BTreeConfig, BTreeKvStore};
+pub use self::cache::CacheAdmission;
+pub use self::codec::Codec;
+pub use self::crypto::EncryptionKey;
+pub use self::evict::EvictionPolicy;
+pub use self::keydir::IndexMode;
+pub(crate) use self::keydir::{get_varint, put_varint};
+pub use self::kv::{KvStore, KvStoreConfig};
+pub(crate) use self::lru::LruCache;
+pub use self::lsm::{LsmConfig, LsmKvStore};
+pub use self::mem::MemKvStore;
+pub use self::merge::{register_merge_operator, MergeOperand};
+pub use self::registry::{engine_names, open_data_dir, open_engine, register_engine};
+pub use self::registry::{EngineDescriptor, EngineFactory, EngineOptions};
+pub use self::secondary::IndexQuery;
 pub use self::sled::SledKvStore;
+pub use self::vfs::{FaultyVfs, Mapping, MemVfs, OsVfs, Vfs, VfsFile};
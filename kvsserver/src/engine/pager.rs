@@ -0,0 +1,294 @@
+use super::Result;
+use super::bloom::fnv1a;
+use super::keydir::corrupted;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+const MAGIC : &[u8; 8] = b"kvsbtree";
+// both meta slots live in page 0 at fixed offsets, so they are found before the page size is known
+const META_SLOT_SIZE : u64 = 512;
+// magic, page size, txid, root, pages, free-list, keys and checksum
+const META_LEN : usize = 8 + 4 + 8 * 6;
+pub const MIN_PAGE_SIZE : usize = 2 * META_SLOT_SIZE as usize;
+pub const MAX_PAGE_SIZE : usize = 1 << 20;
+
+const PAGE_OVERFLOW : u8 = 3;
+const PAGE_FREELIST : u8 = 4;
+// kind, next page of the chain and the number of bytes or ids in the page
+const CHAIN_HEADER : usize = 1 + 8 + 4;
+
+/// The state of a transaction. Once committed it is written to the meta slot
+/// of its txid, the other slot keeps the previous commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meta {
+    pub txid : u64,
+    /// page of the root node, 0 for an empty tree
+    pub root : u64,
+    /// pages of the file, page 0 holds the meta slots
+    pub pages : u64,
+    /// first page of the free-list chain, 0 if no page is free
+    freelist : u64,
+    pub keys : u64,
+}
+
+/// Fixed-size pages of a file with a free-list, written copy-on-write: a page
+/// reachable from the committed root is never written, so a commit only takes
+/// effect when its meta slot is written and a crash before leaves the previous one.
+pub struct Pager {
+    file : File,
+    page_size : usize,
+    sync : bool,
+    /// the committed meta while no transaction is running
+    pub meta : Meta,
+    // free in the committed state, allocations take them first
+    free : Vec<u64>,
+    // freed by the current transaction but still reachable from the committed root
+    pending : Vec<u64>,
+    // the pages holding the committed free-list
+    freelist_pages : Vec<u64>,
+}
+
+impl Pager {
+    /// open the file, creating it with pages of `page_size` bytes.
+    /// An existing file keeps the page size it was created with.
+    pub fn open(path : &Path, page_size : usize, sync : bool) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let fresh = file.metadata()?.len() == 0;
+        let mut pager = Pager {
+            file,
+            page_size,
+            sync,
+            meta : Meta { txid : 0, root : 0, pages : 1, freelist : 0, keys : 0 },
+            free : Vec::new(),
+            pending : Vec::new(),
+            freelist_pages : Vec::new(),
+        };
+        if fresh {
+            pager.write_page(0, Vec::new())?;
+            pager.write_meta()?;
+            pager.file.sync_all()?;
+        } else {
+            pager.load()?;
+        }
+        Ok(pager)
+    }
+
+    /// read the newest valid meta slot and its free-list
+    fn load(&mut self) -> Result<()> {
+        let mut slots = [0; META_SLOT_SIZE as usize * 2];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut slots)?;
+        let (page_size, meta) = decode_meta(&slots[..META_SLOT_SIZE as usize])
+            .into_iter()
+            .chain(decode_meta(&slots[META_SLOT_SIZE as usize..]))
+            .max_by_key(|(_, meta)| meta.txid)
+            .ok_or_else(corrupted)?;
+        self.page_size = page_size;
+        self.meta = meta;
+
+        self.free.clear();
+        self.pending.clear();
+        self.freelist_pages.clear();
+        let mut page = self.meta.freelist;
+        while page != 0 {
+            let (next, ids) = self.read_chain(page, PAGE_FREELIST)?;
+            for id in ids.chunks(8) {
+                self.free.push(u64_at(id));
+            }
+            self.freelist_pages.push(page);
+            page = next;
+        }
+        Ok(())
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn free_pages(&self) -> usize {
+        self.free.len() + self.pending.len()
+    }
+
+    /// a page for the current transaction, a free one before growing the file
+    pub fn allocate(&mut self) -> u64 {
+        match self.free.pop() {
+            Some(page) => page,
+            None => {
+                self.meta.pages += 1;
+                self.meta.pages - 1
+            },
+        }
+    }
+
+    /// free a page of the committed state, it may be reused once the transaction is committed
+    pub fn free(&mut self, page : u64) {
+        self.pending.push(page);
+    }
+
+    pub fn read_page(&mut self, page : u64) -> Result<Vec<u8>> {
+        if page == 0 || page >= self.meta.pages {
+            return Err(corrupted());
+        }
+        let mut buf = vec![0; self.page_size];
+        self.file.seek(SeekFrom::Start(page * self.page_size as u64))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// write the page padded to the page size
+    pub fn write_page(&mut self, page : u64, mut buf : Vec<u8>) -> Result<()> {
+        debug_assert!(buf.len() <= self.page_size, "page {} overflows", page);
+        buf.resize(self.page_size, 0);
+        self.file.seek(SeekFrom::Start(page * self.page_size as u64))?;
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// write the value to a chain of overflow pages and return the first one
+    pub fn write_overflow(&mut self, value : &[u8]) -> Result<u64> {
+        let chunk = self.page_size - CHAIN_HEADER;
+        let pages : Vec<u64> = value.chunks(chunk).map(|_| self.allocate()).collect();
+        for (i, part) in value.chunks(chunk).enumerate() {
+            let next = pages.get(i + 1).cloned().unwrap_or(0);
+            self.write_page(pages[i], chain_page(PAGE_OVERFLOW, next, part.len(), part))?;
+        }
+        pages.first().cloned().ok_or_else(corrupted)
+    }
+
+    pub fn read_overflow(&mut self, mut page : u64, len : u64) -> Result<Vec<u8>> {
+        let mut value = Vec::with_capacity(len as usize);
+        while page != 0 {
+            let (next, part) = self.read_chain(page, PAGE_OVERFLOW)?;
+            value.extend_from_slice(&part);
+            page = next;
+        }
+        if value.len() as u64 != len {
+            return Err(corrupted());
+        }
+        Ok(value)
+    }
+
+    /// free every page of the overflow chain
+    pub fn free_overflow(&mut self, mut page : u64) -> Result<()> {
+        while page != 0 {
+            let mut header = [0; CHAIN_HEADER];
+            self.file.seek(SeekFrom::Start(page * self.page_size as u64))?;
+            self.file.read_exact(&mut header)?;
+            if header[0] != PAGE_OVERFLOW {
+                return Err(corrupted());
+            }
+            self.free(page);
+            page = u64_at(&header[1..9]);
+        }
+        Ok(())
+    }
+
+    /// the next page of the chain and the payload of the page
+    fn read_chain(&mut self, page : u64, kind : u8) -> Result<(u64, Vec<u8>)> {
+        let buf = self.read_page(page)?;
+        let len = u32_at(&buf[9..CHAIN_HEADER]) as usize;
+        let ids = if kind == PAGE_FREELIST { len * 8 } else { len };
+        if buf[0] != kind || CHAIN_HEADER + ids > buf.len() {
+            return Err(corrupted());
+        }
+        Ok((u64_at(&buf[1..9]), buf[CHAIN_HEADER..CHAIN_HEADER + ids].to_vec()))
+    }
+
+    /// write the free-list and then the meta of the transaction to the slot of its txid,
+    /// which switches to the new root at once
+    pub fn commit(&mut self) -> Result<()> {
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        // the free-list takes some of the free pages, the ones it replaces are free once it is committed
+        let per_page = (self.page_size - CHAIN_HEADER) / 8;
+        let mut list_pages = Vec::new();
+        while list_pages.len() * per_page < self.free.len() + self.pending.len() + self.freelist_pages.len() {
+            list_pages.push(self.allocate());
+        }
+        let mut ids = mem::replace(&mut self.free, Vec::new());
+        ids.append(&mut self.pending);
+        ids.append(&mut self.freelist_pages);
+        // allocations pop the lowest pages first, which keeps the file dense
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        for (i, chunk) in ids.chunks(per_page).enumerate() {
+            let mut payload = Vec::with_capacity(chunk.len() * 8);
+            for id in chunk {
+                payload.extend_from_slice(&id.to_be_bytes());
+            }
+            let next = list_pages.get(i + 1).cloned().unwrap_or(0);
+            self.write_page(list_pages[i], chain_page(PAGE_FREELIST, next, chunk.len(), &payload))?;
+        }
+        self.meta.freelist = list_pages.first().cloned().unwrap_or(0);
+        self.meta.txid += 1;
+        self.write_meta()?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        self.free = ids;
+        self.freelist_pages = list_pages;
+        Ok(())
+    }
+
+    /// drop the changes of the current transaction, back to the committed state
+    pub fn rollback(&mut self) -> Result<()> {
+        self.load()
+    }
+
+    fn write_meta(&mut self) -> Result<()> {
+        let mut buf = Vec::with_capacity(META_LEN);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&(self.page_size as u32).to_be_bytes());
+        for field in &[self.meta.txid, self.meta.root, self.meta.pages, self.meta.freelist, self.meta.keys] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+        let checksum = fnv1a(&buf);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+        self.file.seek(SeekFrom::Start(self.meta.txid % 2 * META_SLOT_SIZE))?;
+        self.file.write_all(&buf)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// the page size and meta of a slot, `None` if the slot was never written or is torn
+fn decode_meta(slot : &[u8]) -> Option<(usize, Meta)> {
+    if &slot[..8] != MAGIC || fnv1a(&slot[..META_LEN - 8]) != u64_at(&slot[META_LEN - 8..META_LEN]) {
+        return None;
+    }
+    let page_size = u32_at(&slot[8..12]) as usize;
+    if page_size < MIN_PAGE_SIZE || page_size > MAX_PAGE_SIZE {
+        return None;
+    }
+    let field = |i : usize| u64_at(&slot[12 + i * 8..20 + i * 8]);
+    Some((page_size, Meta {
+        txid : field(0),
+        root : field(1),
+        pages : field(2),
+        freelist : field(3),
+        keys : field(4),
+    }))
+}
+
+fn chain_page(kind : u8, next : u64, len : usize, payload : &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHAIN_HEADER + payload.len());
+    buf.push(kind);
+    buf.extend_from_slice(&next.to_be_bytes());
+    buf.extend_from_slice(&(len as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+pub fn u64_at(buf : &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn u32_at(buf : &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_be_bytes(bytes)
+}
@@ -0,0 +1,231 @@
+use super::{KvsEngine, Result, KvsError};
+use super::{BTreeConfig, BTreeKvStore, CacheAdmission, Codec, EncryptionKey, EvictionPolicy, IndexMode};
+use super::{KvStore, KvStoreConfig, LsmConfig, LsmKvStore, MemKvStore, SledKvStore};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+const MARKER : &str = "engine";
+const DESCRIPTOR_VERSION : u32 = 1;
+
+/// Opens an engine in a data directory with the options it was given
+pub type EngineFactory = dyn Fn(&Path, &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> + Send + Sync;
+
+struct Registered {
+    factory : Arc<EngineFactory>,
+    // whether the engine keeps its data in the directory, which then gets an engine marker
+    persistent : bool,
+}
+
+lazy_static! {
+    static ref ENGINES : RwLock<BTreeMap<String, Registered>> = {
+        let mut engines = BTreeMap::new();
+        let mut builtin = |name : &str, persistent : bool, factory : Arc<EngineFactory>| {
+            engines.insert(name.to_owned(), Registered { factory, persistent });
+        };
+        builtin("kvs", true, Arc::new(open_kvs));
+        builtin("sled", true, Arc::new(|path : &Path, _ : &EngineOptions| Ok(boxed(SledKvStore::new(path)?))));
+        builtin("lsm", true, Arc::new(open_lsm));
+        builtin("btree", true, Arc::new(open_btree));
+        builtin("memory", false, Arc::new(open_memory));
+        RwLock::new(engines)
+    };
+}
+
+/// Register an engine under `name` for every caller in the process, replacing
+/// the engine registered before. `persistent` engines keep their data in the
+/// directory they are opened with, which is then marked with the engine name.
+/// `kvs`, `sled`, `lsm`, `btree` and `memory` are registered by default.
+pub fn register_engine<F>(name : &str, persistent : bool, factory : F)
+    where F : Fn(&Path, &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> + Send + Sync + 'static
+{
+    ENGINES
+        .write()
+        .expect("engines lock poisoned")
+        .insert(name.to_owned(), Registered { factory : Arc::new(factory), persistent });
+}
+
+/// the names of the registered engines, sorted
+pub fn engine_names() -> Vec<String> {
+    ENGINES.read().expect("engines lock poisoned").keys().cloned().collect()
+}
+
+fn registered(name : &str) -> Result<(Arc<EngineFactory>, bool)> {
+    ENGINES
+        .read()
+        .expect("engines lock poisoned")
+        .get(name)
+        .map(|registered| (registered.factory.clone(), registered.persistent))
+        .ok_or_else(|| KvsError::UnknownEngine(name.to_owned()))
+}
+
+/// open the engine registered under `name` in `path`, the engine marker is left alone
+pub fn open_engine(name : &str, path : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let (factory, _) = registered(name)?;
+    factory(path, options)
+}
+
+/// Open the engine registered under `name` to serve the data directory. The marker
+/// of the directory must name the same engine, it is written if there is none yet.
+/// Engines which aren't persistent don't check or write the marker.
+pub fn open_data_dir(name : &str, dir : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let (factory, persistent) = registered(name)?;
+    if persistent {
+        match EngineDescriptor::read(dir)? {
+            Some(ref descriptor) if descriptor.engine != name => {
+                return Err(KvsError::StringError(format!(
+                    "the data directory holds the {} engine, not {}", descriptor.engine, name
+                )));
+            },
+            Some(ref descriptor) if descriptor.version == DESCRIPTOR_VERSION => {},
+            // a missing or legacy marker
+            _ => EngineDescriptor::new(name).write(dir)?,
+        }
+    }
+    factory(dir, options)
+}
+
+/// Options of an engine by name, like the flags of kvs-server without the dashes.
+/// Each engine reads the options it knows and ignores the others.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EngineOptions(BTreeMap<String, String>);
+
+impl EngineOptions {
+    pub fn new() -> Self {
+        EngineOptions::default()
+    }
+
+    pub fn set<N : Into<String>, V : Into<String>>(&mut self, name : N, value : V) -> &mut Self {
+        self.0.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn get(&self, name : &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// the parsed value of the option, `None` if it isn't set
+    pub fn parse<T>(&self, name : &str) -> Result<Option<T>>
+        where T : FromStr, T::Err : Display
+    {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|err| KvsError::StringError(format!("invalid {} {:?}: {}", name, value, err))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The engine marker of a data directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineDescriptor {
+    /// version of the descriptor format
+    pub version : u32,
+    pub engine : String,
+}
+
+impl EngineDescriptor {
+    pub fn new<S : Into<String>>(engine : S) -> Self {
+        EngineDescriptor {
+            version : DESCRIPTOR_VERSION,
+            engine : engine.into(),
+        }
+    }
+
+    /// read the marker of the directory, `None` if there is none.
+    /// A marker holding only the engine name predates the descriptor and reads as version 0.
+    pub fn read(dir : &Path) -> Result<Option<Self>> {
+        let marker = dir.join(MARKER);
+        if !marker.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(marker)?;
+        if !content.trim_start().starts_with('{') {
+            return Ok(Some(EngineDescriptor { version : 0, engine : content.trim().to_owned() }));
+        }
+        let descriptor : EngineDescriptor = serde_json::from_str(&content)?;
+        if descriptor.version > DESCRIPTOR_VERSION {
+            return Err(KvsError::StringError(format!(
+                "the engine marker has version {}, this build reads up to {}", descriptor.version, DESCRIPTOR_VERSION
+            )));
+        }
+        Ok(Some(descriptor))
+    }
+
+    /// replace the marker of the directory, the new marker is synced before it replaces the old one
+    pub fn write(&self, dir : &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MARKER));
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, dir.join(MARKER))?;
+        Ok(())
+    }
+}
+
+fn boxed<E : KvsEngine + Send + 'static>(engine : E) -> Box<dyn KvsEngine + Send> {
+    Box::new(engine)
+}
+
+fn open_kvs(path : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let defaults = KvStoreConfig::default();
+    let encryption_key = match options.get("key-file") {
+        Some(file) => Some(EncryptionKey::from_file(file.as_ref())?),
+        None => EncryptionKey::from_env(EncryptionKey::ENV)?,
+    };
+    let config = KvStoreConfig {
+        use_mmap : options.parse("mmap")?.unwrap_or(defaults.use_mmap),
+        max_versions : options.parse("max-versions")?.unwrap_or(defaults.max_versions),
+        max_live_bytes : options.parse("max-bytes")?.unwrap_or(defaults.max_live_bytes),
+        max_keys : options.parse("max-keys")?.unwrap_or(defaults.max_keys),
+        eviction_policy : options.parse::<EvictionPolicy>("eviction")?.unwrap_or(defaults.eviction_policy),
+        value_cache_entries : options.parse("value-cache")?.unwrap_or(defaults.value_cache_entries),
+        value_cache_admission : options.parse::<CacheAdmission>("cache-admission")?.unwrap_or(defaults.value_cache_admission),
+        blob_threshold : options.parse("blob-threshold")?.unwrap_or(defaults.blob_threshold),
+        compression : options.parse::<Codec>("compression")?.unwrap_or(defaults.compression),
+        index_mode : options.parse::<IndexMode>("index")?.unwrap_or(defaults.index_mode),
+        sorted_index : options.parse("sorted-index")?.unwrap_or(defaults.sorted_index),
+        sync : options.parse("sync")?.unwrap_or(defaults.sync),
+        encryption_key,
+        ..defaults
+    };
+    Ok(boxed(KvStore::with_config(path, config)?))
+}
+
+fn open_lsm(path : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let defaults = LsmConfig::default();
+    let config = LsmConfig {
+        memtable_size : options.parse("memtable-size")?.unwrap_or(defaults.memtable_size),
+        block_cache_blocks : options.parse("block-cache")?.unwrap_or(defaults.block_cache_blocks),
+        wal_compression : options.parse::<Codec>("compression")?.unwrap_or(defaults.wal_compression),
+        ..defaults
+    };
+    Ok(boxed(LsmKvStore::with_config(path, config)?))
+}
+
+fn open_btree(path : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let defaults = BTreeConfig::default();
+    let config = BTreeConfig {
+        page_size : options.parse("page-size")?.unwrap_or(defaults.page_size),
+        buffer_pool_pages : options.parse("buffer-pool")?.unwrap_or(defaults.buffer_pool_pages),
+        sync : options.parse("sync")?.unwrap_or(defaults.sync),
+    };
+    Ok(boxed(BTreeKvStore::with_config(path, config)?))
+}
+
+/// the memory engine only keeps a snapshot in the directory with the `snapshot` option
+fn open_memory(path : &Path, options : &EngineOptions) -> Result<Box<dyn KvsEngine + Send>> {
+    let store = if options.parse("snapshot")?.unwrap_or(false) {
+        MemKvStore::open(path)?
+    } else {
+        MemKvStore::new()
+    };
+    Ok(boxed(store.with_max_versions(options.parse("max-versions")?.unwrap_or(0))))
+}
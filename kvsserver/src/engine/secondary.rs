@@ -0,0 +1,247 @@
+use super::{Result, KvsError};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+// the encoded fields sort as null, false, true, numbers, strings
+const TAG_NULL : u8 = 0;
+const TAG_BOOL : u8 = 1;
+const TAG_NUMBER : u8 = 2;
+const TAG_STRING : u8 = 3;
+
+/// A secondary index over the JSON values of the keys starting with `keyspace`,
+/// by the field at the JSON pointer `pointer`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub keyspace : String,
+    pub pointer : String,
+}
+
+impl IndexDef {
+    pub fn new(keyspace : String, pointer : String) -> Result<Self> {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(KvsError::StringError(format!("{:?} is not a JSON pointer", pointer)));
+        }
+        Ok(IndexDef { keyspace, pointer })
+    }
+
+    pub fn covers(&self, key : &str) -> bool {
+        key.starts_with(&self.keyspace)
+    }
+
+    /// the encoded field of a value, `None` unless the value is JSON with a scalar at the pointer
+    pub fn field(&self, value : &str) -> Option<Vec<u8>> {
+        encode(&self.scalar(value)?)
+    }
+
+    /// the scalar at the pointer, `None` unless the value is JSON with a scalar there
+    pub fn scalar(&self, value : &str) -> Option<Value> {
+        let mut document : Value = serde_json::from_str(value).ok()?;
+        match document.pointer_mut(&self.pointer)?.take() {
+            Value::Array(_) | Value::Object(_) => None,
+            scalar => Some(scalar),
+        }
+    }
+}
+
+/// The keys looked up in a secondary index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum IndexQuery {
+    /// the field equals the value
+    Value(Value),
+    /// the field is between the bounds, both inclusive. A missing bound is open,
+    /// but only fields of the same type as the other bound match
+    Range(Option<Value>, Option<Value>),
+}
+
+impl IndexQuery {
+    /// bounds of the encoded fields matched by the query
+    pub fn bounds(&self) -> Result<(Bound<Vec<u8>>, Bound<Vec<u8>>)> {
+        let (start, end) = match self {
+            IndexQuery::Value(value) => (Some(value), Some(value)),
+            IndexQuery::Range(start, end) => (start.as_ref(), end.as_ref()),
+        };
+        let start = start.map(encode_query).transpose()?;
+        let end = end.map(encode_query).transpose()?;
+        // an open end stays within the type of the other bound, the tag of its encoding
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (Included(start), prefix_end(end)),
+            (Some(start), None) => {
+                let tag = vec![start[0]];
+                (Included(start), prefix_end(tag))
+            },
+            (None, Some(end)) => (Included(vec![end[0]]), prefix_end(end)),
+            (None, None) => (Unbounded, None),
+        };
+        // an encoded field is never the prefix of another one
+        let end = match end {
+            Some(end) => Excluded(end),
+            None => Unbounded,
+        };
+        // an inverted range matches nothing, it becomes the empty range at its start
+        if let (Included(start), Excluded(end)) = (&start, &end) {
+            if start >= end {
+                return Ok((Included(start.clone()), Excluded(start.clone())));
+            }
+        }
+        Ok((start, end))
+    }
+}
+
+/// Encode a scalar so that the bytes sort like the values, `None` for arrays and objects.
+/// Numbers are compared as f64.
+pub fn encode(value : &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => Some(vec![TAG_NULL]),
+        Value::Bool(value) => Some(vec![TAG_BOOL, *value as u8]),
+        Value::Number(number) => {
+            let bits = number.as_f64()?.to_bits();
+            // negative numbers sort reversed, below the positive ones
+            let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+            let mut field = vec![TAG_NUMBER];
+            field.extend_from_slice(&bits.to_be_bytes());
+            Some(field)
+        },
+        Value::String(value) => {
+            let mut field = vec![TAG_STRING];
+            for &byte in value.as_bytes() {
+                field.push(byte);
+                if byte == 0 {
+                    field.push(0xff);
+                }
+            }
+            field.extend_from_slice(&[0, 1]);
+            Some(field)
+        },
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn encode_query(value : &Value) -> Result<Vec<u8>> {
+    encode(value).ok_or_else(|| KvsError::StringError(format!("cannot query by {}", value)))
+}
+
+/// the least bytes greater than every bytes starting with `prefix`
+pub fn prefix_end(mut prefix : Vec<u8>) -> Option<Vec<u8>> {
+    while let Some(last) = prefix.pop() {
+        if last < 0xff {
+            prefix.push(last + 1);
+            return Some(prefix);
+        }
+    }
+    None
+}
+
+/// The secondary indexes of an engine kept in memory, the engine persists their entries
+#[derive(Default)]
+pub struct SecondaryIndexes {
+    indexes : BTreeMap<String, SecondaryIndex>,
+}
+
+struct SecondaryIndex {
+    def : IndexDef,
+    entries : BTreeSet<(Vec<u8>, String)>,
+    // the indexed field of each key
+    fields : HashMap<String, Vec<u8>>,
+}
+
+impl SecondaryIndexes {
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    pub fn contains(&self, name : &str) -> bool {
+        self.indexes.contains_key(name)
+    }
+
+    pub fn def(&self, name : &str) -> Option<&IndexDef> {
+        self.indexes.get(name).map(|index| &index.def)
+    }
+
+    /// the definitions of the indexes, sorted by name
+    pub fn defs(&self) -> impl Iterator<Item = (&String, &IndexDef)> {
+        self.indexes.iter().map(|(name, index)| (name, &index.def))
+    }
+
+    /// whether any index covers the key
+    pub fn covers(&self, key : &str) -> bool {
+        self.indexes.values().any(|index| index.def.covers(key))
+    }
+
+    /// define an empty index, replacing the one of the same name
+    pub fn define(&mut self, name : String, def : IndexDef) {
+        self.indexes.insert(name, SecondaryIndex {
+            def,
+            entries : BTreeSet::new(),
+            fields : HashMap::new(),
+        });
+    }
+
+    pub fn remove(&mut self, name : &str) -> bool {
+        self.indexes.remove(name).is_some()
+    }
+
+    /// index the current value of the key, `None` if it was removed
+    pub fn update(&mut self, key : &str, value : Option<&str>) {
+        for index in self.indexes.values_mut() {
+            if index.def.covers(key) {
+                index.update(key, value);
+            }
+        }
+    }
+
+    pub fn update_index(&mut self, name : &str, key : &str, value : Option<&str>) {
+        if let Some(index) = self.indexes.get_mut(name) {
+            index.update(key, value);
+        }
+    }
+
+    /// the names of the indexes covering the key
+    pub fn covering(&self, key : &str) -> Vec<String> {
+        self.indexes
+            .iter()
+            .filter(|(_, index)| index.def.covers(key))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// index the key by an encoded field already known, `None` if it has none
+    pub fn set_field(&mut self, name : &str, key : &str, field : Option<Vec<u8>>) {
+        if let Some(index) = self.indexes.get_mut(name) {
+            index.set_field(key, field);
+        }
+    }
+
+    /// the keys matched by the query, ordered by field then key
+    pub fn query(&self, name : &str, query : &IndexQuery) -> Result<Vec<String>> {
+        let index = self.indexes.get(name).ok_or_else(|| KvsError::IndexNotFound(name.to_owned()))?;
+        let (start, end) = query.bounds()?;
+        let start = match start {
+            Included(field) => Included((field, String::new())),
+            _ => Unbounded,
+        };
+        let end = match end {
+            Excluded(field) => Excluded((field, String::new())),
+            _ => Unbounded,
+        };
+        Ok(index.entries.range((start, end)).map(|(_, key)| key.clone()).collect())
+    }
+}
+
+impl SecondaryIndex {
+    fn update(&mut self, key : &str, value : Option<&str>) {
+        let field = value.and_then(|value| self.def.field(value));
+        self.set_field(key, field);
+    }
+
+    fn set_field(&mut self, key : &str, field : Option<Vec<u8>>) {
+        if let Some(old) = self.fields.remove(key) {
+            self.entries.remove(&(old, key.to_owned()));
+        }
+        if let Some(field) = field {
+            self.entries.insert((field.clone(), key.to_owned()));
+            self.fields.insert(key.to_owned(), field);
+        }
+    }
+}
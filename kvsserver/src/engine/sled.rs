@@ -1,19 +1,145 @@
-use super::{KvsEngine, Result, KvsError};
-use sled::Db;
-use std::path::PathBuf;
+use super::{KvsEngine, Result, KvsError, MergeOperand};
+use super::secondary::{prefix_end, IndexDef, IndexQuery};
+use fs2::FileExt;
+use sled::{Db, TransactionError, Transactional, Tree};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// attempts to lock the files of a store which was just dropped
+const LOCK_RETRIES : u32 = 50;
 
 pub struct SledKvStore {
     tree : Db,
+    // json-serialized definition of each secondary index, by name
+    index_defs : Tree,
+    // `name \0 field key` of every indexed key, updated in the same transaction as the key
+    index_entries : Tree,
+    defs : BTreeMap<String, IndexDef>,
 }
 
 impl SledKvStore {
     pub fn new<P : Into<PathBuf>>(path : P) -> Result<Self> {
-        let tree = Db::open(&path.into())?;
+        let path = path.into();
+        wait_unlocked(&path)?;
+        let tree = Db::open(&path)?;
+        tree.set_merge_operator(merge_operator);
+        let index_defs = tree.open_tree("index_defs")?;
+        let index_entries = tree.open_tree("index_entries")?;
+        let mut defs = BTreeMap::new();
+        for pair in index_defs.iter() {
+            let (name, def) = pair?;
+            defs.insert(std::str::from_utf8(name.as_ref())?.to_string(), serde_json::from_slice(def.as_ref())?);
+        }
         tree.flush()?;
         Ok(SledKvStore {
-            tree
+            tree,
+            index_defs,
+            index_entries,
+            defs,
         })
     }
+
+    /// set the key, or remove it if `value` is `None`, together with its index entries.
+    /// Returns whether the key existed
+    fn write_indexed(&mut self, key : &str, value : Option<&str>) -> Result<bool> {
+        let covering : Vec<(&String, &IndexDef)> = self.defs.iter().filter(|(_, def)| def.covers(key)).collect();
+        let old = (&*self.tree, &self.index_entries)
+            .transaction(|(tree, entries)| {
+                let old = match value {
+                    Some(value) => tree.insert(key.as_bytes(), value.as_bytes())?,
+                    None => tree.remove(key.as_bytes())?,
+                };
+                let old_value = old.as_ref().and_then(|old| std::str::from_utf8(old.as_ref()).ok());
+                for (name, def) in &covering {
+                    if let Some(field) = old_value.and_then(|old| def.field(old)) {
+                        entries.remove(entry_key(name, &field, key))?;
+                    }
+                    if let Some(field) = value.and_then(|value| def.field(value)) {
+                        entries.insert(entry_key(name, &field, key), key.as_bytes())?;
+                    }
+                }
+                Ok(old.is_some())
+            })
+            .map_err(|err : TransactionError<()>| KvsError::StringError(format!("index transaction failed: {:?}", err)))?;
+        self.tree.flush()?;
+        Ok(old)
+    }
+
+    /// remove every entry of the index
+    fn clear_index(&self, name : &str) -> Result<()> {
+        for entry in self.index_entries.scan_prefix(entry_prefix(name)).keys() {
+            self.index_entries.remove(entry?)?;
+        }
+        Ok(())
+    }
+}
+
+fn entry_prefix(name : &str) -> Vec<u8> {
+    let mut prefix = name.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+/// the encoded fields are prefix-free, so the key follows the field without a separator
+fn entry_key(name : &str, field : &[u8], key : &str) -> Vec<u8> {
+    let mut entry = entry_prefix(name);
+    entry.extend_from_slice(field);
+    entry.extend_from_slice(key.as_bytes());
+    entry
+}
+
+fn bytes(bound : Bound<String>) -> Bound<Vec<u8>> {
+    match bound {
+        Included(key) => Included(key.into_bytes()),
+        Excluded(key) => Excluded(key.into_bytes()),
+        Unbounded => Unbounded,
+    }
+}
+
+/// sled merge operator, the merged bytes are a json-serialized `MergeOperand`.
+/// Operands which cannot be folded leave the value untouched.
+/// Wait while the files of the store are locked. Sled releases the lock from
+/// background threads, it may still be held right after the store was dropped.
+/// A lock held longer is left for sled to report
+fn wait_unlocked(path : &Path) -> Result<()> {
+    let file = match OpenOptions::new().read(true).write(true).open(path.join("db")) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for _ in 0..LOCK_RETRIES {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(FileExt::unlock(&file)?),
+            Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => thread::sleep(Duration::from_millis(20)),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+fn merge_operator(key : &[u8], existing : Option<&[u8]>, merged : &[u8]) -> Option<Vec<u8>> {
+    let result = serde_json::from_slice::<MergeOperand>(merged)
+        .map_err(KvsError::from)
+        .and_then(|operand| {
+            let existing = match existing {
+                Some(existing) => Some(std::str::from_utf8(existing)?),
+                None => None,
+            };
+            operand.apply(existing)
+        });
+
+    match result {
+        Ok(value) => Some(value.into_bytes()),
+        Err(err) => {
+            error!("merge of {:?} failed: {}", String::from_utf8_lossy(key), err);
+            existing.map(|existing| existing.to_vec())
+        }
+    }
 }
 
 impl KvsEngine for SledKvStore {
@@ -25,16 +151,133 @@ impl KvsEngine for SledKvStore {
     }
 
     fn set(&mut self, key : String, value : String) -> Result<()> {
+        if self.defs.values().any(|def| def.covers(&key)) {
+            self.write_indexed(&key, Some(&value))?;
+            return Ok(());
+        }
         self.tree.insert(key.into_bytes(), value.into_bytes())?;
         self.tree.flush()?;
         Ok(())
     }
 
     fn remove(&mut self, key : String) -> Result<()> {
+        if self.defs.values().any(|def| def.covers(&key)) {
+            if !self.write_indexed(&key, None)? {
+                return Err(KvsError::KeyNotFound);
+            }
+            return Ok(());
+        }
         if let None = self.tree.remove(key)? {
             return Err(KvsError::KeyNotFound);
         }
         self.tree.flush()?;
         Ok(())
     }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.tree
+            .iter()
+            .keys()
+            .map(|key| Ok(std::str::from_utf8(key?.as_ref())?.to_string()))
+            .collect()
+    }
+
+    fn scan(&mut self, start : Bound<String>, end : Bound<String>) -> Result<Vec<(String, String)>> {
+        self.tree
+            .range::<Vec<u8>, _>((bytes(start), bytes(end)))
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((std::str::from_utf8(key.as_ref())?.to_string(), std::str::from_utf8(value.as_ref())?.to_string()))
+            })
+            .collect()
+    }
+
+    fn scan_keys(&mut self, start : Bound<String>, end : Bound<String>, limit : usize) -> Result<Vec<String>> {
+        self.tree
+            .range::<Vec<u8>, _>((bytes(start), bytes(end)))
+            .keys()
+            .take(limit)
+            .map(|key| Ok(std::str::from_utf8(key?.as_ref())?.to_string()))
+            .collect()
+    }
+
+    fn merge(&mut self, key : String, operand : MergeOperand) -> Result<()> {
+        // the merge operator cannot report errors, so check the operand against the current value first
+        let existing = self.get(key.clone())?;
+        let value = operand.apply(existing.as_ref().map(String::as_str))?;
+        // the merge operator can't update the index entries, the merged value is set instead
+        if self.defs.values().any(|def| def.covers(&key)) {
+            self.write_indexed(&key, Some(&value))?;
+            return Ok(());
+        }
+
+        self.tree.merge(key.into_bytes(), serde_json::to_vec(&operand)?)?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// the definition is removed while the index is rebuilt, so a crash never leaves it half built
+    fn create_index(&mut self, name : String, keyspace : String, pointer : String) -> Result<()> {
+        let def = IndexDef::new(keyspace, pointer)?;
+        self.defs.remove(&name);
+        self.index_defs.remove(name.as_bytes())?;
+        self.index_defs.flush()?;
+        self.clear_index(&name)?;
+        for pair in self.tree.scan_prefix(def.keyspace.as_bytes()) {
+            let (key, value) = pair?;
+            let key = std::str::from_utf8(key.as_ref())?;
+            if let Some(field) = def.field(std::str::from_utf8(value.as_ref())?) {
+                self.index_entries.insert(entry_key(&name, &field, key), key.as_bytes())?;
+            }
+        }
+        self.index_entries.flush()?;
+        self.index_defs.insert(name.as_bytes(), serde_json::to_vec(&def)?)?;
+        self.index_defs.flush()?;
+        self.defs.insert(name, def);
+        Ok(())
+    }
+
+    fn drop_index(&mut self, name : String) -> Result<()> {
+        if self.defs.remove(&name).is_none() {
+            return Err(KvsError::IndexNotFound(name));
+        }
+        self.index_defs.remove(name.as_bytes())?;
+        self.index_defs.flush()?;
+        self.clear_index(&name)?;
+        self.index_entries.flush()?;
+        Ok(())
+    }
+
+    fn indexes(&mut self) -> Result<Vec<(String, String, String)>> {
+        Ok(self.defs.iter().map(|(name, def)| (name.clone(), def.keyspace.clone(), def.pointer.clone())).collect())
+    }
+
+    fn query(&mut self, index : String, query : IndexQuery) -> Result<Vec<String>> {
+        if !self.defs.contains_key(&index) {
+            return Err(KvsError::IndexNotFound(index));
+        }
+        let prefix = entry_prefix(&index);
+        let with_prefix = |field : Vec<u8>| {
+            let mut bound = prefix.clone();
+            bound.extend(field);
+            bound
+        };
+        let (start, end) = query.bounds()?;
+        let start = match start {
+            Included(field) => Included(with_prefix(field)),
+            _ => Included(prefix.clone()),
+        };
+        let end = match end {
+            Excluded(field) => Excluded(with_prefix(field)),
+            _ => match prefix_end(prefix.clone()) {
+                Some(end) => Excluded(end),
+                None => Unbounded,
+            },
+        };
+        self.index_entries
+            .range::<Vec<u8>, _>((start, end))
+            .values()
+            .map(|key| Ok(std::str::from_utf8(key?.as_ref())?.to_string()))
+            .collect()
+    }
 }
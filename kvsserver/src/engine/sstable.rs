@@ -0,0 +1,346 @@
+use super::{Result, KvsError, LruCache};
+use super::bloom::{key_hash, BloomFilter};
+use super::keydir::{corrupted, get_varint, next_key, push_key, put_varint};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const TABLE_MAGIC : &[u8; 8] = b"kvstable";
+// positions of the index and the bloom filter, the number of entries and the magic
+const FOOTER_LEN : u64 = 8 * 3 + 8;
+
+/// A key with its value, `None` for a tombstone
+pub type Entry = (String, Option<String>);
+
+pub fn table_path(dir : &Path, id : u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Writes a sorted table: blocks of front-coded entries, the index of the
+/// last key of each block, the bloom filter of the keys and the footer
+pub struct TableWriter {
+    dir : PathBuf,
+    id : u64,
+    writer : BufWriter<File>,
+    pos : u64,
+    block_size : usize,
+    bits_per_key : usize,
+    block : Vec<u8>,
+    // the key before in the current block
+    prev_key : String,
+    index : Vec<u8>,
+    // the last key of the previous block
+    index_key : String,
+    hashes : Vec<u64>,
+}
+
+impl TableWriter {
+    pub fn create(dir : &Path, id : u64, block_size : usize, bits_per_key : usize) -> Result<Self> {
+        Ok(TableWriter {
+            dir : dir.to_owned(),
+            id,
+            writer : BufWriter::new(File::create(table_path(dir, id))?),
+            pos : 0,
+            block_size,
+            bits_per_key,
+            block : Vec::new(),
+            prev_key : String::new(),
+            index : Vec::new(),
+            index_key : String::new(),
+            hashes : Vec::new(),
+        })
+    }
+
+    /// append the entry, keys must be added in increasing order
+    pub fn add(&mut self, key : &str, value : Option<&str>) -> Result<()> {
+        if self.hashes.is_empty() {
+            // the smallest key of the table leads the index
+            push_key(&mut self.index, "", key);
+        }
+        push_key(&mut self.block, &self.prev_key, key);
+        match value {
+            Some(value) => {
+                put_varint(&mut self.block, value.len() as u64 + 1);
+                self.block.extend_from_slice(value.as_bytes());
+            },
+            None => put_varint(&mut self.block, 0),
+        }
+        self.prev_key.clear();
+        self.prev_key.push_str(key);
+        self.hashes.push(key_hash(key));
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// bytes written so far
+    pub fn size(&self) -> u64 {
+        self.pos + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        push_key(&mut self.index, &self.index_key, &self.prev_key);
+        put_varint(&mut self.index, self.pos);
+        put_varint(&mut self.index, self.block.len() as u64);
+        self.pos += self.block.len() as u64;
+        self.index_key.clear();
+        self.index_key.push_str(&self.prev_key);
+        self.block.clear();
+        // front coding restarts with every block, so a block is decoded on its own
+        self.prev_key.clear();
+        Ok(())
+    }
+
+    /// write the index, the bloom filter and the footer, and open the synced table
+    pub fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let index_pos = self.pos;
+        self.writer.write_all(&self.index)?;
+        let bloom_pos = index_pos + self.index.len() as u64;
+        let mut bloom = BloomFilter::new(self.hashes.len(), self.bits_per_key);
+        for &hash in &self.hashes {
+            bloom.insert(hash);
+        }
+        self.writer.write_all(&bloom.encode())?;
+        self.writer.write_all(&index_pos.to_be_bytes())?;
+        self.writer.write_all(&bloom_pos.to_be_bytes())?;
+        self.writer.write_all(&(self.hashes.len() as u64).to_be_bytes())?;
+        self.writer.write_all(TABLE_MAGIC)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Table::open(&self.dir, self.id)
+    }
+}
+
+struct BlockHandle {
+    last_key : String,
+    pos : u64,
+    len : u64,
+}
+
+/// Decoded blocks of the tables, by table id and block number
+pub struct BlockCache {
+    blocks : LruCache<(u64, usize), Arc<Vec<Entry>>>,
+    pub hits : u64,
+    pub misses : u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity : usize) -> Self {
+        BlockCache {
+            blocks : LruCache::new(capacity),
+            hits : 0,
+            misses : 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+/// An immutable sorted table. Only its block index and bloom filter are kept in memory.
+pub struct Table {
+    pub id : u64,
+    pub size : u64,
+    pub entries : u64,
+    path : PathBuf,
+    file : File,
+    smallest : String,
+    blocks : Arc<Vec<BlockHandle>>,
+    bloom : BloomFilter,
+}
+
+impl Table {
+    pub fn open(dir : &Path, id : u64) -> Result<Self> {
+        let path = table_path(dir, id);
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(corrupted());
+        }
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        if &footer[24..] != TABLE_MAGIC {
+            return Err(corrupted());
+        }
+        let field = |i : usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&footer[i * 8..(i + 1) * 8]);
+            u64::from_be_bytes(bytes)
+        };
+        let (index_pos, bloom_pos, entries) = (field(0), field(1), field(2));
+        if index_pos > bloom_pos || bloom_pos > size - FOOTER_LEN {
+            return Err(corrupted());
+        }
+
+        file.seek(SeekFrom::Start(index_pos))?;
+        let mut index = vec![0; (bloom_pos - index_pos) as usize];
+        file.read_exact(&mut index)?;
+        let mut bloom = vec![0; (size - FOOTER_LEN - bloom_pos) as usize];
+        file.read_exact(&mut bloom)?;
+
+        let mut buf = &index[..];
+        let mut smallest = String::new();
+        next_key(&mut buf, &mut smallest)?;
+        let mut blocks = Vec::new();
+        let mut last_key = String::new();
+        while !buf.is_empty() {
+            next_key(&mut buf, &mut last_key)?;
+            let pos = get_varint(&mut buf)?;
+            let len = get_varint(&mut buf)?;
+            if pos + len > index_pos {
+                return Err(corrupted());
+            }
+            blocks.push(BlockHandle { last_key : last_key.clone(), pos, len });
+        }
+        if blocks.is_empty() {
+            return Err(corrupted());
+        }
+
+        Ok(Table {
+            id,
+            size,
+            entries,
+            path,
+            file,
+            smallest,
+            blocks : Arc::new(blocks),
+            bloom : BloomFilter::decode(&bloom)?,
+        })
+    }
+
+    pub fn smallest(&self) -> &str {
+        &self.smallest
+    }
+
+    pub fn largest(&self) -> &str {
+        &self.blocks.last().expect("a table has a block").last_key
+    }
+
+    /// whether the keys of the table may overlap `smallest..=largest`
+    pub fn overlaps(&self, smallest : &str, largest : &str) -> bool {
+        self.smallest() <= largest && self.largest() >= smallest
+    }
+
+    /// false if the key is certainly not in the table
+    pub fn may_contain(&self, key : &str) -> bool {
+        self.smallest() <= key && key <= self.largest() && self.bloom.may_contain(key)
+    }
+
+    /// the entry of the key, `Some(None)` for a tombstone
+    pub fn get(&mut self, key : &str, cache : Option<&mut BlockCache>) -> Result<Option<Option<String>>> {
+        let block = self.block_of(key);
+        if block == self.blocks.len() {
+            return Ok(None);
+        }
+        let entries = match cache {
+            Some(cache) => match cache.blocks.get_mut(&(self.id, block)) {
+                Some(entries) => {
+                    cache.hits += 1;
+                    entries.clone()
+                },
+                None => {
+                    cache.misses += 1;
+                    let entries = Arc::new(read_block(&mut self.file, &self.blocks[block])?);
+                    cache.blocks.insert((self.id, block), entries.clone());
+                    entries
+                },
+            },
+            None => Arc::new(read_block(&mut self.file, &self.blocks[block])?),
+        };
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+            .ok()
+            .map(|i| entries[i].1.clone()))
+    }
+
+    /// the first block which may hold the key, the number of blocks if the key is beyond the table
+    fn block_of(&self, key : &str) -> usize {
+        match self.blocks.binary_search_by(|handle| handle.last_key.as_str().cmp(key)) {
+            Ok(block) | Err(block) => block,
+        }
+    }
+
+    /// iterate the entries from the first key not less than `start`, blocks are read as needed
+    pub fn iter(&self, start : Option<&str>) -> Result<TableIter> {
+        let next_block = match start {
+            Some(start) => self.block_of(start),
+            None => 0,
+        };
+        Ok(TableIter {
+            file : File::open(&self.path)?,
+            blocks : self.blocks.clone(),
+            next_block,
+            entries : Vec::new().into_iter(),
+            start : start.map(str::to_owned),
+        })
+    }
+}
+
+/// Iterates the entries of a table in key order
+pub struct TableIter {
+    file : File,
+    blocks : Arc<Vec<BlockHandle>>,
+    next_block : usize,
+    entries : std::vec::IntoIter<Entry>,
+    // entries before it are skipped in the first block
+    start : Option<String>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            let handle = self.blocks.get(self.next_block)?;
+            self.next_block += 1;
+            let mut entries = match read_block(&mut self.file, handle) {
+                Ok(entries) => entries,
+                Err(err) => return Some(Err(err)),
+            };
+            if let Some(start) = self.start.take() {
+                entries.retain(|(key, _)| *key >= start);
+            }
+            self.entries = entries.into_iter();
+        }
+    }
+}
+
+fn read_block(file : &mut File, handle : &BlockHandle) -> Result<Vec<Entry>> {
+    file.seek(SeekFrom::Start(handle.pos))?;
+    let mut block = vec![0; handle.len as usize];
+    file.read_exact(&mut block)?;
+
+    let mut buf = &block[..];
+    let mut entries = Vec::new();
+    let mut key = String::new();
+    while !buf.is_empty() {
+        next_key(&mut buf, &mut key)?;
+        let value = match get_varint(&mut buf)? as usize {
+            0 => None,
+            len if len - 1 <= buf.len() => {
+                let (value, rest) = buf.split_at(len - 1);
+                buf = rest;
+                Some(String::from_utf8(value.to_vec()).map_err(|err| KvsError::Utf8Error(err.utf8_error()))?)
+            },
+            _ => return Err(corrupted()),
+        };
+        entries.push((key.clone(), value));
+    }
+    Ok(entries)
+}
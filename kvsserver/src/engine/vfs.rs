@@ -0,0 +1,503 @@
+use memmap::Mmap;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The bytes of a whole file, memory mapped if the filesystem can
+pub type Mapping = Arc<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// The filesystem the `KvStore` keeps its files in
+pub trait Vfs : fmt::Debug + Send + Sync {
+    /// open the file for reading
+    fn open(&self, path : &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// create the file for writing, truncate it if it exists
+    fn create(&self, path : &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// open the file for appending, create it if it doesn't exist
+    fn append(&self, path : &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    fn rename(&self, from : &Path, to : &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path : &Path) -> io::Result<()>;
+
+    fn exists(&self, path : &Path) -> bool;
+
+    /// the files in the directory, without its subdirectories
+    fn list(&self, dir : &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn read(&self, path : &Path) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.open(path)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// the content of a file which is no longer written, read into memory by default
+    fn map(&self, path : &Path) -> io::Result<Mapping> {
+        Ok(Arc::new(self.read(path)?))
+    }
+}
+
+/// An open file of a `Vfs`
+pub trait VfsFile : Read + Write + Seek + Send {
+    /// make the data written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    fn size(&self) -> io::Result<u64>;
+}
+
+/// The filesystem of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsVfs;
+
+impl VfsFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl Vfs for OsVfs {
+    fn open(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn append(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+
+    fn rename(&self, from : &Path, to : &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path : &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path : &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, dir : &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn read(&self, path : &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn map(&self, path : &Path) -> io::Result<Mapping> {
+        let file = File::open(path)?;
+        // the caller only maps files which are never modified again
+        Ok(Arc::new(unsafe { Mmap::map(&file)? }))
+    }
+}
+
+/// The content of a file in memory, and the part of it which survives a power loss
+#[derive(Default)]
+struct MemData {
+    data : Vec<u8>,
+    synced : Vec<u8>,
+}
+
+/// A filesystem in memory. Clones share the files, which are kept apart
+/// by their full path, and directories are implied by the paths.
+/// Renames and removals are durable at once, the data written to a file
+/// only once the file is synced.
+#[derive(Default, Clone)]
+pub struct MemVfs {
+    files : Arc<Mutex<BTreeMap<PathBuf, Arc<Mutex<MemData>>>>>,
+}
+
+impl MemVfs {
+    pub fn new() -> Self {
+        MemVfs::default()
+    }
+
+    fn files(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Arc<Mutex<MemData>>>> {
+        self.files.lock().expect("files lock poisoned")
+    }
+
+    fn file(&self, path : &Path) -> io::Result<Arc<Mutex<MemData>>> {
+        self.files()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path.display())))
+    }
+
+    /// every file loses the data written since it was last synced
+    pub fn drop_unsynced(&self) {
+        for data in self.files().values() {
+            let mut data = data.lock().expect("file lock poisoned");
+            data.data = data.synced.clone();
+        }
+    }
+}
+
+impl fmt::Debug for MemVfs {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemVfs").field("files", &self.files().keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+impl Vfs for MemVfs {
+    fn open(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(MemFile { data : self.file(path)?, pos : 0, append : false }))
+    }
+
+    fn create(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        let data = Arc::new(Mutex::new(MemData::default()));
+        self.files().insert(path.to_owned(), Arc::clone(&data));
+        Ok(Box::new(MemFile { data, pos : 0, append : false }))
+    }
+
+    fn append(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        let data = Arc::clone(self.files().entry(path.to_owned()).or_default());
+        Ok(Box::new(MemFile { data, pos : 0, append : true }))
+    }
+
+    fn rename(&self, from : &Path, to : &Path) -> io::Result<()> {
+        let data = self.file(from)?;
+        let mut files = self.files();
+        files.remove(from);
+        files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path : &Path) -> io::Result<()> {
+        self.file(path)?;
+        self.files().remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path : &Path) -> bool {
+        self.files().contains_key(path)
+    }
+
+    fn list(&self, dir : &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.files().keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+    }
+}
+
+/// An open file of a `MemVfs`, it stays readable once removed
+struct MemFile {
+    data : Arc<Mutex<MemData>>,
+    pos : u64,
+    append : bool,
+}
+
+impl MemFile {
+    fn data(&self) -> MutexGuard<'_, MemData> {
+        self.data.lock().expect("file lock poisoned")
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let data = self.data();
+            let start = (self.pos as usize).min(data.data.len());
+            let len = buf.len().min(data.data.len() - start);
+            buf[..len].copy_from_slice(&data.data[start..start + len]);
+            len
+        };
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        let pos = {
+            let mut data = self.data();
+            let pos = if self.append { data.data.len() } else { self.pos as usize };
+            if data.data.len() < pos + buf.len() {
+                data.data.resize(pos + buf.len(), 0);
+            }
+            data.data[pos..pos + buf.len()].copy_from_slice(buf);
+            pos
+        };
+        self.pos = (pos + buf.len()) as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos : SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => self.data().data.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for MemFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut data = self.data();
+        data.synced = data.data.clone();
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data().data.len() as u64)
+    }
+}
+
+/// The faults armed on a `FaultyVfs`, writes, syncs and updates are counted from 1
+#[derive(Debug, Default)]
+struct Faults {
+    writes : u64,
+    syncs : u64,
+    // renames and removals of files
+    updates : u64,
+    crash_update : Option<u64>,
+    fail_write : Option<u64>,
+    fail_sync : Option<u64>,
+    // the write and the number of its bytes which reach the file before the crash
+    tear_write : Option<(u64, usize)>,
+    crashed : bool,
+}
+
+/// A `MemVfs` which injects faults: a write or a sync fails, or the process
+/// crashes in the middle of a write or before a rename or removal. Once crashed
+/// every operation fails until it restarts, either after the process crashed
+/// or after a power loss which drops the data that wasn't synced.
+#[derive(Debug, Default, Clone)]
+pub struct FaultyVfs {
+    mem : MemVfs,
+    faults : Arc<Mutex<Faults>>,
+}
+
+impl FaultyVfs {
+    pub fn new() -> Self {
+        FaultyVfs::default()
+    }
+
+    fn faults(&self) -> MutexGuard<'_, Faults> {
+        lock_faults(&self.faults)
+    }
+
+    /// the `nth` write from now fails, without writing anything
+    pub fn fail_write(&self, nth : u64) {
+        let mut faults = self.faults();
+        faults.fail_write = Some(faults.writes + nth);
+    }
+
+    /// the `nth` sync from now fails, the data stays unsynced
+    pub fn fail_sync(&self, nth : u64) {
+        let mut faults = self.faults();
+        faults.fail_sync = Some(faults.syncs + nth);
+    }
+
+    /// crash in the `nth` write from now, once its first `offset` bytes reached the file
+    pub fn tear_write(&self, nth : u64, offset : usize) {
+        let mut faults = self.faults();
+        faults.tear_write = Some((faults.writes + nth, offset));
+    }
+
+    /// crash before the `nth` rename or removal of a file from now
+    pub fn crash_update(&self, nth : u64) {
+        let mut faults = self.faults();
+        faults.crash_update = Some(faults.updates + nth);
+    }
+
+    /// crash now, every operation fails until the restart
+    pub fn crash(&self) {
+        self.faults().crashed = true;
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.faults().crashed
+    }
+
+    /// restart after a crash of the process, the data which wasn't synced is kept.
+    /// The crash and the armed faults are cleared
+    pub fn restart(&self) {
+        let mut faults = self.faults();
+        *faults = Faults { writes : faults.writes, syncs : faults.syncs, updates : faults.updates, ..Faults::default() };
+    }
+
+    /// restart after a power loss, which drops the data that wasn't synced
+    pub fn power_loss(&self) {
+        self.mem.drop_unsynced();
+        self.restart();
+    }
+
+    /// the writes so far, a write is a call to `Write::write`
+    pub fn writes(&self) -> u64 {
+        self.faults().writes
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.faults().syncs
+    }
+
+    /// the renames and removals of files so far
+    pub fn updates(&self) -> u64 {
+        self.faults().updates
+    }
+
+    fn check(&self) -> io::Result<()> {
+        check_crashed(&self.faults())
+    }
+
+    /// count the rename or removal of a file, and crash before it if it is armed
+    fn update(&self) -> io::Result<()> {
+        let mut faults = self.faults();
+        check_crashed(&faults)?;
+        faults.updates += 1;
+        if faults.crash_update == Some(faults.updates) {
+            faults.crash_update = None;
+            faults.crashed = true;
+            return Err(injected("the process crashed before a rename or removal"));
+        }
+        Ok(())
+    }
+
+    fn wrap(&self, file : Box<dyn VfsFile>) -> Box<dyn VfsFile> {
+        Box::new(FaultyFile { inner : file, faults : Arc::clone(&self.faults) })
+    }
+}
+
+fn lock_faults(faults : &Mutex<Faults>) -> MutexGuard<'_, Faults> {
+    faults.lock().expect("faults lock poisoned")
+}
+
+fn check_crashed(faults : &Faults) -> io::Result<()> {
+    if faults.crashed {
+        return Err(injected("the process crashed"));
+    }
+    Ok(())
+}
+
+fn injected(fault : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("injected fault: {}", fault))
+}
+
+impl Vfs for FaultyVfs {
+    fn open(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        Ok(self.wrap(self.mem.open(path)?))
+    }
+
+    fn create(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        Ok(self.wrap(self.mem.create(path)?))
+    }
+
+    fn append(&self, path : &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.check()?;
+        Ok(self.wrap(self.mem.append(path)?))
+    }
+
+    fn rename(&self, from : &Path, to : &Path) -> io::Result<()> {
+        self.update()?;
+        self.mem.rename(from, to)
+    }
+
+    fn remove_file(&self, path : &Path) -> io::Result<()> {
+        self.update()?;
+        self.mem.remove_file(path)
+    }
+
+    fn exists(&self, path : &Path) -> bool {
+        self.mem.exists(path)
+    }
+
+    fn list(&self, dir : &Path) -> io::Result<Vec<PathBuf>> {
+        self.check()?;
+        self.mem.list(dir)
+    }
+}
+
+/// An open file of a `FaultyVfs`
+struct FaultyFile {
+    inner : Box<dyn VfsFile>,
+    faults : Arc<Mutex<Faults>>,
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        check_crashed(&lock_faults(&self.faults))?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        let mut faults = lock_faults(&self.faults);
+        check_crashed(&faults)?;
+        faults.writes += 1;
+        if faults.fail_write == Some(faults.writes) {
+            faults.fail_write = None;
+            return Err(injected("write failed"));
+        }
+        match faults.tear_write {
+            Some((nth, offset)) if nth == faults.writes => {
+                self.inner.write_all(&buf[..offset.min(buf.len())])?;
+                faults.tear_write = None;
+                faults.crashed = true;
+                Err(injected("the process crashed in a write"))
+            },
+            _ => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check_crashed(&lock_faults(&self.faults))?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos : SeekFrom) -> io::Result<u64> {
+        check_crashed(&lock_faults(&self.faults))?;
+        self.inner.seek(pos)
+    }
+}
+
+impl VfsFile for FaultyFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut faults = lock_faults(&self.faults);
+        check_crashed(&faults)?;
+        faults.syncs += 1;
+        if faults.fail_sync == Some(faults.syncs) {
+            faults.fail_sync = None;
+            return Err(injected("sync failed"));
+        }
+        self.inner.sync()
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        check_crashed(&lock_faults(&self.faults))?;
+        self.inner.size()
+    }
+}
@@ -1,4 +1,5 @@
 use failure::Fail;
+use serde::{Serialize, Deserialize};
 use std::io;
 use sled;
 
@@ -19,6 +20,118 @@ pub enum KvsError {
     StringError(String),
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    #[fail(display = "Unknown merge operator {}", _0)]
+    UnknownMergeOperator(String),
+    #[fail(display = "Merge failed: {}", _0)]
+    Merge(String),
+    #[fail(display = "Invalid dump: {}", _0)]
+    Dump(String),
+    #[fail(display = "Unsupported by the engine: {}", _0)]
+    Unsupported(String),
+    #[fail(display = "Version {} not found", _0)]
+    VersionNotFound(u64),
+    #[fail(display = "Compression failed: {}", _0)]
+    Compression(String),
+    #[fail(display = "Encryption failed: {}", _0)]
+    Encryption(String),
+    #[fail(display = "Index {} not found", _0)]
+    IndexNotFound(String),
+    #[fail(display = "Unknown engine {}", _0)]
+    UnknownEngine(String),
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    #[fail(display = "Corrupted data: {}", _0)]
+    Corruption(String),
+    #[fail(display = "Unauthorized: {}", _0)]
+    Unauthorized(String),
+    #[fail(display = "Throttled: {}", _0)]
+    Throttled(String),
+    #[fail(display = "Conflict: {}", _0)]
+    Conflict(String),
+}
+
+/// Code of an error sent by the server, the client turns it back into
+/// the `KvsError` of the same kind. The values are part of the binary protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// an error without a code of its own
+    Internal = 0,
+    KeyNotFound = 1,
+    VersionNotFound = 2,
+    IndexNotFound = 3,
+    Unsupported = 4,
+    Merge = 5,
+    Io = 6,
+    Corruption = 7,
+    Protocol = 8,
+    Unauthorized = 9,
+    Throttled = 10,
+    Conflict = 11,
+}
+
+impl KvsError {
+    /// the code sent to clients for this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::VersionNotFound(_) => ErrorCode::VersionNotFound,
+            KvsError::IndexNotFound(_) => ErrorCode::IndexNotFound,
+            KvsError::Unsupported(_) => ErrorCode::Unsupported,
+            KvsError::Merge(_) | KvsError::UnknownMergeOperator(_) => ErrorCode::Merge,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serde(_)
+            | KvsError::Utf8Error(_)
+            | KvsError::Dump(_)
+            | KvsError::Compression(_)
+            | KvsError::Encryption(_)
+            | KvsError::Corruption(_) => ErrorCode::Corruption,
+            KvsError::Protocol(_) => ErrorCode::Protocol,
+            KvsError::Unauthorized(_) => ErrorCode::Unauthorized,
+            KvsError::Throttled(_) => ErrorCode::Throttled,
+            KvsError::Conflict(_) => ErrorCode::Conflict,
+            KvsError::Sled(_)
+            | KvsError::StringError(_)
+            | KvsError::UnexpectedCommandType
+            | KvsError::UnknownEngine(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// the message sent along with the code, what `from_code` needs to rebuild the error
+    pub fn message(&self) -> String {
+        match self {
+            KvsError::VersionNotFound(version) => version.to_string(),
+            KvsError::IndexNotFound(detail)
+            | KvsError::Unsupported(detail)
+            | KvsError::Merge(detail)
+            | KvsError::Corruption(detail)
+            | KvsError::Protocol(detail)
+            | KvsError::Unauthorized(detail)
+            | KvsError::Throttled(detail)
+            | KvsError::Conflict(detail) => detail.clone(),
+            err => err.to_string(),
+        }
+    }
+
+    /// rebuild the error sent by the server with its code and message
+    pub fn from_code(code : ErrorCode, message : String) -> KvsError {
+        match code {
+            ErrorCode::Internal => KvsError::StringError(message),
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::VersionNotFound => match message.parse() {
+                Ok(version) => KvsError::VersionNotFound(version),
+                Err(_) => KvsError::StringError(message),
+            },
+            ErrorCode::IndexNotFound => KvsError::IndexNotFound(message),
+            ErrorCode::Unsupported => KvsError::Unsupported(message),
+            ErrorCode::Merge => KvsError::Merge(message),
+            ErrorCode::Io => KvsError::Io(io::Error::new(io::ErrorKind::Other, message)),
+            ErrorCode::Corruption => KvsError::Corruption(message),
+            ErrorCode::Protocol => KvsError::Protocol(message),
+            ErrorCode::Unauthorized => KvsError::Unauthorized(message),
+            ErrorCode::Throttled => KvsError::Throttled(message),
+            ErrorCode::Conflict => KvsError::Conflict(message),
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
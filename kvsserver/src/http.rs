@@ -0,0 +1,557 @@
+//! An HTTP/1.1 gateway, spoken by `KvsServer::run_http` so that the store can
+//! be used with curl and a browser. Values are UTF-8 text, lists, stats and
+//! errors are JSON:
+//!
+//! * `GET /keys/{key}`, and `HEAD`, the value with its `ETag`
+//! * `PUT /keys/{key}` with the value as the body, `201 Created` for a new key,
+//!   `204 No Content` otherwise
+//! * `DELETE /keys/{key}`, `204 No Content`
+//! * `GET /keys?prefix=&limit=`, an array of the sorted keys
+//! * `GET /stats`, the counters of the engine
+//! * `GET /health`, `{"status":"ok"}` while the engine answers
+//!
+//! Keys are percent-encoded in the path, a `/` may be left as is. The ETag of a
+//! value is its version with the engines which keep them, a hash of the value
+//! otherwise. `If-Match` and `If-None-Match` are checked against it, a write
+//! whose condition doesn't hold gets `412 Precondition Failed` and a read
+//! `304 Not Modified`.
+//!
+//! Each request holds the engine for its whole run, so the conditional writes
+//! are atomic. Connections are kept alive unless the client asks otherwise.
+
+use crate::engine::{fnv1a, KvsEngine};
+use crate::errors::{ErrorCode, KvsError, Result};
+use crate::server::with_engine;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::Mutex;
+
+/// bodies beyond this are refused
+const MAX_BODY_SIZE : usize = 64 * 1024 * 1024;
+/// the request line and the header lines beyond this are refused
+const MAX_LINE_SIZE : usize = 64 * 1024;
+/// headers of a request beyond this are refused
+const MAX_HEADERS : usize = 100;
+
+/// A request of a client
+#[derive(Debug)]
+struct HttpRequest {
+    method : String,
+    target : String,
+    /// `HTTP/1.0` rather than `HTTP/1.1`
+    http10 : bool,
+    /// with lowercase names
+    headers : Vec<(String, String)>,
+    body : Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name : &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    /// the comma separated values of every header called `name`
+    fn header_list(&self, name : &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header == name)
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect()
+    }
+
+    fn has_token(&self, name : &str, token : &str) -> bool {
+        self.header_list(name).iter().any(|value| value.eq_ignore_ascii_case(token))
+    }
+
+    fn keep_alive(&self) -> bool {
+        if self.http10 {
+            self.has_token("connection", "keep-alive")
+        } else {
+            !self.has_token("connection", "close")
+        }
+    }
+}
+
+/// A response to a request
+#[derive(Debug)]
+struct HttpResponse {
+    status : u16,
+    headers : Vec<(&'static str, String)>,
+    body : Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status : u16) -> HttpResponse {
+        HttpResponse { status, headers : Vec::new(), body : Vec::new() }
+    }
+
+    fn text(status : u16, body : String) -> HttpResponse {
+        HttpResponse::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.into_bytes())
+    }
+
+    fn json(status : u16, body : &Value) -> HttpResponse {
+        HttpResponse::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.to_string().into_bytes())
+    }
+
+    fn error<S : Into<String>>(status : u16, message : S) -> HttpResponse {
+        HttpResponse::json(status, &json!({ "error" : message.into() }))
+    }
+
+    fn header<S : Into<String>>(mut self, name : &'static str, value : S) -> HttpResponse {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn body(mut self, body : Vec<u8>) -> HttpResponse {
+        self.body = body;
+        self
+    }
+
+    /// write the response, without its body when answering a `HEAD`
+    fn write<W : Write>(&self, writer : &mut W, head : bool, keep_alive : bool) -> Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        // these never have a body
+        let bodyless = self.status == 204 || self.status == 304;
+        if !bodyless {
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        if !head && !bodyless {
+            writer.write_all(&self.body)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<KvsError> for HttpResponse {
+    fn from(err : KvsError) -> HttpResponse {
+        let status = match err.code() {
+            ErrorCode::KeyNotFound | ErrorCode::VersionNotFound | ErrorCode::IndexNotFound => 404,
+            ErrorCode::Merge | ErrorCode::Protocol => 400,
+            ErrorCode::Unauthorized => 403,
+            ErrorCode::Conflict => 409,
+            ErrorCode::Throttled => 429,
+            ErrorCode::Unsupported => 501,
+            ErrorCode::Internal | ErrorCode::Io | ErrorCode::Corruption => 500,
+        };
+        HttpResponse::json(status, &json!({ "error" : err.to_string(), "code" : err.code() }))
+    }
+}
+
+fn reason(status : u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Why a request couldn't be read
+enum Refused {
+    /// the stream broke, nothing can be answered
+    Io(io::Error),
+    /// answered with the status before closing the connection
+    Status(u16, String),
+}
+
+impl From<io::Error> for Refused {
+    fn from(err : io::Error) -> Refused {
+        Refused::Io(err)
+    }
+}
+
+fn bad_request<S : Into<String>>(message : S) -> Refused {
+    Refused::Status(400, message.into())
+}
+
+/// serve the HTTP connection until it is closed
+pub(crate) fn serve<E : KvsEngine>(engine : &Mutex<E>, stream : TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(Refused::Io(err)) => return Err(err.into()),
+            Err(Refused::Status(status, message)) => {
+                // the rest of the stream can't be framed
+                HttpResponse::error(status, message.clone()).write(&mut writer, false, false)?;
+                writer.flush()?;
+                return Err(KvsError::Protocol(message));
+            },
+        };
+        debug!("http {} {}", request.method, request.target);
+        let response = with_engine(engine, |engine| handle(engine, &request)).unwrap_or_else(HttpResponse::from);
+        let keep_alive = request.keep_alive();
+        response.write(&mut writer, request.method == "HEAD", keep_alive)?;
+        if !keep_alive {
+            break;
+        }
+        // pipelined requests are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// read the next request with its body, `None` at the end of the stream
+fn read_request<R : BufRead, W : Write>(reader : &mut R, writer : &mut W) -> std::result::Result<Option<HttpRequest>, Refused> {
+    // empty lines before a request are ignored
+    let line = loop {
+        match read_line(reader, 414)? {
+            Some(line) if line.is_empty() => {},
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if !method.is_empty() => (method, target, version),
+        _ => return Err(bad_request("malformed request line")),
+    };
+    let http10 = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Err(Refused::Status(505, format!("{} isn't supported", version))),
+    };
+    if !target.starts_with('/') {
+        return Err(bad_request("the target must be a path"));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, 431)?.ok_or_else(|| bad_request("unexpected end of the stream"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Refused::Status(431, "too many headers".to_owned()));
+        }
+        match line.find(':') {
+            Some(colon) if colon > 0 && !line.starts_with(|c : char| c.is_ascii_whitespace()) => {
+                headers.push((line[..colon].to_ascii_lowercase(), line[colon + 1..].trim().to_owned()));
+            },
+            _ => return Err(bad_request(format!("malformed header {:?}", line))),
+        }
+    }
+    let mut request = HttpRequest {
+        method : method.to_owned(),
+        target : target.to_owned(),
+        http10,
+        headers,
+        body : Vec::new(),
+    };
+
+    let chunked = match request.header("transfer-encoding") {
+        None => false,
+        Some(coding) if coding.eq_ignore_ascii_case("chunked") => true,
+        Some(coding) => return Err(Refused::Status(501, format!("transfer coding {:?} isn't supported", coding))),
+    };
+    let length = match request.header("content-length") {
+        Some(_) if chunked => return Err(bad_request("both Content-Length and Transfer-Encoding")),
+        Some(length) => {
+            let length = length.parse::<usize>().map_err(|_| bad_request("invalid Content-Length"))?;
+            if length > MAX_BODY_SIZE {
+                return Err(Refused::Status(413, format!("bodies are at most {} bytes", MAX_BODY_SIZE)));
+            }
+            Some(length)
+        },
+        None if chunked => None,
+        None if request.method == "PUT" => return Err(Refused::Status(411, "PUT needs a Content-Length".to_owned())),
+        None => Some(0),
+    };
+    // curl waits for this before sending a large body
+    if length != Some(0) && !http10 && request.has_token("expect", "100-continue") {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    request.body = match length {
+        Some(length) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            body
+        },
+        None => read_chunked(reader)?,
+    };
+    Ok(Some(request))
+}
+
+/// read a chunked body, the trailers are dropped
+fn read_chunked<R : BufRead>(reader : &mut R) -> std::result::Result<Vec<u8>, Refused> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader, 400)?.ok_or_else(|| bad_request("unexpected end of the stream"))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad_request("invalid chunk size"))?;
+        if size == 0 {
+            while !read_line(reader, 431)?.ok_or_else(|| bad_request("unexpected end of the stream"))?.is_empty() {}
+            return Ok(body);
+        }
+        if size > MAX_BODY_SIZE - body.len() {
+            return Err(Refused::Status(413, format!("bodies are at most {} bytes", MAX_BODY_SIZE)));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if read_line(reader, 400)? != Some(String::new()) {
+            return Err(bad_request("chunk without CRLF"));
+        }
+    }
+}
+
+/// read a line without its CRLF, `None` at the end of the stream,
+/// a line too long is refused with `too_long`
+fn read_line<R : BufRead>(reader : &mut R, too_long : u16) -> std::result::Result<Option<String>, Refused> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE_SIZE as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > MAX_LINE_SIZE {
+            return Err(Refused::Status(too_long, "line too long".to_owned()));
+        }
+        return Err(bad_request("unexpected end of the stream"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| bad_request("only UTF-8 requests are supported"))
+}
+
+/// route the request to its endpoint
+fn handle<E : KvsEngine + ?Sized>(engine : &mut E, request : &HttpRequest) -> Result<HttpResponse> {
+    let (path, query) = match request.target.find('?') {
+        Some(mark) => (&request.target[..mark], &request.target[mark + 1..]),
+        None => (request.target.as_str(), ""),
+    };
+    let allowed : &[&str] = match path {
+        "/health" | "/stats" | "/keys" => &["GET", "HEAD"],
+        _ if path.starts_with("/keys/") => &["GET", "HEAD", "PUT", "DELETE"],
+        _ => return Ok(HttpResponse::error(404, format!("no such endpoint {}", path))),
+    };
+    let method = request.method.as_str();
+    if !allowed.contains(&method) {
+        return Ok(HttpResponse::error(405, format!("{} isn't allowed on {}", method, path)).header("Allow", allowed.join(", ")));
+    }
+
+    match path {
+        "/health" => Ok(HttpResponse::json(200, &json!({ "status" : "ok" }))),
+        "/stats" => Ok(HttpResponse::json(200, &serde_json::to_value(engine.stats()?)?)),
+        "/keys" => list(engine, query),
+        _ => {
+            let key = match percent_decode(&path["/keys/".len()..], false) {
+                Some(key) if !key.is_empty() => key,
+                Some(_) => return Ok(HttpResponse::error(400, "empty key")),
+                None => return Ok(HttpResponse::error(400, "invalid percent-encoding of the key")),
+            };
+            match method {
+                "PUT" => put(engine, request, key, path),
+                "DELETE" => delete(engine, request, key),
+                _ => get(engine, request, key),
+            }
+        },
+    }
+}
+
+/// `GET /keys/{key}`
+fn get<E : KvsEngine + ?Sized>(engine : &mut E, request : &HttpRequest, key : String) -> Result<HttpResponse> {
+    let value = engine.get(key.clone())?;
+    let current = match &value {
+        Some(value) => Some(etag(engine, &key, value)?),
+        None => None,
+    };
+    if let Some(status) = preconditions(request, current.as_ref(), true) {
+        return Ok(precondition_failed(status, current));
+    }
+    match (value, current) {
+        (Some(value), Some(current)) => Ok(HttpResponse::text(200, value).header("ETag", current)),
+        _ => Ok(HttpResponse::error(404, format!("no such key {:?}", key))),
+    }
+}
+
+/// `PUT /keys/{key}`
+fn put<E : KvsEngine + ?Sized>(engine : &mut E, request : &HttpRequest, key : String, path : &str) -> Result<HttpResponse> {
+    let value = match String::from_utf8(request.body.clone()) {
+        Ok(value) => value,
+        Err(_) => return Ok(HttpResponse::error(400, "only UTF-8 values are supported")),
+    };
+    let current = current_etag(engine, &key)?;
+    if let Some(status) = preconditions(request, current.as_ref(), false) {
+        return Ok(precondition_failed(status, current));
+    }
+    engine.set(key.clone(), value.clone())?;
+    let tag = etag(engine, &key, &value)?;
+    match current {
+        Some(_) => Ok(HttpResponse::new(204).header("ETag", tag)),
+        None => Ok(HttpResponse::new(201).header("ETag", tag).header("Location", path)),
+    }
+}
+
+/// `DELETE /keys/{key}`
+fn delete<E : KvsEngine + ?Sized>(engine : &mut E, request : &HttpRequest, key : String) -> Result<HttpResponse> {
+    let current = current_etag(engine, &key)?;
+    if let Some(status) = preconditions(request, current.as_ref(), false) {
+        return Ok(precondition_failed(status, current));
+    }
+    if current.is_none() {
+        return Ok(HttpResponse::error(404, format!("no such key {:?}", key)));
+    }
+    engine.remove(key)?;
+    Ok(HttpResponse::new(204))
+}
+
+/// `GET /keys?prefix=&limit=`
+fn list<E : KvsEngine + ?Sized>(engine : &mut E, query : &str) -> Result<HttpResponse> {
+    let mut prefix = String::new();
+    let mut limit = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let mut parts = param.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let value = match percent_decode(parts.next().unwrap_or_default(), true) {
+            Some(value) => value,
+            None => return Ok(HttpResponse::error(400, format!("invalid percent-encoding of {}", name))),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "limit" => match value.parse::<usize>() {
+                Ok(value) => limit = Some(value),
+                Err(_) => return Ok(HttpResponse::error(400, format!("invalid limit {:?}", value))),
+            },
+            _ => {},
+        }
+    }
+    let end = match prefix_end(&prefix) {
+        Some(end) => Excluded(end),
+        None => Unbounded,
+    };
+    let keys = engine.scan_keys(Included(prefix), end, limit.unwrap_or(usize::MAX))?;
+    Ok(HttpResponse::json(200, &json!(keys)))
+}
+
+/// the smallest string above every string starting with `prefix`, `None` if there is none.
+/// Strings order like their chars, so the last char which can be is incremented
+fn prefix_end(prefix : &str) -> Option<String> {
+    let mut chars : Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(std::char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// the ETag of the value of the key, its version when the engine keeps them
+fn etag<E : KvsEngine + ?Sized>(engine : &mut E, key : &str, value : &str) -> Result<String> {
+    match engine.version(key.to_owned()) {
+        Ok(Some(version)) => return Ok(format!("\"v{}\"", version)),
+        Ok(None) | Err(KvsError::Unsupported(_)) => {},
+        Err(err) => return Err(err),
+    }
+    Ok(hash_etag(value))
+}
+
+fn hash_etag(value : &str) -> String {
+    format!("\"h{:016x}\"", fnv1a(value.as_bytes()))
+}
+
+/// the ETag of the key, `None` if it doesn't exist. The value is only read without versions
+fn current_etag<E : KvsEngine + ?Sized>(engine : &mut E, key : &str) -> Result<Option<String>> {
+    match engine.version(key.to_owned()) {
+        Ok(version) => return Ok(version.map(|version| format!("\"v{}\"", version))),
+        Err(KvsError::Unsupported(_)) => {},
+        Err(err) => return Err(err),
+    }
+    Ok(engine.get(key.to_owned())?.map(|value| hash_etag(&value)))
+}
+
+/// check `If-Match` and `If-None-Match` against the current ETag of the key,
+/// the status to answer with if they don't hold
+fn preconditions(request : &HttpRequest, current : Option<&String>, read : bool) -> Option<u16> {
+    let if_match = request.header_list("if-match");
+    if !if_match.is_empty() {
+        // the ETags are strong, weak ones never match here
+        let holds = match current {
+            Some(current) => if_match.iter().any(|&tag| tag == "*" || tag == current.as_str()),
+            None => false,
+        };
+        if !holds {
+            return Some(412);
+        }
+    }
+    let if_none_match = request.header_list("if-none-match");
+    if let Some(current) = current {
+        let matches = if_none_match
+            .iter()
+            .any(|&tag| tag == "*" || tag.trim_start_matches("W/") == current.as_str());
+        if matches {
+            return Some(if read { 304 } else { 412 });
+        }
+    }
+    None
+}
+
+fn precondition_failed(status : u16, current : Option<String>) -> HttpResponse {
+    let response = match status {
+        304 => HttpResponse::new(304),
+        _ => HttpResponse::error(status, "precondition failed"),
+    };
+    match current {
+        Some(current) => response.header("ETag", current),
+        None => response,
+    }
+}
+
+/// decode `%XX` escapes, and `+` as a space in a query, `None` if they are
+/// invalid or decode to invalid UTF-8
+fn percent_decode(s : &str, query : bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'%' => {
+                let hex = rest.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                rest = &rest[2..];
+            },
+            b'+' if query => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -1,12 +1,25 @@
 #[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
 
-pub use engine::{KvStore, KvsEngine, SledKvStore};
-pub use client::KvsClient;
+pub use engine::{BTreeConfig, BTreeKvStore, CacheAdmission, Codec, EncryptionKey, EngineStats, EvictionPolicy, IndexMode, KvStore, KvStoreConfig, KvsEngine, LsmConfig, LsmKvStore, MemKvStore, SledKvStore};
+pub use engine::{register_merge_operator, IndexQuery, MergeOperand};
+pub use engine::{FaultyVfs, Mapping, MemVfs, OsVfs, Vfs, VfsFile};
+pub use engine::{engine_names, open_data_dir, open_engine, register_engine, EngineDescriptor, EngineFactory, EngineOptions};
+pub use client::{KvsClient, RequestHandle};
+pub use common::{Request, Response};
 pub use server::KvsServer;
-pub use errors::{Result, KvsError};
+pub use errors::{ErrorCode, Result, KvsError};
+pub use dump::{DumpFormat, DumpReader, DumpWriter};
+pub use protocol::Protocol;
+
+pub mod conformance;
+pub mod dump;
 
 mod common;
 mod engine;
 mod client;
 mod server;
+mod protocol;
+mod resp;
+mod http;
 mod errors;
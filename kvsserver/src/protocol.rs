@@ -0,0 +1,487 @@
+//! The wire protocol between `KvsClient` and `KvsServer`.
+//!
+//! A client opens the connection with the handshake: `MAGIC` and the highest
+//! protocol version it speaks. The server answers with `MAGIC` and the version
+//! it picked, the lower of both, where version 0 is JSON. A connection which
+//! starts with anything else is legacy: a stream of JSON requests, as sent by
+//! clients which predate the handshake, answered in order with `LegacyResponse`s.
+//!
+//! After the handshake every request carries an id chosen by the client, and
+//! its response carries the same id. The server runs the requests of a
+//! connection concurrently and sends each response as soon as it is ready.
+//!
+//! * JSON, requests and responses are `[id, message]` JSON arrays sent one
+//!   after the other
+//! * binary, every message is a frame: the big-endian u32 length of the body,
+//!   then the body. The body starts with the varint id, followed for a request
+//!   by its type code and its fields, for a response by its type code and the
+//!   result, or by the `ErrorCode` and the message of an error. Strings are
+//!   UTF-8 prefixed by their varint length, integers are varints, signed ones
+//!   zigzag coded, and JSON values of queries are sent as strings.
+//!
+//! A binary request which cannot be decoded is answered with a protocol error
+//! and the connection goes on. A frame beyond the maximum size is answered with
+//! a protocol error, with id 0, and ends the connection. So does a JSON or
+//! legacy request which cannot be parsed or is longer than the maximum size of
+//! a frame, since the stream can't be resynchronized.
+
+use crate::common::*;
+use crate::engine::{self, put_varint, IndexQuery};
+use crate::errors::{ErrorCode, KvsError, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+pub const MAGIC : &[u8; 4] = b"\x89KVS";
+/// version of the binary protocol
+pub const BINARY_VERSION : u8 = 1;
+/// frames larger than this are refused by default
+pub const MAX_FRAME_SIZE : usize = 64 * 1024 * 1024;
+
+const GET : u8 = 1;
+const SET : u8 = 2;
+const REMOVE : u8 = 3;
+const SET_EX : u8 = 4;
+const INCR : u8 = 5;
+const APPEND : u8 = 6;
+const GET_VERSION : u8 = 7;
+const HISTORY : u8 = 8;
+const CREATE_INDEX : u8 = 9;
+const DROP_INDEX : u8 = 10;
+const QUERY : u8 = 11;
+
+const QUERY_VALUE : u8 = 0;
+const QUERY_RANGE : u8 = 1;
+
+const VALUE : u8 = 1;
+const DONE : u8 = 2;
+const VERSIONS : u8 = 3;
+const KEYS : u8 = 4;
+const MERGED : u8 = 5;
+const ERR : u8 = 6;
+
+/// every code, at the index of its value
+const ERROR_CODES : &[ErrorCode] = &[
+    ErrorCode::Internal,
+    ErrorCode::KeyNotFound,
+    ErrorCode::VersionNotFound,
+    ErrorCode::IndexNotFound,
+    ErrorCode::Unsupported,
+    ErrorCode::Merge,
+    ErrorCode::Io,
+    ErrorCode::Corruption,
+    ErrorCode::Protocol,
+    ErrorCode::Unauthorized,
+    ErrorCode::Throttled,
+    ErrorCode::Conflict,
+];
+
+/// Protocol of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    /// JSON without the handshake, the only protocol of older servers
+    Legacy,
+    /// JSON after the handshake
+    Json,
+    Binary,
+}
+
+impl Protocol {
+    /// the version sent in the handshake
+    pub fn version(self) -> u8 {
+        match self {
+            Protocol::Legacy | Protocol::Json => 0,
+            Protocol::Binary => BINARY_VERSION,
+        }
+    }
+
+    /// the best protocol of at most `version`
+    fn negotiate(version : u8) -> Protocol {
+        if version >= BINARY_VERSION {
+            Protocol::Binary
+        } else {
+            Protocol::Json
+        }
+    }
+
+    /// write the message with its id in the protocol, the legacy one has no ids.
+    /// The writer isn't flushed
+    pub(crate) fn write<W, M>(self, writer : &mut W, id : u64, message : &M) -> Result<()>
+        where W : Write, M : Message + Serialize
+    {
+        match self {
+            Protocol::Legacy => serde_json::to_writer(&mut *writer, message)?,
+            Protocol::Json => serde_json::to_writer(&mut *writer, &(id, message))?,
+            Protocol::Binary => {
+                let mut body = Vec::new();
+                put_varint(&mut body, id);
+                message.encode(&mut body);
+                writer.write_all(&(body.len() as u32).to_be_bytes())?;
+                writer.write_all(&body)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// read the next message and its id in the protocol, 0 in the legacy one.
+    /// Messages beyond `max_frame_size` are refused
+    pub(crate) fn read<R, M>(self, reader : &mut R, max_frame_size : usize) -> Result<(u64, M)>
+        where R : BufRead, M : Message + DeserializeOwned
+    {
+        let closed = || protocol_error("the connection was closed");
+        match self {
+            Protocol::Legacy => Ok((0, read_json(reader, max_frame_size)?.ok_or_else(closed)?)),
+            Protocol::Json => read_json(reader, max_frame_size)?.ok_or_else(closed),
+            Protocol::Binary => match read_frame(reader, max_frame_size)? {
+                Some(body) => {
+                    let (id, message) = decode_body(&body);
+                    Ok((id, message?))
+                },
+                None => Err(closed()),
+            },
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s : &str) -> Result<Self> {
+        match s {
+            "legacy" => Ok(Protocol::Legacy),
+            "json" => Ok(Protocol::Json),
+            "binary" => Ok(Protocol::Binary),
+            _ => Err(KvsError::StringError(format!("unknown protocol {}", s))),
+        }
+    }
+}
+
+/// the handshake of the client, offering protocols up to `protocol`
+pub(crate) fn offer<R : Read, W : Write>(reader : &mut R, writer : &mut W, protocol : Protocol) -> Result<Protocol> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[protocol.version()])?;
+    writer.flush()?;
+
+    let mut answer = [0; 5];
+    reader.read_exact(&mut answer)?;
+    if &answer[..4] != MAGIC {
+        return Err(protocol_error("the server answered the handshake with an unknown magic"));
+    }
+    Ok(Protocol::negotiate(answer[4]))
+}
+
+/// The handshake of the server, a connection without one is legacy.
+/// `None` if the connection was closed before anything was sent
+pub(crate) fn accept<R : BufRead, W : Write>(reader : &mut R, writer : &mut W) -> Result<Option<Protocol>> {
+    match reader.fill_buf()?.first() {
+        Some(&byte) if byte == MAGIC[0] => {},
+        Some(_) => return Ok(Some(Protocol::Legacy)),
+        None => return Ok(None),
+    }
+    let mut offer = [0; 5];
+    reader.read_exact(&mut offer)?;
+    if &offer[..4] != MAGIC {
+        return Err(protocol_error("unknown handshake magic"));
+    }
+    let protocol = Protocol::negotiate(offer[4]);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[protocol.version()])?;
+    writer.flush()?;
+    Ok(Some(protocol))
+}
+
+/// read the body of the next frame, `None` if the connection was closed between frames
+pub(crate) fn read_frame<R : Read>(reader : &mut R, max_frame_size : usize) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read(&mut len[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut len[1..])?,
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_frame_size {
+        return Err(protocol_error(&format!("frame of {} bytes exceeds the maximum of {}", len, max_frame_size)));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Read the next JSON message, `None` if the connection was closed between messages.
+/// A message beyond `max_size` bytes is refused, an I/O error comes back as `KvsError::Io`
+pub(crate) fn read_json<R : BufRead, M : DeserializeOwned>(reader : &mut R, max_size : usize) -> Result<Option<M>> {
+    // the whitespace between messages
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let blank = buf.iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+        let done = blank < buf.len();
+        reader.consume(blank);
+        if done {
+            break;
+        }
+    }
+    let mut limited = reader.take(max_size as u64);
+    M::deserialize(&mut serde_json::Deserializer::from_reader(&mut limited))
+        .map(Some)
+        .map_err(|err| match err {
+            err if err.is_io() => KvsError::Io(err.into()),
+            _ if limited.limit() == 0 => protocol_error(&format!("message exceeds the maximum of {} bytes", max_size)),
+            err => err.into(),
+        })
+}
+
+/// decode the whole body of a frame, the id is 0 when it can't be read
+pub(crate) fn decode_body<M : Message>(body : &[u8]) -> (u64, Result<M>) {
+    let mut buf = body;
+    let id = match get_varint(&mut buf) {
+        Ok(id) => id,
+        Err(err) => return (0, Err(err)),
+    };
+    let message = M::decode(&mut buf).and_then(|message| {
+        if !buf.is_empty() {
+            return Err(protocol_error(&format!("{} trailing bytes in the frame", buf.len())));
+        }
+        Ok(message)
+    });
+    (id, message)
+}
+
+fn protocol_error(message : &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
+
+/// A message of the binary protocol
+pub(crate) trait Message : Sized {
+    fn encode(&self, buf : &mut Vec<u8>);
+
+    fn decode(buf : &mut &[u8]) -> Result<Self>;
+}
+
+impl Message for Request {
+    fn encode(&self, buf : &mut Vec<u8>) {
+        match self {
+            Request::Get(key) => {
+                buf.push(GET);
+                put_str(buf, key);
+            },
+            Request::Set(key, value) => {
+                buf.push(SET);
+                put_str(buf, key);
+                put_str(buf, value);
+            },
+            Request::Remove(key) => {
+                buf.push(REMOVE);
+                put_str(buf, key);
+            },
+            Request::SetEx(key, value, ttl) => {
+                buf.push(SET_EX);
+                put_str(buf, key);
+                put_str(buf, value);
+                put_varint(buf, *ttl);
+            },
+            Request::Incr(key, delta) => {
+                buf.push(INCR);
+                put_str(buf, key);
+                put_varint(buf, ((delta << 1) ^ (delta >> 63)) as u64);
+            },
+            Request::Append(key, suffix) => {
+                buf.push(APPEND);
+                put_str(buf, key);
+                put_str(buf, suffix);
+            },
+            Request::GetVersion(key, version) => {
+                buf.push(GET_VERSION);
+                put_str(buf, key);
+                put_varint(buf, *version);
+            },
+            Request::History(key) => {
+                buf.push(HISTORY);
+                put_str(buf, key);
+            },
+            Request::CreateIndex(name, keyspace, pointer) => {
+                buf.push(CREATE_INDEX);
+                put_str(buf, name);
+                put_str(buf, keyspace);
+                put_str(buf, pointer);
+            },
+            Request::DropIndex(name) => {
+                buf.push(DROP_INDEX);
+                put_str(buf, name);
+            },
+            Request::Query(index, query) => {
+                buf.push(QUERY);
+                put_str(buf, index);
+                match query {
+                    IndexQuery::Value(value) => {
+                        buf.push(QUERY_VALUE);
+                        put_str(buf, &value.to_string());
+                    },
+                    IndexQuery::Range(start, end) => {
+                        buf.push(QUERY_RANGE);
+                        put_option(buf, &start.as_ref().map(Value::to_string));
+                        put_option(buf, &end.as_ref().map(Value::to_string));
+                    },
+                }
+            },
+        }
+    }
+
+    fn decode(buf : &mut &[u8]) -> Result<Self> {
+        let request = match get_u8(buf)? {
+            GET => Request::Get(get_str(buf)?),
+            SET => Request::Set(get_str(buf)?, get_str(buf)?),
+            REMOVE => Request::Remove(get_str(buf)?),
+            SET_EX => Request::SetEx(get_str(buf)?, get_str(buf)?, get_varint(buf)?),
+            INCR => {
+                let key = get_str(buf)?;
+                let zigzag = get_varint(buf)?;
+                Request::Incr(key, (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+            },
+            APPEND => Request::Append(get_str(buf)?, get_str(buf)?),
+            GET_VERSION => Request::GetVersion(get_str(buf)?, get_varint(buf)?),
+            HISTORY => Request::History(get_str(buf)?),
+            CREATE_INDEX => Request::CreateIndex(get_str(buf)?, get_str(buf)?, get_str(buf)?),
+            DROP_INDEX => Request::DropIndex(get_str(buf)?),
+            QUERY => {
+                let index = get_str(buf)?;
+                let query = match get_u8(buf)? {
+                    QUERY_VALUE => IndexQuery::Value(get_value(buf)?),
+                    QUERY_RANGE => {
+                        let start = match get_option(buf)? {
+                            Some(start) => Some(serde_json::from_str(&start)?),
+                            None => None,
+                        };
+                        let end = match get_option(buf)? {
+                            Some(end) => Some(serde_json::from_str(&end)?),
+                            None => None,
+                        };
+                        IndexQuery::Range(start, end)
+                    },
+                    tag => return Err(protocol_error(&format!("unknown query type {}", tag))),
+                };
+                Request::Query(index, query)
+            },
+            code => return Err(protocol_error(&format!("unknown request type {}", code))),
+        };
+        Ok(request)
+    }
+}
+
+impl Message for Response {
+    fn encode(&self, buf : &mut Vec<u8>) {
+        match self {
+            Response::Value(value) => {
+                buf.push(VALUE);
+                put_option(buf, value);
+            },
+            Response::Done => buf.push(DONE),
+            Response::History(versions) => {
+                buf.push(VERSIONS);
+                put_varint(buf, versions.len() as u64);
+                for (version, value) in versions {
+                    put_varint(buf, *version);
+                    put_option(buf, value);
+                }
+            },
+            Response::Keys(keys) => {
+                buf.push(KEYS);
+                put_varint(buf, keys.len() as u64);
+                for key in keys {
+                    put_str(buf, key);
+                }
+            },
+            Response::Merged(value) => {
+                buf.push(MERGED);
+                put_str(buf, value);
+            },
+            Response::Err(code, message) => {
+                buf.push(ERR);
+                buf.push(*code as u8);
+                put_str(buf, message);
+            },
+        }
+    }
+
+    fn decode(buf : &mut &[u8]) -> Result<Self> {
+        let response = match get_u8(buf)? {
+            VALUE => Response::Value(get_option(buf)?),
+            DONE => Response::Done,
+            VERSIONS => {
+                let count = get_varint(buf)?;
+                let mut versions = Vec::new();
+                for _ in 0..count {
+                    versions.push((get_varint(buf)?, get_option(buf)?));
+                }
+                Response::History(versions)
+            },
+            KEYS => {
+                let count = get_varint(buf)?;
+                let mut keys = Vec::new();
+                for _ in 0..count {
+                    keys.push(get_str(buf)?);
+                }
+                Response::Keys(keys)
+            },
+            MERGED => Response::Merged(get_str(buf)?),
+            ERR => {
+                // codes of newer servers are kept as internal errors
+                let code = ERROR_CODES.get(get_u8(buf)? as usize).cloned().unwrap_or(ErrorCode::Internal);
+                Response::Err(code, get_str(buf)?)
+            },
+            code => return Err(protocol_error(&format!("unknown response type {}", code))),
+        };
+        Ok(response)
+    }
+}
+
+fn put_str(buf : &mut Vec<u8>, s : &str) {
+    put_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_option(buf : &mut Vec<u8>, s : &Option<String>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            put_str(buf, s);
+        },
+        None => buf.push(0),
+    }
+}
+
+fn get_u8(buf : &mut &[u8]) -> Result<u8> {
+    let (&byte, rest) = buf.split_first().ok_or_else(|| protocol_error("truncated message"))?;
+    *buf = rest;
+    Ok(byte)
+}
+
+/// a varint coded like the ones of the sorted index
+fn get_varint(buf : &mut &[u8]) -> Result<u64> {
+    engine::get_varint(buf).map_err(|_| protocol_error("truncated or overlong varint"))
+}
+
+fn get_str(buf : &mut &[u8]) -> Result<String> {
+    let len = get_varint(buf)?;
+    if len > buf.len() as u64 {
+        return Err(protocol_error("truncated message"));
+    }
+    let (bytes, rest) = buf.split_at(len as usize);
+    *buf = rest;
+    Ok(std::str::from_utf8(bytes)?.to_owned())
+}
+
+fn get_option(buf : &mut &[u8]) -> Result<Option<String>> {
+    match get_u8(buf)? {
+        0 => Ok(None),
+        1 => Ok(Some(get_str(buf)?)),
+        flag => Err(protocol_error(&format!("unknown option flag {}", flag))),
+    }
+}
+
+fn get_value(buf : &mut &[u8]) -> Result<Value> {
+    Ok(serde_json::from_str(&get_str(buf)?)?)
+}
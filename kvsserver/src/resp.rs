@@ -0,0 +1,544 @@
+//! The Redis serialization protocol, spoken by `KvsServer::run_resp` so that
+//! `redis-cli` and Redis client libraries can use the store.
+//!
+//! Connections start in RESP2, `HELLO 3` switches them to RESP3 which only
+//! changes how nulls and maps are sent. Commands are arrays of bulk strings,
+//! or inline commands split on whitespace. The commands map onto `KvsEngine`:
+//!
+//! * `GET key`, `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+//! * `DEL key...`, `EXISTS key...`, `MGET key...`, `MSET key value...`
+//! * `INCR key`, through the merge operator of `Incr` requests
+//! * `SCAN cursor [MATCH pattern] [COUNT count]`, in key order. A cursor stands for
+//!   the last key returned and is only valid once on its connection, so a scan
+//!   neither skips nor repeats keys which stay during it
+//! * `PING`, `ECHO`, `INFO`, `HELLO`, `SELECT 0`, `QUIT`, and `COMMAND` and
+//!   `CLIENT SETNAME | SETINFO` which clients send when they connect
+//!
+//! Each connection runs its commands in order, every command holding the engine
+//! for its whole run, so `SET` with `NX` or `XX` and `MSET` are atomic.
+
+use crate::engine::{KvsEngine, MergeOperand};
+use crate::errors::{KvsError, Result};
+use crate::server::with_engine;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// bulk strings beyond this are refused, as Redis does
+const MAX_BULK_SIZE : usize = 512 * 1024 * 1024;
+/// arguments of a command beyond this are refused
+const MAX_ARGUMENTS : usize = 1024 * 1024;
+/// inline commands and the lines of headers beyond this are refused
+const MAX_LINE_SIZE : usize = 64 * 1024;
+/// keys returned by a `SCAN` without `COUNT`
+const SCAN_COUNT : usize = 10;
+/// pending `SCAN` cursors of a connection beyond this drop the oldest one
+const MAX_CURSORS : usize = 1024;
+
+/// A reply to a command
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null reply
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+    /// sent as a flat array in RESP2
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK")
+    }
+
+    fn null() -> Reply {
+        Reply::Bulk(None)
+    }
+
+    fn bulk<S : Into<String>>(s : S) -> Reply {
+        Reply::Bulk(Some(s.into()))
+    }
+
+    fn error<S : Into<String>>(s : S) -> Reply {
+        Reply::Error(s.into())
+    }
+
+    fn syntax_error() -> Reply {
+        Reply::error("ERR syntax error")
+    }
+
+    fn write<W : Write>(&self, writer : &mut W, version : u8) -> Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            // a line break would end the error early
+            Reply::Error(s) => write!(writer, "-{}\r\n", s.replace(&['\r', '\n'][..], " "))?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(Some(s)) => {
+                write!(writer, "${}\r\n", s.len())?;
+                writer.write_all(s.as_bytes())?;
+                writer.write_all(b"\r\n")?;
+            },
+            Reply::Bulk(None) if version >= 3 => writer.write_all(b"_\r\n")?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write(writer, version)?;
+                }
+            },
+            Reply::Map(pairs) => {
+                if version >= 3 {
+                    write!(writer, "%{}\r\n", pairs.len())?;
+                } else {
+                    write!(writer, "*{}\r\n", pairs.len() * 2)?;
+                }
+                for (key, value) in pairs {
+                    key.write(writer, version)?;
+                    value.write(writer, version)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl From<KvsError> for Reply {
+    fn from(err : KvsError) -> Reply {
+        match err {
+            KvsError::Merge(_) => Reply::error("ERR value is not an integer or out of range"),
+            err => Reply::error(format!("ERR {}", err)),
+        }
+    }
+}
+
+/// A RESP connection
+struct Session<'a, E> {
+    engine : &'a Mutex<E>,
+    id : u64,
+    /// the RESP version of the replies
+    version : u8,
+    quit : bool,
+    cursors : Cursors,
+}
+
+/// The last key returned by the pending `SCAN`s of a connection. Clients parse
+/// cursors as integers, so they are numbers standing for the keys
+struct Cursors {
+    last_keys : BTreeMap<u64, String>,
+    next : u64,
+}
+
+impl Cursors {
+    /// a new cursor going on after `key`, 0 is never one
+    fn insert(&mut self, key : String) -> u64 {
+        let cursor = self.next;
+        self.next += 1;
+        self.last_keys.insert(cursor, key);
+        if self.last_keys.len() > MAX_CURSORS {
+            let oldest = *self.last_keys.keys().next().expect("a pending cursor");
+            self.last_keys.remove(&oldest);
+        }
+        cursor
+    }
+
+    fn take(&mut self, cursor : u64) -> Option<String> {
+        self.last_keys.remove(&cursor)
+    }
+}
+
+/// serve the RESP connection until it is closed, `id` is reported by `HELLO`
+pub(crate) fn serve<E : KvsEngine>(engine : &Mutex<E>, stream : TcpStream, id : u64) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let cursors = Cursors { last_keys : BTreeMap::new(), next : 1 };
+    let mut session = Session { engine, id, version : 2, quit : false, cursors };
+    while !session.quit {
+        match read_command(&mut reader) {
+            // empty lines are skipped
+            Ok(Some(args)) if args.is_empty() => {},
+            Ok(Some(args)) => session.execute(args).write(&mut writer, session.version)?,
+            Ok(None) => break,
+            Err(KvsError::Protocol(err)) => {
+                // the rest of the stream can't be parsed, Redis closes the connection as well
+                Reply::error(format!("ERR Protocol error: {}", err)).write(&mut writer, session.version)?;
+                writer.flush()?;
+                return Err(KvsError::Protocol(err));
+            },
+            Err(err) => return Err(err),
+        }
+        // pipelined commands are answered together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// read the arguments of the next command, `None` at the end of the stream
+fn read_command<R : BufRead>(reader : &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with(b"*") {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_length(&line[1..], MAX_ARGUMENTS)?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of the stream"))?;
+        if !header.starts_with(b"$") {
+            return Err(protocol_error(&format!("expected '$', got '{}'", String::from_utf8_lossy(&header[..1.min(header.len())]))));
+        }
+        let len = parse_length(&header[1..], MAX_BULK_SIZE)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string without CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// read a line without its CRLF, `None` at the end of the stream
+fn read_line<R : BufRead>(reader : &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE_SIZE as u64 + 2).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > MAX_LINE_SIZE {
+            return Err(protocol_error("too big inline request"));
+        }
+        return Err(protocol_error("unexpected end of the stream"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// the length of an array or a bulk string, negative ones are empty
+fn parse_length(digits : &[u8], max : usize) -> Result<usize> {
+    let len = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    if len > max as i64 {
+        return Err(protocol_error("invalid length"));
+    }
+    Ok(len.max(0) as usize)
+}
+
+fn protocol_error(message : &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
+
+impl<'a, E : KvsEngine> Session<'a, E> {
+    fn execute(&mut self, args : Vec<Vec<u8>>) -> Reply {
+        let args = match args.into_iter().map(String::from_utf8).collect::<std::result::Result<Vec<_>, _>>() {
+            Ok(args) => args,
+            Err(_) => return Reply::error("ERR only UTF-8 keys and values are supported"),
+        };
+        let command = &args[0];
+        let name = command.to_ascii_uppercase();
+        let args = &args[1..];
+        let arity = match name.as_str() {
+            "PING" => args.len() <= 1,
+            "ECHO" | "GET" | "INCR" | "SELECT" => args.len() == 1,
+            "SET" => args.len() >= 2,
+            "DEL" | "EXISTS" | "MGET" | "SCAN" | "CLIENT" => !args.is_empty(),
+            "MSET" => !args.is_empty() && args.len() % 2 == 0,
+            "INFO" | "HELLO" | "COMMAND" | "QUIT" => true,
+            _ => return Reply::error(format!("ERR unknown command '{}'", preview(command))),
+        };
+        if !arity {
+            return Reply::error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()));
+        }
+
+        match name.as_str() {
+            "PING" => match args.first() {
+                Some(message) => Reply::bulk(message.clone()),
+                None => Reply::Simple("PONG"),
+            },
+            "ECHO" => Reply::bulk(args[0].clone()),
+            "HELLO" => self.hello(args),
+            "SELECT" => match args[0].as_str() {
+                "0" => Reply::ok(),
+                _ => Reply::error("ERR DB index is out of range"),
+            },
+            "QUIT" => {
+                self.quit = true;
+                Reply::ok()
+            },
+            "COMMAND" => Reply::Array(Vec::new()),
+            "CLIENT" => match args[0].to_ascii_uppercase().as_str() {
+                "SETNAME" | "SETINFO" => Reply::ok(),
+                "ID" => Reply::Integer(self.id as i64),
+                _ => Reply::error(format!("ERR unknown subcommand '{}'", args[0])),
+            },
+            _ => {
+                let cursors = &mut self.cursors;
+                let reply = with_engine(self.engine, |engine| match name.as_str() {
+                    "GET" => engine.get(args[0].clone()).map(Reply::Bulk),
+                    "SET" => set(engine, args),
+                    "DEL" => del(engine, args),
+                    "EXISTS" => exists(engine, args),
+                    "MGET" => mget(engine, args),
+                    "MSET" => mset(engine, args),
+                    "INCR" => incr(engine, &args[0]),
+                    "SCAN" => scan(engine, cursors, args),
+                    "INFO" => info(engine, args),
+                    _ => unreachable!("{} has no arity", name),
+                });
+                reply.unwrap_or_else(Reply::from)
+            },
+        }
+    }
+
+    /// `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+    fn hello(&mut self, args : &[String]) -> Reply {
+        if let Some(version) = args.first() {
+            match version.as_str() {
+                "2" => self.version = 2,
+                "3" => self.version = 3,
+                _ => return Reply::error("NOPROTO unsupported protocol version"),
+            }
+        }
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_str() {
+                "AUTH" => return Reply::error("ERR AUTH is not supported, the server has no users"),
+                "SETNAME" if options.next().is_some() => {},
+                _ => return Reply::syntax_error(),
+            }
+        }
+        Reply::Map(vec![
+            (Reply::bulk("server"), Reply::bulk("kvs")),
+            (Reply::bulk("version"), Reply::bulk(env!("CARGO_PKG_VERSION"))),
+            (Reply::bulk("proto"), Reply::Integer(i64::from(self.version))),
+            (Reply::bulk("id"), Reply::Integer(self.id as i64)),
+            (Reply::bulk("mode"), Reply::bulk("standalone")),
+            (Reply::bulk("role"), Reply::bulk("master")),
+            (Reply::bulk("modules"), Reply::Array(Vec::new())),
+        ])
+    }
+}
+
+/// the command quoted in errors, cut to a sane length
+fn preview(command : &str) -> String {
+    command.chars().take(128).collect()
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+fn set<E : KvsEngine + ?Sized>(engine : &mut E, args : &[String]) -> Result<Reply> {
+    let (key, value) = (args[0].clone(), args[1].clone());
+    let mut ttl = None;
+    let mut only_if = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let option = option.to_ascii_uppercase();
+        match option.as_str() {
+            "EX" | "PX" if ttl.is_none() => {
+                let amount = match options.next().map(|amount| amount.parse::<u64>()) {
+                    Some(Ok(amount)) if amount > 0 => amount,
+                    Some(_) => return Ok(Reply::error("ERR invalid expire time in 'set' command")),
+                    None => return Ok(Reply::syntax_error()),
+                };
+                ttl = Some(if option == "EX" { Duration::from_secs(amount) } else { Duration::from_millis(amount) });
+            },
+            "NX" | "XX" if only_if.is_none() => only_if = Some(option == "XX"),
+            _ => return Ok(Reply::syntax_error()),
+        }
+    }
+
+    if let Some(exists) = only_if {
+        if engine.get(key.clone())?.is_some() != exists {
+            return Ok(Reply::null());
+        }
+    }
+    match ttl {
+        Some(ttl) => engine.set_with_ttl(key, value, ttl)?,
+        None => engine.set(key, value)?,
+    }
+    Ok(Reply::ok())
+}
+
+/// `DEL key...`, the number of keys removed
+fn del<E : KvsEngine + ?Sized>(engine : &mut E, keys : &[String]) -> Result<Reply> {
+    let mut removed = 0;
+    for key in keys {
+        match engine.remove(key.clone()) {
+            Ok(()) => removed += 1,
+            Err(KvsError::KeyNotFound) => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Reply::Integer(removed))
+}
+
+/// `EXISTS key...`, the number of keys which exist, a key given twice counts twice
+fn exists<E : KvsEngine + ?Sized>(engine : &mut E, keys : &[String]) -> Result<Reply> {
+    let mut count = 0;
+    for key in keys {
+        if engine.get(key.clone())?.is_some() {
+            count += 1;
+        }
+    }
+    Ok(Reply::Integer(count))
+}
+
+fn mget<E : KvsEngine + ?Sized>(engine : &mut E, keys : &[String]) -> Result<Reply> {
+    let mut values = Vec::with_capacity(keys.len());
+    for key in keys {
+        values.push(Reply::Bulk(engine.get(key.clone())?));
+    }
+    Ok(Reply::Array(values))
+}
+
+fn mset<E : KvsEngine + ?Sized>(engine : &mut E, pairs : &[String]) -> Result<Reply> {
+    for pair in pairs.chunks(2) {
+        engine.set(pair[0].clone(), pair[1].clone())?;
+    }
+    Ok(Reply::ok())
+}
+
+fn incr<E : KvsEngine + ?Sized>(engine : &mut E, key : &str) -> Result<Reply> {
+    engine.merge(key.to_owned(), MergeOperand::add(1))?;
+    let value = engine.get(key.to_owned())?.ok_or(KvsError::KeyNotFound)?;
+    match value.parse() {
+        Ok(value) => Ok(Reply::Integer(value)),
+        Err(_) => Err(KvsError::Merge(format!("{:?} is not an integer", value))),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`, from the key after the one the cursor stands for
+fn scan<E : KvsEngine + ?Sized>(engine : &mut E, cursors : &mut Cursors, args : &[String]) -> Result<Reply> {
+    let start = match args[0].parse::<u64>() {
+        Ok(0) => Unbounded,
+        Ok(cursor) => match cursors.take(cursor) {
+            Some(last) => Excluded(last),
+            None => return Ok(Reply::error("ERR invalid cursor")),
+        },
+        Err(_) => return Ok(Reply::error("ERR invalid cursor")),
+    };
+    let mut pattern = None;
+    let mut count = SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (option.to_ascii_uppercase().as_str(), options.next()) {
+            ("MATCH", Some(glob)) => pattern = Some(glob.as_str()),
+            ("COUNT", Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Ok(Reply::error("ERR value is not an integer or out of range")),
+            },
+            _ => return Ok(Reply::syntax_error()),
+        }
+    }
+
+    // like Redis, COUNT is the number of keys looked at, not the number returned
+    let keys = engine.scan_keys(start, Unbounded, count)?;
+    let next = match keys.last() {
+        Some(last) if keys.len() == count => cursors.insert(last.clone()),
+        _ => 0,
+    };
+    let found = keys
+        .into_iter()
+        .filter(|key| pattern.map_or(true, |pattern| glob_match(pattern.as_bytes(), key.as_bytes())))
+        .map(Reply::bulk)
+        .collect();
+    Ok(Reply::Array(vec![Reply::bulk(next.to_string()), Reply::Array(found)]))
+}
+
+/// `INFO [section...]`, the sections are server, stats and keyspace
+fn info<E : KvsEngine + ?Sized>(engine : &mut E, sections : &[String]) -> Result<Reply> {
+    let wanted = |section : &str| {
+        sections.is_empty()
+            || sections.iter().any(|wanted| {
+                let wanted = wanted.to_ascii_lowercase();
+                wanted == section || wanted == "all" || wanted == "default" || wanted == "everything"
+            })
+    };
+    let mut text = String::new();
+    if wanted("server") {
+        text.push_str("# Server\r\n");
+        text.push_str(&format!("kvs_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+        text.push_str("redis_mode:standalone\r\n\r\n");
+    }
+    if wanted("stats") {
+        text.push_str("# Stats\r\n");
+        for (name, value) in engine.stats()?.iter() {
+            text.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        text.push_str("\r\n");
+    }
+    if wanted("keyspace") {
+        text.push_str("# Keyspace\r\n");
+        let keys = match engine.keys() {
+            Ok(keys) => keys.len(),
+            // an engine which can't list its keys
+            Err(KvsError::Unsupported(_)) => 0,
+            Err(err) => return Err(err),
+        };
+        if keys > 0 {
+            let expires = engine.stats()?.get("expiring_keys").unwrap_or(0);
+            text.push_str(&format!("db0:keys={},expires={},avg_ttl=0\r\n", keys, expires));
+        }
+    }
+    Ok(Reply::bulk(text))
+}
+
+/// match `s` against a glob pattern with `*`, `?`, `[...]` classes and `\` escapes
+fn glob_match(pattern : &[u8], s : &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|skip| glob_match(rest, &s[skip..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'[', rest)) => {
+            let (first, tail) = match s.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let (negated, rest) = match rest.split_first() {
+                Some((b'^', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            let end = match rest.iter().position(|&byte| byte == b']') {
+                Some(end) => end,
+                // an unclosed class matches itself
+                None => return *first == b'[' && glob_match(&pattern[1..], tail),
+            };
+            let class = &rest[..end];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= *first && *first <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == *first;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_match(&rest[end + 1..], tail)
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => s.first() == Some(&rest[0]) && glob_match(&rest[1..], &s[1..]),
+        Some((byte, rest)) => s.first() == Some(byte) && glob_match(rest, &s[1..]),
+    }
+}
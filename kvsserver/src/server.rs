@@ -1,72 +1,359 @@
 use std::net::{TcpStream, TcpListener, ToSocketAddrs};
-use std::io::{Write, BufWriter};
+use std::io::{Write, BufReader, BufWriter};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
 
-use serde_json::{self, Deserializer, Serializer};
-use crate::errors::{Result, KvsError};
+use crate::errors::{ErrorCode, Result, KvsError};
 use crate::common::*;
-use crate::engine::{KvsEngine, KvStore};
+use crate::protocol::{self, Protocol, MAX_FRAME_SIZE};
+use crate::{http, resp};
+use crate::engine::{KvsEngine, KvStore, MergeOperand};
 
+/// workers running requests by default
+const WORKERS : usize = 4;
+/// requests of a connection running or waiting for a worker by default
+const MAX_IN_FLIGHT : usize = 128;
+
+type Job = Box<dyn FnOnce() + Send>;
 
 /// The server of the KvStroe
 pub struct KvsServer<E : KvsEngine> {
-    engine : E, 
+    engine : Arc<Mutex<E>>,
+    max_frame_size : usize,
+    workers : usize,
+    max_in_flight : usize,
+    // started by the first connection
+    jobs : Option<mpsc::Sender<Job>>,
 }
 
-impl<E : KvsEngine> KvsServer<E> {
+impl<E : KvsEngine + Send + 'static> KvsServer<E> {
     /// Create new KvsServer use specified engine
     pub fn new(engine : E) -> Self {
         KvsServer {
-            engine 
-        }  
+            engine : Arc::new(Mutex::new(engine)),
+            max_frame_size : MAX_FRAME_SIZE,
+            workers : WORKERS,
+            max_in_flight : MAX_IN_FLIGHT,
+            jobs : None,
+        }
     }
 
-    /// Start server and handle request, now we only support single request!
+    /// Start server and serve each connection on its own thread,
+    /// the requests of all connections share the workers
     pub fn run<A : ToSocketAddrs>(&mut self, addr : A) -> Result<()>{
         let mut listener = TcpListener::bind(addr)?;
 
         for stream in listener.incoming() {
-            if let Err(err) = self.handle_request(stream?) {
-                debug!("Error {:?} has occured in handling request", err); 
-            }
+            let stream = stream?;
+            let connection = self.connection();
+            thread::spawn(move || {
+                if let Err(err) = connection.serve(stream) {
+                    debug!("Error {:?} has occured in handling request", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Start a RESP listener for Redis clients instead, each connection on its
+    /// own thread, see the `resp` module for the supported commands
+    pub fn run_resp<A : ToSocketAddrs>(&mut self, addr : A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+
+        for (id, stream) in listener.incoming().enumerate() {
+            let stream = stream?;
+            let engine = self.engine.clone();
+            thread::spawn(move || {
+                if let Err(err) = resp::serve(&engine, stream, id as u64 + 1) {
+                    debug!("Error {:?} has occured in handling RESP connection", err);
+                }
+            });
         }
 
         Ok(())
     }
 
-    /// handler of kvserver
+    /// Start an HTTP/1.1 gateway instead, each connection on its own thread,
+    /// see the `http` module for the endpoints
+    pub fn run_http<A : ToSocketAddrs>(&mut self, addr : A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let engine = self.engine.clone();
+            thread::spawn(move || {
+                if let Err(err) = http::serve(&engine, stream) {
+                    debug!("Error {:?} has occured in handling HTTP connection", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// refuse binary frames and JSON messages larger than `max_frame_size` bytes
+    pub fn with_max_frame_size(mut self, max_frame_size : usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// run the requests on `workers` threads, at least one
+    pub fn with_workers(mut self, workers : usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// stop reading a connection while `max_in_flight` of its requests are
+    /// running or waiting for a worker, at least one
+    pub fn with_max_in_flight(mut self, max_in_flight : usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// handler of kvserver, serve the connection until it is closed
     pub fn handle_request(&mut self, streamer : TcpStream) -> Result<()> {
-        let client_addr = streamer.peer_addr()?; 
-        let mut writer = BufWriter::new(&streamer);
-        let req_reader = Deserializer::from_reader(&streamer).into_iter::<Request>();
-
-        macro_rules! send_response {
-            ($resp : expr)  => { {
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("streamer send to {}, {:?}", client_addr, resp);
-            };};
+        self.connection().serve(streamer)
+    }
+
+    /// a connection sharing the workers of the server, which are started once
+    fn connection(&mut self) -> Connection<E> {
+        let workers = self.workers;
+        let jobs = self.jobs.get_or_insert_with(|| spawn_workers(workers)).clone();
+        Connection {
+            engine : self.engine.clone(),
+            jobs,
+            max_frame_size : self.max_frame_size,
+            max_in_flight : self.max_in_flight,
         }
+    }
+}
 
-        for request in req_reader {
-            let request = request?;
-            match request {
-                Request::Get(key) => send_response!(match KvsEngine::get(&mut self.engine, key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(err)  => GetResponse::Err(format!("{}", err)) 
-                }),
-                Request::Set(key, value) => send_response!(match self.engine.set(key, value) {
-                    Ok(()) => SetResponse::Ok(()),
-                    Err(err) => SetResponse::Err(format!("{}", err))
-                }),
-                Request::Remove(key) => send_response!(match self.engine.remove(key) {
-                    Ok(()) => RemoveResponse::Ok(()) ,
-                    Err(err) => RemoveResponse::Err(format!("{}", err))
-                }),
+/// start the workers, they stop once every sender of jobs is dropped
+fn spawn_workers(workers : usize) -> mpsc::Sender<Job> {
+    let (jobs, received) = mpsc::channel::<Job>();
+    let received = Arc::new(Mutex::new(received));
+    for _ in 0..workers {
+        let received = received.clone();
+        thread::spawn(move || loop {
+            let job = match received.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+                Ok(job) => job,
+                Err(_) => break,
             };
+            // a panicking job must not take the worker with it
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                error!("a job of the workers panicked: {}", panic_message(&*panic));
+            }
+        });
+    }
+    jobs
+}
+
+/// A client connection, its requests run on the workers
+struct Connection<E> {
+    engine : Arc<Mutex<E>>,
+    jobs : mpsc::Sender<Job>,
+    max_frame_size : usize,
+    max_in_flight : usize,
+}
+
+/// The responses of a connection, a request takes a slot until its response is
+/// written so that a client can't queue more than the slots on the workers
+struct InFlight {
+    responses : mpsc::Sender<(u64, Response)>,
+    slots : mpsc::SyncSender<()>,
+}
+
+impl InFlight {
+    /// take a slot, waiting while the slots are taken, it fails once the responses can't be written
+    fn acquire(&self) -> Result<()> {
+        self.slots
+            .send(())
+            .map_err(|_| KvsError::StringError("the responses of the connection can't be written".to_owned()))
+    }
+
+    /// answer without running a request
+    fn respond(&self, id : u64, response : Response) -> Result<()> {
+        self.acquire()?;
+        // the responder is gone if it can't take it, which `acquire` reports next
+        let _ = self.responses.send((id, response));
+        Ok(())
+    }
+}
+
+impl<E : KvsEngine + Send + 'static> Connection<E> {
+    fn serve(self, streamer : TcpStream) -> Result<()> {
+        let client_addr = streamer.peer_addr()?;
+        let mut reader = BufReader::new(streamer.try_clone()?);
+        let mut writer = BufWriter::new(streamer);
+        let protocol = match protocol::accept(&mut reader, &mut writer)? {
+            Some(protocol) => protocol,
+            None => return Ok(()),
+        };
+        debug!("{} speaks {:?}", client_addr, protocol);
+
+        // legacy clients expect the responses in order
+        if protocol == Protocol::Legacy {
+            loop {
+                let request = match protocol::read_json::<_, Request>(&mut reader, self.max_frame_size) {
+                    Ok(Some(request)) => request,
+                    Ok(None) => return Ok(()),
+                    Err(KvsError::Io(err)) => return Err(KvsError::Io(err)),
+                    // the stream can't be resynchronized after a malformed request
+                    Err(err) => {
+                        send_response(Response::Err(ErrorCode::Protocol, err.to_string()), protocol, 0, &mut writer)?;
+                        writer.flush()?;
+                        return Err(err);
+                    },
+                };
+                let response = execute(&self.engine, request);
+                send_response(response, protocol, 0, &mut writer)?;
+                writer.flush()?;
+            }
+        }
+
+        // the responses are written as the workers finish them
+        let (responses, finished) = mpsc::channel::<(u64, Response)>();
+        let (slots, taken) = mpsc::sync_channel::<()>(self.max_in_flight);
+        let responder = thread::spawn(move || -> Result<()> {
+            for (id, response) in finished.iter() {
+                send_response(response, protocol, id, &mut writer)?;
+                let _ = taken.try_recv();
+                // flush once the finished responses are written
+                while let Ok((id, response)) = finished.try_recv() {
+                    send_response(response, protocol, id, &mut writer)?;
+                    let _ = taken.try_recv();
+                }
+                writer.flush()?;
+            }
+            Ok(())
+        });
+        let in_flight = InFlight { responses, slots };
+        let read = self.read_requests(protocol, reader, &in_flight);
+        drop(in_flight);
+        let written = responder.join().expect("the responder panicked");
+        read.and(written)
+    }
+
+    /// read the requests of the connection and hand them to the workers,
+    /// reading waits while `max_in_flight` of them aren't answered
+    fn read_requests(&self, protocol : Protocol, mut reader : BufReader<TcpStream>, in_flight : &InFlight) -> Result<()> {
+        match protocol {
+            Protocol::Binary => loop {
+                let body = match protocol::read_frame(&mut reader, self.max_frame_size) {
+                    Ok(Some(body)) => body,
+                    Ok(None) => return Ok(()),
+                    Err(KvsError::Protocol(err)) => {
+                        // the rest of the frame can't be skipped safely
+                        let _ = in_flight.respond(0, Response::Err(ErrorCode::Protocol, err.clone()));
+                        return Err(KvsError::Protocol(err));
+                    },
+                    Err(err) => return Err(err),
+                };
+                match protocol::decode_body(&body) {
+                    (id, Ok(request)) => self.dispatch(id, request, in_flight)?,
+                    (id, Err(err)) => {
+                        debug!("malformed request {}: {}", id, err);
+                        in_flight.respond(id, Response::Err(ErrorCode::Protocol, err.to_string()))?;
+                    },
+                }
+            },
+            _ => loop {
+                let (id, request) = match protocol::read_json::<_, (u64, Request)>(&mut reader, self.max_frame_size) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return Ok(()),
+                    Err(KvsError::Io(err)) => return Err(KvsError::Io(err)),
+                    // the stream can't be resynchronized after a malformed message
+                    Err(err) => {
+                        let _ = in_flight.respond(0, Response::Err(ErrorCode::Protocol, err.to_string()));
+                        return Err(err);
+                    },
+                };
+                self.dispatch(id, request, in_flight)?;
+            },
+        }
+    }
+
+    fn dispatch(&self, id : u64, request : Request, in_flight : &InFlight) -> Result<()> {
+        in_flight.acquire()?;
+        let engine = self.engine.clone();
+        let responses = in_flight.responses.clone();
+        let job = Box::new(move || {
+            // the connection may be gone already
+            let _ = responses.send((id, execute(&engine, request)));
+        });
+        if self.jobs.send(job).is_err() {
+            error!("the workers of the server are gone");
         }
-        
         Ok(())
     }
 }
 
+/// Run `f` on the locked engine, a panic comes back as an internal error.
+/// What a panic left of the engine is unknown, like `KvStore` after a failed write
+/// it refuses every later request once the panic has poisoned its lock
+pub(crate) fn with_engine<E, T, F : FnOnce(&mut E) -> Result<T>>(engine : &Mutex<E>, f : F) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut engine = engine.lock().map_err(|_| {
+            KvsError::StringError("a request panicked while holding the engine, the server must be restarted".to_owned())
+        })?;
+        f(&mut *engine)
+    }))
+    .unwrap_or_else(|panic| {
+        let message = panic_message(&*panic);
+        error!("a request panicked: {}", message);
+        Err(KvsError::StringError(format!("the request panicked: {}", message)))
+    })
+}
+
+fn panic_message(panic : &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => (*message).to_owned(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_owned(),
+    }
+}
+
+/// run the request on the engine
+fn execute<E : KvsEngine>(engine : &Mutex<E>, request : Request) -> Response {
+    with_engine(engine, |engine| run(engine, request)).unwrap_or_else(Response::from)
+}
+
+fn run<E : KvsEngine>(engine : &mut E, request : Request) -> Result<Response> {
+    match request {
+        Request::Get(key) => KvsEngine::get(engine, key).map(Response::Value),
+        Request::Set(key, value) => engine.set(key, value).map(|()| Response::Done),
+        Request::SetEx(key, value, ttl) => engine
+            .set_with_ttl(key, value, Duration::from_secs(ttl))
+            .map(|()| Response::Done),
+        Request::Remove(key) => engine.remove(key).map(|()| Response::Done),
+        Request::GetVersion(key, version) => engine.get_version(key, version).map(Response::Value),
+        Request::History(key) => engine.history(key).map(Response::History),
+        Request::CreateIndex(name, keyspace, pointer) => engine
+            .create_index(name, keyspace, pointer)
+            .map(|()| Response::Done),
+        Request::DropIndex(name) => engine.drop_index(name).map(|()| Response::Done),
+        Request::Query(index, query) => engine.query(index, query).map(Response::Keys),
+        Request::Incr(key, delta) => merge(engine, key, MergeOperand::add(delta)).map(Response::Merged),
+        Request::Append(key, suffix) => merge(engine, key, MergeOperand::append(suffix)).map(Response::Merged),
+    }
+}
+
+/// merge the operand and read back the merged value
+fn merge<E : KvsEngine>(engine : &mut E, key : String, operand : MergeOperand) -> Result<String> {
+    engine.merge(key.clone(), operand)?;
+    engine.get(key)?.ok_or(KvsError::KeyNotFound)
+}
+
+/// write the response in the protocol of the connection, the writer isn't flushed
+fn send_response<W : Write>(response : Response, protocol : Protocol, id : u64, writer : &mut W) -> Result<()> {
+    debug!("streamer send {} {:?}", id, response);
+    match protocol {
+        Protocol::Legacy => serde_json::to_writer(&mut *writer, &response.into_legacy()?)?,
+        _ => protocol.write(writer, id, &response)?,
+    }
+    Ok(())
+}
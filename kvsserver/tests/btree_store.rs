@@ -0,0 +1,113 @@
+use kvsserver::{BTreeConfig, BTreeKvStore, KvsEngine, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Bound::{Excluded, Included};
+use tempfile::TempDir;
+
+fn small_pages() -> BTreeConfig {
+    BTreeConfig {
+        page_size : 1024,
+        buffer_pool_pages : 16,
+        ..BTreeConfig::default()
+    }
+}
+
+// Should split and merge nodes as keys come and go, keeping every read in order
+#[test]
+fn splits_and_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = BTreeKvStore::with_config(temp_dir.path(), small_pages())?;
+    let mut expected = BTreeMap::new();
+    for i in 0..2000 {
+        // a scattered insertion order, some values go to overflow pages
+        let key = format!("key{:05}", i * 7919 % 2000);
+        let value = "v".repeat(i % 500);
+        store.set(key.clone(), value.clone())?;
+        expected.insert(key, value);
+    }
+    let stats = store.stats()?;
+    assert!(stats.get("depth") > Some(2));
+    assert_eq!(stats.get("keys"), Some(2000));
+
+    let scanned = store.scan(Included("key00100".to_owned()), Excluded("key00200".to_owned()))?;
+    let wanted : Vec<(String, String)> = expected
+        .range("key00100".to_owned().."key00200".to_owned())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    assert_eq!(scanned, wanted);
+
+    for i in 0..1950 {
+        let key = format!("key{:05}", i * 7919 % 2000);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+    assert_eq!(store.keys()?, expected.keys().cloned().collect::<Vec<_>>());
+    let depth = store.stats()?.get("depth");
+    assert!(depth < stats.get("depth"));
+
+    drop(store);
+    let mut store = BTreeKvStore::with_config(temp_dir.path(), small_pages())?;
+    for (key, value) in &expected {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+    assert_eq!(store.stats()?.get("depth"), depth);
+    Ok(())
+}
+
+// Should reuse the pages freed by earlier writes instead of growing the file
+#[test]
+fn free_pages_are_reused() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = BTreeKvStore::with_config(temp_dir.path(), small_pages())?;
+    let mut pages = Vec::new();
+    for round in 0..10 {
+        for i in 0..300 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        pages.push(store.stats()?.get("pages").unwrap());
+    }
+    assert!(pages[9] <= pages[0] + pages[0] / 4, "pages grew from {} to {}", pages[0], pages[9]);
+    assert!(store.stats()?.get("free_pages") > Some(0));
+
+    store.set("large".to_owned(), "x".repeat(100_000))?;
+    let with_large = store.stats()?.get("pages").unwrap();
+    store.remove("large".to_owned())?;
+    store.set("large".to_owned(), "y".repeat(100_000))?;
+    assert!(store.stats()?.get("pages").unwrap() <= with_large + 2);
+    Ok(())
+}
+
+// Should fall back to the previous commit when the newest meta slot is torn
+#[test]
+fn torn_meta_slot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = BTreeKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // the two meta slots are at offsets 0 and 512, their txid follows the magic and the page size
+    let path = temp_dir.path().join("btree.db");
+    let file = fs::read(&path)?;
+    let txid = |slot : usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&file[slot * 512 + 12..slot * 512 + 20]);
+        u64::from_be_bytes(bytes)
+    };
+    let newest = if txid(0) > txid(1) { 0 } else { 1 };
+    let mut file = OpenOptions::new().write(true).open(&path)?;
+    file.seek(SeekFrom::Start(newest * 512 + 20))?;
+    file.write_all(&[0xff; 8])?;
+    drop(file);
+
+    let mut store = BTreeKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = BTreeKvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
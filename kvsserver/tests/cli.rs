@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvsserver::{BTreeKvStore, EngineDescriptor, IndexQuery, KvStore, KvsEngine, SledKvStore};
+use serde_json::json;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -224,6 +226,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // sled keeps its file lock until the process is gone
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -307,6 +311,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // sled keeps its file lock until the process is gone
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -339,3 +345,343 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+
+    // nothing is left in the directory
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_incr_append() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "-3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["append", "counter", "x", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2x\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    // the rejected operand didn't break the key
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-2x\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let dump = temp_dir.path().join("pairs.jsonl");
+    fs::write(
+        &dump,
+        "{\"Header\":{\"version\":1}}\n\
+         {\"Pair\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"End\":{\"count\":1}}\n",
+    )
+    .unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["import", "--engine", "kvs"])
+        .arg(&data_dir)
+        .arg(&dump)
+        .assert()
+        .success();
+    fs::write(data_dir.join("engine"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(&data_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert_eq!(EngineDescriptor::read(&data_dir).unwrap(), Some(EngineDescriptor::new("sled")));
+    assert!(!data_dir.join("1.log").exists());
+
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// An interrupted migration should start over while the marker names the old engine,
+// and be finished once it names the new one. Secondary indexes should be copied.
+#[test]
+fn cli_admin_migrate_interrupted() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    {
+        let mut store = KvStore::open(&data_dir).unwrap();
+        store.set("user1".to_owned(), "{\"age\":30}".to_owned()).unwrap();
+        store.set("user2".to_owned(), "{\"age\":40}".to_owned()).unwrap();
+        store.create_index("age".to_owned(), "user".to_owned(), "/age".to_owned()).unwrap();
+    }
+    EngineDescriptor::new("kvs").write(&data_dir).unwrap();
+    // a crash before the marker was rewritten, with a truncated dump and a partial copy
+    File::create(data_dir.join("migrate.dump")).unwrap();
+    fs::create_dir(data_dir.join("migrate.tmp")).unwrap();
+    fs::write(data_dir.join("conf"), "partial").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert_eq!(EngineDescriptor::read(&data_dir).unwrap(), Some(EngineDescriptor::new("sled")));
+    assert!(!data_dir.join("migrate.dump").exists());
+    assert!(!data_dir.join("migrate.tmp").exists());
+    {
+        let mut store = SledKvStore::new(&data_dir).unwrap();
+        assert_eq!(store.get("user2".to_owned()).unwrap(), Some("{\"age\":40}".to_owned()));
+        assert_eq!(store.indexes().unwrap(), vec![("age".to_owned(), "user".to_owned(), "/age".to_owned())]);
+        assert_eq!(store.query("age".to_owned(), IndexQuery::Value(json!(40))).unwrap(), vec!["user2"]);
+    }
+
+    // a crash after the marker was rewritten, the old files are left over
+    KvStore::open(&data_dir).unwrap().set("stale".to_owned(), "value".to_owned()).unwrap();
+    File::create(data_dir.join("migrate.dump")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert!(!data_dir.join("1.log").exists());
+    assert!(!data_dir.join("migrate.dump").exists());
+    assert_eq!(SledKvStore::new(&data_dir).unwrap().keys().unwrap().len(), 2);
+}
+
+#[test]
+fn cli_admin_rekey() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("old.key");
+    let new_key_file = temp_dir.path().join("new.key");
+    fs::write(&key_file, "0f".repeat(32)).unwrap();
+    fs::write(&new_key_file, format!("{}\n", "a5".repeat(32))).unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["rekey", "--key-file"])
+        .arg(&key_file)
+        .arg(&data_dir)
+        .env_remove("KVS_NEW_ENCRYPTION_KEY")
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["rekey", "--key-file"])
+        .arg(&key_file)
+        .arg("--new-key-file")
+        .arg(&new_key_file)
+        .arg(&data_dir)
+        .assert()
+        .success();
+
+    let dump = temp_dir.path().join("pairs.jsonl");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--engine", "kvs"])
+        .arg(&data_dir)
+        .arg(&dump)
+        .env("KVS_ENCRYPTION_KEY", "0f".repeat(32))
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--engine", "kvs"])
+        .arg(&data_dir)
+        .arg(&dump)
+        .env("KVS_ENCRYPTION_KEY", "a5".repeat(32))
+        .assert()
+        .success();
+    assert!(fs::read_to_string(&dump).unwrap().contains("value1"));
+}
+
+#[test]
+fn cli_secondary_index() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args : &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(&["--addr", addr]);
+        command
+    };
+    client(&["set", "user:1", r#"{"city":"paris","age":30}"#]).assert().success();
+    client(&["set", "user:2", r#"{"city":"rome","age":25}"#]).assert().success();
+    client(&["create-index", "by_city", "user:", "/city"]).assert().success();
+    client(&["create-index", "by_age", "user:", "/age"]).assert().success();
+    client(&["create-index", "bad", "user:", "city"]).assert().failure();
+
+    client(&["query", "by_city", "paris"]).assert().success().stdout("user:1\n");
+    client(&["query", "by_city", r#""rome""#]).assert().success().stdout("user:2\n");
+    client(&["query", "by_age", "--from", "20", "--to", "30"]).assert().success().stdout("user:2\nuser:1\n");
+    client(&["query", "by_age", "--from", "26"]).assert().success().stdout("user:1\n");
+
+    client(&["drop-index", "by_city"]).assert().success();
+    client(&["query", "by_city", "paris"]).assert().failure();
+    client(&["drop-index", "by_city"]).assert().failure();
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server` should pass engine options through and upgrade a legacy engine marker
+#[test]
+fn cli_engine_options() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "btree").unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "btree", "--option", "page-size=8192", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(EngineDescriptor::read(temp_dir.path()).unwrap(), Some(EngineDescriptor::new("btree")));
+    // an existing btree file keeps the page size it was created with
+    let mut store = BTreeKvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.stats().unwrap().get("page_size"), Some(8192));
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "btree", "--option", "page-size", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "unknown", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,104 @@
+use kvsserver::{conformance, BTreeConfig, BTreeKvStore, KvStore, KvStoreConfig, LsmConfig, LsmKvStore, MemKvStore, MemVfs, Result, SledKvStore};
+use std::sync::Arc;
+
+#[test]
+fn kv_store() -> Result<()> {
+    conformance::run(|path| KvStore::open(path))
+}
+
+#[test]
+fn kv_store_small_generations() -> Result<()> {
+    conformance::run(|path| {
+        let config = KvStoreConfig {
+            max_file_size : 4096,
+            max_open_readers : 4,
+            use_mmap : true,
+            ..KvStoreConfig::default()
+        };
+        KvStore::with_config(path, config)
+    })
+}
+
+#[test]
+fn kv_store_mem_vfs() -> Result<()> {
+    let vfs = Arc::new(MemVfs::new());
+    conformance::run(|path| {
+        let config = KvStoreConfig {
+            max_file_size : 4096,
+            use_mmap : true,
+            blob_threshold : 1024,
+            vfs : vfs.clone(),
+            ..KvStoreConfig::default()
+        };
+        KvStore::with_config(path, config)
+    })
+}
+
+#[test]
+fn sled_kv_store() -> Result<()> {
+    conformance::run(|path| SledKvStore::new(path))
+}
+
+#[test]
+fn lsm_kv_store() -> Result<()> {
+    conformance::run(|path| LsmKvStore::open(path))
+}
+
+#[test]
+fn lsm_kv_store_small_tables() -> Result<()> {
+    conformance::run(|path| {
+        let config = LsmConfig {
+            memtable_size : 1024,
+            block_size : 256,
+            table_size : 2048,
+            level0_tables : 2,
+            level_base_bytes : 8192,
+            level_multiplier : 2,
+            block_cache_blocks : 0,
+            ..LsmConfig::default()
+        };
+        LsmKvStore::with_config(path, config)
+    })
+}
+
+#[test]
+fn btree_kv_store() -> Result<()> {
+    conformance::run(|path| BTreeKvStore::open(path))
+}
+
+#[test]
+fn btree_kv_store_small_pages() -> Result<()> {
+    conformance::run(|path| {
+        let config = BTreeConfig {
+            page_size : 1024,
+            buffer_pool_pages : 4,
+            ..BTreeConfig::default()
+        };
+        BTreeKvStore::with_config(path, config)
+    })
+}
+
+#[test]
+fn secondary_indexes() -> Result<()> {
+    conformance::secondary_indexes(|path| KvStore::open(path))?;
+    conformance::secondary_indexes(|path| SledKvStore::new(path))?;
+    conformance::secondary_indexes(|path| MemKvStore::open(path))
+}
+
+#[test]
+fn versions() -> Result<()> {
+    conformance::versions(|path| KvStore::with_config(path, KvStoreConfig { max_versions : 2, ..KvStoreConfig::default() }))?;
+    conformance::versions(|path| MemKvStore::open(path).map(|store| store.with_max_versions(2)))
+}
+
+#[test]
+fn expiry() -> Result<()> {
+    // the kvs engine expires keys in cache mode only
+    conformance::expiry(|path| KvStore::with_config(path, KvStoreConfig { max_keys : 10000, ..KvStoreConfig::default() }))?;
+    conformance::expiry(|path| MemKvStore::open(path))
+}
+
+#[test]
+fn mem_kv_store() -> Result<()> {
+    conformance::run(|path| MemKvStore::open(path))
+}
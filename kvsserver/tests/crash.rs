@@ -0,0 +1,339 @@
+use kvsserver::{EncryptionKey, FaultyVfs, KvStore, KvStoreConfig, KvsEngine, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(&'static str, String),
+    Remove(&'static str),
+    Compact,
+}
+
+/// the fault armed before the workload, at each of its writes or syncs in turn
+#[derive(Debug, Clone, Copy)]
+enum Fault {
+    // the number of bytes of the write which reach the file
+    TearWrite(usize),
+    FailWrite,
+    FailSync,
+}
+
+fn open(vfs : &FaultyVfs) -> Result<KvStore> {
+    let config = KvStoreConfig {
+        sync : true,
+        max_file_size : 512,
+        blob_threshold : 64,
+        vfs : Arc::new(vfs.clone()),
+        ..KvStoreConfig::default()
+    };
+    KvStore::with_config("/kvs", config)
+}
+
+fn apply(store : &mut KvStore, op : &Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => store.set(key.to_string(), value.clone()),
+        Op::Remove(key) => store.remove(key.to_string()),
+        Op::Compact => store.compact(),
+    }
+}
+
+fn apply_expected(expected : &mut BTreeMap<String, String>, op : &Op) {
+    match op {
+        Op::Set(key, value) => {
+            expected.insert(key.to_string(), value.clone());
+        },
+        Op::Remove(key) => {
+            expected.remove(*key);
+        },
+        Op::Compact => {},
+    }
+}
+
+fn contents(store : &mut KvStore) -> Result<BTreeMap<String, String>> {
+    let mut pairs = BTreeMap::new();
+    for key in store.keys()? {
+        let value = store.get(key.clone())?.expect("listed key has a value");
+        pairs.insert(key, value);
+    }
+    Ok(pairs)
+}
+
+/// Run `prepare`, then `ops` with the fault armed at each of their writes or syncs.
+/// Once reopened after the crash and after the power loss, every acknowledged
+/// operation must be kept and the failed one either applied or lost as a whole.
+fn check_faults(prepare : &[Op], ops : &[Op], fault : Fault) -> Result<()> {
+    let events = {
+        let vfs = FaultyVfs::new();
+        let mut store = open(&vfs)?;
+        for op in prepare {
+            apply(&mut store, op)?;
+        }
+        let (writes, syncs) = (vfs.writes(), vfs.syncs());
+        for op in ops {
+            apply(&mut store, op)?;
+        }
+        match fault {
+            Fault::FailSync => vfs.syncs() - syncs,
+            _ => vfs.writes() - writes,
+        }
+    };
+    assert!(events > 0);
+
+    for nth in 1..=events {
+        let vfs = FaultyVfs::new();
+        let mut store = open(&vfs)?;
+        let mut acked = BTreeMap::new();
+        for op in prepare {
+            apply(&mut store, op)?;
+            apply_expected(&mut acked, op);
+        }
+        match fault {
+            Fault::TearWrite(offset) => vfs.tear_write(nth, offset),
+            Fault::FailWrite => vfs.fail_write(nth),
+            Fault::FailSync => vfs.fail_sync(nth),
+        }
+
+        let mut failed = None;
+        for op in ops {
+            match apply(&mut store, op) {
+                Ok(()) => apply_expected(&mut acked, op),
+                Err(_) => {
+                    failed = Some(op);
+                    break;
+                },
+            }
+        }
+        let failed = failed.unwrap_or_else(|| panic!("{:?} at event {} wasn't hit", fault, nth));
+        let mut applied = acked.clone();
+        apply_expected(&mut applied, failed);
+        let check = |store : &mut KvStore| -> Result<()> {
+            let found = contents(store)?;
+            assert!(
+                found == acked || found == applied,
+                "{:?} at event {} of {:?}: found {:?}, expected {:?} or {:?}",
+                fault, nth, failed, found, acked, applied
+            );
+            Ok(())
+        };
+        drop(store);
+
+        // the torn write is still in the files when only the process crashed
+        if let Fault::TearWrite(_) = fault {
+            assert!(vfs.crashed());
+            vfs.restart();
+            check(&mut open(&vfs)?)?;
+        }
+        vfs.power_loss();
+        let mut store = open(&vfs)?;
+        check(&mut store)?;
+        store.set("after".to_owned(), "crash".to_owned())?;
+        assert_eq!(store.get("after".to_owned())?, Some("crash".to_owned()));
+    }
+    Ok(())
+}
+
+const TEARS : &[Fault] = &[Fault::TearWrite(0), Fault::TearWrite(1), Fault::TearWrite(9), Fault::TearWrite(1000)];
+
+/// sets over a few keys, every third value is large enough for a blob
+fn sets(keys : &[&'static str], rounds : usize) -> Vec<Op> {
+    let mut ops = Vec::new();
+    for round in 0..rounds {
+        for (i, &key) in keys.iter().enumerate() {
+            let value = if (round + i) % 3 == 0 {
+                format!("{}-{}", key, "x".repeat(100))
+            } else {
+                format!("{}-{}", key, round)
+            };
+            ops.push(Op::Set(key, value));
+        }
+    }
+    ops
+}
+
+// Should keep every acknowledged set when a crash tears a later write
+#[test]
+fn set_crash_consistency() -> Result<()> {
+    let ops = sets(&["key1", "key2", "key3", "key4"], 3);
+    for &fault in TEARS {
+        check_faults(&[], &ops, fault)?;
+    }
+    Ok(())
+}
+
+// Should never bring back a removed key, nor lose a kept one, when a crash tears a removal
+#[test]
+fn remove_crash_consistency() -> Result<()> {
+    let prepare = sets(&["key1", "key2", "key3", "key4", "key5", "key6"], 2);
+    let ops = vec![
+        Op::Remove("key1"),
+        Op::Remove("key3"),
+        Op::Set("key1", "again".to_owned()),
+        Op::Remove("key5"),
+        Op::Remove("key6"),
+        Op::Remove("key1"),
+    ];
+    for &fault in TEARS {
+        check_faults(&prepare, &ops, fault)?;
+    }
+    Ok(())
+}
+
+// Should keep every pair when a crash tears a write of the compaction
+#[test]
+fn compact_crash_consistency() -> Result<()> {
+    let mut prepare = sets(&["key1", "key2", "key3", "key4", "key5"], 4);
+    prepare.push(Op::Remove("key2"));
+    prepare.push(Op::Remove("key4"));
+    prepare.push(Op::Set("key4", "back".to_owned()));
+    let ops = vec![Op::Compact, Op::Set("key6", "value6".to_owned()), Op::Compact];
+    for &fault in TEARS {
+        check_faults(&prepare, &ops, fault)?;
+    }
+    Ok(())
+}
+
+// Should reopen to a consistent state after a write or sync failed
+#[test]
+fn failed_writes_and_syncs() -> Result<()> {
+    let prepare = sets(&["key1", "key2", "key3"], 3);
+    let mut ops = sets(&["key2", "key4"], 2);
+    ops.push(Op::Remove("key1"));
+    ops.push(Op::Compact);
+    ops.push(Op::Remove("key4"));
+    for &fault in &[Fault::FailWrite, Fault::FailSync] {
+        check_faults(&prepare, &ops, fault)?;
+    }
+    Ok(())
+}
+
+// Should lose the writes which were never synced, compaction syncs what it writes
+#[test]
+fn power_loss_drops_unsynced_writes() -> Result<()> {
+    let vfs = FaultyVfs::new();
+    let config = || KvStoreConfig { vfs : Arc::new(vfs.clone()), ..KvStoreConfig::default() };
+    let mut store = KvStore::with_config("/kvs", config())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    vfs.power_loss();
+
+    let mut store = KvStore::with_config("/kvs", config())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    vfs.power_loss();
+
+    let mut store = KvStore::with_config("/kvs", config())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should never lose a durable blob to an unsynced value replacing it
+#[test]
+fn power_loss_keeps_replaced_blobs() -> Result<()> {
+    let big = |name : &str| format!("{}-{}", name, "x".repeat(100));
+    for &max_versions in &[0, 2] {
+        let vfs = FaultyVfs::new();
+        let config = || KvStoreConfig {
+            blob_threshold : 64,
+            max_versions,
+            vfs : Arc::new(vfs.clone()),
+            ..KvStoreConfig::default()
+        };
+        let mut store = KvStore::with_config("/kvs", config())?;
+        store.set("key1".to_owned(), big("first"))?;
+        store.set("key2".to_owned(), big("kept"))?;
+        store.compact()?;
+        store.set("key1".to_owned(), big("second"))?;
+        store.set("key1".to_owned(), big("third"))?;
+        store.remove("key2".to_owned())?;
+        drop(store);
+        vfs.power_loss();
+
+        // the unsynced writes may be lost, but every value left must be readable
+        let mut store = KvStore::with_config("/kvs", config())?;
+        let key1 = store.get("key1".to_owned())?;
+        assert!(["first", "second", "third"].iter().any(|name| key1 == Some(big(name))), "{:?}", key1);
+        let key2 = store.get("key2".to_owned())?;
+        assert!(key2 == None || key2 == Some(big("kept")), "{:?}", key2);
+        store.set("key1".to_owned(), big("fourth"))?;
+        store.compact()?;
+        store.set("key1".to_owned(), "small".to_owned())?;
+        drop(store);
+        vfs.power_loss();
+
+        let mut store = KvStore::with_config("/kvs", config())?;
+        let key1 = store.get("key1".to_owned())?;
+        assert!(key1 == Some(big("fourth")) || key1 == Some("small".to_owned()), "{:?}", key1);
+        assert_eq!(store.get("key2".to_owned())?, key2);
+    }
+    Ok(())
+}
+
+// Should open an interrupted rekey with the old key until its log is durable and with
+// the new key after, whichever write, rename or removal it crashed at, also after a power loss
+#[test]
+fn rekey_crash_consistency() -> Result<()> {
+    let old_key = EncryptionKey::from_hex(&"0f".repeat(32))?;
+    let new_key = EncryptionKey::from_hex(&"a5".repeat(32))?;
+    let open_with = |vfs : &FaultyVfs, key : &EncryptionKey| {
+        let config = KvStoreConfig {
+            sync : true,
+            max_file_size : 512,
+            blob_threshold : 64,
+            encryption_key : Some(key.clone()),
+            vfs : Arc::new(vfs.clone()),
+            ..KvStoreConfig::default()
+        };
+        KvStore::with_config("/kvs", config)
+    };
+    let prepare = sets(&["key1", "key2", "key3"], 4);
+    let mut expected = BTreeMap::new();
+    for op in &prepare {
+        apply_expected(&mut expected, op);
+    }
+    let prepared = || -> Result<(FaultyVfs, KvStore)> {
+        let vfs = FaultyVfs::new();
+        let mut store = open_with(&vfs, &old_key)?;
+        for op in &prepare {
+            apply(&mut store, op)?;
+        }
+        Ok((vfs, store))
+    };
+
+    let (writes, updates) = {
+        let (vfs, mut store) = prepared()?;
+        let (writes, updates) = (vfs.writes(), vfs.updates());
+        store.rekey(Some(new_key.clone()))?;
+        (vfs.writes() - writes, vfs.updates() - updates)
+    };
+    let faults = (1..=writes).map(|nth| (true, nth)).chain((1..=updates).map(|nth| (false, nth)));
+    for (at_write, nth) in faults {
+        for &power_loss in &[false, true] {
+            let (vfs, mut store) = prepared()?;
+            if at_write {
+                vfs.tear_write(nth, 0);
+            } else {
+                vfs.crash_update(nth);
+            }
+            assert!(store.rekey(Some(new_key.clone())).is_err());
+            drop(store);
+            if power_loss {
+                vfs.power_loss();
+            } else {
+                vfs.restart();
+            }
+
+            // the recovery on open leaves the store under exactly one of the keys
+            let mut store = match open_with(&vfs, &old_key) {
+                Ok(store) => store,
+                Err(_) => open_with(&vfs, &new_key)?,
+            };
+            assert_eq!(contents(&mut store)?, expected, "crash at {} {}", if at_write { "write" } else { "update" }, nth);
+            store.set("after".to_owned(), "crash".to_owned())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use kvsserver::{DumpFormat, DumpReader, DumpWriter, KvStore, KvsEngine, KvsError, MemKvStore, Result, SledKvStore};
+use std::io::Cursor;
+use tempfile::TempDir;
+
+fn round_trip(format : DumpFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "多字节\n\"quoted\"".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump, format)?, 2);
+    assert_eq!(DumpReader::new(Cursor::new(&dump))?.format(), format);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sled = SledKvStore::new(sled_dir.path())?;
+    assert_eq!(sled.import(&mut Cursor::new(&dump))?, 2);
+    assert_eq!(sled.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(sled.get("key2".to_owned())?, Some("多字节\n\"quoted\"".to_owned()));
+    assert_eq!(sled.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn json_lines_round_trip() -> Result<()> {
+    round_trip(DumpFormat::JsonLines)
+}
+
+#[test]
+fn binary_round_trip() -> Result<()> {
+    round_trip(DumpFormat::Binary)
+}
+
+// Should refuse a dump without its trailer
+#[test]
+fn truncated_dump() -> Result<()> {
+    for &format in &[DumpFormat::JsonLines, DumpFormat::Binary] {
+        let mut dump = Vec::new();
+        let mut writer = DumpWriter::new(&mut dump, format)?;
+        writer.write("key1", "value1")?;
+        writer.write("key2", "value2")?;
+        writer.finish()?;
+        dump.truncate(dump.len() - 3);
+
+        let mut store = MemKvStore::new();
+        match store.import(&mut Cursor::new(&dump)) {
+            Err(KvsError::Dump(_)) | Err(KvsError::Io(_)) | Err(KvsError::Serde(_)) => {},
+            other => panic!("import of a truncated {:?} dump returned {:?}", format, other),
+        }
+    }
+    Ok(())
+}
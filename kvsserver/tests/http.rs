@@ -0,0 +1,324 @@
+use assert_cmd::prelude::*;
+use kvsserver::{KvStore, KvsEngine, KvsServer, MemKvStore, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A response of the server
+#[derive(Debug)]
+struct Reply {
+    status : u16,
+    headers : Vec<(String, String)>,
+    body : String,
+}
+
+impl Reply {
+    fn header(&self, name : &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn etag(&self) -> String {
+        self.header("ETag").expect("no ETag").to_owned()
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// A tiny HTTP/1.1 client keeping its connection alive
+struct Client {
+    reader : BufReader<TcpStream>,
+    writer : TcpStream,
+}
+
+impl Client {
+    fn connect(addr : &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        Client { reader : BufReader::new(stream.try_clone().unwrap()), writer : stream }
+    }
+
+    fn send(&mut self, method : &str, path : &str, headers : &[(&str, &str)], body : Option<&str>) {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(body) = body {
+            request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        } else {
+            request.push_str("\r\n");
+        }
+        self.writer.write_all(request.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, method : &str, path : &str, headers : &[(&str, &str)], body : Option<&str>) -> Reply {
+        self.send(method, path, headers, body);
+        self.read(method == "HEAD")
+    }
+
+    fn get(&mut self, path : &str) -> Reply {
+        self.call("GET", path, &[], None)
+    }
+
+    fn put(&mut self, path : &str, body : &str) -> Reply {
+        self.call("PUT", path, &[], Some(body))
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "{:?} doesn't end with CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read(&mut self, head : bool) -> Reply {
+        let status_line = self.read_line();
+        let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                break;
+            }
+            let colon = line.find(':').unwrap();
+            headers.push((line[..colon].to_owned(), line[colon + 1..].trim().to_owned()));
+        }
+        let mut reply = Reply { status, headers, body : String::new() };
+        if !head {
+            if let Some(length) = reply.header("Content-Length") {
+                let mut body = vec![0; length.parse().unwrap()];
+                self.reader.read_exact(&mut body).unwrap();
+                reply.body = String::from_utf8(body).unwrap();
+            }
+        }
+        reply
+    }
+
+    /// whether the server closed the connection
+    fn closed(&mut self) -> bool {
+        self.reader.read(&mut [0; 1]).unwrap() == 0
+    }
+}
+
+fn spawn_server<E : KvsEngine + Send + 'static, F : FnOnce(&TempDir) -> E + Send + 'static>(addr : &'static str, engine : F) {
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        KvsServer::new(engine(&temp_dir)).run_http(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+}
+
+fn spawn_kv_server(addr : &'static str) {
+    spawn_server(addr, |temp_dir| KvStore::open(temp_dir.path()).unwrap());
+}
+
+/// An engine which doesn't keep versions
+struct Unversioned(MemKvStore);
+
+impl KvsEngine for Unversioned {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+// Should read, write and remove keys with their status codes
+#[test]
+fn http_keys() {
+    let addr = "127.0.0.1:4026";
+    spawn_kv_server(addr);
+    let mut client = Client::connect(addr);
+
+    assert_eq!(client.get("/keys/key1").status, 404);
+    let created = client.put("/keys/key1", "value1");
+    assert_eq!(created.status, 201);
+    assert_eq!(created.header("Location"), Some("/keys/key1"));
+    let reply = client.get("/keys/key1");
+    assert_eq!((reply.status, reply.body.as_str()), (200, "value1"));
+    assert_eq!(reply.header("Content-Type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(reply.etag(), created.etag());
+
+    let updated = client.put("/keys/key1", "value2");
+    assert_eq!(updated.status, 204);
+    assert_ne!(updated.etag(), created.etag());
+    let reply = client.call("HEAD", "/keys/key1", &[], None);
+    assert_eq!((reply.status, reply.header("Content-Length")), (200, Some("6")));
+    assert_eq!(reply.etag(), updated.etag());
+    assert_eq!(client.get("/keys/key1").body, "value2");
+
+    assert_eq!(client.call("DELETE", "/keys/key1", &[], None).status, 204);
+    assert_eq!(client.get("/keys/key1").status, 404);
+    let reply = client.call("DELETE", "/keys/key1", &[], None);
+    assert_eq!(reply.status, 404);
+    assert!(reply.json()["error"].is_string());
+
+    // keys are percent-encoded, slashes may be left as is
+    assert_eq!(client.put("/keys/app/name%20%E2%9C%93", "caf\u{e9}").status, 201);
+    assert_eq!(client.get("/keys/app%2Fname%20%E2%9C%93").body, "caf\u{e9}");
+    assert_eq!(client.get("/keys/bad%zz").status, 400);
+    assert_eq!(client.get("/keys/").status, 400);
+}
+
+// Should honor If-Match and If-None-Match against the versions of the values
+#[test]
+fn http_conditional_requests() {
+    let addr = "127.0.0.1:4027";
+    spawn_kv_server(addr);
+    let mut client = Client::connect(addr);
+
+    let if_none_match = [("If-None-Match", "*")];
+    let first = client.call("PUT", "/keys/config", &if_none_match, Some("a"));
+    assert_eq!(first.status, 201);
+    let conflict = client.call("PUT", "/keys/config", &if_none_match, Some("b"));
+    assert_eq!(conflict.status, 412);
+    assert_eq!(conflict.etag(), first.etag());
+
+    let second = client.call("PUT", "/keys/config", &[("If-Match", &first.etag())], Some("b"));
+    assert_eq!(second.status, 204);
+    // a stale version is refused and leaves the value alone
+    let stale = client.call("PUT", "/keys/config", &[("If-Match", &first.etag())], Some("c"));
+    assert_eq!(stale.status, 412);
+    assert_eq!(stale.etag(), second.etag());
+    assert_eq!(client.get("/keys/config").body, "b");
+    let either = format!("{}, {}", first.etag(), second.etag());
+    assert_eq!(client.call("PUT", "/keys/config", &[("If-Match", &either)], Some("c")).status, 204);
+    let third = client.get("/keys/config");
+
+    assert_eq!(client.call("GET", "/keys/config", &[("If-None-Match", &third.etag())], None).status, 304);
+    assert_eq!(client.call("GET", "/keys/config", &[("If-None-Match", &second.etag())], None).status, 200);
+    assert_eq!(client.call("PUT", "/keys/missing", &[("If-Match", "*")], Some("x")).status, 412);
+    assert_eq!(client.get("/keys/missing").status, 404);
+
+    assert_eq!(client.call("DELETE", "/keys/config", &[("If-Match", &second.etag())], None).status, 412);
+    assert_eq!(client.call("DELETE", "/keys/config", &[("If-Match", &third.etag())], None).status, 204);
+    // a new value of the key gets a new version
+    assert_ne!(client.put("/keys/config", "c").etag(), third.etag());
+}
+
+// Should fall back to ETags hashing the values with engines without versions
+#[test]
+fn http_hashed_etags() {
+    let addr = "127.0.0.1:4028";
+    spawn_server(addr, |_| Unversioned(MemKvStore::new()));
+    let mut client = Client::connect(addr);
+
+    let first = client.put("/keys/key1", "value1");
+    let second = client.put("/keys/key1", "value2");
+    assert_ne!(first.etag(), second.etag());
+    assert_eq!(client.put("/keys/key1", "value1").etag(), first.etag());
+    assert_eq!(client.call("PUT", "/keys/key1", &[("If-Match", &second.etag())], Some("x")).status, 412);
+    assert_eq!(client.call("PUT", "/keys/key1", &[("If-Match", &first.etag())], Some("x")).status, 204);
+}
+
+// Should list the keys by prefix and report the stats and health
+#[test]
+fn http_list_and_stats() {
+    let addr = "127.0.0.1:4029";
+    spawn_kv_server(addr);
+    let mut client = Client::connect(addr);
+    for key in &["app/b", "app/a", "app/c", "other", "app%20b", "%C3%A9t%C3%A9", "%C3%A9tu"] {
+        client.put(&format!("/keys/{}", key), "x");
+    }
+
+    assert_eq!(client.get("/keys").json(), json!(["app b", "app/a", "app/b", "app/c", "other", "étu", "été"]));
+    assert_eq!(client.get("/keys?prefix=%C3%A9t%C3%A9").json(), json!(["été"]));
+    assert_eq!(client.get("/keys?prefix=app%2F").json(), json!(["app/a", "app/b", "app/c"]));
+    assert_eq!(client.get("/keys?limit=2&prefix=app/").json(), json!(["app/a", "app/b"]));
+    assert_eq!(client.get("/keys?prefix=app+").json(), json!(["app b"]));
+    assert_eq!(client.get("/keys?prefix=none").json(), json!([]));
+    assert_eq!(client.get("/keys?limit=many").status, 400);
+
+    let stats = client.get("/stats");
+    assert_eq!(stats.header("Content-Type"), Some("application/json"));
+    assert!(stats.json().is_object());
+    assert_eq!(client.get("/health").json(), json!({ "status" : "ok" }));
+
+    assert_eq!(client.get("/nowhere").status, 404);
+    let reply = client.call("POST", "/keys/key1", &[], Some("x"));
+    assert_eq!(reply.status, 405);
+    assert_eq!(reply.header("Allow"), Some("GET, HEAD, PUT, DELETE"));
+    assert_eq!(client.call("DELETE", "/keys", &[], None).status, 405);
+}
+
+// Should frame the requests as HTTP/1.1 clients send them
+#[test]
+fn http_framing() {
+    let addr = "127.0.0.1:4030";
+    spawn_kv_server(addr);
+    let mut client = Client::connect(addr);
+
+    // pipelined requests are answered in order
+    client.put("/keys/a", "1");
+    client.send("GET", "/keys/a", &[], None);
+    client.send("GET", "/keys/missing", &[], None);
+    assert_eq!(client.read(false).body, "1");
+    assert_eq!(client.read(false).status, 404);
+
+    // chunked bodies, and the interim response curl waits for
+    client.writer.write_all(b"PUT /keys/chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).status, 100);
+    client.writer.write_all(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).status, 201);
+    assert_eq!(client.get("/keys/chunked").body, "hello world");
+
+    assert_eq!(client.call("PUT", "/keys/a", &[], None).status, 411);
+    assert!(client.closed());
+
+    let mut client = Client::connect(addr);
+    let reply = client.call("GET", "/keys/a", &[("Connection", "close")], None);
+    assert_eq!((reply.status, reply.header("Connection")), (200, Some("close")));
+    assert!(client.closed());
+
+    let mut client = Client::connect(addr);
+    client.writer.write_all(b"GET /keys/a HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).body, "1");
+    assert!(client.closed());
+
+    let mut client = Client::connect(addr);
+    client.writer.write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 100000000000\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).status, 413);
+    assert!(client.closed());
+
+    let mut client = Client::connect(addr);
+    client.writer.write_all(b"GARBAGE\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).status, 400);
+    assert!(client.closed());
+
+    let mut client = Client::connect(addr);
+    client.writer.write_all(b"GET /keys/a HTTP/2.0\r\n\r\n").unwrap();
+    assert_eq!(client.read(false).status, 505);
+}
+
+// `kvs-server --protocol http` should serve curl and browsers
+#[test]
+fn cli_http_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4031";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--protocol", "http", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.put("/keys/key1", "value1").status, 201);
+    assert_eq!(client.get("/keys/key1").body, "value1");
+    child.kill().expect("server exited before killed");
+}
@@ -1,4 +1,10 @@
-use kvsserver::{KvStore, KvsEngine, Result};
+use kvsserver::{CacheAdmission, Codec, EncryptionKey, EvictionPolicy, IndexMode, IndexQuery, KvStore, KvStoreConfig, KvsEngine, MergeOperand, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +130,812 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should roll the active generation once it exceeds `max_file_size`
+#[test]
+fn rotate_active_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size : 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let log_files = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "log"))
+            .count()
+    };
+    assert!(log_files() > 1, "active generation was never rolled");
+
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Should only merge the selected generations
+#[test]
+fn compact_selected_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    // generation 1 is the active one, roll it and merge it alone
+    store.compact_gens(&[1])?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.compact_gens(&[])?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// Should reopen evicted generation readers on demand
+#[test]
+fn bounded_reader_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size : 256,
+        max_open_readers : 2,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.get("generations").unwrap() > 2);
+    assert!(stats.get("open_readers").unwrap() <= 2);
+    assert!(stats.get("reader_cache_misses").unwrap() > 0);
+    assert_eq!(stats.get("reader_cache_hits").unwrap() + stats.get("reader_cache_misses").unwrap(), 100);
+
+    Ok(())
+}
+
+// Should read the immutable generations through memory maps
+#[test]
+fn mmap_read_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size : 512,
+        max_open_readers : 4,
+        use_mmap : true,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    // the maps are bounded like the open readers
+    let mapped = store.stats()?.get("mapped_generations").unwrap();
+    assert!(mapped > 0 && mapped <= 4);
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Should never map the generation a compaction is still writing,
+// which a hashed index reads the keys back from
+#[test]
+fn mmap_hashed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size : 256,
+        use_mmap : true,
+        index_mode : IndexMode::Hashed,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    let generations = |dir : &Path| -> Vec<u64> {
+        let mut gens : Vec<u64> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.ends_with(".log"))
+            .filter_map(|name| name.trim_end_matches(".log").parse().ok())
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+
+    let mut expected = BTreeMap::new();
+    for round in 0..6 {
+        for key_id in 0..40 {
+            let value = format!("value{}-{}", key_id, round);
+            store.set(format!("key{}", key_id), value.clone())?;
+            expected.insert(format!("key{}", key_id), value);
+        }
+        for key_id in (round..40).step_by(7) {
+            store.remove(format!("key{}", key_id))?;
+            expected.remove(&format!("key{}", key_id));
+        }
+        store.merge("key1".to_owned(), MergeOperand::append("!".to_owned()))?;
+        if let Some(value) = expected.get_mut("key1") {
+            value.push('!');
+        }
+        // the tombstones of the newer generations are kept for the older ones
+        let gens = generations(temp_dir.path());
+        store.compact_gens(&gens[gens.len() / 2..])?;
+        for (key, value) in &expected {
+            assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+        }
+    }
+    drop(store);
+
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    for (key, value) in &expected {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+    assert_eq!(store.keys()?.len(), expected.len());
+    Ok(())
+}
+
+// Removed keys should leave the index, and tombstones should only be kept
+// while an older generation may still hold the key
+#[test]
+fn tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // move both keys into generation 2, generation 3 is the active one
+    store.compact()?;
+    store.remove("key1".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.get("keys"), Some(1));
+    assert_eq!(stats.get("tombstones"), Some(1));
+
+    // compacting only the generation of the tombstone must keep it
+    store.compact_gens(&[3])?;
+    assert_eq!(store.stats()?.get("tombstones"), Some(1));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // a full compaction reclaims it
+    store.compact()?;
+    assert_eq!(store.stats()?.get("tombstones"), Some(0));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.get("tombstones"), Some(0));
+
+    Ok(())
+}
+
+// Should keep the configured number of previous versions, also across compaction
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_versions : 2,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.merge("key1".to_owned(), MergeOperand::append("!".to_owned()))?;
+
+    let expected = vec![
+        (3, None),
+        (4, Some("value3".to_owned())),
+        (5, Some("value3!".to_owned())),
+    ];
+    assert_eq!(store.history("key1".to_owned())?, expected);
+    assert_eq!(store.get_version("key1".to_owned(), 4)?, Some("value3".to_owned()));
+    assert_eq!(store.get_version("key1".to_owned(), 3)?, None);
+    assert!(store.get_version("key1".to_owned(), 2).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3!".to_owned()));
+
+    store.compact()?;
+    assert_eq!(store.history("key1".to_owned())?, expected);
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.history("key1".to_owned())?, expected);
+    assert_eq!(store.stats()?.get("history_entries"), Some(2));
+
+    // new versions keep counting after a reopen
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.history("key1".to_owned())?, vec![
+        (4, Some("value3".to_owned())),
+        (5, Some("value3!".to_owned())),
+        (6, Some("value4".to_owned())),
+    ]);
+
+    // without kept versions only the current one is known
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1".to_owned())?, vec![(6, Some("value4".to_owned()))]);
+    assert_eq!(store.get_version("key1".to_owned(), 6)?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+fn cache_config(eviction_policy : EvictionPolicy) -> KvStoreConfig {
+    KvStoreConfig {
+        max_keys : 3,
+        eviction_policy,
+        ..KvStoreConfig::default()
+    }
+}
+
+// Should evict keys by the configured policy once the budget is reached
+#[test]
+fn cache_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::with_config(temp_dir.path(), cache_config(EvictionPolicy::Lru))?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.get("key0".to_owned())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.get("evictions"), Some(1));
+    // the eviction tombstone is in the log
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), cache_config(EvictionPolicy::Lru))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.keys()?.len(), 3);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::with_config(temp_dir.path(), cache_config(EvictionPolicy::Lfu))?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        for _ in 0..3 - key_id {
+            store.get(format!("key{}", key_id))?;
+        }
+    }
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.get("key3".to_owned())?;
+    store.set("key4".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::with_config(temp_dir.path(), cache_config(EvictionPolicy::TtlNearest))?;
+    store.set_with_ttl("key0".to_owned(), "value".to_owned(), Duration::from_secs(200))?;
+    store.set_with_ttl("key1".to_owned(), "value".to_owned(), Duration::from_secs(100))?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key4".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the live bytes within the budget, and expire keys after their ttl
+#[test]
+fn cache_budget_and_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_live_bytes : 1024,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        assert!(store.stats()?.get("live_bytes").unwrap() <= 1024);
+    }
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert!(store.stats()?.get("evictions").unwrap() > 0);
+
+    store.set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+    store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(100))?;
+    sleep(Duration::from_millis(10));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.stats()?.get("expirations"), Some(1));
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert!(store.stats()?.get("live_bytes").unwrap() <= 1024);
+
+    // ttls are only kept in cache mode
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_secs(1)).is_err());
+
+    Ok(())
+}
+
+// Should serve hot values from memory and keep them coherent with writes
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_cache_entries : 2,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    for _ in 0..4 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.get("value_cache_hits"), Some(3));
+    assert_eq!(stats.get("value_cache_misses"), Some(1));
+    assert_eq!(stats.get("value_cache_hit_rate"), Some(75));
+
+    // writes must not leave stale values behind
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.merge("key1".to_owned(), MergeOperand::append("!".to_owned()))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3!".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // compaction moves the records but keeps the cached values
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.compact()?;
+    let hits = store.stats()?.get("value_cache_hits").unwrap();
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.get("value_cache_hits"), Some(hits + 1));
+
+    // values read once are only admitted on their second read
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        value_cache_entries : 2,
+        value_cache_admission : CacheAdmission::SecondHit,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..3 {
+        store.get("key1".to_owned())?;
+    }
+    assert_eq!(store.stats()?.get("value_cache_hits"), Some(1));
+    assert_eq!(store.stats()?.get("value_cache_entries"), Some(1));
+
+    Ok(())
+}
+
+fn blob_count(path : &Path) -> usize {
+    fs::read_dir(path)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "blob"))
+        .count()
+}
+
+// Should keep large values in blob files, and remove the blobs nothing refers to
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        blob_threshold : 1024,
+        max_versions : 1,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    let large = "x".repeat(64 * 1024);
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    assert_eq!(blob_count(temp_dir.path()), 1);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    let mut value = String::new();
+    store.get_reader("large".to_owned())?.expect("large value").read_to_string(&mut value)?;
+    assert_eq!(value, large);
+    store.set_from_reader("streamed".to_owned(), &mut Cursor::new(large.clone().into_bytes()))?;
+    assert_eq!(blob_count(temp_dir.path()), 2);
+
+    // the previous version keeps its blob, the one before is removed
+    store.set("large".to_owned(), "y".repeat(2048))?;
+    assert_eq!(store.get_version("large".to_owned(), 2)?, Some(large.clone()));
+    store.set("large".to_owned(), "z".repeat(2048))?;
+    assert_eq!(blob_count(temp_dir.path()), 3);
+
+    // compaction leaves the blobs where they are and the log small
+    store.compact()?;
+    let log_size : u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum();
+    assert!(log_size < 1024);
+
+    // a blob left without its record by a crash is removed on open
+    fs::write(temp_dir.path().join("999.blob"), "orphan")?;
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(blob_count(temp_dir.path()), 3);
+    assert_eq!(store.get("streamed".to_owned())?, Some(large));
+    assert_eq!(store.get("large".to_owned())?, Some("z".repeat(2048)));
+    assert_eq!(store.stats()?.get("blobs"), Some(3));
+
+    store.remove("streamed".to_owned())?;
+    store.remove("large".to_owned())?;
+    // the removals are the kept previous versions now
+    assert_eq!(blob_count(temp_dir.path()), 0);
+    assert_eq!(store.stats()?.get("blobs"), Some(0));
+
+    Ok(())
+}
+
+fn log_size(path : &Path) -> u64 {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// Should read compressed and plain records side by side, and recompress them on compaction
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |key_id : u32| format!("{{\"id\": {}, \"payload\": \"{}\"}}", key_id, "verbose json ".repeat(50));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    drop(store);
+    let plain_size = log_size(temp_dir.path());
+
+    for &compression in &[Codec::Deflate, Codec::Snappy] {
+        let config = KvStoreConfig {
+            compression,
+            ..KvStoreConfig::default()
+        };
+        let mut store = KvStore::with_config(temp_dir.path(), config)?;
+        for key_id in 50..100 {
+            store.set(format!("key{}", key_id), value(key_id))?;
+        }
+        store.set("small".to_owned(), "value".to_owned())?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+        }
+    }
+
+    let config = KvStoreConfig {
+        compression : Codec::Deflate,
+        recompress : true,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    store.compact()?;
+    assert!(log_size(temp_dir.path()) * 2 < plain_size);
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // back to plain records
+    let config = KvStoreConfig {
+        recompress : true,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    store.compact()?;
+    assert!(log_size(temp_dir.path()) > plain_size);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some(value(99)));
+
+    Ok(())
+}
+
+fn store_contains(path : &Path, needle : &str) -> bool {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .any(|content| content.windows(needle.len()).any(|window| window == needle.as_bytes()))
+}
+
+// Should seal records and blobs at rest, refuse plain records unless they are sealed by rekey,
+// and rewrite everything on rekey
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::from_hex(&"0f".repeat(32))?;
+    let new_key = EncryptionKey::from_hex(&"a5".repeat(32))?;
+    let encrypted = |key : &EncryptionKey| KvStoreConfig {
+        blob_threshold : 1024,
+        compression : Codec::Deflate,
+        encryption_key : Some(key.clone()),
+        ..KvStoreConfig::default()
+    };
+    let large = "secret blob ".repeat(200);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "written before".to_owned())?;
+    drop(store);
+
+    // records written before encryption can't be authenticated
+    assert!(KvStore::with_config(temp_dir.path(), encrypted(&key)).is_err());
+    let mut store = KvStore::with_config(temp_dir.path(), KvStoreConfig { allow_plaintext : true, ..encrypted(&key) })?;
+    store.rekey(Some(key.clone()))?;
+    drop(store);
+
+    let mut store = KvStore::with_config(temp_dir.path(), encrypted(&key))?;
+    assert_eq!(store.get("plain".to_owned())?, Some("written before".to_owned()));
+    store.set("key1".to_owned(), "secret value".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set_from_reader("streamed".to_owned(), &mut Cursor::new(large.clone()))?;
+    let mut streamed = String::new();
+    store.get_reader("streamed".to_owned())?.unwrap().read_to_string(&mut streamed)?;
+    assert_eq!(streamed, large);
+    store.compact()?;
+    drop(store);
+    assert!(!store_contains(temp_dir.path(), "secret"));
+    assert!(!store_contains(temp_dir.path(), "written before"));
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::with_config(temp_dir.path(), encrypted(&new_key)).is_err());
+
+    let mut store = KvStore::with_config(temp_dir.path(), encrypted(&key))?;
+    store.rekey(Some(new_key.clone()))?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+    assert!(KvStore::with_config(temp_dir.path(), encrypted(&key)).is_err());
+
+    let mut store = KvStore::with_config(temp_dir.path(), encrypted(&new_key))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("streamed".to_owned())?, Some(large.clone()));
+    store.rekey(None)?;
+    drop(store);
+    assert!(store_contains(temp_dir.path(), "secret value"));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("written before".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    drop(store);
+
+    // a plain blob put in place of a sealed one is refused
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::with_config(temp_dir.path(), encrypted(&key))?;
+    store.set("large".to_owned(), large.clone())?;
+    let blob = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map_or(false, |ext| ext == "blob"))
+        .expect("no blob");
+    fs::write(blob, "injected")?;
+    assert!(store.get("large".to_owned()).is_err());
+    drop(store);
+    // and so is a plain record
+    fs::write(temp_dir.path().join("999.log"), r#"{"Set":{"key":"injected","value":"value"}}"#)?;
+    assert!(KvStore::with_config(temp_dir.path(), encrypted(&key)).is_err());
+
+    Ok(())
+}
+
+// Should give the same answers in every index mode, with and without a sorted index
+#[test]
+fn index_modes() -> Result<()> {
+    for &index_mode in &[IndexMode::Tree, IndexMode::Hashed, IndexMode::Prefix] {
+        for &sorted_index in &[false, true] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let config = KvStoreConfig {
+                index_mode,
+                sorted_index,
+                ..KvStoreConfig::default()
+            };
+            let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+            for key_id in 0..300 {
+                store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+            }
+            store.compact()?;
+            for key_id in (0..300).step_by(3) {
+                store.set(format!("key{:04}", key_id), format!("new{}", key_id))?;
+            }
+            for key_id in (1..300).step_by(3) {
+                store.remove(format!("key{:04}", key_id))?;
+            }
+            store.merge("key0002".to_owned(), MergeOperand::append("!".to_owned()))?;
+            store.set("other".to_owned(), "value".to_owned())?;
+
+            let expected = |key_id : u32| match key_id % 3 {
+                0 => Some(format!("new{}", key_id)),
+                1 => None,
+                _ if key_id == 2 => Some("value2!".to_owned()),
+                _ => Some(format!("value{}", key_id)),
+            };
+            for round in 0..3 {
+                for key_id in 0..300 {
+                    assert_eq!(store.get(format!("key{:04}", key_id))?, expected(key_id));
+                }
+                assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+                let mut keys = store.keys()?;
+                keys.sort();
+                assert_eq!(keys.len(), 201);
+                assert_eq!(keys[0], "key0000");
+                assert_eq!(store.stats()?.get("keys"), Some(201));
+                if sorted_index && round > 0 {
+                    assert!(store.stats()?.get("sorted_index_keys") > Some(0));
+                }
+
+                if round == 0 {
+                    drop(store);
+                    store = KvStore::with_config(temp_dir.path(), config.clone())?;
+                } else {
+                    store.compact()?;
+                }
+            }
+            drop(store);
+            assert_eq!(temp_dir.path().join("sorted.index").exists(), sorted_index);
+        }
+    }
+    Ok(())
+}
+
+// Should rebuild the index from the log when the sorted index doesn't match it
+#[test]
+fn stale_sorted_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let index_path = temp_dir.path().join("sorted.index");
+    let config = KvStoreConfig {
+        index_mode : IndexMode::Hashed,
+        sorted_index : true,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.compact()?;
+    let stale = fs::read(&index_path)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.compact()?;
+    drop(store);
+
+    fs::write(&index_path, stale)?;
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+    }
+
+    // without a sorted index the file is dropped
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), KvStoreConfig::default())?;
+    assert!(!index_path.exists());
+    assert_eq!(store.get("key7".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Should keep secondary indexes and their drops across partial compactions and reopen
+#[test]
+fn secondary_index_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size : 2048,
+        ..KvStoreConfig::default()
+    };
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    store.create_index("old".to_owned(), "doc:".to_owned(), "/n".to_owned())?;
+    store.create_index("by_n".to_owned(), "doc:".to_owned(), "/n".to_owned())?;
+    for key_id in 0..200 {
+        store.set(format!("doc:{:03}", key_id), format!(r#"{{"n":{}}}"#, key_id % 10))?;
+    }
+    store.drop_index("old".to_owned())?;
+    for key_id in (0..200).step_by(2) {
+        store.remove(format!("doc:{:03}", key_id))?;
+    }
+    // only the generations holding the drop and the latest writes
+    let stats = store.stats()?;
+    assert!(stats.get("generations") > Some(2));
+    store.compact_gens(&[2, 3])?;
+    drop(store);
+
+    let mut store = KvStore::with_config(temp_dir.path(), config.clone())?;
+    assert!(store.query("old".to_owned(), IndexQuery::Value(1.into())).is_err());
+    let expected : Vec<String> = (0..200).filter(|key_id| key_id % 10 == 3).map(|key_id| format!("doc:{:03}", key_id)).collect();
+    assert_eq!(store.query("by_n".to_owned(), IndexQuery::Value(3.into()))?, expected);
+    assert_eq!(store.query("by_n".to_owned(), IndexQuery::Value(4.into()))?, Vec::<String>::new());
+    assert_eq!(store.query("by_n".to_owned(), IndexQuery::Range(Some(2.into()), Some(5.into())))?.len(), 40);
+    assert_eq!(store.stats()?.get("secondary_indexes"), Some(1));
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::with_config(temp_dir.path(), config)?;
+    assert_eq!(store.query("by_n".to_owned(), IndexQuery::Value(3.into()))?, expected);
+    assert!(store.query("old".to_owned(), IndexQuery::Value(1.into())).is_err());
+
+    Ok(())
+}
+
+// Should load the secondary indexes from their entries in the log, and index
+// a key by its value once a crash cut its entries off
+#[test]
+fn secondary_index_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("doc:{:02}", key_id), format!(r#"{{"n":{}}}"#, key_id % 4))?;
+    }
+    store.create_index("by_n".to_owned(), "doc:".to_owned(), "/n".to_owned())?;
+    store.set("doc:00".to_owned(), r#"{"n":3}"#.to_owned())?;
+    store.remove("doc:03".to_owned())?;
+    store.merge("doc:05".to_owned(), MergeOperand::append(" "))?;
+    drop(store);
+
+    let by_n = |store : &mut KvStore| store.query("by_n".to_owned(), IndexQuery::Value(3.into()));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.get("secondary_rebuilt_keys"), Some(0));
+    assert_eq!(by_n(&mut store)?, vec!["doc:00", "doc:07", "doc:11", "doc:15", "doc:19"]);
+    store.set("doc:04".to_owned(), r#"{"n":3}"#.to_owned())?;
+    drop(store);
+
+    // tear the entry record written after the last set
+    let mut logs : Vec<(u64, u64)> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.expect("a directory entry").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .map(|path| {
+            let gen = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()).expect("a generation");
+            (gen, fs::metadata(&path).expect("log metadata").len())
+        })
+        .filter(|&(_, len)| len > 0)
+        .collect();
+    logs.sort_unstable();
+    let (gen, len) = logs.pop().expect("a written generation");
+    let log = fs::OpenOptions::new().write(true).open(temp_dir.path().join(format!("{}.log", gen)))?;
+    log.set_len(len - 1)?;
+    drop(log);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.get("secondary_rebuilt_keys"), Some(1));
+    assert_eq!(by_n(&mut store)?, vec!["doc:00", "doc:04", "doc:07", "doc:11", "doc:15", "doc:19"]);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.get("secondary_rebuilt_keys"), Some(0));
+    assert_eq!(by_n(&mut store)?, vec!["doc:00", "doc:04", "doc:07", "doc:11", "doc:15", "doc:19"]);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use kvsserver::{KvsEngine, LsmConfig, LsmKvStore, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Bound::{Included, Unbounded};
+use tempfile::TempDir;
+
+fn small_config() -> LsmConfig {
+    LsmConfig {
+        memtable_size : 4096,
+        block_size : 512,
+        table_size : 2048,
+        level0_tables : 3,
+        level_base_bytes : 4096,
+        level_multiplier : 2,
+        ..LsmConfig::default()
+    }
+}
+
+// Should spread the tables over several levels and keep every read correct
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmKvStore::with_config(temp_dir.path(), small_config())?;
+    let mut expected = BTreeMap::new();
+    for round in 0..5 {
+        for key_id in 0..1000 {
+            if (key_id + round) % 7 == 0 {
+                if expected.remove(&format!("key{:04}", key_id)).is_some() {
+                    store.remove(format!("key{:04}", key_id))?;
+                }
+            } else {
+                let value = format!("value{}-{}", key_id, round);
+                store.set(format!("key{:04}", key_id), value.clone())?;
+                expected.insert(format!("key{:04}", key_id), value);
+            }
+        }
+    }
+
+    let stats = store.stats()?;
+    assert!(stats.get("compactions") > Some(0));
+    assert!(stats.get("level2_tables") > Some(0));
+    assert!(stats.get("level0_tables") < Some(3));
+    for key_id in 0..1000 {
+        let key = format!("key{:04}", key_id);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert!(store.stats()?.get("bloom_skips") > Some(0));
+
+    let scanned = store.scan(Included("key0100".to_owned()), Unbounded)?;
+    let wanted : Vec<(String, String)> = expected.range("key0100".to_owned()..).map(|(key, value)| (key.clone(), value.clone())).collect();
+    assert_eq!(scanned, wanted);
+
+    drop(store);
+    let mut store = LsmKvStore::with_config(temp_dir.path(), small_config())?;
+    assert_eq!(store.keys()?, expected.keys().cloned().collect::<Vec<_>>());
+
+    // a full compaction leaves no tombstone behind
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.get("table_entries"), Some(expected.len() as u64));
+    assert_eq!(stats.get("level0_tables"), Some(0));
+    Ok(())
+}
+
+// Should replay the write-ahead log, dropping a record torn by a crash
+#[test]
+fn wal_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map_or(false, |ext| ext == "wal"))
+        .expect("a write-ahead log");
+    OpenOptions::new().append(true).open(&wal)?.write_all(b"{\"Set\":{\"key\":\"key3\",\"va")?;
+
+    let mut store = LsmKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = LsmKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats()?.get("memtable_keys"), Some(3));
+    Ok(())
+}
+
+// Should refuse a config whose compaction could never settle
+#[test]
+fn invalid_config() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let configs = vec![
+        LsmConfig { level0_tables : 0, ..small_config() },
+        LsmConfig { level_base_bytes : 0, ..small_config() },
+        LsmConfig { level_multiplier : 0, ..small_config() },
+    ];
+    for config in configs {
+        assert!(LsmKvStore::with_config(temp_dir.path(), config.clone()).is_err(), "{:?} was accepted", config);
+    }
+    assert!(LsmKvStore::with_config(temp_dir.path(), small_config()).is_ok());
+}
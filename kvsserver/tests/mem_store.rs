@@ -0,0 +1,55 @@
+use kvsserver::{KvsEngine, MemKvStore, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let mut store = MemKvStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should snapshot on drop and load the snapshot on open
+#[test]
+fn snapshot_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MemKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    drop(store);
+    let mut store = MemKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should keep the versions and ttls in the snapshot
+#[test]
+fn snapshot_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = MemKvStore::open(temp_dir.path())?.with_max_versions(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+
+    drop(store);
+    let mut store = MemKvStore::open(temp_dir.path())?;
+    let history = store.history("key1".to_owned())?;
+    let values : Vec<Option<String>> = history.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, vec![Some("value1".to_owned()), Some("value3".to_owned())]);
+    assert_eq!(store.stats()?.get("expiring_keys"), Some(1));
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use kvsserver::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MemKvStore, Protocol, Request, Response, Result};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_server(addr : &'static str) {
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        KvsServer::new(KvStore::open(temp_dir.path()).unwrap())
+            .with_workers(4)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+}
+
+// Should answer every pipelined request, whatever order they are waited for in
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4018";
+    spawn_server(addr);
+    for &protocol in &[Protocol::Binary, Protocol::Json, Protocol::Legacy] {
+        let mut client = KvsClient::with_protocol(addr, protocol)?;
+        let sets = (0..500).map(|i| Request::Set(format!("key{}", i), format!("{:?}{}", protocol, i)));
+        let mut handles = client.send_many(sets)?;
+        handles.reverse();
+        for handle in handles {
+            assert_eq!(client.wait(handle)?, Response::Done);
+        }
+
+        let gets = (0..500).map(|i| Request::Get(format!("key{}", i)));
+        let handles = client.send_many(gets.chain(vec![Request::Remove("missing".to_owned())]))?;
+        let mut handles = handles.into_iter();
+        let remove = handles.next_back().unwrap();
+        match client.wait(remove) {
+            Err(KvsError::KeyNotFound) => {},
+            Err(_) if protocol == Protocol::Legacy => {},
+            result => panic!("{:?} over {:?}", result, protocol),
+        }
+        for (i, handle) in handles.enumerate() {
+            assert_eq!(client.wait(handle)?, Response::Value(Some(format!("{:?}{}", protocol, i))));
+        }
+        // the plain calls go on after the pipeline
+        assert_eq!(client.get("key0".to_owned())?, Some(format!("{:?}0", protocol)));
+    }
+    Ok(())
+}
+
+// Should serve connections at the same time
+#[test]
+fn concurrent_connections() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    spawn_server(addr);
+    let clients : Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::new(addr)?;
+                let handles = client.send_many((0..100).map(|_| Request::Incr("counter".to_owned(), 1)))?;
+                for handle in handles {
+                    match client.wait(handle)? {
+                        Response::Merged(_) => {},
+                        response => panic!("unexpected {:?}", response),
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.incr("counter".to_owned(), 0)?, 800);
+    Ok(())
+}
+
+// Should match responses sent out of order to their requests by id
+#[test]
+fn out_of_order_responses() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    let listener = TcpListener::bind(addr)?;
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut offer = [0; 5];
+        stream.read_exact(&mut offer).unwrap();
+        // answer in JSON whatever the offer
+        stream.write_all(b"\x89KVS\x00").unwrap();
+
+        let mut requests = serde_json::Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Value>();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(requests.next().unwrap().unwrap());
+        }
+        for request in received.iter().rev() {
+            let key = request[1]["Get"].as_str().unwrap();
+            let response = json!([request[0], {"Value": format!("value of {}", key)}]);
+            serde_json::to_writer(&mut stream, &response).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.protocol(), Protocol::Json);
+    let handles = client.send_many(vec![
+        Request::Get("key1".to_owned()),
+        Request::Get("key2".to_owned()),
+        Request::Get("key3".to_owned()),
+    ])?;
+    let ids : Vec<u64> = handles.iter().map(|handle| handle.id()).collect();
+    assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(client.wait(handle)?, Response::Value(Some(format!("value of key{}", i + 1))));
+    }
+    server.join().unwrap();
+    Ok(())
+}
+
+/// An engine panicking on the key "panic"
+struct PanickyEngine(MemKvStore);
+
+impl KvsEngine for PanickyEngine {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        if key == "panic" {
+            panic!("asked to panic");
+        }
+        self.0.get(key)
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.0.keys()
+    }
+}
+
+// Should answer a panicking request with an internal error, and refuse every
+// later request since the engine may have been left half updated
+#[test]
+fn panicking_requests() -> Result<()> {
+    let addr = "127.0.0.1:4032";
+    thread::spawn(move || {
+        KvsServer::new(PanickyEngine(MemKvStore::new())).with_workers(2).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::with_protocol(addr, Protocol::Binary)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    // more panics than workers, only the first one runs
+    let handles = client.send_many((0..5).map(|_| Request::Get("panic".to_owned())))?;
+    let mut panicked = 0;
+    for handle in handles {
+        match client.wait(handle) {
+            Err(KvsError::StringError(ref message)) if message.contains("asked to panic") => panicked += 1,
+            Err(KvsError::StringError(ref message)) if message.contains("must be restarted") => {},
+            result => panic!("{:?}", result),
+        }
+    }
+    assert_eq!(panicked, 1);
+    for &protocol in &[Protocol::Binary, Protocol::Legacy] {
+        let mut client = KvsClient::with_protocol(addr, protocol)?;
+        match client.get("key1".to_owned()) {
+            Err(KvsError::StringError(ref message)) if message.contains("must be restarted") => {},
+            result => panic!("{:?} over {:?}", result, protocol),
+        }
+    }
+    Ok(())
+}
+
+// Should answer every request of a pipeline longer than the requests in flight
+#[test]
+fn bounded_in_flight() -> Result<()> {
+    let addr = "127.0.0.1:4033";
+    thread::spawn(move || {
+        KvsServer::new(MemKvStore::new()).with_workers(2).with_max_in_flight(1).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::with_protocol(addr, Protocol::Binary)?;
+    let handles = client.send_many((0..200).map(|i| Request::Set(format!("key{}", i), format!("value{}", i))))?;
+    for handle in handles {
+        assert_eq!(client.wait(handle)?, Response::Done);
+    }
+    let handles = client.send_many((0..200).map(|i| Request::Get(format!("key{}", i))))?;
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(client.wait(handle)?, Response::Value(Some(format!("value{}", i))));
+    }
+    Ok(())
+}
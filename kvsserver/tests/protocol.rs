@@ -0,0 +1,245 @@
+use kvsserver::{ErrorCode, IndexQuery, KvStore, KvsClient, KvsError, KvsServer, Protocol, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_server(addr : &'static str, max_frame_size : usize) {
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        KvsServer::new(KvStore::open(temp_dir.path()).unwrap())
+            .with_max_frame_size(max_frame_size)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+}
+
+/// the handshake of a binary client, done by hand
+fn binary_stream(addr : &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"\x89KVS\x01").unwrap();
+    let mut answer = [0; 5];
+    stream.read_exact(&mut answer).unwrap();
+    assert_eq!(&answer, b"\x89KVS\x01");
+    stream
+}
+
+fn write_frame(stream : &mut TcpStream, body : &[u8]) {
+    stream.write_all(&(body.len() as u32).to_be_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+}
+
+fn read_frame(stream : &mut TcpStream) -> Vec<u8> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body).unwrap();
+    body
+}
+
+fn exercise(client : &mut KvsClient) -> Result<()> {
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    client.set("ünïcode".to_owned(), "x".repeat(1000))?;
+    assert_eq!(client.get("ünïcode".to_owned())?, Some("x".repeat(1000)));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    assert_eq!(client.incr("counter".to_owned(), -300)?, -300);
+    assert_eq!(client.incr("counter".to_owned(), 1 << 40)?, (1 << 40) - 300);
+    assert_eq!(client.append("log".to_owned(), "a".to_owned())?, "a");
+    assert_eq!(client.append("log".to_owned(), "b".to_owned())?, "ab");
+
+    client.set("user:1".to_owned(), r#"{"age":30}"#.to_owned())?;
+    client.set("user:2".to_owned(), r#"{"age":25}"#.to_owned())?;
+    client.create_index("by_age".to_owned(), "user:".to_owned(), "/age".to_owned())?;
+    assert_eq!(client.query("by_age".to_owned(), IndexQuery::Value(json!(30)))?, vec!["user:1".to_owned()]);
+    assert_eq!(
+        client.query("by_age".to_owned(), IndexQuery::Range(Some(json!(20)), None))?,
+        vec!["user:2".to_owned(), "user:1".to_owned()]
+    );
+    client.drop_index("by_age".to_owned())?;
+    assert!(client.query("by_age".to_owned(), IndexQuery::Range(None, None)).is_err());
+    Ok(())
+}
+
+// Should negotiate the binary protocol and serve every request with it
+#[test]
+fn binary_round_trip() -> Result<()> {
+    let addr = "127.0.0.1:4012";
+    spawn_server(addr, 1 << 20);
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.protocol(), Protocol::Binary);
+    exercise(&mut client)
+}
+
+// Should still serve JSON, negotiated or sent by a client without the handshake
+#[test]
+fn json_fallback() -> Result<()> {
+    let addr = "127.0.0.1:4013";
+    spawn_server(addr, 1 << 20);
+    let mut client = KvsClient::with_protocol(addr, Protocol::Json)?;
+    assert_eq!(client.protocol(), Protocol::Json);
+    exercise(&mut client)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Set":["legacy","value"]}{"Get":"legacy"}"#)?;
+    let mut responses = serde_json::Deserializer::from_reader(stream).into_iter::<Value>();
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": null}));
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": "value"}));
+
+    // a malformed request is answered with a protocol error before the connection closes
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"\x89KVS\x00")?;
+    let mut answer = [0; 5];
+    stream.read_exact(&mut answer)?;
+    stream.write_all(br#"[1,{"Nope":"key"}]"#)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let reply : Value = serde_json::from_str(&reply)?;
+    assert_eq!(reply[0], json!(0));
+    assert!(reply[1].get("Err").is_some(), "{}", reply);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Nope":"key"}"#)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    let reply : Value = serde_json::from_str(&reply)?;
+    assert!(reply["Err"].as_str().map_or(false, |err| err.contains("Nope")), "{}", reply);
+    Ok(())
+}
+
+// Should answer a malformed frame with an error and keep the connection
+#[test]
+fn malformed_frames() -> Result<()> {
+    let addr = "127.0.0.1:4014";
+    spawn_server(addr, 1 << 20);
+    let mut client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut stream = binary_stream(addr);
+    // an unknown request type, a truncated get and a get with trailing bytes, after their ids
+    for body in &[&b"\x05\xff"[..], &b"\x06\x01\x05ke"[..], &b"\x07\x01\x04key1\x00"[..]] {
+        write_frame(&mut stream, body);
+        let response = read_frame(&mut stream);
+        assert_eq!(response[..3], [body[0], 6, ErrorCode::Protocol as u8], "{:?} wasn't refused", body);
+    }
+    write_frame(&mut stream, b"\x08\x01\x04key1");
+    assert_eq!(read_frame(&mut stream), b"\x08\x01\x01\x06value1");
+    Ok(())
+}
+
+// Should refuse a frame beyond the maximum size and close the connection
+#[test]
+fn oversize_frame() -> Result<()> {
+    let addr = "127.0.0.1:4015";
+    spawn_server(addr, 1024);
+    let mut stream = binary_stream(addr);
+    stream.write_all(&(1u32 << 30).to_be_bytes())?;
+    let response = read_frame(&mut stream);
+    assert_eq!(response[..3], [0, 6, ErrorCode::Protocol as u8]);
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+
+    // values up to the limit still go through, in JSON as well, and the refusal comes back as it was sent
+    for &protocol in &[Protocol::Binary, Protocol::Json] {
+        let mut client = KvsClient::with_protocol(addr, protocol)?;
+        client.set("key1".to_owned(), "x".repeat(1000))?;
+        match client.set("key2".to_owned(), "x".repeat(2000)) {
+            Err(KvsError::Protocol(message)) => assert!(message.contains("exceeds the maximum of 1024"), "{}", message),
+            result => panic!("{:?} over {:?}", result, protocol),
+        }
+    }
+    Ok(())
+}
+
+// Should turn the errors sent by the server back into the errors of the engine
+#[test]
+fn typed_errors() -> Result<()> {
+    let addr = "127.0.0.1:4017";
+    spawn_server(addr, 1 << 20);
+    for &protocol in &[Protocol::Binary, Protocol::Json, Protocol::Legacy] {
+        let mut client = KvsClient::with_protocol(addr, protocol)?;
+        assert_eq!(client.protocol(), protocol);
+        client.set("key1".to_owned(), "value1".to_owned())?;
+
+        let errors = vec![
+            client.remove("missing".to_owned()).unwrap_err(),
+            client.get_version("key1".to_owned(), 1000).unwrap_err(),
+            client.query("missing".to_owned(), IndexQuery::Range(None, None)).unwrap_err(),
+            client.set_with_ttl("key1".to_owned(), "value1".to_owned(), 10).unwrap_err(),
+            client.incr("key1".to_owned(), 1).unwrap_err(),
+        ];
+        if protocol == Protocol::Legacy {
+            // only the message is known to clients without the handshake
+            assert_eq!(errors[0].to_string(), "Key not found");
+            assert!(errors.iter().all(|err| err.code() == ErrorCode::Internal));
+            continue;
+        }
+        match &errors[..] {
+            [KvsError::KeyNotFound, KvsError::VersionNotFound(1000), KvsError::IndexNotFound(index), KvsError::Unsupported(_), KvsError::Merge(_)] => {
+                assert_eq!(index, "missing")
+            },
+            errors => panic!("{:?} over {:?}", errors, protocol),
+        }
+    }
+    Ok(())
+}
+
+// Should keep the kind of every error through its code and message
+#[test]
+fn error_codes_round_trip() {
+    let errors = vec![
+        KvsError::KeyNotFound,
+        KvsError::VersionNotFound(7),
+        KvsError::IndexNotFound("by_age".to_owned()),
+        KvsError::Unsupported("ttl".to_owned()),
+        KvsError::Merge("not an integer".to_owned()),
+        KvsError::Io(std::io::Error::new(std::io::ErrorKind::Other, "disk full")),
+        KvsError::Corruption("bad checksum".to_owned()),
+        KvsError::Protocol("truncated message".to_owned()),
+        KvsError::Unauthorized("no token".to_owned()),
+        KvsError::Throttled("too many requests".to_owned()),
+        KvsError::Conflict("version 3 expected".to_owned()),
+        KvsError::StringError("something".to_owned()),
+    ];
+    for err in errors {
+        let rebuilt = KvsError::from_code(err.code(), err.message());
+        assert_eq!(rebuilt.code(), err.code());
+        assert_eq!(rebuilt.to_string(), err.to_string());
+    }
+}
+
+// Should fall back to the legacy protocol with a server which predates the handshake
+#[test]
+fn client_falls_back_to_legacy() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let listener = TcpListener::bind(addr)?;
+    let server = thread::spawn(move || {
+        // a JSON server fails to parse the handshake and drops the connection
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        assert_eq!(reader.fill_buf().unwrap()[0], 0x89);
+        drop(reader);
+
+        let (stream, _) = listener.accept().unwrap();
+        let requests = serde_json::Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Value>();
+        let mut writer = stream;
+        for request in requests {
+            assert_eq!(request.unwrap(), json!({"Get": "key1"}));
+            writer.write_all(br#"{"Ok":"value1"}"#).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.protocol(), Protocol::Legacy);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    server.join().unwrap();
+    Ok(())
+}
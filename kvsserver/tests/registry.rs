@@ -0,0 +1,116 @@
+use kvsserver::{conformance, engine_names, open_data_dir, open_engine, register_engine};
+use kvsserver::{EngineDescriptor, EngineOptions, KvsEngine, KvsError, MemKvStore, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Bound::Unbounded;
+use tempfile::TempDir;
+
+// Should run every built-in engine through the registry
+#[test]
+fn builtin_engines() -> Result<()> {
+    let names = engine_names();
+    for name in &["kvs", "sled", "lsm", "btree", "memory"] {
+        assert!(names.iter().any(|registered| registered == name), "{} is registered", name);
+    }
+    conformance::run(|path| open_engine("btree", path, &EngineOptions::new()))?;
+    conformance::run(|path| {
+        let mut options = EngineOptions::new();
+        options.set("max-versions", "2").set("index", "prefix");
+        open_engine("kvs", path, &options)
+    })
+}
+
+// Should open an engine registered by another crate and guard its data directory
+#[test]
+fn custom_engine() -> Result<()> {
+    register_engine("snapshots", true, |path, _| Ok(Box::new(MemKvStore::open(path)?)));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut engine = open_data_dir("snapshots", temp_dir.path(), &EngineOptions::new())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    assert_eq!(EngineDescriptor::read(temp_dir.path())?, Some(EngineDescriptor::new("snapshots")));
+
+    let mut engine = open_data_dir("snapshots", temp_dir.path(), &EngineOptions::new())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(engine);
+    assert!(open_data_dir("kvs", temp_dir.path(), &EngineOptions::new()).is_err());
+
+    match open_engine("missing", temp_dir.path(), &EngineOptions::new()) {
+        Err(KvsError::UnknownEngine(name)) => assert_eq!(name, "missing"),
+        _ => panic!("opened an unregistered engine"),
+    }
+    Ok(())
+}
+
+/// an engine implementing only the required methods
+#[derive(Default)]
+struct MinimalEngine(HashMap<String, String>);
+
+impl KvsEngine for MinimalEngine {
+    fn set(&mut self, key : String, value : String) -> Result<()> {
+        self.0.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key : String) -> Result<Option<String>> {
+        Ok(self.0.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key : String) -> Result<()> {
+        self.0.remove(&key).map(|_| ()).ok_or(KvsError::KeyNotFound)
+    }
+}
+
+// Should serve an engine with the required methods only, listing keys is unsupported
+#[test]
+fn minimal_engine() -> Result<()> {
+    register_engine("minimal", false, |_, _| Ok(Box::new(MinimalEngine::default())));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut engine = open_data_dir("minimal", temp_dir.path(), &EngineOptions::new())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    match engine.keys() {
+        Err(KvsError::Unsupported(_)) => {},
+        other => panic!("keys of the minimal engine returned {:?}", other),
+    }
+    assert!(engine.scan(Unbounded, Unbounded).is_err());
+    Ok(())
+}
+
+// Should read legacy markers, rewrite them as descriptors and refuse newer ones
+#[test]
+fn engine_descriptor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let marker = temp_dir.path().join("engine");
+    fs::write(&marker, "lsm")?;
+    assert_eq!(
+        EngineDescriptor::read(temp_dir.path())?,
+        Some(EngineDescriptor { version : 0, engine : "lsm".to_owned() })
+    );
+    open_data_dir("lsm", temp_dir.path(), &EngineOptions::new())?;
+    assert_eq!(EngineDescriptor::read(temp_dir.path())?, Some(EngineDescriptor::new("lsm")));
+
+    fs::write(&marker, r#"{"version":99,"engine":"lsm"}"#)?;
+    assert!(EngineDescriptor::read(temp_dir.path()).is_err());
+    assert!(open_data_dir("lsm", temp_dir.path(), &EngineOptions::new()).is_err());
+
+    // the memory engine leaves the directory alone
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    open_data_dir("memory", temp_dir.path(), &EngineOptions::new())?;
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+    Ok(())
+}
+
+// Should reject options which don't parse
+#[test]
+fn invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = EngineOptions::new();
+    options.set("page-size", "large");
+    assert!(open_engine("btree", temp_dir.path(), &options).is_err());
+    let mut options = EngineOptions::new();
+    options.set("eviction", "random");
+    assert!(open_engine("kvs", temp_dir.path(), &options).is_err());
+}
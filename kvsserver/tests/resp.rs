@@ -0,0 +1,319 @@
+use assert_cmd::prelude::*;
+use kvsserver::{KvStore, KvStoreConfig, KvsServer};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A reply of the server, both RESP2 and RESP3 types
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+use Reply::*;
+
+fn bulk(s : &str) -> Reply {
+    Bulk(s.to_owned())
+}
+
+fn ok() -> Reply {
+    Simple("OK".to_owned())
+}
+
+/// A tiny RESP client
+struct Client {
+    reader : BufReader<TcpStream>,
+    writer : TcpStream,
+}
+
+impl Client {
+    fn connect(addr : &str) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        Client { reader : BufReader::new(stream.try_clone().unwrap()), writer : stream }
+    }
+
+    fn send(&mut self, args : &[&str]) {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, args : &[&str]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "{:?} doesn't end with CRLF", line);
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Simple(rest.to_owned()),
+            "-" => Error(rest.to_owned()),
+            ":" => Integer(rest.parse().unwrap()),
+            "_" => Null,
+            "$" if rest == "-1" => Null,
+            "$" => {
+                let mut value = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut value).unwrap();
+                value.truncate(value.len() - 2);
+                Bulk(String::from_utf8(value).unwrap())
+            },
+            "*" => Array((0..rest.parse().unwrap()).map(|_| self.read()).collect()),
+            "%" => Map((0..rest.parse().unwrap()).map(|_| (self.read(), self.read())).collect()),
+            _ => panic!("unknown reply {:?}", line),
+        }
+    }
+}
+
+fn spawn_server(addr : &'static str) {
+    thread::spawn(move || {
+        let temp_dir = TempDir::new().unwrap();
+        // the cache mode expires keys
+        let config = KvStoreConfig { max_keys : 10000, ..KvStoreConfig::default() };
+        KvsServer::new(KvStore::with_config(temp_dir.path(), config).unwrap())
+            .run_resp(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+}
+
+// Should map the string commands onto the engine
+#[test]
+fn resp_commands() {
+    let addr = "127.0.0.1:4021";
+    spawn_server(addr);
+    let mut client = Client::connect(addr);
+
+    assert_eq!(client.call(&["PING"]), Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["ping", "hello"]), bulk("hello"));
+    assert_eq!(client.call(&["GET", "key1"]), Null);
+    assert_eq!(client.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(client.call(&["SET", "key1", "value2", "NX"]), Null);
+    assert_eq!(client.call(&["SET", "key2", "value2", "xx"]), Null);
+    assert_eq!(client.call(&["SET", "key2", "value2", "NX"]), ok());
+    assert_eq!(client.call(&["SET", "key1", "value3", "XX"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value3"));
+
+    assert_eq!(client.call(&["EXISTS", "key1", "key2", "missing", "key1"]), Integer(3));
+    assert_eq!(client.call(&["DEL", "key1", "missing", "key2"]), Integer(2));
+    assert_eq!(client.call(&["EXISTS", "key1"]), Integer(0));
+
+    assert_eq!(client.call(&["MSET", "a", "1", "b", "2", "c", "3"]), ok());
+    assert_eq!(client.call(&["MGET", "a", "missing", "c"]), Array(vec![bulk("1"), Null, bulk("3")]));
+    assert_eq!(client.call(&["INCR", "a"]), Integer(2));
+    assert_eq!(client.call(&["INCR", "counter"]), Integer(1));
+    assert_eq!(client.call(&["GET", "a"]), bulk("2"));
+    client.call(&["SET", "text", "abc"]);
+    assert_eq!(client.call(&["INCR", "text"]), Error("ERR value is not an integer or out of range".to_owned()));
+
+    assert_eq!(client.call(&["SET", "ttl", "value", "EX", "1"]), ok());
+    assert_eq!(client.call(&["SET", "ttl2", "value", "PX", "100000"]), ok());
+    assert_eq!(client.call(&["GET", "ttl"]), bulk("value"));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.call(&["GET", "ttl"]), Null);
+    assert_eq!(client.call(&["GET", "ttl2"]), bulk("value"));
+
+    match client.call(&["INFO"]) {
+        Bulk(info) => {
+            assert!(info.contains("# Server\r\nkvs_version:"));
+            assert!(info.contains("# Keyspace\r\ndb0:keys="));
+        },
+        reply => panic!("unexpected {:?}", reply),
+    }
+    match client.call(&["INFO", "keyspace"]) {
+        Bulk(info) => {
+            assert!(!info.contains("# Server"));
+            // only ttl2 is still to expire
+            assert!(info.contains(",expires=1,"), "{}", info);
+        },
+        reply => panic!("unexpected {:?}", reply),
+    }
+    assert_eq!(client.call(&["QUIT"]), ok());
+    assert_eq!(client.reader.read(&mut [0; 1]).unwrap(), 0);
+}
+
+// Should reply with errors to bad commands and keep the connection
+#[test]
+fn resp_errors() {
+    let addr = "127.0.0.1:4022";
+    spawn_server(addr);
+    let mut client = Client::connect(addr);
+
+    assert_eq!(client.call(&["NOPE", "x"]), Error("ERR unknown command 'NOPE'".to_owned()));
+    assert_eq!(client.call(&["GET"]), Error("ERR wrong number of arguments for 'get' command".to_owned()));
+    assert_eq!(client.call(&["MSET", "a", "1", "b"]), Error("ERR wrong number of arguments for 'mset' command".to_owned()));
+    assert_eq!(client.call(&["SET", "a", "1", "NX", "XX"]), Error("ERR syntax error".to_owned()));
+    assert_eq!(client.call(&["SET", "a", "1", "EX", "0"]), Error("ERR invalid expire time in 'set' command".to_owned()));
+    assert_eq!(client.call(&["SET", "a", "1", "EX"]), Error("ERR syntax error".to_owned()));
+    assert_eq!(client.call(&["SCAN", "x"]), Error("ERR invalid cursor".to_owned()));
+    assert_eq!(client.call(&["HELLO", "4"]), Error("NOPROTO unsupported protocol version".to_owned()));
+    assert_eq!(client.call(&["GET", "a"]), Null);
+
+    // inline commands, as typed in telnet
+    client.writer.write_all(b"SET inline value\r\nGET inline\r\n\r\n").unwrap();
+    assert_eq!(client.read(), ok());
+    assert_eq!(client.read(), bulk("value"));
+
+    // a broken stream is refused and closed
+    client.writer.write_all(b"*1\r\n+GET\r\n").unwrap();
+    match client.read() {
+        Error(err) => assert!(err.starts_with("ERR Protocol error"), "{}", err),
+        reply => panic!("unexpected {:?}", reply),
+    }
+    assert_eq!(client.reader.read(&mut [0; 1]).unwrap(), 0);
+}
+
+// Should scan every key in pages, with patterns
+#[test]
+fn resp_scan() {
+    let addr = "127.0.0.1:4023";
+    spawn_server(addr);
+    let mut client = Client::connect(addr);
+    for i in 0..25 {
+        client.call(&["SET", &format!("user:{:02}", i), "x"]);
+    }
+    client.call(&["SET", "other", "x"]);
+
+    let scan = |client : &mut Client, options : &[&str]| {
+        let mut cursor = "0".to_owned();
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        loop {
+            let mut args = vec!["SCAN", &cursor];
+            args.extend_from_slice(options);
+            match client.call(&args) {
+                Array(mut reply) => {
+                    match reply.pop() {
+                        Some(Array(found)) => keys.extend(found),
+                        reply => panic!("unexpected {:?}", reply),
+                    }
+                    match reply.pop() {
+                        Some(Bulk(next)) => cursor = next,
+                        reply => panic!("unexpected {:?}", reply),
+                    }
+                },
+                reply => panic!("unexpected {:?}", reply),
+            }
+            pages += 1;
+            if cursor == "0" {
+                return (keys, pages);
+            }
+        }
+    };
+
+    let (keys, pages) = scan(&mut client, &[]);
+    assert_eq!(keys.len(), 26);
+    assert_eq!(pages, 3);
+    let (keys, pages) = scan(&mut client, &["MATCH", "user:1?", "COUNT", "5"]);
+    assert_eq!(keys, (10..20).map(|i| Bulk(format!("user:{}", i))).collect::<Vec<_>>());
+    assert_eq!(pages, 6);
+    let (keys, _) = scan(&mut client, &["MATCH", "user:[02]*", "COUNT", "100"]);
+    assert_eq!(keys.len(), 10 + 5);
+    let (keys, _) = scan(&mut client, &["MATCH", "oth*"]);
+    assert_eq!(keys, vec![bulk("other")]);
+
+    // removing the keys already returned doesn't make the scan skip the others
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let (next, found) = match client.call(&["SCAN", &cursor, "COUNT", "4"]) {
+            Array(mut reply) => match (reply.pop(), reply.pop()) {
+                (Some(Array(found)), Some(Bulk(next))) => (next, found),
+                reply => panic!("unexpected {:?}", reply),
+            },
+            reply => panic!("unexpected {:?}", reply),
+        };
+        for key in &found {
+            if let Bulk(key) = key {
+                assert_eq!(client.call(&["DEL", key]), Integer(1));
+            }
+        }
+        keys.extend(found);
+        cursor = next;
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 26);
+    assert_eq!(client.call(&["SCAN", "12345"]), Error("ERR invalid cursor".to_owned()));
+}
+
+// Should switch to RESP3 with HELLO and answer pipelined commands
+#[test]
+fn resp3_and_pipelining() {
+    let addr = "127.0.0.1:4024";
+    spawn_server(addr);
+    let mut client = Client::connect(addr);
+
+    match client.call(&["HELLO", "3", "SETNAME", "test"]) {
+        Map(pairs) => {
+            assert!(pairs.contains(&(bulk("server"), bulk("kvs"))));
+            assert!(pairs.contains(&(bulk("proto"), Integer(3))));
+        },
+        reply => panic!("unexpected {:?}", reply),
+    }
+    assert_eq!(client.call(&["GET", "missing"]), Null);
+    assert_eq!(client.call(&["MGET", "missing"]), Array(vec![Null]));
+
+    for i in 0..100 {
+        client.send(&["SET", &format!("key{}", i), &i.to_string()]);
+        client.send(&["GET", &format!("key{}", i)]);
+    }
+    for i in 0..100 {
+        assert_eq!(client.read(), ok());
+        assert_eq!(client.read(), Bulk(i.to_string()));
+    }
+
+    match client.call(&["HELLO", "2"]) {
+        Array(fields) => assert_eq!(fields.len(), 14),
+        reply => panic!("unexpected {:?}", reply),
+    }
+    client.writer.write_all(b"GET missing\r\n").unwrap();
+    assert_eq!(client.read_line(), "$-1");
+}
+
+// `kvs-server --protocol resp` should serve Redis clients
+#[test]
+fn cli_resp_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--protocol", "resp", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = Client::connect(addr);
+    assert_eq!(client.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    child.kill().expect("server exited before killed");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--protocol", "gopher", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}